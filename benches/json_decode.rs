@@ -0,0 +1,64 @@
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use melt::{
+    config::TIMPSTAMP_FIELD_NAME,
+    fusion::{decoder::JsonDecoder, recordbatch, schema},
+    utils::{json, time::parse_timestamp},
+};
+use serde_json::Value;
+
+fn gen_lines(n: usize) -> Bytes {
+    let mut body = String::new();
+    for i in 0..n {
+        body.push_str(&format!(
+            r#"{{"timestamp": {}, "level": "info", "host": "host-{}", "latency": {}.5, "status": {}, "ok": true, "message": "request {} finished"}}"#,
+            1_701_424_800_000 + i as i64,
+            i % 16,
+            i % 1000,
+            200 + i % 5,
+            i
+        ));
+        body.push('\n');
+    }
+    body.into()
+}
+
+// the ingest path before JsonDecoder: parse into `Value`, normalize the
+// timestamp in place, infer the schema and decode through arrow_json
+fn decode_with_values(body: Bytes) -> usize {
+    let mut records = json::parse_lines(body).unwrap();
+    for record in records.iter_mut() {
+        let stamp = parse_timestamp(TIMPSTAMP_FIELD_NAME, record).unwrap();
+        record
+            .as_object_mut()
+            .unwrap()
+            .insert(TIMPSTAMP_FIELD_NAME.into(), Value::from(stamp));
+    }
+    let schema = schema::infer_schema(&records).unwrap();
+    let batch = recordbatch::json_to_recordbatch(&schema, &records).unwrap();
+    batch.num_rows()
+}
+
+fn decode_with_decoder(body: Bytes) -> usize {
+    let mut decoder = JsonDecoder::new();
+    for line in body.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+        decoder.decode(line).unwrap();
+    }
+    decoder.flush().unwrap().unwrap().num_rows()
+}
+
+fn bench_json_decode(c: &mut Criterion) {
+    let body = gen_lines(8192);
+    let mut group = c.benchmark_group("json_decode");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    group.bench_function("json_to_recordbatch", |b| {
+        b.iter(|| decode_with_values(black_box(body.clone())))
+    });
+    group.bench_function("json_decoder", |b| {
+        b.iter(|| decode_with_decoder(black_box(body.clone())))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_json_decode);
+criterion_main!(benches);
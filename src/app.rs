@@ -1,18 +1,20 @@
-use crate::{ingest::IngestService, storage::Storage};
+use crate::{config::Config, ingest::IngestService, storage::Storage};
 
 // #[derive(Clone,Copy)]
 pub struct AppState {
     app_name: String,
     service: IngestService,
+    config: Config,
 }
 
 impl AppState {
-    pub fn new(name: &str, root: &str) -> AppState {
-        let storage = Storage::new(root);
-        let service = IngestService::new(storage);
+    pub fn new(name: &str, config: &Config) -> AppState {
+        let storage = Storage::new(&config.root);
+        let service = IngestService::new(storage).with_max_encode_tasks(config.max_encode_tasks);
         AppState {
             app_name: name.to_owned(),
             service,
+            config: config.clone(),
         }
     }
     pub fn app_name(&self) -> &str {
@@ -21,4 +23,7 @@ impl AppState {
     pub fn service(&self) -> &IngestService {
         &self.service
     }
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
 }
@@ -15,8 +15,8 @@ use std::sync::Arc;
 // tranlate data
 // merge data
 pub async fn merge_segments(
-    schema: Arc<Schema>,
-    records: Vec<RecordBatch>,
+    _schema: Arc<Schema>,
+    _records: Vec<RecordBatch>,
 ) -> Result<(), anyhow::Error> {
     Ok(())
 }
@@ -27,7 +27,7 @@ mod tests {
 
     #[test]
     fn test_name() {
-        let items = vec![Schema::empty()];
+        let _items = [Schema::empty()];
         // merge_schema(items.iter());
     }
 }
@@ -1,3 +1,274 @@
+use std::{collections::HashMap, path::Path};
+
+use serde_derive::{Deserialize, Serialize};
+
 pub static TIMPSTAMP_FIELD_NAME: &str = "timestamp";
+// segments with this column get a trace id bloom filter in their meta
+pub static TRACE_ID_FIELD_NAME: &str = "trace_id";
 pub static PARQUET_EXT: &str = "parquet";
 pub static SCHEMA_EXT: &str = "schema";
+
+// width of a table partition
+pub static HOUR_MICROS: i64 = 3600 * 1_000_000;
+
+// limit of request bodies as sent on the wire
+pub static MAX_PAYLOAD_SIZE: usize = 10 * 1024 * 1024;
+// limit of ingest bodies after Content-Encoding is removed
+pub static MAX_DECOMPRESSED_SIZE: usize = 100 * 1024 * 1024;
+// number of records a streaming ingest buffers before writing them out
+pub static STREAM_BATCH_SIZE: usize = 8192;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub addr: String,
+    pub port: u16,
+    pub root: String,
+    /// number of parquet encodings allowed to run at the same time
+    pub max_encode_tasks: usize,
+    pub otlp: OtlpConfig,
+    pub prometheus: PrometheusConfig,
+    pub loki: LokiConfig,
+    pub influx: InfluxConfig,
+    pub syslog: SyslogConfig,
+    pub fluent: FluentConfig,
+    pub flight: FlightConfig,
+    pub postgres: PostgresConfig,
+    pub tail: TailConfig,
+    pub zipkin: ZipkinConfig,
+    pub jaeger: JaegerConfig,
+}
+
+/// Tables the OpenTelemetry endpoints write into. Metrics keep `__name__`
+/// and `value` like Prometheus samples and their labels in one JSON column
+/// PromQL reads, a `metrics_table` set to the Prometheus table can be
+/// queried with PromQL.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtlpConfig {
+    pub logs_table: String,
+    pub traces_table: String,
+    pub metrics_table: String,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        OtlpConfig {
+            logs_table: "otel_logs".to_owned(),
+            traces_table: "otel_traces".to_owned(),
+            metrics_table: "otel_metrics".to_owned(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: "127.0.0.1".to_owned(),
+            port: 8080,
+            root: "data".to_owned(),
+            max_encode_tasks: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            otlp: OtlpConfig::default(),
+            prometheus: PrometheusConfig::default(),
+            loki: LokiConfig::default(),
+            influx: InfluxConfig::default(),
+            syslog: SyslogConfig::default(),
+            fluent: FluentConfig::default(),
+            flight: FlightConfig::default(),
+            postgres: PostgresConfig::default(),
+            tail: TailConfig::default(),
+            zipkin: ZipkinConfig::default(),
+            jaeger: JaegerConfig::default(),
+        }
+    }
+}
+
+/// Table the Prometheus endpoints write into and query.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrometheusConfig {
+    pub table: String,
+}
+
+impl Default for PrometheusConfig {
+    fn default() -> Self {
+        PrometheusConfig {
+            table: "prometheus".to_owned(),
+        }
+    }
+}
+
+/// Tables the Loki push endpoint writes into. Streams carrying the
+/// `table_label` label go to the table it names, the rest to `table`.
+/// Queries, and the label endpoints given a `query`, read the table named
+/// by an equality matcher on `table_label` in their selector.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LokiConfig {
+    pub table: String,
+    pub table_label: Option<String>,
+}
+
+impl Default for LokiConfig {
+    fn default() -> Self {
+        LokiConfig {
+            table: "loki".to_owned(),
+            table_label: None,
+        }
+    }
+}
+
+/// Tables the InfluxDB write endpoints write into: `table` with a
+/// `measurement` column, or one table per measurement with
+/// `measurement_tables`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InfluxConfig {
+    pub table: String,
+    pub measurement_tables: bool,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        InfluxConfig {
+            table: "influx".to_owned(),
+            measurement_tables: false,
+        }
+    }
+}
+
+/// Syslog listeners, off unless an address is set. Messages are written
+/// out every `flush_interval_ms` or sooner when a batch fills up.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyslogConfig {
+    pub tcp_addr: Option<String>,
+    pub udp_addr: Option<String>,
+    pub table: String,
+    pub flush_interval_ms: u64,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        SyslogConfig {
+            tcp_addr: None,
+            udp_addr: None,
+            table: "syslog".to_owned(),
+            flush_interval_ms: 1000,
+        }
+    }
+}
+
+/// Fluentd Forward listener, off unless `addr` is set. Events without an
+/// ack request are written out every `flush_interval_ms`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FluentConfig {
+    pub addr: Option<String>,
+    pub flush_interval_ms: u64,
+}
+
+impl Default for FluentConfig {
+    fn default() -> Self {
+        FluentConfig {
+            addr: None,
+            flush_interval_ms: 1000,
+        }
+    }
+}
+
+/// Arrow Flight service, off unless `addr` is set.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlightConfig {
+    pub addr: Option<String>,
+}
+
+/// PostgreSQL wire protocol listener, off unless `addr` is set. Clients
+/// log in with a user and password of `users`, which must not be empty
+/// unless `trust` is set to accept any login without a password.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PostgresConfig {
+    pub addr: Option<String>,
+    pub users: HashMap<String, String>,
+    pub trust: bool,
+}
+
+/// File inputs, polled every `poll_interval_ms`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TailConfig {
+    pub inputs: Vec<TailInput>,
+    pub poll_interval_ms: u64,
+}
+
+impl Default for TailConfig {
+    fn default() -> Self {
+        TailConfig {
+            inputs: vec![],
+            poll_interval_ms: 1000,
+        }
+    }
+}
+
+/// Files matching any of the `paths` globs are tailed into `table`. With
+/// `multiline_start`, lines not matching it are joined to the line before.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TailInput {
+    pub paths: Vec<String>,
+    pub table: String,
+    #[serde(default)]
+    pub format: LineFormat,
+    #[serde(default)]
+    pub multiline_start: Option<String>,
+}
+
+/// How tailed lines are read: JSON objects keep their fields, other lines
+/// become the `message` field.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineFormat {
+    Json,
+    #[default]
+    Text,
+}
+
+/// Table the Zipkin endpoint writes into, the OTLP traces table when
+/// unset so both kinds of spans share the trace view.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZipkinConfig {
+    pub table: Option<String>,
+}
+
+/// Table the Jaeger query API reads, the OTLP traces table when unset.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JaegerConfig {
+    pub table: Option<String>,
+}
+
+impl Config {
+    /// Loads a JSON config file, settings it leaves out keep their default.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config, anyhow::Error> {
+        let datas = std::fs::read(path)?;
+        Ok(serde_json::from_slice(&datas)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config: Config = serde_json::from_str(r#"{"port": 9200}"#).unwrap();
+        assert_eq!(config.port, 9200);
+        assert_eq!(config.root, "data");
+        assert!(config.max_encode_tasks > 0);
+        assert_eq!(config.otlp.logs_table, "otel_logs");
+    }
+}
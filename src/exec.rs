@@ -1,12 +1,16 @@
-use arrow_schema::Schema;
+use std::collections::HashSet;
+
+use arrow_schema::{Schema, SchemaRef};
 use datafusion::{
     arrow::record_batch::RecordBatch,
     execution::{context::SessionContext, options::ParquetReadOptions},
     logical_expr::{and, ident, lit, or, Expr},
 };
+use futures::{stream, stream::BoxStream, Stream, StreamExt, TryStreamExt};
 
 use crate::{
     config::TIMPSTAMP_FIELD_NAME,
+    fusion::compute,
     query::{ComparisionOperator, LogicOperator, QueryExpr},
 };
 
@@ -17,6 +21,14 @@ pub struct Query {
 }
 
 impl Query {
+    pub fn new(expr: QueryExpr, min_ts: Option<i64>, max_ts: Option<i64>) -> Query {
+        Query {
+            expr,
+            min_ts,
+            max_ts,
+        }
+    }
+
     pub fn from_str(
         s: &str,
         min_ts: Option<i64>,
@@ -37,15 +49,15 @@ impl Query {
         } else {
             expr
         };
-        let expr = if let Some(t) = self.max_ts {
+        if let Some(t) = self.max_ts {
             expr.and(ident(TIMPSTAMP_FIELD_NAME).lt_eq(lit(t)))
         } else {
             expr
-        };
-        expr
+        }
     }
 }
 
+#[allow(clippy::only_used_in_recursion)]
 pub fn queryexpr_to_expr(ops: &QueryExpr, schema: &Schema) -> Expr {
     match ops {
         QueryExpr::LogicalOp(left, ops, right) => match ops {
@@ -91,6 +103,122 @@ pub async fn exec_search(
     Ok(res)
 }
 
+/// Filters every file with the expression `filter` builds from the file
+/// schema, files it returns `None` for are skipped.
+pub async fn exec_filter(
+    files: Vec<String>,
+    filter: impl Fn(&Schema) -> Option<Expr>,
+) -> Result<Vec<RecordBatch>, anyhow::Error> {
+    let mut res = vec![];
+    let ctx = SessionContext::new();
+    for file in files {
+        ctx.register_parquet("t", &file, ParquetReadOptions::default())
+            .await?;
+        let df = ctx.table("t").await?;
+        let schema: Schema = df.schema().into();
+        if let Some(expr) = filter(&schema) {
+            let records = df.filter(expr)?.collect().await?;
+            res.extend_from_slice(&records);
+        }
+        ctx.deregister_table("t")?;
+    }
+    Ok(res)
+}
+
+/// Groups the rows of every file matching `filter` by `group` and computes
+/// `aggr` per group, only the columns they read are loaded. Groups are per
+/// file, a key found in several files comes back once for each.
+pub async fn exec_aggregate(
+    files: Vec<String>,
+    filter: impl Fn(&Schema) -> Option<Expr>,
+    group: Vec<Expr>,
+    aggr: Vec<Expr>,
+) -> Result<Vec<RecordBatch>, anyhow::Error> {
+    let mut res = vec![];
+    let ctx = SessionContext::new();
+    for file in files {
+        ctx.register_parquet("t", &file, ParquetReadOptions::default())
+            .await?;
+        let df = ctx.table("t").await?;
+        let schema: Schema = df.schema().into();
+        if let Some(expr) = filter(&schema) {
+            let records = df
+                .filter(expr)?
+                .aggregate(group.clone(), aggr.clone())?
+                .collect()
+                .await?;
+            res.extend_from_slice(&records);
+        }
+        ctx.deregister_table("t")?;
+    }
+    Ok(res)
+}
+
+/// Reads the schema of every file from its footer, without scanning rows.
+pub async fn exec_schemas(files: Vec<String>) -> Result<Vec<Schema>, anyhow::Error> {
+    let mut res = vec![];
+    let ctx = SessionContext::new();
+    for file in files {
+        ctx.register_parquet("t", &file, ParquetReadOptions::default())
+            .await?;
+        let df = ctx.table("t").await?;
+        res.push(df.schema().into());
+        ctx.deregister_table("t")?;
+    }
+    Ok(res)
+}
+
+/// Streams the rows of every file matching `filter`, in the columns of
+/// `schema` it has and cast to their types. Files without a column the
+/// filter reads are skipped.
+pub fn exec_stream(
+    files: Vec<String>,
+    filter: Expr,
+    schema: SchemaRef,
+) -> impl Stream<Item = Result<RecordBatch, anyhow::Error>> + Send + 'static {
+    stream::iter(files)
+        .then(move |file| file_stream(file, filter.clone(), schema.clone()))
+        .try_flatten()
+}
+
+async fn file_stream(
+    file: String,
+    filter: Expr,
+    schema: SchemaRef,
+) -> Result<BoxStream<'static, Result<RecordBatch, anyhow::Error>>, anyhow::Error> {
+    let ctx = SessionContext::new();
+    let df = ctx
+        .read_parquet(file, ParquetReadOptions::default())
+        .await?;
+    let file_schema: Schema = df.schema().into();
+    let names: HashSet<&str> = file_schema
+        .fields()
+        .iter()
+        .map(|f| f.name().as_str())
+        .collect();
+    if filter
+        .to_columns()?
+        .iter()
+        .any(|c| !names.contains(c.name.as_str()))
+    {
+        return Ok(stream::empty().boxed());
+    }
+    let projection: Vec<&str> = schema
+        .fields()
+        .iter()
+        .map(|f| f.name().as_str())
+        .filter(|n| names.contains(n))
+        .collect();
+    let batches = df
+        .filter(filter)?
+        .select_columns(&projection)?
+        .execute_stream()
+        .await?;
+    Ok(batches
+        .map(move |batch| compute::cast(&schema, batch?))
+        .boxed())
+}
+
 #[cfg(test)]
 mod tests {
     use datafusion::execution::context::SessionContext;
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use arrow_buffer::Buffer;
+use arrow_ipc::{
+    convert::fb_to_schema,
+    reader::{read_dictionary, read_record_batch},
+    root_as_message,
+    writer::{write_message, DictionaryTracker, EncodedData, IpcDataGenerator, IpcWriteOptions},
+    MessageHeader,
+};
+use arrow_schema::{Schema, SchemaRef};
+use datafusion::arrow::{array::ArrayRef, record_batch::RecordBatch};
+use std::sync::Arc;
+
+use super::proto::FlightData;
+
+impl From<EncodedData> for FlightData {
+    fn from(data: EncodedData) -> Self {
+        FlightData {
+            flight_descriptor: None,
+            data_header: data.ipc_message.into(),
+            app_metadata: Default::default(),
+            data_body: data.arrow_data.into(),
+        }
+    }
+}
+
+/// A schema as an encapsulated IPC message, the form of `FlightInfo` and
+/// `SchemaResult`.
+pub fn schema_bytes(schema: &Schema) -> Result<Vec<u8>, anyhow::Error> {
+    let options = IpcWriteOptions::default();
+    let encoded = IpcDataGenerator::default().schema_to_bytes(schema, &options);
+    let mut bytes = vec![];
+    write_message(&mut bytes, encoded, &options)?;
+    Ok(bytes)
+}
+
+/// Writes a stream of batches of one schema as Flight messages.
+pub struct FlightEncoder {
+    generator: IpcDataGenerator,
+    tracker: DictionaryTracker,
+    options: IpcWriteOptions,
+}
+
+impl Default for FlightEncoder {
+    fn default() -> Self {
+        FlightEncoder {
+            generator: IpcDataGenerator::default(),
+            tracker: DictionaryTracker::new(false),
+            options: IpcWriteOptions::default(),
+        }
+    }
+}
+
+impl FlightEncoder {
+    /// The first message of a stream.
+    pub fn encode_schema(&self, schema: &Schema) -> FlightData {
+        self.generator.schema_to_bytes(schema, &self.options).into()
+    }
+
+    /// The dictionaries the batch adds to the stream, then the batch.
+    pub fn encode_batch(&mut self, batch: &RecordBatch) -> Result<Vec<FlightData>, anyhow::Error> {
+        let (dictionaries, batch) =
+            self.generator
+                .encoded_batch(batch, &mut self.tracker, &self.options)?;
+        Ok(dictionaries
+            .into_iter()
+            .chain(std::iter::once(batch))
+            .map(FlightData::from)
+            .collect())
+    }
+}
+
+/// Reads the batches of a stream of Flight messages, the first of which
+/// holds the schema.
+#[derive(Default)]
+pub struct FlightDecoder {
+    schema: Option<SchemaRef>,
+    dictionaries: HashMap<i64, ArrayRef>,
+}
+
+impl FlightDecoder {
+    /// Reads one message, returns the batch it holds if any.
+    pub fn decode(&mut self, data: &FlightData) -> Result<Option<RecordBatch>, anyhow::Error> {
+        if data.data_header.is_empty() {
+            // a message carrying only a descriptor or app metadata
+            return Ok(None);
+        }
+        let message = root_as_message(&data.data_header)
+            .map_err(|e| anyhow!("invalid IPC message: {}", e))?;
+        match message.header_type() {
+            MessageHeader::Schema => {
+                let schema = message
+                    .header_as_schema()
+                    .ok_or_else(|| anyhow!("invalid schema message"))?;
+                self.schema = Some(Arc::new(fb_to_schema(schema)));
+                self.dictionaries.clear();
+                Ok(None)
+            }
+            MessageHeader::DictionaryBatch => {
+                let schema = self.schema()?;
+                let batch = message
+                    .header_as_dictionary_batch()
+                    .ok_or_else(|| anyhow!("invalid dictionary message"))?;
+                read_dictionary(
+                    &Buffer::from(data.data_body.as_ref()),
+                    batch,
+                    &schema,
+                    &mut self.dictionaries,
+                    &message.version(),
+                )?;
+                Ok(None)
+            }
+            MessageHeader::RecordBatch => {
+                let schema = self.schema()?;
+                let batch = message
+                    .header_as_record_batch()
+                    .ok_or_else(|| anyhow!("invalid record batch message"))?;
+                let batch = read_record_batch(
+                    &Buffer::from(data.data_body.as_ref()),
+                    batch,
+                    schema,
+                    &self.dictionaries,
+                    None,
+                    &message.version(),
+                )?;
+                Ok(Some(batch))
+            }
+            t => Err(anyhow!("unexpected IPC message {:?}", t)),
+        }
+    }
+
+    fn schema(&self) -> Result<SchemaRef, anyhow::Error> {
+        self.schema
+            .clone()
+            .ok_or_else(|| anyhow!("record batch before the schema"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fusion::recordbatch::build_tests_recordbatch;
+
+    use super::*;
+
+    #[test]
+    fn test_flight_data() {
+        let (schema, batch) = build_tests_recordbatch();
+        let mut encoder = FlightEncoder::default();
+        let mut messages = vec![encoder.encode_schema(&schema)];
+        messages.extend(encoder.encode_batch(&batch).unwrap());
+        messages.extend(encoder.encode_batch(&batch).unwrap());
+
+        let mut decoder = FlightDecoder::default();
+        let batches: Vec<RecordBatch> = messages
+            .iter()
+            .filter_map(|m| decoder.decode(m).unwrap())
+            .collect();
+        assert_eq!(batches, vec![batch.clone(), batch.clone()]);
+
+        let mut decoder = FlightDecoder::default();
+        assert!(decoder.decode(&messages[1]).is_err());
+    }
+}
@@ -0,0 +1,519 @@
+//! Arrow Flight service. DoPut writes the batches of a stream into the
+//! table its descriptor names, DoGet streams the result of the melt query
+//! in a ticket straight from DataFusion. Descriptors and tickets holding
+//! Flight SQL commands are served by `sql`. Methods not listed in the
+//! routing answer UNIMPLEMENTED.
+
+pub mod data;
+pub mod proto;
+pub mod sql;
+
+use std::{convert::Infallible, sync::Arc};
+
+use actix_web::web;
+use anyhow::anyhow;
+use arrow_schema::SchemaRef;
+use datafusion::arrow::{compute::concat_batches, record_batch::RecordBatch};
+use futures::{stream, StreamExt};
+use prost::Message;
+use serde_derive::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tonic::{
+    codec::ProstCodec,
+    codegen::{http, BoxFuture, BoxStream, Context, Poll, Service, StdError},
+    server::{Grpc, NamedService, ServerStreamingService, StreamingService, UnaryService},
+    Request, Response, Status, Streaming,
+};
+
+use self::{
+    data::{schema_bytes, FlightDecoder, FlightEncoder},
+    proto::{
+        sql::DoPutPreparedStatementResult, Action, ActionResult, ActionType, Empty, FlightData,
+        FlightDescriptor, FlightEndpoint, FlightInfo, HandshakeRequest, HandshakeResponse,
+        PutResult, SchemaResult, Ticket,
+    },
+    sql::{Command, FlightSql},
+};
+use crate::{
+    app::AppState,
+    config::{FlightConfig, STREAM_BATCH_SIZE},
+    ingest::InvalidRecords,
+    storage::is_valid_table_name,
+    utils::net,
+};
+
+// largest message accepted, a batch of a few MiB is typical
+pub static MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// What a DoGet ticket holds, as JSON: a query like the one of `_search`
+/// and the columns to return, all of them when `fields` is empty.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FlightQuery {
+    pub table: String,
+    #[serde(default)]
+    pub query: String,
+    pub start_time: Option<i64>,
+    pub end_time: Option<i64>,
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+/// The table a descriptor names: its first path element, or its command.
+pub fn descriptor_table(descriptor: &FlightDescriptor) -> Result<String, anyhow::Error> {
+    let table = match descriptor.path.first() {
+        Some(table) => table.clone(),
+        None => String::from_utf8(descriptor.cmd.clone())?,
+    };
+    if !is_valid_table_name(&table) {
+        return Err(anyhow!("invalid table name {:?}", table));
+    }
+    Ok(table)
+}
+
+fn invalid(e: anyhow::Error) -> Status {
+    Status::invalid_argument(e.to_string())
+}
+
+// records that can not be stored are refused, a failed write is internal
+// so that clients retry it
+fn ingest_error(e: anyhow::Error) -> Status {
+    if e.is::<InvalidRecords>() {
+        invalid(e)
+    } else {
+        log::error!("Error writing flight data: {:?}", e);
+        Status::internal(e.to_string())
+    }
+}
+
+// actions of Flight SQL prepared statements
+static ACTIONS: [(&str, &str); 2] = [
+    (
+        "CreatePreparedStatement",
+        "Plans a query, returns its handle",
+    ),
+    (
+        "ClosePreparedStatement",
+        "Drops the handle of a prepared query",
+    ),
+];
+
+#[derive(Clone)]
+pub struct FlightService {
+    app: Arc<AppState>,
+    sql: Arc<FlightSql>,
+}
+
+impl FlightService {
+    pub fn new(app: Arc<AppState>) -> FlightService {
+        let sql = Arc::new(FlightSql::new(app.clone()));
+        FlightService { app, sql }
+    }
+
+    async fn handshake(
+        &self,
+        mut requests: Streaming<HandshakeRequest>,
+    ) -> Result<BoxStream<HandshakeResponse>, Status> {
+        // there is no authentication, the first request is echoed
+        let request = requests.message().await?.unwrap_or_default();
+        let response = HandshakeResponse {
+            protocol_version: request.protocol_version,
+            payload: request.payload,
+        };
+        Ok(stream::iter([Ok(response)]).boxed())
+    }
+
+    // the result of a Flight SQL command, or a whole table for a path
+    async fn describe(&self, descriptor: &FlightDescriptor) -> Result<(SchemaRef, Ticket), Status> {
+        if let Some(command) = Command::decode(&descriptor.cmd) {
+            let (schema, ticket) = self
+                .sql
+                .flight_info(command.map_err(invalid)?)
+                .await
+                .map_err(invalid)?;
+            let ticket = Ticket {
+                ticket: ticket.encode(),
+            };
+            return Ok((schema, ticket));
+        }
+        let table = descriptor_table(descriptor).map_err(invalid)?;
+        let schema = match self
+            .app
+            .service()
+            .table_provider(&table)
+            .await
+            .map_err(invalid)?
+        {
+            Some(provider) => provider.schema(),
+            None => return Err(Status::not_found(format!("no table {}", table))),
+        };
+        let query = FlightQuery {
+            table,
+            ..Default::default()
+        };
+        let ticket = serde_json::to_vec(&query).map_err(|e| invalid(e.into()))?;
+        Ok((schema, Ticket { ticket }))
+    }
+
+    async fn get_flight_info(&self, descriptor: FlightDescriptor) -> Result<FlightInfo, Status> {
+        let (schema, ticket) = self.describe(&descriptor).await?;
+        Ok(FlightInfo {
+            schema: schema_bytes(&schema).map_err(invalid)?,
+            flight_descriptor: Some(descriptor),
+            endpoint: vec![FlightEndpoint {
+                ticket: Some(ticket),
+                location: vec![],
+            }],
+            total_records: -1,
+            total_bytes: -1,
+        })
+    }
+
+    async fn get_schema(&self, descriptor: FlightDescriptor) -> Result<SchemaResult, Status> {
+        let (schema, _) = self.describe(&descriptor).await?;
+        Ok(SchemaResult {
+            schema: schema_bytes(&schema).map_err(invalid)?,
+        })
+    }
+
+    async fn do_get(&self, ticket: Ticket) -> Result<BoxStream<FlightData>, Status> {
+        let (schema, batches) = match Command::decode(&ticket.ticket) {
+            Some(command) => self
+                .sql
+                .execute(command.map_err(invalid)?)
+                .await
+                .map_err(invalid)?,
+            None => {
+                let query: FlightQuery = serde_json::from_slice(&ticket.ticket)
+                    .map_err(|e| Status::invalid_argument(format!("invalid ticket: {}", e)))?;
+                self.app
+                    .service()
+                    .query_stream(
+                        &query.table,
+                        &query.query,
+                        query.start_time,
+                        query.end_time,
+                        &query.fields,
+                    )
+                    .await
+                    .map_err(invalid)?
+            }
+        };
+
+        let mut encoder = FlightEncoder::default();
+        let head = stream::iter([Ok(encoder.encode_schema(&schema))]);
+        let messages = batches.flat_map(move |batch| {
+            let messages = batch.and_then(|batch| encoder.encode_batch(&batch));
+            match messages {
+                Ok(messages) => stream::iter(messages.into_iter().map(Ok).collect::<Vec<_>>()),
+                Err(e) => {
+                    log::error!("Error streaming flight data {:?}", e);
+                    stream::iter(vec![Err(Status::internal(e.to_string()))])
+                }
+            }
+        });
+        Ok(head.chain(messages).boxed())
+    }
+
+    async fn do_put(
+        &self,
+        mut messages: Streaming<FlightData>,
+    ) -> Result<BoxStream<PutResult>, Status> {
+        let first = messages
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("empty stream"))?;
+        let descriptor = first
+            .flight_descriptor
+            .clone()
+            .ok_or_else(|| Status::invalid_argument("missing flight descriptor"))?;
+        let result = match Command::decode(&descriptor.cmd) {
+            Some(command) => {
+                self.bind(command.map_err(invalid)?, first, messages)
+                    .await?
+            }
+            None => {
+                let table = descriptor_table(&descriptor).map_err(invalid)?;
+                self.ingest(&table, first, messages).await?;
+                PutResult::default()
+            }
+        };
+        Ok(stream::iter([Ok(result)]).boxed())
+    }
+
+    async fn ingest(
+        &self,
+        table: &str,
+        first: FlightData,
+        mut messages: Streaming<FlightData>,
+    ) -> Result<(), Status> {
+        let mut decoder = FlightDecoder::default();
+        let mut pending: Vec<RecordBatch> = vec![];
+        let mut rows = 0;
+        let mut message = Some(first);
+        while let Some(data) = message {
+            if let Some(batch) = decoder.decode(&data).map_err(invalid)? {
+                rows += batch.num_rows();
+                pending.push(batch);
+            }
+            message = messages.message().await?;
+            if rows >= STREAM_BATCH_SIZE || (message.is_none() && !pending.is_empty()) {
+                // the batches of a stream share its schema
+                let batch = concat_batches(&pending[0].schema(), &pending)
+                    .map_err(|e| invalid(e.into()))?;
+                self.app
+                    .service()
+                    .ingest_batches(table, vec![batch])
+                    .await
+                    .map_err(ingest_error)?;
+                pending.clear();
+                rows = 0;
+            }
+        }
+        Ok(())
+    }
+
+    // binds the parameters of a prepared statement
+    async fn bind(
+        &self,
+        command: Command,
+        first: FlightData,
+        mut messages: Streaming<FlightData>,
+    ) -> Result<PutResult, Status> {
+        let Command::CommandPreparedStatementQuery(command) = command else {
+            return Err(Status::unimplemented("melt is read only over Flight SQL"));
+        };
+        let mut decoder = FlightDecoder::default();
+        let mut batches = vec![];
+        let mut message = Some(first);
+        while let Some(data) = message {
+            batches.extend(decoder.decode(&data).map_err(invalid)?);
+            message = messages.message().await?;
+        }
+        self.sql
+            .bind(&command.prepared_statement_handle, &batches)
+            .map_err(invalid)?;
+        let result = DoPutPreparedStatementResult {
+            prepared_statement_handle: Some(command.prepared_statement_handle),
+        };
+        Ok(PutResult {
+            app_metadata: result.encode_to_vec().into(),
+        })
+    }
+
+    async fn do_action(&self, action: Action) -> Result<BoxStream<ActionResult>, Status> {
+        let results = match action.r#type.as_str() {
+            "CreatePreparedStatement" => {
+                let request = sql::unpack("ActionCreatePreparedStatementRequest", &action.body)
+                    .map_err(invalid)?;
+                let result = self.sql.create_prepared(request).await.map_err(invalid)?;
+                vec![ActionResult {
+                    body: sql::pack("ActionCreatePreparedStatementResult", &result),
+                }]
+            }
+            "ClosePreparedStatement" => {
+                let request = sql::unpack("ActionClosePreparedStatementRequest", &action.body)
+                    .map_err(invalid)?;
+                self.sql.close_prepared(request);
+                vec![]
+            }
+            t => {
+                return Err(Status::unimplemented(format!(
+                    "action {} is not supported",
+                    t
+                )))
+            }
+        };
+        Ok(stream::iter(results.into_iter().map(Ok)).boxed())
+    }
+
+    async fn list_actions(&self, _: Empty) -> Result<BoxStream<ActionType>, Status> {
+        let actions: Vec<ActionType> = ACTIONS
+            .iter()
+            .map(|(t, description)| ActionType {
+                r#type: t.to_string(),
+                description: description.to_string(),
+            })
+            .collect();
+        Ok(stream::iter(actions.into_iter().map(Ok)).boxed())
+    }
+}
+
+// adapters from the service traits of tonic to the methods above, what
+// tonic-build would generate
+macro_rules! unary {
+    ($svc:ident, $method:ident, $req:ty, $resp:ty) => {
+        struct $svc(FlightService);
+
+        impl UnaryService<$req> for $svc {
+            type Response = $resp;
+            type Future = BoxFuture<Response<$resp>, Status>;
+
+            fn call(&mut self, request: Request<$req>) -> Self::Future {
+                let service = self.0.clone();
+                Box::pin(async move {
+                    let response = service.$method(request.into_inner()).await?;
+                    Ok(Response::new(response))
+                })
+            }
+        }
+    };
+}
+
+macro_rules! server_streaming {
+    ($svc:ident, $method:ident, $req:ty, $resp:ty) => {
+        struct $svc(FlightService);
+
+        impl ServerStreamingService<$req> for $svc {
+            type Response = $resp;
+            type ResponseStream = BoxStream<$resp>;
+            type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+            fn call(&mut self, request: Request<$req>) -> Self::Future {
+                let service = self.0.clone();
+                Box::pin(async move {
+                    let responses = service.$method(request.into_inner()).await?;
+                    Ok(Response::new(responses))
+                })
+            }
+        }
+    };
+}
+
+macro_rules! streaming {
+    ($svc:ident, $method:ident, $req:ty, $resp:ty) => {
+        struct $svc(FlightService);
+
+        impl StreamingService<$req> for $svc {
+            type Response = $resp;
+            type ResponseStream = BoxStream<$resp>;
+            type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+            fn call(&mut self, request: Request<Streaming<$req>>) -> Self::Future {
+                let service = self.0.clone();
+                Box::pin(async move {
+                    let responses = service.$method(request.into_inner()).await?;
+                    Ok(Response::new(responses))
+                })
+            }
+        }
+    };
+}
+
+streaming!(Handshake, handshake, HandshakeRequest, HandshakeResponse);
+unary!(GetFlightInfo, get_flight_info, FlightDescriptor, FlightInfo);
+unary!(GetSchema, get_schema, FlightDescriptor, SchemaResult);
+server_streaming!(DoGet, do_get, Ticket, FlightData);
+streaming!(DoPut, do_put, FlightData, PutResult);
+server_streaming!(DoAction, do_action, Action, ActionResult);
+server_streaming!(ListActions, list_actions, Empty, ActionType);
+
+fn grpc<T, U>() -> Grpc<ProstCodec<T, U>>
+where
+    T: prost::Message + Send + 'static,
+    U: prost::Message + Default + Send + 'static,
+{
+    Grpc::new(ProstCodec::default())
+        .max_decoding_message_size(MAX_MESSAGE_SIZE)
+        .max_encoding_message_size(MAX_MESSAGE_SIZE)
+}
+
+// the routing tonic-build would generate, for the methods served
+impl<B> Service<http::Request<B>> for FlightService
+where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+        let method = req
+            .uri()
+            .path()
+            .strip_prefix("/arrow.flight.protocol.FlightService/")
+            .unwrap_or_default()
+            .to_owned();
+        Box::pin(async move {
+            let response = match method.as_str() {
+                "Handshake" => grpc().streaming(Handshake(service), req).await,
+                "GetFlightInfo" => grpc().unary(GetFlightInfo(service), req).await,
+                "GetSchema" => grpc().unary(GetSchema(service), req).await,
+                "DoGet" => grpc().server_streaming(DoGet(service), req).await,
+                "DoPut" => grpc().streaming(DoPut(service), req).await,
+                "DoAction" => grpc().server_streaming(DoAction(service), req).await,
+                "ListActions" => grpc().server_streaming(ListActions(service), req).await,
+                method => Status::unimplemented(format!("{} is not supported", method)).to_http(),
+            };
+            Ok(response)
+        })
+    }
+}
+
+impl NamedService for FlightService {
+    const NAME: &'static str = "arrow.flight.protocol.FlightService";
+}
+
+/// Binds the configured address and starts serving, does nothing when no
+/// address is set.
+pub fn start(app: web::Data<AppState>, config: &FlightConfig) -> std::io::Result<()> {
+    let Some(addr) = &config.addr else {
+        return Ok(());
+    };
+    let listener = net::bind_tcp(addr, "arrow flight")?;
+    let service = FlightService::new(app.into_inner());
+    actix_web::rt::spawn(async move {
+        let listener = match TcpListener::from_std(listener) {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("arrow flight listener stopped: {}", e);
+                return;
+            }
+        };
+        // tonic stops at the first error of the stream, so it gets none
+        let incoming = stream::unfold(listener, |listener| async move {
+            let (stream, _) = net::accept(&listener, "arrow flight").await;
+            Some((Ok::<_, std::io::Error>(stream), listener))
+        });
+        let res = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming(incoming)
+            .await;
+        if let Err(e) = res {
+            log::error!("arrow flight listener stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptor_table() {
+        let descriptor = FlightDescriptor {
+            r#type: 1,
+            path: vec!["logs".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(descriptor_table(&descriptor).unwrap(), "logs");
+        let descriptor = FlightDescriptor {
+            r#type: 2,
+            cmd: b"metrics".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(descriptor_table(&descriptor).unwrap(), "metrics");
+        let descriptor = FlightDescriptor {
+            r#type: 2,
+            cmd: b"../x".to_vec(),
+            ..Default::default()
+        };
+        assert!(descriptor_table(&descriptor).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+//! Arrow Flight messages, hand written with prost like the OTLP ones.
+//! Field names and tags follow Flight.proto of the `arrow.flight.protocol`
+//! package and FlightSql.proto of `arrow.flight.protocol.sql`, only the
+//! messages of the methods melt serves are here.
+
+use bytes::Bytes;
+
+/// Names a dataset, melt reads a table from the first `path` element or
+/// from `cmd`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FlightDescriptor {
+    // 0 unknown, 1 path, 2 cmd
+    #[prost(int32, tag = "1")]
+    pub r#type: i32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub cmd: Vec<u8>,
+    #[prost(string, repeated, tag = "3")]
+    pub path: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Ticket {
+    #[prost(bytes = "vec", tag = "1")]
+    pub ticket: Vec<u8>,
+}
+
+/// One IPC message: `data_header` is its flatbuffer metadata and
+/// `data_body` its buffers.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FlightData {
+    #[prost(message, optional, tag = "1")]
+    pub flight_descriptor: Option<FlightDescriptor>,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub data_header: Bytes,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub app_metadata: Bytes,
+    #[prost(bytes = "bytes", tag = "1000")]
+    pub data_body: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PutResult {
+    #[prost(bytes = "bytes", tag = "1")]
+    pub app_metadata: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HandshakeRequest {
+    #[prost(uint64, tag = "1")]
+    pub protocol_version: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HandshakeResponse {
+    #[prost(uint64, tag = "1")]
+    pub protocol_version: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Location {
+    #[prost(string, tag = "1")]
+    pub uri: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FlightEndpoint {
+    #[prost(message, optional, tag = "1")]
+    pub ticket: Option<Ticket>,
+    #[prost(message, repeated, tag = "2")]
+    pub location: Vec<Location>,
+}
+
+/// How to get a dataset, `schema` is an encapsulated IPC schema message.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FlightInfo {
+    #[prost(bytes = "vec", tag = "1")]
+    pub schema: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub flight_descriptor: Option<FlightDescriptor>,
+    #[prost(message, repeated, tag = "3")]
+    pub endpoint: Vec<FlightEndpoint>,
+    #[prost(int64, tag = "4")]
+    pub total_records: i64,
+    #[prost(int64, tag = "5")]
+    pub total_bytes: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SchemaResult {
+    #[prost(bytes = "vec", tag = "1")]
+    pub schema: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Action {
+    #[prost(string, tag = "1")]
+    pub r#type: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub body: Vec<u8>,
+}
+
+/// `Result` in Flight.proto.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ActionResult {
+    #[prost(bytes = "vec", tag = "1")]
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ActionType {
+    #[prost(string, tag = "1")]
+    pub r#type: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+/// `google.protobuf.Any`, Flight SQL commands travel in one.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Any {
+    #[prost(string, tag = "1")]
+    pub type_url: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+pub mod sql {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandGetSqlInfo {
+        #[prost(uint32, repeated, tag = "1")]
+        pub info: Vec<u32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandGetCatalogs {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandGetDbSchemas {
+        #[prost(string, optional, tag = "1")]
+        pub catalog: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        pub db_schema_filter_pattern: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandGetTables {
+        #[prost(string, optional, tag = "1")]
+        pub catalog: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        pub db_schema_filter_pattern: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        pub table_name_filter_pattern: Option<String>,
+        #[prost(string, repeated, tag = "4")]
+        pub table_types: Vec<String>,
+        #[prost(bool, tag = "5")]
+        pub include_schema: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandGetTableTypes {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandStatementQuery {
+        #[prost(string, tag = "1")]
+        pub query: String,
+        #[prost(bytes = "vec", optional, tag = "2")]
+        pub transaction_id: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TicketStatementQuery {
+        #[prost(bytes = "vec", tag = "1")]
+        pub statement_handle: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandPreparedStatementQuery {
+        #[prost(bytes = "vec", tag = "1")]
+        pub prepared_statement_handle: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ActionCreatePreparedStatementRequest {
+        #[prost(string, tag = "1")]
+        pub query: String,
+        #[prost(bytes = "vec", optional, tag = "2")]
+        pub transaction_id: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ActionCreatePreparedStatementResult {
+        #[prost(bytes = "vec", tag = "1")]
+        pub prepared_statement_handle: Vec<u8>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub dataset_schema: Vec<u8>,
+        #[prost(bytes = "vec", tag = "3")]
+        pub parameter_schema: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ActionClosePreparedStatementRequest {
+        #[prost(bytes = "vec", tag = "1")]
+        pub prepared_statement_handle: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DoPutPreparedStatementResult {
+        #[prost(bytes = "vec", optional, tag = "1")]
+        pub prepared_statement_handle: Option<Vec<u8>>,
+    }
+}
@@ -0,0 +1,593 @@
+//! Flight SQL over the melt tables. Statements run on DataFusion with the
+//! tables in catalog `melt`, schema `public`, each read in the merged
+//! schema of its segments. Statement handles carry the SQL itself, only
+//! prepared statements keep state, their bound parameters.
+
+use std::{
+    any::Any as StdAny,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use arrow_buffer::Buffer;
+use arrow_schema::{DataType, Field, Schema, SchemaRef, UnionFields, UnionMode};
+use async_trait::async_trait;
+use datafusion::{
+    arrow::{
+        array::{
+            new_empty_array, ArrayRef, BinaryArray, BooleanArray, Int32Array, Int64Array,
+            StringArray, UInt32Array, UnionArray,
+        },
+        record_batch::RecordBatch,
+    },
+    catalog::{schema::SchemaProvider, CatalogProvider, MemoryCatalogProvider},
+    dataframe::DataFrame,
+    datasource::TableProvider,
+    execution::context::{SQLOptions, SessionConfig, SessionContext},
+    scalar::ScalarValue,
+};
+use futures::{stream, stream::BoxStream, StreamExt, TryStreamExt};
+use prost::Message;
+use regex::Regex;
+
+use super::{data::schema_bytes, proto::sql::*, proto::Any};
+use crate::app::AppState;
+
+pub static CATALOG: &str = "melt";
+pub static DB_SCHEMA: &str = "public";
+pub static TABLE_TYPE: &str = "TABLE";
+static TYPE_URL_PREFIX: &str = "type.googleapis.com/arrow.flight.protocol.sql.";
+
+/// A Flight SQL message packed in an `Any`.
+pub fn pack<M: Message>(name: &str, message: &M) -> Vec<u8> {
+    Any {
+        type_url: format!("{}{}", TYPE_URL_PREFIX, name),
+        value: message.encode_to_vec(),
+    }
+    .encode_to_vec()
+}
+
+pub fn unpack<M: Message + Default>(name: &str, data: &[u8]) -> Result<M, anyhow::Error> {
+    let any = Any::decode(data)?;
+    if any.type_url.strip_prefix(TYPE_URL_PREFIX) != Some(name) {
+        return Err(anyhow!("expected {}, got {:?}", name, any.type_url));
+    }
+    Ok(M::decode(&any.value[..])?)
+}
+
+macro_rules! commands {
+    ($($name:ident),*) => {
+        /// The Flight SQL commands of descriptors and tickets.
+        #[derive(Clone, Debug, PartialEq)]
+        pub enum Command {
+            $($name($name)),*
+        }
+
+        impl Command {
+            /// `None` when `data` is no Flight SQL message, e.g. a melt
+            /// query ticket.
+            pub fn decode(data: &[u8]) -> Option<Result<Command, anyhow::Error>> {
+                let any = Any::decode(data).ok()?;
+                let name = any.type_url.strip_prefix(TYPE_URL_PREFIX)?;
+                let command = match name {
+                    $(stringify!($name) => $name::decode(&any.value[..])
+                        .map(Command::$name)
+                        .map_err(Into::into),)*
+                    _ => Err(anyhow!("unsupported command {}", name)),
+                };
+                Some(command)
+            }
+
+            pub fn encode(&self) -> Vec<u8> {
+                match self {
+                    $(Command::$name(c) => pack(stringify!($name), c)),*
+                }
+            }
+        }
+    };
+}
+
+commands!(
+    CommandStatementQuery,
+    TicketStatementQuery,
+    CommandPreparedStatementQuery,
+    CommandGetCatalogs,
+    CommandGetDbSchemas,
+    CommandGetTables,
+    CommandGetTableTypes,
+    CommandGetSqlInfo
+);
+
+/// Whether `s` matches the SQL LIKE `pattern`.
+pub fn like(pattern: &str, s: &str) -> bool {
+    let mut re = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '%' => re.push_str(".*"),
+            '_' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).is_ok_and(|re| re.is_match(s))
+}
+
+/// The melt tables, loaded when a statement names them.
+pub struct MeltSchemaProvider {
+    app: Arc<AppState>,
+}
+
+#[async_trait]
+impl SchemaProvider for MeltSchemaProvider {
+    fn as_any(&self) -> &dyn StdAny {
+        self
+    }
+
+    fn table_names(&self) -> Vec<String> {
+        self.app.service().tables()
+    }
+
+    async fn table(&self, name: &str) -> Option<Arc<dyn TableProvider>> {
+        match self.app.service().table_provider(name).await {
+            Ok(table) => table,
+            Err(e) => {
+                log::error!("Error loading table {}: {:?}", name, e);
+                None
+            }
+        }
+    }
+
+    fn table_exist(&self, name: &str) -> bool {
+        self.table_names().iter().any(|t| t == name)
+    }
+}
+
+/// A DataFusion session over the melt tables, the one of the SQL
+/// endpoints.
+pub fn session_context(app: Arc<AppState>) -> Result<SessionContext, anyhow::Error> {
+    let config = SessionConfig::new()
+        .with_default_catalog_and_schema(CATALOG, DB_SCHEMA)
+        .with_information_schema(true);
+    let ctx = SessionContext::new_with_config(config);
+    let catalog = MemoryCatalogProvider::new();
+    catalog.register_schema(DB_SCHEMA, Arc::new(MeltSchemaProvider { app }))?;
+    ctx.register_catalog(CATALOG, Arc::new(catalog));
+    Ok(ctx)
+}
+
+/// Plans a query, DDL, DML and statements like `SET` are refused so
+/// clients can not reach files or settings of the server.
+pub async fn plan(ctx: &SessionContext, query: &str) -> Result<DataFrame, anyhow::Error> {
+    let options = SQLOptions::new()
+        .with_allow_ddl(false)
+        .with_allow_dml(false)
+        .with_allow_statements(false);
+    Ok(ctx.sql_with_options(query, options).await?)
+}
+
+fn catalogs_schema() -> Schema {
+    Schema::new(vec![Field::new("catalog_name", DataType::Utf8, false)])
+}
+
+fn db_schemas_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("catalog_name", DataType::Utf8, true),
+        Field::new("db_schema_name", DataType::Utf8, false),
+    ])
+}
+
+fn tables_schema(include_schema: bool) -> Schema {
+    let mut fields = vec![
+        Field::new("catalog_name", DataType::Utf8, true),
+        Field::new("db_schema_name", DataType::Utf8, true),
+        Field::new("table_name", DataType::Utf8, false),
+        Field::new("table_type", DataType::Utf8, false),
+    ];
+    if include_schema {
+        fields.push(Field::new("table_schema", DataType::Binary, false));
+    }
+    Schema::new(fields)
+}
+
+fn table_types_schema() -> Schema {
+    Schema::new(vec![Field::new("table_type", DataType::Utf8, false)])
+}
+
+fn sql_info_fields() -> UnionFields {
+    let map_entries = Field::new(
+        "entries",
+        DataType::Struct(
+            vec![
+                Field::new("keys", DataType::Int32, false),
+                Field::new(
+                    "values",
+                    DataType::List(Arc::new(Field::new("item", DataType::Int32, true))),
+                    true,
+                ),
+            ]
+            .into(),
+        ),
+        false,
+    );
+    UnionFields::new(
+        0..6,
+        vec![
+            Field::new("string_value", DataType::Utf8, false),
+            Field::new("bool_value", DataType::Boolean, false),
+            Field::new("bigint_value", DataType::Int64, false),
+            Field::new("int32_bitmask", DataType::Int32, false),
+            Field::new(
+                "string_list",
+                DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+                false,
+            ),
+            Field::new(
+                "int32_to_int32_list_map",
+                DataType::Map(Arc::new(map_entries), false),
+                false,
+            ),
+        ],
+    )
+}
+
+fn sql_info_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("info_name", DataType::UInt32, false),
+        Field::new(
+            "value",
+            DataType::Union(sql_info_fields(), UnionMode::Dense),
+            false,
+        ),
+    ])
+}
+
+enum InfoValue {
+    String(&'static str),
+    Bool(bool),
+    Int32(i32),
+}
+
+// the SqlInfo ids of FlightSql.proto melt answers
+fn sql_info() -> Vec<(u32, InfoValue)> {
+    vec![
+        // server name, version and arrow version
+        (0, InfoValue::String("melt")),
+        (1, InfoValue::String(env!("CARGO_PKG_VERSION"))),
+        (2, InfoValue::String("49.0.0")),
+        // read only, SQL, no Substrait, no transactions, no cancel
+        (3, InfoValue::Bool(true)),
+        (4, InfoValue::Bool(true)),
+        (5, InfoValue::Bool(false)),
+        (8, InfoValue::Int32(0)),
+        (9, InfoValue::Bool(false)),
+        // no DDL for catalogs, schemas and tables
+        (500, InfoValue::Bool(false)),
+        (501, InfoValue::Bool(false)),
+        (502, InfoValue::Bool(false)),
+        // unquoted identifiers are lowercased, quoted with "
+        (503, InfoValue::Int32(3)),
+        (504, InfoValue::String("\"")),
+        (506, InfoValue::Bool(true)),
+    ]
+}
+
+fn sql_info_batch(info: &[u32]) -> Result<RecordBatch, anyhow::Error> {
+    let (mut names, mut type_ids, mut offsets) = (vec![], vec![], vec![]);
+    let (mut strings, mut bools, mut ints) = (vec![], vec![], vec![]);
+    for (name, value) in sql_info() {
+        if !info.is_empty() && !info.contains(&name) {
+            continue;
+        }
+        names.push(name);
+        let (type_id, offset): (i8, i32) = match value {
+            InfoValue::String(v) => (0, push(&mut strings, v)),
+            InfoValue::Bool(v) => (1, push(&mut bools, v)),
+            InfoValue::Int32(v) => (3, push(&mut ints, v)),
+        };
+        type_ids.push(type_id);
+        offsets.push(offset);
+    }
+    let fields = sql_info_fields();
+    let children: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(strings)),
+        Arc::new(BooleanArray::from(bools)),
+        Arc::new(Int64Array::from(Vec::<i64>::new())),
+        Arc::new(Int32Array::from(ints)),
+        new_empty_array(fields.iter().nth(4).unwrap().1.data_type()),
+        new_empty_array(fields.iter().nth(5).unwrap().1.data_type()),
+    ];
+    let values = UnionArray::try_new(
+        &(0..6).collect::<Vec<i8>>(),
+        Buffer::from_vec(type_ids),
+        Some(Buffer::from_vec(offsets)),
+        fields
+            .iter()
+            .map(|(_, f)| f.as_ref().clone())
+            .zip(children)
+            .collect(),
+    )?;
+    Ok(RecordBatch::try_new(
+        Arc::new(sql_info_schema()),
+        vec![Arc::new(UInt32Array::from(names)), Arc::new(values)],
+    )?)
+}
+
+fn push<T>(values: &mut Vec<T>, v: T) -> i32 {
+    values.push(v);
+    values.len() as i32 - 1
+}
+
+fn strings(values: Vec<&str>) -> ArrayRef {
+    Arc::new(StringArray::from(values))
+}
+
+struct Prepared {
+    query: String,
+    params: Vec<ScalarValue>,
+}
+
+pub struct FlightSql {
+    app: Arc<AppState>,
+    prepared: Mutex<HashMap<Vec<u8>, Prepared>>,
+}
+
+impl FlightSql {
+    pub fn new(app: Arc<AppState>) -> FlightSql {
+        FlightSql {
+            app,
+            prepared: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn statement(
+        &self,
+        query: &str,
+        params: Vec<ScalarValue>,
+    ) -> Result<DataFrame, anyhow::Error> {
+        let df = plan(&session_context(self.app.clone())?, query).await?;
+        if params.is_empty() {
+            return Ok(df);
+        }
+        Ok(df.with_param_values(params)?)
+    }
+
+    fn prepared(&self, handle: &[u8]) -> Result<(String, Vec<ScalarValue>), anyhow::Error> {
+        let prepared = self.prepared.lock().unwrap();
+        let prepared = prepared
+            .get(handle)
+            .ok_or_else(|| anyhow!("unknown prepared statement"))?;
+        Ok((prepared.query.clone(), prepared.params.clone()))
+    }
+
+    /// The schema of a command's result and the ticket that fetches it.
+    pub async fn flight_info(
+        &self,
+        command: Command,
+    ) -> Result<(SchemaRef, Command), anyhow::Error> {
+        match command {
+            Command::CommandStatementQuery(c) => {
+                let df = self.statement(&c.query, vec![]).await?;
+                let ticket = Command::TicketStatementQuery(TicketStatementQuery {
+                    statement_handle: c.query.into_bytes(),
+                });
+                Ok((Arc::new(df.schema().into()), ticket))
+            }
+            Command::CommandPreparedStatementQuery(c) => {
+                let (query, params) = self.prepared(&c.prepared_statement_handle)?;
+                let df = self.statement(&query, params).await?;
+                Ok((
+                    Arc::new(df.schema().into()),
+                    Command::CommandPreparedStatementQuery(c),
+                ))
+            }
+            Command::TicketStatementQuery(_) => Err(anyhow!("a ticket is no command")),
+            Command::CommandGetCatalogs(_) => Ok((Arc::new(catalogs_schema()), command)),
+            Command::CommandGetDbSchemas(_) => Ok((Arc::new(db_schemas_schema()), command)),
+            Command::CommandGetTables(ref c) => {
+                Ok((Arc::new(tables_schema(c.include_schema)), command))
+            }
+            Command::CommandGetTableTypes(_) => Ok((Arc::new(table_types_schema()), command)),
+            Command::CommandGetSqlInfo(_) => Ok((Arc::new(sql_info_schema()), command)),
+        }
+    }
+
+    /// Runs the command of a ticket, statements stream from DataFusion.
+    pub async fn execute(
+        &self,
+        command: Command,
+    ) -> Result<
+        (
+            SchemaRef,
+            BoxStream<'static, Result<RecordBatch, anyhow::Error>>,
+        ),
+        anyhow::Error,
+    > {
+        let df = match command {
+            Command::CommandStatementQuery(c) => self.statement(&c.query, vec![]).await?,
+            Command::TicketStatementQuery(t) => {
+                let query = String::from_utf8(t.statement_handle)?;
+                self.statement(&query, vec![]).await?
+            }
+            Command::CommandPreparedStatementQuery(c) => {
+                let (query, params) = self.prepared(&c.prepared_statement_handle)?;
+                self.statement(&query, params).await?
+            }
+            command => {
+                let batch = self.metadata(command).await?;
+                return Ok((batch.schema(), stream::iter([Ok(batch)]).boxed()));
+            }
+        };
+        let batches = df.execute_stream().await?;
+        Ok((batches.schema(), batches.map_err(Into::into).boxed()))
+    }
+
+    async fn metadata(&self, command: Command) -> Result<RecordBatch, anyhow::Error> {
+        let catalog_matches =
+            |catalog: &Option<String>| catalog.as_deref().is_none_or(|c| c == CATALOG);
+        let schema_matches =
+            |pattern: &Option<String>| pattern.as_deref().is_none_or(|p| like(p, DB_SCHEMA));
+        match command {
+            Command::CommandGetCatalogs(_) => Ok(RecordBatch::try_new(
+                Arc::new(catalogs_schema()),
+                vec![strings(vec![CATALOG])],
+            )?),
+            Command::CommandGetDbSchemas(c) => {
+                let found =
+                    catalog_matches(&c.catalog) && schema_matches(&c.db_schema_filter_pattern);
+                let n = found as usize;
+                Ok(RecordBatch::try_new(
+                    Arc::new(db_schemas_schema()),
+                    vec![strings(vec![CATALOG; n]), strings(vec![DB_SCHEMA; n])],
+                )?)
+            }
+            Command::CommandGetTables(c) => {
+                let found = catalog_matches(&c.catalog)
+                    && schema_matches(&c.db_schema_filter_pattern)
+                    && (c.table_types.is_empty() || c.table_types.iter().any(|t| t == TABLE_TYPE));
+                let tables: Vec<String> = if found {
+                    self.app
+                        .service()
+                        .tables()
+                        .into_iter()
+                        .filter(|t| {
+                            c.table_name_filter_pattern
+                                .as_deref()
+                                .is_none_or(|p| like(p, t))
+                        })
+                        .collect()
+                } else {
+                    vec![]
+                };
+                let n = tables.len();
+                let mut columns = vec![
+                    strings(vec![CATALOG; n]),
+                    strings(vec![DB_SCHEMA; n]),
+                    strings(tables.iter().map(String::as_str).collect()),
+                    strings(vec![TABLE_TYPE; n]),
+                ];
+                if c.include_schema {
+                    let mut schemas = vec![];
+                    for table in &tables {
+                        let schema = match self.app.service().table_provider(table).await? {
+                            Some(provider) => provider.schema(),
+                            None => Arc::new(Schema::empty()),
+                        };
+                        schemas.push(schema_bytes(&schema)?);
+                    }
+                    columns.push(Arc::new(BinaryArray::from_iter_values(schemas)));
+                }
+                Ok(RecordBatch::try_new(
+                    Arc::new(tables_schema(c.include_schema)),
+                    columns,
+                )?)
+            }
+            Command::CommandGetTableTypes(_) => Ok(RecordBatch::try_new(
+                Arc::new(table_types_schema()),
+                vec![strings(vec![TABLE_TYPE])],
+            )?),
+            Command::CommandGetSqlInfo(c) => sql_info_batch(&c.info),
+            command => Err(anyhow!("{:?} is no metadata command", command)),
+        }
+    }
+
+    /// Plans `query` and keeps it under a new handle, parameters are
+    /// positional (`$1`, `$2`, ...).
+    pub async fn create_prepared(
+        &self,
+        request: ActionCreatePreparedStatementRequest,
+    ) -> Result<ActionCreatePreparedStatementResult, anyhow::Error> {
+        let df = plan(&session_context(self.app.clone())?, &request.query).await?;
+        let dataset: Schema = df.schema().into();
+        let mut params: Vec<Field> = df
+            .logical_plan()
+            .get_parameter_types()?
+            .into_iter()
+            .map(|(id, t)| Field::new(id, t.unwrap_or(DataType::Null), true))
+            .collect();
+        // $2 before $10
+        params.sort_by_key(|f| (f.name().len(), f.name().clone()));
+
+        let handle = uuid::Uuid::new_v4().to_string().into_bytes();
+        self.prepared.lock().unwrap().insert(
+            handle.clone(),
+            Prepared {
+                query: request.query,
+                params: vec![],
+            },
+        );
+        Ok(ActionCreatePreparedStatementResult {
+            prepared_statement_handle: handle,
+            dataset_schema: schema_bytes(&dataset)?,
+            parameter_schema: schema_bytes(&Schema::new(params))?,
+        })
+    }
+
+    pub fn close_prepared(&self, request: ActionClosePreparedStatementRequest) {
+        self.prepared
+            .lock()
+            .unwrap()
+            .remove(&request.prepared_statement_handle);
+    }
+
+    /// Binds the parameters of a prepared statement to the one row of
+    /// `batches`.
+    pub fn bind(&self, handle: &[u8], batches: &[RecordBatch]) -> Result<(), anyhow::Error> {
+        let rows: usize = batches.iter().map(RecordBatch::num_rows).sum();
+        if rows != 1 {
+            return Err(anyhow!("expected one row of parameters, got {}", rows));
+        }
+        let batch = batches.iter().find(|b| b.num_rows() == 1).unwrap();
+        let params = batch
+            .columns()
+            .iter()
+            .map(|c| ScalarValue::try_from_array(c, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut prepared = self.prepared.lock().unwrap();
+        let prepared = prepared
+            .get_mut(handle)
+            .ok_or_else(|| anyhow!("unknown prepared statement"))?;
+        prepared.params = params;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::AsArray;
+
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        let command = Command::CommandGetTables(CommandGetTables {
+            table_name_filter_pattern: Some("otel%".to_owned()),
+            include_schema: true,
+            ..Default::default()
+        });
+        let data = command.encode();
+        assert_eq!(Command::decode(&data).unwrap().unwrap(), command);
+        assert!(Command::decode(br#"{"table":"logs"}"#).is_none());
+        let other = pack("CommandStatementUpdate", &CommandStatementQuery::default());
+        assert!(Command::decode(&other).unwrap().is_err());
+
+        assert!(like("otel%", "otel_traces"));
+        assert!(like("lo_s", "logs"));
+        assert!(!like("logs", "logs2"));
+        assert!(like("a.b", "a.b") && !like("a.b", "axb"));
+    }
+
+    #[test]
+    fn test_sql_info() {
+        let batch = sql_info_batch(&[0, 3, 504]).unwrap();
+        assert_eq!(batch.num_rows(), 3);
+        let values = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<UnionArray>()
+            .unwrap();
+        assert_eq!(values.type_id(1), 1);
+        assert_eq!(values.value(2).as_string::<i32>().value(0), "\"");
+        assert_eq!(sql_info_batch(&[]).unwrap().num_rows(), sql_info().len());
+    }
+}
@@ -0,0 +1,415 @@
+//! Fluentd Forward protocol messages, see
+//! https://github.com/fluent/fluentd/wiki/Forward-Protocol-Specification-v1
+
+use std::io::Read;
+
+use anyhow::anyhow;
+use flate2::read::MultiGzDecoder;
+use serde_json::{Map, Value};
+
+use crate::{
+    config::{MAX_DECOMPRESSED_SIZE, TIMPSTAMP_FIELD_NAME},
+    utils::json::flatten_json,
+};
+
+/// One event, its time in microseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub time: i64,
+    pub record: Map<String, Value>,
+}
+
+impl Event {
+    /// The melt record: nested maps are flattened into dotted names, like
+    /// `kubernetes.pod_name`, and a `timestamp` sent in the record itself
+    /// is kept as `exported_timestamp`.
+    pub fn to_record(mut self) -> Result<Value, anyhow::Error> {
+        if let Some(v) = self.record.remove(TIMPSTAMP_FIELD_NAME) {
+            self.record
+                .insert(format!("exported_{}", TIMPSTAMP_FIELD_NAME), v);
+        }
+        self.record
+            .insert(TIMPSTAMP_FIELD_NAME.to_owned(), Value::from(self.time));
+        flatten_json(&Value::Object(self.record))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub tag: String,
+    pub events: Vec<Event>,
+    // errors of the entries that could not be read, the others are kept
+    pub rejected: Vec<String>,
+    // set when the forwarder waits for an ack
+    pub chunk: Option<String>,
+}
+
+/// Length of the first MessagePack value in `buf`, `None` until all of
+/// it has arrived. Only headers are read, so scanning a partly received
+/// chunk again is cheap.
+pub fn value_len(buf: &[u8]) -> Result<Option<usize>, anyhow::Error> {
+    let mut pos = 0usize;
+    let mut pending = 1u64;
+    // big endian length of `n` bytes after the marker
+    let read_len = |pos: usize, n: usize| -> Option<usize> {
+        let bytes = buf.get(pos + 1..pos + 1 + n)?;
+        Some(bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize))
+    };
+    while pending > 0 {
+        let Some(&marker) = buf.get(pos) else {
+            return Ok(None);
+        };
+        pending -= 1;
+        // header size, payload size and the number of nested values
+        let (header, payload, children) = match marker {
+            0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (1, 0, 0),
+            0x80..=0x8f => (1, 0, 2 * (marker & 0x0f) as u64),
+            0x90..=0x9f => (1, 0, (marker & 0x0f) as u64),
+            0xa0..=0xbf => (1, (marker & 0x1f) as usize, 0),
+            0xc1 => return Err(anyhow!("invalid messagepack marker 0xc1")),
+            0xc4 | 0xd9 => (2, 1, 0),
+            0xc5 | 0xda => (3, 2, 0),
+            0xc6 | 0xdb => (5, 4, 0),
+            0xc7 => (3, 1, 0),
+            0xc8 => (4, 2, 0),
+            0xc9 => (6, 4, 0),
+            0xca | 0xce | 0xd2 => (5, 0, 0),
+            0xcb | 0xcf | 0xd3 => (9, 0, 0),
+            0xcc | 0xd0 => (2, 0, 0),
+            0xcd | 0xd1 => (3, 0, 0),
+            0xd4 => (3, 0, 0),
+            0xd5 => (4, 0, 0),
+            0xd6 => (6, 0, 0),
+            0xd7 => (10, 0, 0),
+            0xd8 => (18, 0, 0),
+            0xdc => (3, 0, 0),
+            0xdd => (5, 0, 0),
+            0xde => (3, 0, 0),
+            0xdf => (5, 0, 0),
+        };
+        let (payload, children) = match marker {
+            // the payload byte of these markers is the size of a length
+            0xc4..=0xc9 | 0xd9..=0xdb => match read_len(pos, payload) {
+                Some(len) => (len, children),
+                None => return Ok(None),
+            },
+            0xdc | 0xdd => match read_len(pos, header - 1) {
+                Some(n) => (0, n as u64),
+                None => return Ok(None),
+            },
+            0xde | 0xdf => match read_len(pos, header - 1) {
+                Some(n) => (0, 2 * n as u64),
+                None => return Ok(None),
+            },
+            _ => (payload, children),
+        };
+        pos = pos.saturating_add(header).saturating_add(payload);
+        pending += children;
+        if pos > buf.len() {
+            return Ok(None);
+        }
+    }
+    Ok(Some(pos))
+}
+
+fn to_json(value: rmpv::Value) -> Value {
+    match value {
+        rmpv::Value::Nil => Value::Null,
+        rmpv::Value::Boolean(v) => Value::from(v),
+        rmpv::Value::Integer(v) => match v.as_i64() {
+            Some(v) => Value::from(v),
+            None => v.as_u64().map(Value::from).unwrap_or_default(),
+        },
+        rmpv::Value::F32(v) => serde_json::Number::from_f64(v as f64)
+            .map(Value::Number)
+            .unwrap_or_default(),
+        rmpv::Value::F64(v) => serde_json::Number::from_f64(v)
+            .map(Value::Number)
+            .unwrap_or_default(),
+        // forwarders send text as either type
+        rmpv::Value::String(v) => Value::from(String::from_utf8_lossy(v.as_bytes())),
+        rmpv::Value::Binary(v) => Value::from(String::from_utf8_lossy(&v)),
+        rmpv::Value::Array(v) => Value::Array(v.into_iter().map(to_json).collect()),
+        rmpv::Value::Map(v) => Value::Object(to_map(v)),
+        rmpv::Value::Ext(..) => Value::Null,
+    }
+}
+
+fn to_map(pairs: Vec<(rmpv::Value, rmpv::Value)>) -> Map<String, Value> {
+    pairs
+        .into_iter()
+        .map(|(k, v)| {
+            let key = match k.as_slice() {
+                Some(k) => String::from_utf8_lossy(k).into_owned(),
+                None => k.to_string(),
+            };
+            (key, to_json(v))
+        })
+        .collect()
+}
+
+// integer or float seconds, or the EventTime extension with nanoseconds.
+// Fluent Bit may send `[time, metadata]` in place of the time.
+fn event_time(value: &rmpv::Value) -> Result<i64, anyhow::Error> {
+    match value {
+        rmpv::Value::Integer(v) => v
+            .as_i64()
+            .and_then(|s| s.checked_mul(1_000_000))
+            .ok_or_else(|| anyhow!("invalid event time {}", v)),
+        rmpv::Value::F32(v) => Ok((*v as f64 * 1e6) as i64),
+        rmpv::Value::F64(v) => Ok((v * 1e6) as i64),
+        rmpv::Value::Ext(0, data) if data.len() == 8 => {
+            let secs = u32::from_be_bytes(data[..4].try_into()?) as i64;
+            let nanos = u32::from_be_bytes(data[4..].try_into()?) as i64;
+            Ok(secs * 1_000_000 + nanos / 1000)
+        }
+        rmpv::Value::Array(v) if !v.is_empty() => event_time(&v[0]),
+        v => Err(anyhow!("invalid event time {}", v)),
+    }
+}
+
+fn event(entry: rmpv::Value) -> Result<Event, anyhow::Error> {
+    let rmpv::Value::Array(mut entry) = entry else {
+        return Err(anyhow!("event entries must be [time, record] arrays"));
+    };
+    if entry.len() != 2 {
+        return Err(anyhow!("event entries must be [time, record] arrays"));
+    }
+    let record = match entry.pop() {
+        Some(rmpv::Value::Map(v)) => to_map(v),
+        _ => return Err(anyhow!("event records must be maps")),
+    };
+    Ok(Event {
+        time: event_time(&entry[0])?,
+        record,
+    })
+}
+
+// the entries of PackedForward mode, one MessagePack value after another
+fn packed_entries(mut data: &[u8]) -> Result<Vec<rmpv::Value>, anyhow::Error> {
+    let mut entries = vec![];
+    while !data.is_empty() {
+        let entry = rmpv::decode::read_value(&mut data)
+            .map_err(|e| anyhow!("invalid packed entries: {}", e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Decodes a message in any of the Message, Forward, PackedForward and
+/// CompressedPackedForward modes.
+pub fn decode(value: rmpv::Value) -> Result<Message, anyhow::Error> {
+    let rmpv::Value::Array(mut items) = value else {
+        return Err(anyhow!("messages must be arrays"));
+    };
+    if items.len() < 2 {
+        return Err(anyhow!("messages need a tag and entries"));
+    }
+    let tag = match &items[0] {
+        rmpv::Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+        _ => return Err(anyhow!("message tags must be strings")),
+    };
+    // Message mode sends the event itself, its option comes one later
+    let is_message = !matches!(
+        items[1],
+        rmpv::Value::Array(_) | rmpv::Value::String(_) | rmpv::Value::Binary(_)
+    );
+    let option_index = if is_message { 3 } else { 2 };
+    let option = match items.get(option_index) {
+        Some(rmpv::Value::Map(v)) => to_map(v.clone()),
+        Some(rmpv::Value::Nil) | None => Map::new(),
+        Some(_) => return Err(anyhow!("message options must be a map")),
+    };
+    items.truncate(option_index);
+
+    let entries = if is_message {
+        let record = items.pop().ok_or_else(|| anyhow!("missing event record"))?;
+        let time = items.pop().ok_or_else(|| anyhow!("missing event time"))?;
+        vec![rmpv::Value::Array(vec![time, record])]
+    } else {
+        match items.pop() {
+            Some(rmpv::Value::Array(entries)) => entries,
+            Some(entries) => {
+                let data = entries.as_slice().unwrap_or_default();
+                match option.get("compressed").and_then(Value::as_str) {
+                    None | Some("text") => packed_entries(data)?,
+                    Some("gzip") => {
+                        // one byte past the limit tells a full chunk from a bomb
+                        let mut decoded = vec![];
+                        MultiGzDecoder::new(data)
+                            .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                            .read_to_end(&mut decoded)?;
+                        if decoded.len() > MAX_DECOMPRESSED_SIZE {
+                            return Err(anyhow!(
+                                "decompressed entries exceed the limit of {} bytes",
+                                MAX_DECOMPRESSED_SIZE
+                            ));
+                        }
+                        packed_entries(&decoded)?
+                    }
+                    Some(v) => return Err(anyhow!("unsupported compression {:?}", v)),
+                }
+            }
+            None => vec![],
+        }
+    };
+    let mut events = vec![];
+    let mut rejected = vec![];
+    for entry in entries {
+        match event(entry) {
+            Ok(v) => events.push(v),
+            Err(e) => rejected.push(e.to_string()),
+        }
+    }
+    Ok(Message {
+        tag,
+        events,
+        rejected,
+        chunk: option
+            .get("chunk")
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+    })
+}
+
+/// The response acknowledging `chunk`.
+pub fn ack(chunk: &str) -> Vec<u8> {
+    let response = rmpv::Value::Map(vec![(rmpv::Value::from("ack"), rmpv::Value::from(chunk))]);
+    let mut buf = vec![];
+    // writing into a Vec does not fail
+    let _ = rmpv::encode::write_value(&mut buf, &response);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use rmpv::Value as Mp;
+    use serde_json::json;
+
+    use super::*;
+
+    fn encode(value: &Mp) -> Vec<u8> {
+        let mut buf = vec![];
+        rmpv::encode::write_value(&mut buf, value).unwrap();
+        buf
+    }
+
+    fn entry(time: Mp, message: &str) -> Mp {
+        Mp::Array(vec![
+            time,
+            Mp::Map(vec![(Mp::from("message"), Mp::from(message))]),
+        ])
+    }
+
+    #[test]
+    fn test_value_len() {
+        let value = Mp::Array(vec![
+            Mp::from("tag"),
+            Mp::Binary(vec![0; 300]),
+            Mp::Map(vec![(Mp::from("size"), Mp::from(70000u32))]),
+            Mp::Array((0..20).map(Mp::from).collect()),
+            Mp::Ext(0, vec![0; 8]),
+            Mp::F64(1.5),
+        ]);
+        let buf = encode(&value);
+        for end in 0..buf.len() {
+            assert_eq!(value_len(&buf[..end]).unwrap(), None);
+        }
+        let mut more = buf.clone();
+        more.extend_from_slice(&encode(&Mp::from(1)));
+        assert_eq!(value_len(&more).unwrap(), Some(buf.len()));
+        assert!(value_len(&[0xc1]).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let event_time = Mp::Ext(0, [1_700_000_000u32, 5_000].map(u32::to_be_bytes).concat());
+        let message = decode(Mp::Array(vec![
+            Mp::from("app.access"),
+            event_time.clone(),
+            Mp::Map(vec![
+                (Mp::from("message"), Mp::from("hi")),
+                (Mp::from("timestamp"), Mp::from("x")),
+                (Mp::from("raw"), Mp::Binary(b"bytes".to_vec())),
+                (
+                    Mp::from("kubernetes"),
+                    Mp::Map(vec![
+                        (Mp::from("pod_name"), Mp::from("api-0")),
+                        (
+                            Mp::from("labels"),
+                            Mp::Map(vec![(Mp::from("app"), Mp::from("api"))]),
+                        ),
+                    ]),
+                ),
+                (Mp::from("tags"), Mp::Array(vec![Mp::from("a")])),
+            ]),
+            Mp::Map(vec![(Mp::from("chunk"), Mp::from("c1"))]),
+        ]))
+        .unwrap();
+        assert_eq!(message.tag, "app.access");
+        assert_eq!(message.chunk.as_deref(), Some("c1"));
+        let record = message.events[0].clone().to_record().unwrap();
+        assert_eq!(
+            record,
+            json!({
+                "message": "hi",
+                "raw": "bytes",
+                "kubernetes.pod_name": "api-0",
+                "kubernetes.labels.app": "api",
+                "tags": "[\"a\"]",
+                "exported_timestamp": "x",
+                "timestamp": 1_700_000_000_000_005i64
+            })
+        );
+
+        let message = decode(Mp::Array(vec![
+            Mp::from("app"),
+            Mp::Array(vec![
+                entry(Mp::from(1_700_000_000), "a"),
+                entry(Mp::Array(vec![event_time.clone(), Mp::Map(vec![])]), "b"),
+            ]),
+        ]))
+        .unwrap();
+        assert_eq!(message.chunk, None);
+        assert_eq!(message.events.len(), 2);
+        assert_eq!(message.events[0].time, 1_700_000_000_000_000);
+        assert_eq!(message.events[1].time, 1_700_000_000_000_005);
+
+        let packed = [entry(Mp::F64(1.5), "a"), entry(event_time, "b")]
+            .iter()
+            .flat_map(encode)
+            .collect::<Vec<u8>>();
+        let message = decode(Mp::Array(vec![Mp::from("app"), Mp::Binary(packed.clone())])).unwrap();
+        assert_eq!(message.events.len(), 2);
+        assert_eq!(message.events[0].time, 1_500_000);
+
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(&packed).unwrap();
+        let message = decode(Mp::Array(vec![
+            Mp::from("app"),
+            Mp::Binary(gz.finish().unwrap()),
+            Mp::Map(vec![(Mp::from("compressed"), Mp::from("gzip"))]),
+        ]))
+        .unwrap();
+        assert_eq!(message.events[1].record["message"], "b");
+
+        assert!(decode(Mp::Array(vec![Mp::from("app")])).is_err());
+        // invalid events are rejected one by one, the rest of the message
+        // is kept
+        let overflow = entry(Mp::from(i64::MAX), "a");
+        let entries = Mp::Array(vec![overflow, entry(Mp::from(1), "b")]);
+        let message = decode(Mp::Array(vec![Mp::from("app"), entries])).unwrap();
+        assert_eq!(message.events.len(), 1);
+        assert_eq!(message.rejected.len(), 1);
+        let message = decode(Mp::Array(vec![Mp::from("app"), Mp::from(1), Mp::from("x")])).unwrap();
+        assert!(message.events.is_empty());
+        assert_eq!(message.rejected, vec!["event records must be maps"]);
+        assert!(decode(Mp::Array(vec![Mp::from(1), Mp::Array(vec![])])).is_err());
+
+        let response = rmpv::decode::read_value(&mut &ack("c1")[..]).unwrap();
+        assert_eq!(response, Mp::Map(vec![(Mp::from("ack"), Mp::from("c1"))]));
+    }
+}
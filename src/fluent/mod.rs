@@ -0,0 +1,196 @@
+//! Fluentd Forward listener. Events go to the table named after their tag,
+//! acknowledged chunks are written before the ack so the forwarder can
+//! resend them, others are batched per table like syslog messages. Invalid
+//! events are dropped one by one, only a failed write withholds the ack.
+
+pub mod forward;
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::web;
+use anyhow::anyhow;
+use bytes::{Buf, BytesMut};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use crate::{app::AppState, config::FluentConfig, utils::net};
+
+// largest message accepted, forwarders send chunks of a few MiB
+pub static MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+// batches waiting for the dispatcher before connections slow down
+static CHANNEL_SIZE: usize = 1024;
+// tables batched at the same time, see `dispatch`
+static MAX_BATCHERS: usize = 256;
+
+/// The table of a tag: chars a table name may not hold become `_`, so
+/// `app.access` lands in `app_access`.
+pub fn tag_table(tag: &str) -> Option<String> {
+    let table: String = tag
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    (!table.is_empty()).then_some(table)
+}
+
+async fn serve_connection(
+    app: Arc<AppState>,
+    mut stream: TcpStream,
+    sender: mpsc::Sender<(String, Vec<Value>)>,
+) -> Result<(), anyhow::Error> {
+    let mut buf = BytesMut::with_capacity(64 * 1024);
+    loop {
+        while let Some(len) = forward::value_len(&buf)? {
+            let value = rmpv::decode::read_value(&mut &buf[..len])
+                .map_err(|e| anyhow!("invalid message: {}", e))?;
+            buf.advance(len);
+            // past the framing, invalid data is dropped and the rest kept:
+            // resending it could never succeed
+            let message = match forward::decode(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("dropping forward message: {}", e);
+                    continue;
+                }
+            };
+            for e in &message.rejected {
+                log::warn!("dropping event tagged {:?}: {}", message.tag, e);
+            }
+            let records: Vec<Value> = message
+                .events
+                .into_iter()
+                .filter_map(|event| match event.to_record() {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        log::warn!("dropping event tagged {:?}: {}", message.tag, e);
+                        None
+                    }
+                })
+                .collect();
+            let table = tag_table(&message.tag);
+            if table.is_none() {
+                log::warn!("dropping events with the invalid tag {:?}", message.tag);
+            }
+            match (table, &message.chunk) {
+                (Some(table), Some(chunk)) => {
+                    // without the ack the forwarder sends the chunk again
+                    match app.service().ingest_valid_records(&table, &records).await {
+                        Ok(()) => stream.write_all(&forward::ack(chunk)).await?,
+                        Err(e) => log::error!("Error writing records to {}: {:?}", table, e),
+                    }
+                }
+                (None, Some(chunk)) => stream.write_all(&forward::ack(chunk)).await?,
+                (Some(table), None) => sender.send((table, records)).await?,
+                (None, None) => {}
+            }
+        }
+        if buf.len() > MAX_MESSAGE_SIZE {
+            return Err(anyhow!("message is too large"));
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            if !buf.is_empty() {
+                return Err(anyhow!("connection closed inside a message"));
+            }
+            return Ok(());
+        }
+    }
+}
+
+// one batcher per table, started on the first events of its tag. Past
+// MAX_BATCHERS, the batcher used the longest ago is closed, which writes
+// out its records, so senders making up tags can not pile up tasks.
+async fn dispatch(
+    app: Arc<AppState>,
+    mut receiver: mpsc::Receiver<(String, Vec<Value>)>,
+    interval: Duration,
+) {
+    let mut tables: HashMap<String, (mpsc::Sender<Value>, Instant)> = HashMap::new();
+    while let Some((table, records)) = receiver.recv().await {
+        if !tables.contains_key(&table) && tables.len() >= MAX_BATCHERS {
+            let oldest = tables
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(table, _)| table.clone());
+            if let Some(oldest) = oldest {
+                tables.remove(&oldest);
+            }
+        }
+        let (sender, used) = tables.entry(table.clone()).or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel(CHANNEL_SIZE);
+            let app = app.clone();
+            actix_web::rt::spawn(async move {
+                app.service()
+                    .ingest_channel(&table, receiver, interval)
+                    .await
+            });
+            (sender, Instant::now())
+        });
+        *used = Instant::now();
+        for record in records {
+            if sender.send(record).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+async fn serve(
+    app: Arc<AppState>,
+    listener: std::net::TcpListener,
+    sender: mpsc::Sender<(String, Vec<Value>)>,
+) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::from_std(listener)?;
+    loop {
+        let (stream, peer) = net::accept(&listener, "fluentd forward").await;
+        let (app, sender) = (app.clone(), sender.clone());
+        actix_web::rt::spawn(async move {
+            if let Err(e) = serve_connection(app, stream, sender).await {
+                log::warn!("forward connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Binds the configured address and starts the dispatcher, does nothing
+/// when no address is set.
+pub fn start(app: web::Data<AppState>, config: &FluentConfig) -> std::io::Result<()> {
+    let Some(addr) = &config.addr else {
+        return Ok(());
+    };
+    let listener = net::bind_tcp(addr, "fluentd forward")?;
+    let app: Arc<AppState> = app.into_inner();
+    let (sender, receiver) = mpsc::channel(CHANNEL_SIZE);
+    let interval = Duration::from_millis(config.flush_interval_ms.max(1));
+    actix_web::rt::spawn(dispatch(app.clone(), receiver, interval));
+    actix_web::rt::spawn(async move {
+        if let Err(e) = serve(app, listener, sender).await {
+            log::error!("fluentd forward listener stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_table() {
+        assert_eq!(tag_table("app.access").unwrap(), "app_access");
+        assert_eq!(tag_table("kube-logs_1").unwrap(), "kube-logs_1");
+        assert_eq!(tag_table(""), None);
+    }
+}
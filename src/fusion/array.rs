@@ -31,7 +31,7 @@ pub fn build_null_arrays(num: usize, t: &DataType) -> Result<Arc<dyn Array>, any
         DataType::Float64 => Ok(make_empty_array!(Float64Builder, num)),
         DataType::Utf8 => {
             let mut builder = StringBuilder::with_capacity(num, 0);
-            iter::repeat(0).take(num).for_each(|_| {
+            iter::repeat_n(0, num).for_each(|_| {
                 builder.append_null();
             });
             Ok(ArrayBuilder::finish(&mut builder))
@@ -54,8 +54,8 @@ mod tests {
 
     #[test]
     fn test_cast() {
-        let (schema, record) = build_tests_recordbatch();
-        let to = Arc::new(Schema::new(vec![
+        let (_schema, _record) = build_tests_recordbatch();
+        let _to = Arc::new(Schema::new(vec![
             Field::new("a", DataType::Utf8, false),
             Field::new("b", DataType::Utf8, false),
             Field::new("c", DataType::Int64, false),
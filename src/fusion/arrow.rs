@@ -1,20 +1,11 @@
+use std::io::Write;
+
 use arrow_ipc::writer::StreamWriter;
-use datafusion::arrow::{
-    datatypes::Schema,
-    record_batch::{RecordBatch, RecordBatchWriter},
-};
+use datafusion::arrow::datatypes::Schema;
 
-pub fn new_arrow_writer<'a>(
-    datas: &'a mut Vec<u8>,
+pub fn new_arrow_writer<W: Write>(
+    datas: W,
     schema: &Schema,
-) -> Result<StreamWriter<&'a mut Vec<u8>>, anyhow::Error> {
+) -> Result<StreamWriter<W>, anyhow::Error> {
     Ok(StreamWriter::try_new(datas, schema)?)
 }
-
-pub fn write_recordbatch(record: &RecordBatch, schema: &Schema) -> Result<Vec<u8>, anyhow::Error> {
-    let mut datas = Vec::new();
-    let mut file = new_arrow_writer(&mut datas, schema)?;
-    file.write(record)?;
-    file.close()?;
-    Ok(datas)
-}
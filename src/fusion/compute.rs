@@ -1,8 +1,8 @@
 use arrow_schema::Schema;
 use datafusion::{
     arrow::{
-        array::{Array, AsArray},
-        compute::{concat_batches, max_array, min_array},
+        array::{Array, AsArray, UInt32Array},
+        compute::{concat_batches, kernels::take, max_array, min_array},
         datatypes::ArrowNumericType,
         record_batch::RecordBatch,
     },
@@ -14,6 +14,7 @@ use datafusion::{
 use std::sync::Arc;
 
 use super::array;
+use crate::utils::bloom::BloomFilter;
 
 pub fn compute_min_max<T: ArrowNumericType>(
     record: &RecordBatch,
@@ -30,7 +31,7 @@ pub async fn sort(
     batch: RecordBatch,
     field: &[(&str, bool)],
 ) -> Result<RecordBatch, anyhow::Error> {
-    Ok(sort_batches(batch.schema(), vec![batch], field).await?)
+    sort_batches(batch.schema(), vec![batch], field).await
 }
 
 pub async fn sort_batches(
@@ -44,7 +45,7 @@ pub async fn sort_batches(
 
     let df = ctx.table("t").await?;
     let expr: Vec<_> = sort_by
-        .into_iter()
+        .iter()
         .map(|(f, asc)| ident(*f).sort(*asc, false))
         .collect();
     let df = df.sort(expr)?;
@@ -53,10 +54,28 @@ pub async fn sort_batches(
     Ok(batch)
 }
 
+pub fn take(batch: &RecordBatch, indices: &UInt32Array) -> Result<RecordBatch, anyhow::Error> {
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|c| take::take(c, indices, None))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+/// Builds a bloom filter of the values of a Utf8 column, `None` when the
+/// batch has no such column.
+pub fn build_bloom_filter(batch: &RecordBatch, name: &str) -> Option<BloomFilter> {
+    let array = batch.column_by_name(name)?.as_string_opt::<i32>()?;
+    let mut filter = BloomFilter::with_capacity(array.len(), 0.01);
+    array.iter().flatten().for_each(|v| filter.insert(v));
+    Some(filter)
+}
+
 pub fn cast(schema: &Arc<Schema>, batch: RecordBatch) -> Result<RecordBatch, anyhow::Error> {
     let row_num = batch.num_rows();
     let arrays = schema
-        .all_fields()
+        .fields()
         .iter()
         .map(move |f| {
             let field = batch.column_by_name(f.name());
@@ -109,10 +128,20 @@ mod tests {
         assert_eq!(b, 100);
     }
 
+    #[test]
+    fn test_build_bloom_filter() {
+        let (_schema, batch) = build_tests_recordbatch();
+        let filter = build_bloom_filter(&batch, "a").unwrap();
+        assert!(filter.contains("c"));
+        assert!(!filter.contains("zzzz"));
+        assert!(build_bloom_filter(&batch, "b").is_none());
+        assert!(build_bloom_filter(&batch, "x").is_none());
+    }
+
     #[tokio::test]
     async fn test_sort_batch() {
-        let (schema, record) = build_tests_recordbatch();
-        let record = sort(record, &vec![("a", false)]).await.unwrap();
+        let (_schema, record) = build_tests_recordbatch();
+        let record = sort(record, &[("a", false)]).await.unwrap();
         let a: &StringArray = record
             .column_by_name("a")
             .unwrap()
@@ -129,7 +158,7 @@ mod tests {
 
     #[test]
     fn test_cast() {
-        let (schema, record) = build_tests_recordbatch();
+        let (_schema, record) = build_tests_recordbatch();
         let to = Arc::new(Schema::new(vec![
             Field::new("a", DataType::Utf8, true),
             Field::new("b", DataType::Utf8, true),
@@ -157,7 +186,7 @@ mod tests {
             .downcast_ref()
             .unwrap();
         for i in 0..c.len() {
-            assert_eq!(c.is_null(i), true);
+            assert!(c.is_null(i));
         }
     }
 }
@@ -0,0 +1,657 @@
+use std::{fmt, mem, sync::Arc};
+
+use ahash::AHashMap;
+use anyhow::anyhow;
+use arrow_schema::{DataType, Field, Schema};
+use chrono::Utc;
+use datafusion::arrow::{
+    array::{
+        new_null_array, Array, ArrayBuilder, ArrayRef, AsArray, BooleanBuilder, Float64Builder,
+        Int64Builder, StringBuilder,
+    },
+    datatypes::{Float64Type, Int64Type},
+    record_batch::RecordBatch,
+};
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde_json::Value;
+
+use crate::{
+    config::TIMPSTAMP_FIELD_NAME,
+    utils::time::{parse_i64_to_timestamp_micros, parse_str_to_timestamp_micros},
+};
+
+// column builder that starts untyped and widens the same way
+// `schema::coerce_data_type` does when a value of another type shows up
+enum ColumnBuilder {
+    Null(usize),
+    Boolean(BooleanBuilder),
+    Int64(Int64Builder),
+    Float64(Float64Builder),
+    Utf8(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn len(&self) -> usize {
+        match self {
+            ColumnBuilder::Null(n) => *n,
+            ColumnBuilder::Boolean(b) => b.len(),
+            ColumnBuilder::Int64(b) => b.len(),
+            ColumnBuilder::Float64(b) => b.len(),
+            ColumnBuilder::Utf8(b) => b.len(),
+        }
+    }
+
+    // the type of the values, `None` while there are only nulls
+    fn value_type(&self) -> Option<DataType> {
+        match self {
+            ColumnBuilder::Null(_) => None,
+            c => Some(c.data_type()),
+        }
+    }
+
+    fn data_type(&self) -> DataType {
+        match self {
+            // a column without any value is stored as Utf8, like infer_schema does
+            ColumnBuilder::Null(_) | ColumnBuilder::Utf8(_) => DataType::Utf8,
+            ColumnBuilder::Boolean(_) => DataType::Boolean,
+            ColumnBuilder::Int64(_) => DataType::Int64,
+            ColumnBuilder::Float64(_) => DataType::Float64,
+        }
+    }
+
+    fn append_null(&mut self) {
+        match self {
+            ColumnBuilder::Null(n) => *n += 1,
+            ColumnBuilder::Boolean(b) => b.append_null(),
+            ColumnBuilder::Int64(b) => b.append_null(),
+            ColumnBuilder::Float64(b) => b.append_null(),
+            ColumnBuilder::Utf8(b) => b.append_null(),
+        }
+    }
+
+    fn append_bool(&mut self, v: bool) -> Result<(), anyhow::Error> {
+        match self {
+            ColumnBuilder::Boolean(b) => b.append_value(v),
+            ColumnBuilder::Utf8(b) => b.append_value(if v { "true" } else { "false" }),
+            ColumnBuilder::Null(_) => {
+                self.promote(&DataType::Boolean)?;
+                return self.append_bool(v);
+            }
+            _ => {
+                self.promote(&DataType::Utf8)?;
+                return self.append_bool(v);
+            }
+        }
+        Ok(())
+    }
+
+    fn append_i64(&mut self, v: i64) -> Result<(), anyhow::Error> {
+        match self {
+            ColumnBuilder::Int64(b) => b.append_value(v),
+            ColumnBuilder::Float64(b) => b.append_value(v as f64),
+            ColumnBuilder::Utf8(b) => b.append_value(v.to_string()),
+            ColumnBuilder::Null(_) => {
+                self.promote(&DataType::Int64)?;
+                return self.append_i64(v);
+            }
+            ColumnBuilder::Boolean(_) => {
+                self.promote(&DataType::Utf8)?;
+                return self.append_i64(v);
+            }
+        }
+        Ok(())
+    }
+
+    fn append_f64(&mut self, v: f64) -> Result<(), anyhow::Error> {
+        match self {
+            ColumnBuilder::Float64(b) => b.append_value(v),
+            ColumnBuilder::Utf8(b) => match serde_json::Number::from_f64(v) {
+                Some(n) => b.append_value(n.to_string()),
+                None => b.append_null(),
+            },
+            ColumnBuilder::Null(_) | ColumnBuilder::Int64(_) => {
+                self.promote(&DataType::Float64)?;
+                return self.append_f64(v);
+            }
+            ColumnBuilder::Boolean(_) => {
+                self.promote(&DataType::Utf8)?;
+                return self.append_f64(v);
+            }
+        }
+        Ok(())
+    }
+
+    fn append_str(&mut self, v: &str) -> Result<(), anyhow::Error> {
+        match self {
+            ColumnBuilder::Utf8(b) => b.append_value(v),
+            _ => {
+                self.promote(&DataType::Utf8)?;
+                return self.append_str(v);
+            }
+        }
+        Ok(())
+    }
+
+    // rebuilds the values seen so far with a wider type, this only happens
+    // when a column changes type so it stays off the hot path
+    fn promote(&mut self, to: &DataType) -> Result<(), anyhow::Error> {
+        let array = self.finish();
+        *self = ColumnBuilder::from_array(&array, to)?;
+        Ok(())
+    }
+
+    // keeps the first `len` values, stored as `to`, or as nulls only when
+    // it is `None`; used to drop a failed row so it is off the hot path too
+    fn truncate(&mut self, len: usize, to: Option<DataType>) -> Result<(), anyhow::Error> {
+        let array = self.finish().slice(0, len);
+        *self = match to {
+            Some(to) => ColumnBuilder::from_array(&array, &to)?,
+            None => ColumnBuilder::Null(len),
+        };
+        Ok(())
+    }
+
+    fn from_array(array: &ArrayRef, to: &DataType) -> Result<ColumnBuilder, anyhow::Error> {
+        let array = arrow_cast::cast(array, to)?;
+        Ok(match to {
+            DataType::Boolean => {
+                let mut b = BooleanBuilder::with_capacity(array.len());
+                array.as_boolean().iter().for_each(|v| b.append_option(v));
+                ColumnBuilder::Boolean(b)
+            }
+            DataType::Int64 => {
+                let mut b = Int64Builder::with_capacity(array.len());
+                array
+                    .as_primitive::<Int64Type>()
+                    .iter()
+                    .for_each(|v| b.append_option(v));
+                ColumnBuilder::Int64(b)
+            }
+            DataType::Float64 => {
+                let mut b = Float64Builder::with_capacity(array.len());
+                array
+                    .as_primitive::<Float64Type>()
+                    .iter()
+                    .for_each(|v| b.append_option(v));
+                ColumnBuilder::Float64(b)
+            }
+            DataType::Utf8 => {
+                let mut b = StringBuilder::with_capacity(array.len(), 0);
+                array
+                    .as_string::<i32>()
+                    .iter()
+                    .for_each(|v| b.append_option(v));
+                ColumnBuilder::Utf8(b)
+            }
+            _ => return Err(anyhow!("not support data type {}", to)),
+        })
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Null(n) => new_null_array(&DataType::Utf8, mem::take(n)),
+            ColumnBuilder::Boolean(b) => ArrayBuilder::finish(b),
+            ColumnBuilder::Int64(b) => ArrayBuilder::finish(b),
+            ColumnBuilder::Float64(b) => ArrayBuilder::finish(b),
+            ColumnBuilder::Utf8(b) => ArrayBuilder::finish(b),
+        }
+    }
+}
+
+/// Decodes JSON records straight into Arrow builders. The schema is inferred
+/// while decoding and `timestamp` is normalized to microseconds on the way,
+/// so a record is only walked once.
+pub struct JsonDecoder {
+    names: Vec<String>,
+    columns: Vec<ColumnBuilder>,
+    index: AHashMap<String, usize>,
+    timestamps: Int64Builder,
+    row_timestamp: Option<i64>,
+    rows: usize,
+    // the columns whose type changed since the last checkpoint, with the
+    // type they had before
+    promoted: Vec<(usize, Option<DataType>)>,
+}
+
+impl Default for JsonDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsonDecoder {
+    pub fn new() -> JsonDecoder {
+        JsonDecoder {
+            names: Vec::new(),
+            columns: Vec::new(),
+            index: AHashMap::new(),
+            timestamps: Int64Builder::new(),
+            row_timestamp: None,
+            rows: 0,
+            promoted: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    /// Decodes a JSON object, or an array of objects, from raw bytes. On
+    /// error none of them is kept and the decoder is as it was before.
+    pub fn decode(&mut self, data: &[u8]) -> Result<(), anyhow::Error> {
+        let checkpoint = self.checkpoint();
+        let mut de = serde_json::Deserializer::from_slice(data);
+        let res = de.deserialize_any(RowsVisitor(self)).and_then(|_| de.end());
+        if let Err(e) = res {
+            self.rollback(checkpoint)?;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Decodes a record that was already parsed into a `Value`, a record
+    /// that fails is not kept.
+    pub fn decode_value(&mut self, val: &Value) -> Result<(), anyhow::Error> {
+        let checkpoint = self.checkpoint();
+        if let Err(e) = RowSeed(self).deserialize(val) {
+            self.rollback(checkpoint)?;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    // the row and column counts to roll back to
+    fn checkpoint(&mut self) -> (usize, usize) {
+        self.promoted.clear();
+        (self.rows, self.columns.len())
+    }
+
+    // drops everything decoded since `checkpoint`: the values, the columns
+    // that were added and the type changes
+    fn rollback(&mut self, (rows, columns): (usize, usize)) -> Result<(), anyhow::Error> {
+        self.row_timestamp = None;
+        for name in self.names.drain(columns..) {
+            self.index.remove(&name);
+        }
+        self.columns.truncate(columns);
+        let mut types: Vec<Option<DataType>> =
+            self.columns.iter().map(ColumnBuilder::value_type).collect();
+        for (i, to) in self.promoted.drain(..) {
+            if i < columns {
+                types[i] = to;
+            }
+        }
+        for (column, to) in self.columns.iter_mut().zip(types) {
+            if column.len() > rows || column.value_type() != to {
+                column.truncate(rows, to)?;
+            }
+        }
+        if self.timestamps.len() > rows {
+            let stamps = ArrayBuilder::finish(&mut self.timestamps).slice(0, rows);
+            stamps
+                .as_primitive::<Int64Type>()
+                .iter()
+                .for_each(|v| self.timestamps.append_option(v));
+        }
+        self.rows = rows;
+        Ok(())
+    }
+
+    /// Returns the decoded records and resets the decoder for the next batch.
+    pub fn flush(&mut self) -> Result<Option<RecordBatch>, anyhow::Error> {
+        if self.rows == 0 {
+            return Ok(None);
+        }
+        let mut fields = vec![Field::new(TIMPSTAMP_FIELD_NAME, DataType::Int64, true)];
+        let mut arrays: Vec<ArrayRef> = vec![ArrayBuilder::finish(&mut self.timestamps)];
+        for (name, column) in self.names.iter().zip(self.columns.iter_mut()) {
+            fields.push(Field::new(name, column.data_type(), true));
+            arrays.push(column.finish());
+        }
+        self.names.clear();
+        self.columns.clear();
+        self.index.clear();
+        self.rows = 0;
+
+        let schema = Arc::new(Schema::new(fields));
+        Ok(Some(RecordBatch::try_new(schema, arrays)?))
+    }
+
+    fn column(&mut self, name: &str) -> Column {
+        if name == TIMPSTAMP_FIELD_NAME {
+            return Column::Timestamp;
+        }
+        if let Some(&i) = self.index.get(name) {
+            return Column::Index(i);
+        }
+        let i = self.columns.len();
+        self.names.push(name.to_string());
+        self.columns.push(ColumnBuilder::Null(self.rows));
+        self.index.insert(name.to_string(), i);
+        Column::Index(i)
+    }
+
+    fn end_row(&mut self) {
+        let stamp = self
+            .row_timestamp
+            .take()
+            .unwrap_or_else(|| Utc::now().timestamp_micros());
+        self.timestamps.append_value(stamp);
+        self.rows += 1;
+        for column in self.columns.iter_mut() {
+            if column.len() < self.rows {
+                column.append_null();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Column {
+    Timestamp,
+    Index(usize),
+}
+
+struct RowsVisitor<'a>(&'a mut JsonDecoder);
+
+impl<'de, 'a> Visitor<'de> for RowsVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object or an array of objects")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<(), A::Error> {
+        RowVisitor(self.0).visit_map(map)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while seq.next_element_seed(RowSeed(self.0))?.is_some() {}
+        Ok(())
+    }
+}
+
+struct RowSeed<'a>(&'a mut JsonDecoder);
+
+impl<'de, 'a> DeserializeSeed<'de> for RowSeed<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(RowVisitor(self.0))
+    }
+}
+
+struct RowVisitor<'a>(&'a mut JsonDecoder);
+
+impl<'de, 'a> Visitor<'de> for RowVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("only support object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let decoder = self.0;
+        decoder.row_timestamp = None;
+        while let Some(column) = map.next_key_seed(KeySeed(decoder))? {
+            // a repeated key keeps its first value
+            let seen = match column {
+                Column::Timestamp => decoder.row_timestamp.is_some(),
+                Column::Index(i) => decoder.columns[i].len() > decoder.rows,
+            };
+            if seen {
+                map.next_value::<IgnoredAny>()?;
+            } else {
+                map.next_value_seed(ValueSeed { decoder, column })?;
+            }
+        }
+        decoder.end_row();
+        Ok(())
+    }
+}
+
+struct KeySeed<'a>(&'a mut JsonDecoder);
+
+impl<'de, 'a> DeserializeSeed<'de> for KeySeed<'a> {
+    type Value = Column;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Column, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for KeySeed<'a> {
+    type Value = Column;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a field name")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Column, E> {
+        Ok(self.0.column(v))
+    }
+}
+
+struct ValueSeed<'a> {
+    decoder: &'a mut JsonDecoder,
+    column: Column,
+}
+
+impl<'a> ValueSeed<'a> {
+    fn append<E: de::Error>(
+        self,
+        f: impl FnOnce(&mut ColumnBuilder) -> Result<(), anyhow::Error>,
+        stamp: impl FnOnce() -> Result<i64, anyhow::Error>,
+    ) -> Result<(), E> {
+        match self.column {
+            Column::Index(i) => {
+                let column = &mut self.decoder.columns[i];
+                let before = column.value_type();
+                f(column).map_err(E::custom)?;
+                if column.value_type() != before
+                    && self.decoder.promoted.iter().all(|(j, _)| *j != i)
+                {
+                    self.decoder.promoted.push((i, before));
+                }
+                Ok(())
+            }
+            Column::Timestamp => {
+                self.decoder.row_timestamp = Some(stamp().map_err(E::custom)?);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for ValueSeed<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for ValueSeed<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a scalar value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<(), E> {
+        self.append(
+            |c| c.append_bool(v),
+            || Err(anyhow!("Invalid time format [type]")),
+        )
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<(), E> {
+        self.append(|c| c.append_i64(v), || Ok(parse_i64_to_timestamp_micros(v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<(), E> {
+        match i64::try_from(v) {
+            Ok(v) => self.visit_i64(v),
+            Err(_) => self.visit_f64(v as f64),
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<(), E> {
+        self.append(
+            |c| c.append_f64(v),
+            || Ok(parse_i64_to_timestamp_micros(v as i64)),
+        )
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<(), E> {
+        self.append(|c| c.append_str(v), || parse_str_to_timestamp_micros(v))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        match self.column {
+            // the missing value is filled with null when the row ends
+            Column::Index(_) => Ok(()),
+            Column::Timestamp => Err(E::custom("Invalid time format [type]")),
+        }
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<(), E> {
+        self.visit_unit()
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, _seq: A) -> Result<(), A::Error> {
+        Err(de::Error::custom("not support array or object"))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, _map: A) -> Result<(), A::Error> {
+        Err(de::Error::custom("not support array or object"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mut decoder = JsonDecoder::new();
+        decoder
+            .decode(br#"{"a": 1, "b": "zhong", "timestamp": 1700000000}"#)
+            .unwrap();
+        decoder
+            .decode(br#"[{"a": 2.5, "c": true, "a": "dup"}, {"b": null, "d": 3}]"#)
+            .unwrap();
+        assert_eq!(decoder.len(), 3);
+
+        let batch = decoder.flush().unwrap().unwrap();
+        assert!(decoder.flush().unwrap().is_none());
+        let schema = batch.schema();
+        let names = schema
+            .fields()
+            .iter()
+            .map(|f| f.name().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["timestamp", "a", "b", "c", "d"]);
+        assert_eq!(
+            schema.field_with_name("a").unwrap().data_type(),
+            &DataType::Float64
+        );
+        assert_eq!(
+            schema.field_with_name("c").unwrap().data_type(),
+            &DataType::Boolean
+        );
+
+        let stamps = batch.column(0).as_primitive::<Int64Type>();
+        assert_eq!(stamps.value(0), 1_700_000_000_000_000);
+        let a = batch
+            .column_by_name("a")
+            .unwrap()
+            .as_primitive::<Float64Type>();
+        assert_eq!(a.value(0), 1.0);
+        assert_eq!(a.value(1), 2.5);
+        assert!(a.is_null(2));
+        let d = batch
+            .column_by_name("d")
+            .unwrap()
+            .as_primitive::<Int64Type>();
+        assert!(d.is_null(0));
+        assert_eq!(d.value(2), 3);
+    }
+
+    #[test]
+    fn test_decode_coerce() {
+        let mut decoder = JsonDecoder::new();
+        decoder.decode(br#"{"a": 1, "b": true}"#).unwrap();
+        decoder.decode(br#"{"a": "zhong", "b": 12.5}"#).unwrap();
+        let batch = decoder.flush().unwrap().unwrap();
+
+        let a = batch.column_by_name("a").unwrap().as_string::<i32>();
+        assert_eq!(a.value(0), "1");
+        assert_eq!(a.value(1), "zhong");
+        let b = batch.column_by_name("b").unwrap().as_string::<i32>();
+        assert_eq!(b.value(0), "true");
+        assert_eq!(b.value(1), "12.5");
+    }
+
+    #[test]
+    fn test_decode_value() {
+        let mut decoder = JsonDecoder::new();
+        decoder
+            .decode_value(&json!({"a": 1, "timestamp": "2023-12-01T10:00:00Z"}))
+            .unwrap();
+        let batch = decoder.flush().unwrap().unwrap();
+        let stamps = batch.column(0).as_primitive::<Int64Type>();
+        assert_eq!(stamps.value(0), 1_701_424_800_000_000);
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let mut decoder = JsonDecoder::new();
+        assert!(decoder.decode(br#"{"a": {"b": 1}}"#).is_err());
+        let mut decoder = JsonDecoder::new();
+        assert!(decoder.decode(br#"[1, 2]"#).is_err());
+        let mut decoder = JsonDecoder::new();
+        assert!(decoder.decode(br#"{"timestamp": true}"#).is_err());
+        let mut decoder = JsonDecoder::new();
+        assert!(decoder.decode(br#"{"a": 1} {"a": 2}"#).is_err());
+    }
+
+    #[test]
+    fn test_decode_rollback() {
+        let mut decoder = JsonDecoder::new();
+        decoder.decode(br#"{"a": 1, "b": "x"}"#).unwrap();
+        // fails after it widened `a`, filled `b` and added `c`
+        let bad = br#"{"a": 2.5, "b": "y", "c": 1, "d": {"e": 1}}"#;
+        assert!(decoder.decode(bad).is_err());
+        assert!(decoder
+            .decode_value(&json!({"b": "z", "timestamp": true}))
+            .is_err());
+        assert!(decoder.decode(br#"[{"a": 3}, {"a": []}]"#).is_err());
+        assert_eq!(decoder.len(), 1);
+        decoder.decode(br#"{"b": "w", "a": 4}"#).unwrap();
+
+        let batch = decoder.flush().unwrap().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        let schema = batch.schema();
+        let names = schema
+            .fields()
+            .iter()
+            .map(|f| f.name().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["timestamp", "a", "b"]);
+        let a = batch
+            .column_by_name("a")
+            .unwrap()
+            .as_primitive::<Int64Type>();
+        assert_eq!(a.values().to_vec(), vec![1, 4]);
+        let b = batch.column_by_name("b").unwrap().as_string::<i32>();
+        assert_eq!(b.value(0), "x");
+        assert_eq!(b.value(1), "w");
+    }
+}
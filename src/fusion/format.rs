@@ -0,0 +1,364 @@
+use std::{
+    future::ready,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use arrow_cast::display::{ArrayFormatter, FormatOptions};
+use arrow_ipc::writer::StreamWriter;
+use arrow_json::LineDelimitedWriter;
+use arrow_schema::{Field, Schema, SchemaRef};
+use bytes::Bytes;
+use datafusion::arrow::{
+    array::{Array, ArrayRef, StringArray},
+    csv::WriterBuilder,
+    record_batch::RecordBatch,
+};
+use futures::{stream, stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use parquet::arrow::ArrowWriter;
+
+use super::{arrow::new_arrow_writer, parquet::writer_properties};
+
+// a parquet row group is written out once this much is buffered
+static PARQUET_ROW_GROUP_SIZE: usize = 8 * 1024 * 1024;
+
+/// How query results are sent. JSON is the `hits` document, the others
+/// are streamed a batch at a time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResultFormat {
+    Json,
+    Ndjson,
+    Csv,
+    Arrow,
+    Parquet,
+}
+
+impl ResultFormat {
+    /// A format named by a `format` parameter.
+    pub fn from_name(name: &str) -> Result<ResultFormat, anyhow::Error> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(ResultFormat::Json),
+            "ndjson" | "jsonl" => Ok(ResultFormat::Ndjson),
+            "csv" => Ok(ResultFormat::Csv),
+            "arrow" => Ok(ResultFormat::Arrow),
+            "parquet" => Ok(ResultFormat::Parquet),
+            _ => Err(anyhow!("unsupported format: {}", name)),
+        }
+    }
+
+    /// The first format of an Accept header melt knows, JSON when there
+    /// is none. Quality values are not weighed.
+    pub fn from_accept(value: Option<&str>) -> ResultFormat {
+        let accepted = value.unwrap_or_default().split(',').find_map(|t| {
+            let media_type = t.split(';').next().unwrap_or_default();
+            match media_type.trim().to_ascii_lowercase().as_str() {
+                "application/json" => Some(ResultFormat::Json),
+                "application/x-ndjson" | "application/jsonl" => Some(ResultFormat::Ndjson),
+                "text/csv" => Some(ResultFormat::Csv),
+                "application/vnd.apache.arrow.stream" => Some(ResultFormat::Arrow),
+                "application/vnd.apache.parquet" | "application/x-parquet" => {
+                    Some(ResultFormat::Parquet)
+                }
+                _ => None,
+            }
+        });
+        accepted.unwrap_or(ResultFormat::Json)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ResultFormat::Json => "application/json",
+            ResultFormat::Ndjson => "application/x-ndjson",
+            ResultFormat::Csv => "text/csv",
+            ResultFormat::Arrow => "application/vnd.apache.arrow.stream",
+            ResultFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ResultFormat::Json => "json",
+            ResultFormat::Ndjson => "ndjson",
+            ResultFormat::Csv => "csv",
+            ResultFormat::Arrow => "arrow",
+            ResultFormat::Parquet => "parquet",
+        }
+    }
+}
+
+// the sink of the parquet writer, emptied after every batch
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Bytes {
+        std::mem::take(&mut *self.0.lock().unwrap()).into()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Encoder {
+    Ndjson,
+    Csv { header: bool },
+    Arrow(StreamWriter<Vec<u8>>),
+    Parquet(ArrowWriter<SharedBuffer>, SharedBuffer),
+}
+
+/// Encodes the batches of one schema into the chunks of a streamed
+/// response.
+pub struct BatchEncoder {
+    schema: SchemaRef,
+    encoder: Encoder,
+}
+
+impl BatchEncoder {
+    pub fn new(format: ResultFormat, schema: SchemaRef) -> Result<BatchEncoder, anyhow::Error> {
+        let encoder = match format {
+            ResultFormat::Json => return Err(anyhow!("JSON results are not streamed")),
+            ResultFormat::Ndjson => Encoder::Ndjson,
+            ResultFormat::Csv => Encoder::Csv { header: true },
+            ResultFormat::Arrow => Encoder::Arrow(new_arrow_writer(vec![], &schema)?),
+            ResultFormat::Parquet => {
+                let buffer = SharedBuffer::default();
+                let writer = ArrowWriter::try_new(
+                    buffer.clone(),
+                    schema.clone(),
+                    Some(writer_properties()),
+                )?;
+                Encoder::Parquet(writer, buffer)
+            }
+        };
+        Ok(BatchEncoder { schema, encoder })
+    }
+
+    /// The bytes of a batch, empty while a parquet row group fills up.
+    pub fn encode(&mut self, batch: &RecordBatch) -> Result<Bytes, anyhow::Error> {
+        match &mut self.encoder {
+            Encoder::Ndjson => {
+                let mut writer = LineDelimitedWriter::new(vec![]);
+                writer.write(batch)?;
+                writer.finish()?;
+                Ok(writer.into_inner().into())
+            }
+            Encoder::Csv { header } => {
+                let mut writer = WriterBuilder::new().with_header(*header).build(vec![]);
+                writer.write(&csv_batch(batch)?)?;
+                *header = false;
+                Ok(writer.into_inner().into())
+            }
+            Encoder::Arrow(writer) => {
+                writer.write(batch)?;
+                Ok(std::mem::take(writer.get_mut()).into())
+            }
+            Encoder::Parquet(writer, buffer) => {
+                writer.write(batch)?;
+                if writer.in_progress_size() >= PARQUET_ROW_GROUP_SIZE {
+                    writer.flush()?;
+                }
+                Ok(buffer.take())
+            }
+        }
+    }
+
+    // parquet encoding and compression are CPU bound, like segment writes
+    // they run on the blocking pool so they do not stall the async workers
+    fn is_cpu_bound(&self) -> bool {
+        matches!(self.encoder, Encoder::Parquet(..))
+    }
+
+    /// The end of the stream: the parquet footer, the IPC end marker, the
+    /// CSV header when no batch came.
+    pub fn finish(self) -> Result<Bytes, anyhow::Error> {
+        match self.encoder {
+            Encoder::Ndjson | Encoder::Csv { header: false } => Ok(Bytes::new()),
+            Encoder::Csv { header: true } => {
+                let mut writer = WriterBuilder::new().with_header(true).build(vec![]);
+                writer.write(&csv_batch(&RecordBatch::new_empty(self.schema))?)?;
+                Ok(writer.into_inner().into())
+            }
+            Encoder::Arrow(mut writer) => {
+                writer.finish()?;
+                Ok(writer.into_inner()?.into())
+            }
+            Encoder::Parquet(writer, buffer) => {
+                writer.close()?;
+                Ok(buffer.take())
+            }
+        }
+    }
+}
+
+// CSV cells are scalars, list columns are written in their text form
+fn csv_batch(batch: &RecordBatch) -> Result<RecordBatch, anyhow::Error> {
+    if !batch
+        .schema()
+        .fields()
+        .iter()
+        .any(|f| f.data_type().is_nested())
+    {
+        return Ok(batch.clone());
+    }
+    let options = FormatOptions::default();
+    let columns = batch
+        .schema()
+        .fields()
+        .iter()
+        .zip(batch.columns())
+        .map(|(field, array)| {
+            if !field.data_type().is_nested() {
+                return Ok((field.name().clone(), array.clone()));
+            }
+            let formatter = ArrayFormatter::try_new(array.as_ref(), &options)?;
+            let text: StringArray = (0..array.len())
+                .map(|i| array.is_valid(i).then(|| formatter.value(i).to_string()))
+                .collect();
+            Ok((field.name().clone(), Arc::new(text) as ArrayRef))
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    let fields: Vec<Field> = columns
+        .iter()
+        .map(|(name, array)| Field::new(name, array.data_type().clone(), true))
+        .collect();
+    Ok(RecordBatch::try_new(
+        Arc::new(Schema::new(fields)),
+        columns.into_iter().map(|(_, a)| a).collect(),
+    )?)
+}
+
+/// Encodes a stream of batches, a chunk per batch, the stream ends at
+/// the first error.
+pub fn encode_stream(
+    format: ResultFormat,
+    schema: SchemaRef,
+    batches: BoxStream<'static, Result<RecordBatch, anyhow::Error>>,
+) -> Result<impl Stream<Item = Result<Bytes, anyhow::Error>>, anyhow::Error> {
+    let encoder = BatchEncoder::new(format, schema)?;
+    let chunks = stream::try_unfold(
+        (Some(encoder), batches),
+        |(encoder, mut batches)| async move {
+            let Some(mut encoder) = encoder else {
+                return Ok(None);
+            };
+            match batches.next().await {
+                Some(batch) => {
+                    let batch = batch?;
+                    let chunk = if encoder.is_cpu_bound() {
+                        let (back, chunk) = tokio::task::spawn_blocking(move || {
+                            let chunk = encoder.encode(&batch);
+                            (encoder, chunk)
+                        })
+                        .await?;
+                        encoder = back;
+                        chunk?
+                    } else {
+                        encoder.encode(&batch)?
+                    };
+                    Ok(Some((chunk, (Some(encoder), batches))))
+                }
+                None => {
+                    let chunk = if encoder.is_cpu_bound() {
+                        tokio::task::spawn_blocking(move || encoder.finish()).await??
+                    } else {
+                        encoder.finish()?
+                    };
+                    Ok(Some((chunk, (None, batches))))
+                }
+            }
+        },
+    );
+    Ok(chunks.try_filter(|chunk| ready(!chunk.is_empty())))
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::{
+        array::{Int64Builder, ListBuilder},
+        util::pretty::pretty_format_batches,
+    };
+
+    use crate::{
+        fusion::recordbatch::build_tests_recordbatch,
+        import::arrow::{read_ipc, read_parquet},
+    };
+
+    use super::*;
+
+    async fn encode(format: ResultFormat, batches: Vec<RecordBatch>) -> Bytes {
+        let schema = batches[0].schema();
+        let batches = stream::iter(batches.into_iter().map(Ok)).boxed();
+        let chunks: Vec<Bytes> = encode_stream(format, schema, batches)
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        chunks.concat().into()
+    }
+
+    #[tokio::test]
+    async fn test_encode_stream() {
+        let (_, batch) = build_tests_recordbatch();
+        let batches = vec![batch.clone(), batch.clone()];
+
+        let body = encode(ResultFormat::Arrow, batches.clone()).await;
+        assert_eq!(read_ipc(&body).unwrap(), batches);
+        let body = encode(ResultFormat::Parquet, batches.clone()).await;
+        let read = read_parquet(body).unwrap();
+        assert_eq!(
+            pretty_format_batches(&read).unwrap().to_string(),
+            pretty_format_batches(&batches).unwrap().to_string()
+        );
+
+        let body = encode(ResultFormat::Csv, batches.clone()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 1 + 2 * batch.num_rows());
+        assert_eq!(lines.iter().filter(|l| **l == lines[0]).count(), 1);
+        let body = encode(ResultFormat::Ndjson, batches).await;
+        assert_eq!(
+            body.split(|b| *b == b'\n').count(),
+            2 * batch.num_rows() + 1
+        );
+
+        // an empty result still has its schema
+        let empty = RecordBatch::new_empty(batch.schema());
+        let body = encode(ResultFormat::Arrow, vec![empty.clone()]).await;
+        assert_eq!(read_ipc(&body).unwrap()[0].schema(), batch.schema());
+        let body = encode(ResultFormat::Csv, vec![empty]).await;
+        assert_eq!(body.split(|b| *b == b'\n').count(), 2);
+        assert!(BatchEncoder::new(ResultFormat::Json, batch.schema()).is_err());
+
+        // list columns are written as text in CSV
+        let mut counts = ListBuilder::new(Int64Builder::new());
+        counts.append_value([Some(1), Some(2)]);
+        counts.append_null();
+        let batch =
+            RecordBatch::try_from_iter(vec![("counts", Arc::new(counts.finish()) as ArrayRef)])
+                .unwrap();
+        let body = encode(ResultFormat::Csv, vec![batch]).await;
+        assert_eq!(body, "counts\n\"[1, 2]\"\n\"\"\n");
+    }
+
+    #[test]
+    fn test_result_format() {
+        let accept = "text/html, text/csv;q=0.9, */*";
+        assert_eq!(ResultFormat::from_accept(Some(accept)), ResultFormat::Csv);
+        assert_eq!(ResultFormat::from_accept(Some("*/*")), ResultFormat::Json);
+        assert_eq!(ResultFormat::from_accept(None), ResultFormat::Json);
+        assert_eq!(
+            ResultFormat::from_name("Parquet").unwrap(),
+            ResultFormat::Parquet
+        );
+        assert!(ResultFormat::from_name("xml").is_err());
+    }
+}
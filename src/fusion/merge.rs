@@ -13,5 +13,5 @@ pub async fn merge_batchrecord(
         .map(|batch| compute::cast(&schema, batch))
         .collect::<Result<Vec<_>, _>>()?;
 
-    Ok(compute::sort_batches(schema, records, sort_by).await?)
+    compute::sort_batches(schema, records, sort_by).await
 }
@@ -1,6 +1,8 @@
 pub mod array;
 pub mod arrow;
 pub mod compute;
+pub mod decoder;
+pub mod format;
 pub mod merge;
 pub mod parquet;
 pub mod recordbatch;
@@ -4,15 +4,22 @@ use datafusion::arrow::{datatypes::Schema, record_batch::RecordBatch};
 use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
 
 // TODO to optimize
-pub fn new_parquet_writer<'a>(
-    dest: &'a mut Vec<u8>,
-    schema: Arc<Schema>,
-) -> Result<ArrowWriter<&'a mut Vec<u8>>, anyhow::Error> {
-    let props = WriterProperties::builder()
+pub fn writer_properties() -> WriterProperties {
+    WriterProperties::builder()
         .set_compression(Compression::SNAPPY)
         .set_dictionary_enabled(false)
-        .build();
-    Ok(ArrowWriter::try_new(dest, schema, Some(props))?)
+        .build()
+}
+
+pub fn new_parquet_writer(
+    dest: &mut Vec<u8>,
+    schema: Arc<Schema>,
+) -> Result<ArrowWriter<&mut Vec<u8>>, anyhow::Error> {
+    Ok(ArrowWriter::try_new(
+        dest,
+        schema,
+        Some(writer_properties()),
+    )?)
 }
 
 pub fn write_recordbatch(
@@ -22,7 +29,7 @@ pub fn write_recordbatch(
     let mut datas = Vec::new();
     // let batch = recordbatch::json_to_recordbatch(schema, &records)?;
     let mut writer = new_parquet_writer(&mut datas, schema)?;
-    writer.write(&batch)?;
+    writer.write(batch)?;
     writer.close()?;
     Ok(datas)
 }
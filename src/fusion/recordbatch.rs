@@ -20,10 +20,17 @@ pub fn json_to_recordbatch(
     Ok(decoder.flush()?.unwrap())
 }
 
+// segments of one table may carry different schemas, and
+// `record_batches_to_json_rows` names every column after the first batch's
+// schema, so convert batch by batch
 pub fn recordbatch_to_jsons(
     batchs: &[&RecordBatch],
 ) -> Result<Vec<JsonMap<String, Value>>, anyhow::Error> {
-    Ok(record_batches_to_json_rows(batchs)?)
+    let mut rows = Vec::new();
+    for batch in batchs {
+        rows.extend(record_batches_to_json_rows(&[batch])?);
+    }
+    Ok(rows)
 }
 
 #[allow(dead_code)]
@@ -44,3 +51,33 @@ pub fn build_tests_recordbatch() -> (Arc<Schema>, RecordBatch) {
     .unwrap();
     (schema, batch)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recordbatch_to_jsons() {
+        let (_schema, batch) = build_tests_recordbatch();
+        // the same columns in the other order, as another segment may have them
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("b", DataType::Int64, false),
+            Field::new("a", DataType::Utf8, false),
+        ]));
+        let swapped = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(vec![1000])),
+                Arc::new(StringArray::from(vec!["e"])),
+            ],
+        )
+        .unwrap();
+
+        let rows = recordbatch_to_jsons(&[&batch, &swapped]).unwrap();
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0]["a"], "a");
+        assert_eq!(rows[0]["b"], 1);
+        assert_eq!(rows[4]["a"], "e");
+        assert_eq!(rows[4]["b"], 1000);
+    }
+}
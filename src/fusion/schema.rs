@@ -6,7 +6,7 @@ use std::collections::{HashMap, HashSet};
 pub fn merge_schema(schemas: &[&Schema]) -> Result<Schema, anyhow::Error> {
     let mut field_types: HashMap<&str, HashSet<DataType>> = HashMap::new();
     for s in schemas {
-        for f in s.all_fields() {
+        for f in s.fields() {
             field_types
                 .entry(f.name())
                 .or_default()
@@ -51,7 +51,7 @@ pub fn infer_schema(vals: &[Value]) -> Result<Schema, anyhow::Error> {
         }
     }
 
-    Ok(generate_schema(&field_types)?)
+    generate_schema(&field_types)
 }
 
 fn generate_schema(
@@ -63,23 +63,19 @@ fn generate_schema(
 fn generate_fields(fields: &HashMap<&str, HashSet<DataType>>) -> Result<Fields, anyhow::Error> {
     fields
         .iter()
-        .map(|(&k, v)| {
-            Ok(Field::new(
-                k.to_string(),
-                coerce_data_type(v.into_iter())?,
-                true,
-            ))
-        })
+        .map(|(&k, v)| Ok(Field::new(k.to_string(), coerce_data_type(v.iter())?, true)))
         .collect()
 }
 
-fn coerce_data_type<'a, I: Iterator<Item = &'a DataType>>(
+/// The type a column holding values of all the types `dt` is stored as.
+pub fn coerce_data_type<'a, I: Iterator<Item = &'a DataType>>(
     dt: I,
 ) -> Result<DataType, anyhow::Error> {
     let mut dt_iter = dt.into_iter().cloned();
     let dt_init = dt_iter.next().unwrap_or(DataType::Utf8);
 
     Ok(dt_iter.fold(dt_init, |l, r| match (l, r) {
+        (l, r) if l == r => l,
         (DataType::Null, o) | (o, DataType::Null) => o,
         (DataType::Boolean, DataType::Boolean) => DataType::Boolean,
         (DataType::Int64, DataType::Int64) => DataType::Int64,
@@ -129,4 +125,41 @@ mod tests {
             &DataType::Float64
         );
     }
+
+    #[test]
+    fn test_merge_schema_nested() {
+        let counts = DataType::new_list(DataType::Int64, true);
+        let resource =
+            DataType::Struct(Fields::from(vec![Field::new("host", DataType::Utf8, true)]));
+        let a = Schema::new(vec![
+            Field::new("timestamp", DataType::Int64, true),
+            Field::new("counts", counts.clone(), true),
+            Field::new("resource", resource.clone(), true),
+        ]);
+        let b = Schema::new(vec![
+            Field::new("counts", counts.clone(), true),
+            Field::new("bounds", DataType::new_list(DataType::Float64, true), true),
+        ]);
+        let schema = merge_schema(&[&a, &b]).unwrap();
+        // the children of nested fields are not columns of their own
+        let mut names: Vec<_> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["bounds", "counts", "resource", "timestamp"]);
+        assert_eq!(
+            schema.field_with_name("counts").unwrap().data_type(),
+            &counts
+        );
+        assert_eq!(
+            schema.field_with_name("resource").unwrap().data_type(),
+            &resource
+        );
+
+        // a list in one segment and a scalar in another is stored as text
+        let c = Schema::new(vec![Field::new("counts", DataType::Int64, true)]);
+        let schema = merge_schema(&[&a, &c]).unwrap();
+        assert_eq!(
+            schema.field_with_name("counts").unwrap().data_type(),
+            &DataType::Utf8
+        );
+    }
 }
@@ -0,0 +1,245 @@
+use std::{io::Cursor, sync::Arc};
+
+use anyhow::anyhow;
+use arrow_ipc::reader::{FileReader, StreamReader};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use bytes::Bytes;
+use datafusion::arrow::{
+    array::{Array, ArrayRef, AsArray, Int64Array},
+    datatypes::Int64Type,
+    record_batch::RecordBatch,
+};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+use crate::{
+    config::TIMPSTAMP_FIELD_NAME,
+    fusion::{array::cast_array, compute, schema::coerce_data_type},
+    utils::time::{parse_i64_to_timestamp_micros, parse_str_to_timestamp_micros},
+};
+
+// the IPC file format starts with this, the stream format does not
+static IPC_FILE_MAGIC: &[u8] = b"ARROW1";
+
+/// Reads an Arrow IPC stream, or an IPC file when the body is one.
+pub fn read_ipc(body: &[u8]) -> Result<Vec<RecordBatch>, anyhow::Error> {
+    if body.starts_with(IPC_FILE_MAGIC) {
+        let reader = FileReader::try_new(Cursor::new(body), None)?;
+        return Ok(reader.collect::<Result<_, _>>()?);
+    }
+    let reader = StreamReader::try_new(body, None)?;
+    Ok(reader.collect::<Result<_, _>>()?)
+}
+
+pub fn read_parquet(body: Bytes) -> Result<Vec<RecordBatch>, anyhow::Error> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(body)?.build()?;
+    Ok(reader.collect::<Result<_, _>>()?)
+}
+
+/// The type a column of type `dt` is stored as, `None` for binary columns
+/// and nested ones other than lists of numbers.
+pub fn column_type(dt: &DataType) -> Option<DataType> {
+    match dt {
+        DataType::List(item) | DataType::LargeList(item) => column_type(item.data_type())
+            .filter(DataType::is_numeric)
+            .map(|t| DataType::new_list(t, true)),
+        DataType::Null => Some(DataType::Null),
+        DataType::Boolean => Some(DataType::Boolean),
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64 => Some(DataType::Int64),
+        DataType::Float16
+        | DataType::Float32
+        | DataType::Float64
+        | DataType::Decimal128(_, _)
+        | DataType::Decimal256(_, _) => Some(DataType::Float64),
+        // times other than the record's are kept as text, like in JSON
+        DataType::Utf8
+        | DataType::LargeUtf8
+        | DataType::Timestamp(_, _)
+        | DataType::Date32
+        | DataType::Date64
+        | DataType::Time32(_)
+        | DataType::Time64(_) => Some(DataType::Utf8),
+        DataType::Dictionary(_, v) => column_type(v).filter(|t| *t == DataType::Utf8),
+        _ => None,
+    }
+}
+
+// the record times in micros, read like the `timestamp` of a JSON record
+fn timestamp_micros(array: &ArrayRef, now: i64) -> Result<ArrayRef, anyhow::Error> {
+    let stamps: Int64Array = match array.data_type() {
+        DataType::Null => (0..array.len()).map(|_| Some(now)).collect(),
+        DataType::Timestamp(_, _) | DataType::Date32 | DataType::Date64 => {
+            let array = cast_array(
+                array.clone(),
+                &DataType::Timestamp(TimeUnit::Microsecond, None),
+            )?;
+            let array = cast_array(array, &DataType::Int64)?;
+            array
+                .as_primitive::<Int64Type>()
+                .iter()
+                .map(|v| Some(v.unwrap_or(now)))
+                .collect()
+        }
+        DataType::Utf8 | DataType::LargeUtf8 => {
+            let array = cast_array(array.clone(), &DataType::Utf8)?;
+            array
+                .as_string::<i32>()
+                .iter()
+                .enumerate()
+                .map(|(i, v)| match v {
+                    Some(v) => parse_str_to_timestamp_micros(v)
+                        .map(Some)
+                        .map_err(|e| anyhow!("row {}: {}", i + 1, e)),
+                    None => Ok(Some(now)),
+                })
+                .collect::<Result<_, _>>()?
+        }
+        dt if column_type(dt).is_some_and(|t| t.is_numeric()) => {
+            let array = cast_array(array.clone(), &DataType::Int64)?;
+            array
+                .as_primitive::<Int64Type>()
+                .iter()
+                .map(|v| Some(v.map(parse_i64_to_timestamp_micros).unwrap_or(now)))
+                .collect()
+        }
+        dt => {
+            return Err(anyhow!(
+                "{} can not be of type {}",
+                TIMPSTAMP_FIELD_NAME,
+                dt
+            ))
+        }
+    };
+    Ok(Arc::new(stamps))
+}
+
+/// Casts a batch to the types of `table`, the merged schema of the
+/// table's segments: columns the table has take the type both coerce
+/// to, new ones the type of `column_type`. Missing times are `now`.
+pub fn conform(batch: RecordBatch, table: &Schema, now: i64) -> Result<RecordBatch, anyhow::Error> {
+    let mut fields = vec![Field::new(TIMPSTAMP_FIELD_NAME, DataType::Int64, true)];
+    let mut columns: Vec<(String, ArrayRef)> = vec![];
+    for (field, array) in batch.schema().fields().iter().zip(batch.columns()) {
+        if field.name() == TIMPSTAMP_FIELD_NAME {
+            columns.push((field.name().clone(), timestamp_micros(array, now)?));
+            continue;
+        }
+        let dt = column_type(field.data_type()).ok_or_else(|| {
+            anyhow!(
+                "column {} has unsupported type {}",
+                field.name(),
+                field.data_type()
+            )
+        })?;
+        let dt = match table.field_with_name(field.name()) {
+            Ok(f) => coerce_data_type([f.data_type(), &dt].into_iter())?,
+            // a column of nulls is stored as text
+            Err(_) if dt == DataType::Null => DataType::Utf8,
+            Err(_) => dt,
+        };
+        fields.push(Field::new(field.name(), dt, true));
+        columns.push((field.name().clone(), array.clone()));
+    }
+    if batch.column_by_name(TIMPSTAMP_FIELD_NAME).is_none() {
+        let stamps: Int64Array = (0..batch.num_rows()).map(|_| Some(now)).collect();
+        columns.push((TIMPSTAMP_FIELD_NAME.to_owned(), Arc::new(stamps)));
+    }
+
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_from_iter(columns)?;
+    compute::cast(&schema, batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_ipc::writer::StreamWriter;
+    use datafusion::arrow::array::{
+        Float32Array, Int32Array, StringArray, TimestampMillisecondArray,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_conform() {
+        let batch = RecordBatch::try_from_iter(vec![
+            (
+                "timestamp",
+                Arc::new(TimestampMillisecondArray::from(vec![
+                    Some(1_700_000_000_000),
+                    None,
+                ])) as ArrayRef,
+            ),
+            (
+                "code",
+                Arc::new(Int32Array::from(vec![200, 500])) as ArrayRef,
+            ),
+            (
+                "took",
+                Arc::new(Float32Array::from(vec![0.5, 1.5])) as ArrayRef,
+            ),
+            (
+                "path",
+                Arc::new(StringArray::from(vec!["/a", "/b"])) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+
+        let mut body = vec![];
+        let mut writer = StreamWriter::try_new(&mut body, &batch.schema()).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let batches = read_ipc(&body).unwrap();
+        assert_eq!(batches.len(), 1);
+
+        // the table stores `code` as text
+        let table = Schema::new(vec![Field::new("code", DataType::Utf8, true)]);
+        let batch = conform(batches[0].clone(), &table, 7).unwrap();
+        let schema = batch.schema();
+        let types: Vec<(&str, &DataType)> = schema
+            .fields()
+            .iter()
+            .map(|f| (f.name().as_str(), f.data_type()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("timestamp", &DataType::Int64),
+                ("code", &DataType::Utf8),
+                ("took", &DataType::Float64),
+                ("path", &DataType::Utf8),
+            ]
+        );
+        let stamps = batch.column(0).as_primitive::<Int64Type>();
+        assert_eq!(stamps.values().to_vec(), vec![1_700_000_000_000_000, 7]);
+        assert_eq!(batch.column(1).as_string::<i32>().value(1), "500");
+
+        // times come in seconds, millis and so on like in JSON
+        let batch = RecordBatch::try_from_iter(vec![(
+            "timestamp",
+            Arc::new(Int32Array::from(vec![1_700_000_000])) as ArrayRef,
+        )])
+        .unwrap();
+        let batch = conform(batch, &Schema::empty(), 7).unwrap();
+        assert_eq!(
+            batch.column(0).as_primitive::<Int64Type>().value(0),
+            1_700_000_000_000_000
+        );
+
+        let batch = RecordBatch::try_from_iter(vec![(
+            "tags",
+            Arc::new(datafusion::arrow::array::BinaryArray::from(vec![
+                b"x".as_ref()
+            ])) as ArrayRef,
+        )])
+        .unwrap();
+        assert!(conform(batch, &Schema::empty(), 7).is_err());
+        assert!(read_ipc(b"not arrow").is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use arrow_schema::DataType;
+use serde_json::{Map, Value};
+
+use crate::{
+    config::TIMPSTAMP_FIELD_NAME,
+    fusion::schema::coerce_data_type,
+    utils::time::{parse_str_to_timestamp_micros, parse_str_with_format_to_timestamp_micros},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Header {
+    // a first row of distinct text cells is the header
+    Auto,
+    Present,
+    Absent,
+}
+
+impl Header {
+    pub fn from_param(v: Option<&str>) -> Result<Header, anyhow::Error> {
+        match v {
+            None | Some("auto") => Ok(Header::Auto),
+            Some("true") | Some("present") => Ok(Header::Present),
+            Some("false") | Some("absent") => Ok(Header::Absent),
+            Some(v) => Err(anyhow!("invalid header option {:?}", v)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub header: Header,
+    // column holding the record time, parsed with `timestamp_format` when
+    // set and otherwise like any `timestamp` value
+    pub timestamp_column: Option<String>,
+    pub timestamp_format: Option<String>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            header: Header::Auto,
+            timestamp_column: None,
+            timestamp_format: None,
+        }
+    }
+}
+
+/// A single ASCII char, `tab` or `\t`.
+pub fn parse_delimiter(v: &str) -> Result<u8, anyhow::Error> {
+    match v {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        v if v.len() == 1 && v.is_ascii() => Ok(v.as_bytes()[0]),
+        v => Err(anyhow!("invalid delimiter {:?}", v)),
+    }
+}
+
+/// The type of one cell, empty cells are `Null`.
+pub fn cell_type(cell: &str) -> DataType {
+    if cell.is_empty() {
+        DataType::Null
+    } else if cell.eq_ignore_ascii_case("true") || cell.eq_ignore_ascii_case("false") {
+        DataType::Boolean
+    } else if cell.parse::<i64>().is_ok() {
+        DataType::Int64
+    } else if cell.parse::<f64>().is_ok_and(f64::is_finite) {
+        DataType::Float64
+    } else {
+        DataType::Utf8
+    }
+}
+
+fn cell_value(cell: &str, data_type: &DataType) -> Option<Value> {
+    if cell.is_empty() {
+        return None;
+    }
+    let value = match data_type {
+        DataType::Boolean => Value::from(cell.eq_ignore_ascii_case("true")),
+        DataType::Int64 => Value::from(cell.parse::<i64>().ok()?),
+        DataType::Float64 => Value::from(cell.parse::<f64>().ok()?),
+        _ => Value::from(cell),
+    };
+    Some(value)
+}
+
+fn is_header(row: &[String]) -> bool {
+    let mut names: Vec<&String> = row.iter().collect();
+    names.sort();
+    names.dedup();
+    names.len() == row.len() && row.iter().all(|c| cell_type(c) == DataType::Utf8)
+}
+
+/// Reads a CSV body into records. Each column gets one type for the whole
+/// body, the one `coerce_data_type` picks for the types of its cells.
+pub fn csv_to_records(body: &[u8], options: &CsvOptions) -> Result<Vec<Value>, anyhow::Error> {
+    let mut reader = ::csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .has_headers(false)
+        .from_reader(body);
+    let mut rows: Vec<Vec<String>> = vec![];
+    for (i, row) in reader.records().enumerate() {
+        let row = row.map_err(|e| anyhow!("row {}: {}", i + 1, e))?;
+        rows.push(row.iter().map(|c| c.trim().to_owned()).collect());
+    }
+    if rows.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let has_header = match options.header {
+        Header::Present => true,
+        Header::Absent => false,
+        Header::Auto => is_header(&rows[0]),
+    };
+    let names: Vec<String> = if has_header {
+        let names: Vec<String> = rows
+            .remove(0)
+            .into_iter()
+            .enumerate()
+            .map(|(i, n)| {
+                if n.is_empty() {
+                    format!("column_{}", i + 1)
+                } else {
+                    n
+                }
+            })
+            .collect();
+        if names.iter().collect::<HashSet<_>>().len() != names.len() {
+            return Err(anyhow!("the header has duplicate column names"));
+        }
+        if rows.is_empty() {
+            return Ok(vec![]);
+        }
+        names
+    } else {
+        (1..=rows[0].len())
+            .map(|i| format!("column_{}", i))
+            .collect()
+    };
+    // header rows are exempt from the `row N` count of errors
+    let first_row = if has_header { 2 } else { 1 };
+
+    let types: Vec<DataType> = (0..names.len())
+        .map(|i| {
+            let types: Vec<DataType> = rows.iter().map(|r| cell_type(&r[i])).collect();
+            coerce_data_type(types.iter())
+        })
+        .collect::<Result<_, _>>()?;
+    let time_index = match &options.timestamp_column {
+        Some(name) => Some(
+            names
+                .iter()
+                .position(|n| n == name)
+                .ok_or_else(|| anyhow!("no column named {:?}", name))?,
+        ),
+        None => None,
+    };
+
+    let mut records = Vec::with_capacity(rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        let mut record = Map::new();
+        for ((name, cell), data_type) in names.iter().zip(row).zip(&types) {
+            if let Some(v) = cell_value(cell, data_type) {
+                record.insert(name.clone(), v);
+            }
+        }
+        if let Some(index) = time_index {
+            let cell = &row[index];
+            let time = match &options.timestamp_format {
+                Some(fmt) => parse_str_with_format_to_timestamp_micros(cell, fmt),
+                None => parse_str_to_timestamp_micros(cell),
+            }
+            .map_err(|e| anyhow!("row {}: {}", i + first_row, e))?;
+            // a `timestamp` column that is not the time keeps its values
+            if names[index] != TIMPSTAMP_FIELD_NAME {
+                if let Some(v) = record.remove(TIMPSTAMP_FIELD_NAME) {
+                    record.insert(format!("exported_{}", TIMPSTAMP_FIELD_NAME), v);
+                }
+            }
+            record.insert(TIMPSTAMP_FIELD_NAME.to_owned(), Value::from(time));
+        }
+        records.push(Value::Object(record));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_csv_to_records() {
+        let body = "id,amount,paid,note,when\n1,2.5,true,\"a, b\",2024-01-02 03:04:05\n2,3,FALSE,,2024-01-02 03:04:06\n";
+        let options = CsvOptions {
+            timestamp_column: Some("when".to_owned()),
+            ..Default::default()
+        };
+        let records = csv_to_records(body.as_bytes(), &options).unwrap();
+        assert_eq!(
+            records[0],
+            json!({"id": 1, "amount": 2.5, "paid": true, "note": "a, b", "when": "2024-01-02 03:04:05", "timestamp": 1_704_164_645_000_000i64})
+        );
+        // ints in a column with floats are floats, empty cells are left out
+        assert_eq!(
+            records[1],
+            json!({"id": 2, "amount": 3.0, "paid": false, "when": "2024-01-02 03:04:06", "timestamp": 1_704_164_646_000_000i64})
+        );
+
+        // no header, tab separated, a custom time format
+        let body = "a\t02/01/2024 03:04\t7\nb\t02/01/2024 03:05\tx\n";
+        let options = CsvOptions {
+            delimiter: parse_delimiter("tab").unwrap(),
+            timestamp_column: Some("column_2".to_owned()),
+            timestamp_format: Some("%d/%m/%Y %H:%M".to_owned()),
+            ..Default::default()
+        };
+        let records = csv_to_records(body.as_bytes(), &options).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["column_1"], "a");
+        assert_eq!(records[0]["column_3"], "7");
+        assert_eq!(records[1]["timestamp"], 1_704_164_700_000_000i64);
+
+        let body = "timestamp,other\n1700000000,x\n";
+        let options = CsvOptions {
+            timestamp_column: Some("other".to_owned()),
+            ..Default::default()
+        };
+        let err = csv_to_records(body.as_bytes(), &options).unwrap_err();
+        assert!(err.to_string().starts_with("row 2"), "{}", err);
+        let records = csv_to_records(body.as_bytes(), &CsvOptions::default()).unwrap();
+        assert_eq!(
+            records[0],
+            json!({"timestamp": 1_700_000_000, "other": "x"})
+        );
+
+        assert!(csv_to_records(b"a,b\n1\n", &CsvOptions::default()).is_err());
+        assert!(parse_delimiter("ab").is_err());
+        assert_eq!(Header::from_param(Some("false")).unwrap(), Header::Absent);
+    }
+}
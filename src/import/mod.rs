@@ -0,0 +1,4 @@
+//! Bulk imports of files exported by other tools.
+
+pub mod arrow;
+pub mod csv;
@@ -0,0 +1,240 @@
+use anyhow::anyhow;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    UInteger(u64),
+    String(String),
+    Boolean(bool),
+}
+
+/// One line: `measurement[,tag=value...] field=value[,...] [timestamp]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    // in the precision of the request
+    pub timestamp: Option<i64>,
+}
+
+/// Parses a write body, blank lines and `#` comments are skipped. A bad
+/// line fails the whole body.
+pub fn parse_lines(body: &str) -> Result<Vec<Point>, anyhow::Error> {
+    let mut points = vec![];
+    for (i, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let point = parse_line(line).map_err(|e| anyhow!("line {}: {}", i + 1, e))?;
+        points.push(point);
+    }
+    Ok(points)
+}
+
+struct Cursor<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> Cursor<'a> {
+    // reads up to an unescaped `stop` char, a backslash only escapes the
+    // chars in `escapable` and is kept before anything else
+    fn read(&mut self, stop: &[char], escapable: &[char]) -> String {
+        let mut out = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if stop.contains(&c) {
+                break;
+            }
+            self.chars.next();
+            if c == '\\' {
+                match self.chars.peek() {
+                    Some(&n) if escapable.contains(&n) => {
+                        out.push(n);
+                        self.chars.next();
+                    }
+                    _ => out.push(c),
+                }
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.chars.peek() == Some(&c) {
+            self.chars.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.eat(' ') {}
+    }
+
+    fn quoted(&mut self) -> Result<String, anyhow::Error> {
+        let mut out = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(out),
+                Some('\\') => match self.chars.peek() {
+                    Some(&n @ ('"' | '\\')) => {
+                        out.push(n);
+                        self.chars.next();
+                    }
+                    _ => out.push('\\'),
+                },
+                Some(c) => out.push(c),
+                None => return Err(anyhow!("unterminated string field value")),
+            }
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Result<Point, anyhow::Error> {
+    let mut cursor = Cursor {
+        chars: line.chars().peekable(),
+    };
+    let measurement = cursor.read(&[',', ' '], &[',', ' ']);
+    if measurement.is_empty() {
+        return Err(anyhow!("missing measurement"));
+    }
+
+    let mut tags = vec![];
+    while cursor.eat(',') {
+        let key = cursor.read(&['=', ',', ' '], &[',', '=', ' ']);
+        if !cursor.eat('=') || key.is_empty() {
+            return Err(anyhow!("invalid tag in {:?}", measurement));
+        }
+        let value = cursor.read(&[',', ' '], &[',', '=', ' ']);
+        if value.is_empty() {
+            return Err(anyhow!("missing value of tag {:?}", key));
+        }
+        tags.push((key, value));
+    }
+    if !cursor.eat(' ') {
+        return Err(anyhow!("missing fields"));
+    }
+    cursor.skip_spaces();
+
+    let mut fields = vec![];
+    loop {
+        let key = cursor.read(&['=', ',', ' '], &[',', '=', ' ']);
+        if !cursor.eat('=') || key.is_empty() {
+            return Err(anyhow!("invalid field {:?}", key));
+        }
+        let value = if cursor.eat('"') {
+            FieldValue::String(cursor.quoted()?)
+        } else {
+            parse_field_value(&cursor.read(&[',', ' '], &[]))
+                .ok_or_else(|| anyhow!("invalid value of field {:?}", key))?
+        };
+        fields.push((key, value));
+        if !cursor.eat(',') {
+            break;
+        }
+    }
+
+    cursor.skip_spaces();
+    let rest: String = cursor.chars.collect();
+    let timestamp = match rest.trim_end() {
+        "" => None,
+        v => Some(
+            v.parse::<i64>()
+                .map_err(|_| anyhow!("invalid timestamp {:?}", v))?,
+        ),
+    };
+    Ok(Point {
+        measurement,
+        tags,
+        fields,
+        timestamp,
+    })
+}
+
+fn parse_field_value(v: &str) -> Option<FieldValue> {
+    match v {
+        "t" | "T" | "true" | "True" | "TRUE" => return Some(FieldValue::Boolean(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Some(FieldValue::Boolean(false)),
+        _ => {}
+    }
+    if let Some(n) = v.strip_suffix('i') {
+        return n.parse().ok().map(FieldValue::Integer);
+    }
+    if let Some(n) = v.strip_suffix('u') {
+        return n.parse().ok().map(FieldValue::UInteger);
+    }
+    // influx has no NaN or infinite values
+    v.parse::<f64>()
+        .ok()
+        .filter(|f| f.is_finite())
+        .map(FieldValue::Float)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(k: &str, v: &str) -> (String, String) {
+        (k.to_owned(), v.to_owned())
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let point = parse_line(
+            r#"cpu,host=server\ 01,region=us\,west usage=0.5,count=3i,big=18446744073709551615u,up=T,msg="say \"hi\" \\o/" 1700000000000000000"#,
+        )
+        .unwrap();
+        assert_eq!(point.measurement, "cpu");
+        assert_eq!(
+            point.tags,
+            vec![tag("host", "server 01"), tag("region", "us,west")]
+        );
+        assert_eq!(
+            point.fields,
+            vec![
+                ("usage".to_owned(), FieldValue::Float(0.5)),
+                ("count".to_owned(), FieldValue::Integer(3)),
+                ("big".to_owned(), FieldValue::UInteger(u64::MAX)),
+                ("up".to_owned(), FieldValue::Boolean(true)),
+                (
+                    "msg".to_owned(),
+                    FieldValue::String(r#"say "hi" \o/"#.to_owned())
+                ),
+            ]
+        );
+        assert_eq!(point.timestamp, Some(1_700_000_000_000_000_000));
+
+        let point = parse_line(r#"my\ measure\,ment field\=key="a,b c""#).unwrap();
+        assert_eq!(point.measurement, "my measure,ment");
+        assert_eq!(
+            point.fields,
+            vec![(
+                "field=key".to_owned(),
+                FieldValue::String("a,b c".to_owned())
+            )]
+        );
+        assert_eq!(point.timestamp, None);
+
+        assert!(parse_line("cpu").is_err());
+        assert!(parse_line("cpu,host usage=1").is_err());
+        assert!(parse_line("cpu usage=").is_err());
+        assert!(parse_line("cpu usage=inf").is_err());
+        assert!(parse_line(r#"cpu msg="open"#).is_err());
+        assert!(parse_line("cpu usage=1 12ab").is_err());
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let body = "# comment\ncpu usage=1\n\nmem used=2i 10\r\n";
+        let points = parse_lines(body).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[1].timestamp, Some(10));
+        let err = parse_lines("cpu usage=1\ncpu usage=x").unwrap_err();
+        assert!(err.to_string().starts_with("line 2"), "{}", err);
+    }
+}
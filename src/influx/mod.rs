@@ -0,0 +1,179 @@
+//! InfluxDB line protocol ingestion, for Telegraf and the v1/v2 write APIs.
+
+pub mod line_protocol;
+
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use serde_json::{Map, Value};
+
+use self::line_protocol::{FieldValue, Point};
+use crate::{config::TIMPSTAMP_FIELD_NAME, storage::is_valid_table_name};
+
+// column of the measurement when all of them share one table
+pub static MEASUREMENT_FIELD_NAME: &str = "measurement";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+impl Precision {
+    /// Reads the `precision` parameter, v1 and v2 spellings are accepted.
+    pub fn from_param(value: Option<&str>) -> Result<Precision, anyhow::Error> {
+        match value {
+            None | Some("") | Some("n") | Some("ns") => Ok(Precision::Nanoseconds),
+            Some("u") | Some("us") | Some("µ") => Ok(Precision::Microseconds),
+            Some("ms") => Ok(Precision::Milliseconds),
+            Some("s") => Ok(Precision::Seconds),
+            Some("m") => Ok(Precision::Minutes),
+            Some("h") => Ok(Precision::Hours),
+            Some(v) => Err(anyhow!("invalid precision {:?}", v)),
+        }
+    }
+
+    /// Fails when the time does not fit in microseconds.
+    pub fn to_micros(&self, v: i64) -> Result<i64, anyhow::Error> {
+        let factor = match self {
+            Precision::Nanoseconds => return Ok(v / 1000),
+            Precision::Microseconds => 1,
+            Precision::Milliseconds => 1000,
+            Precision::Seconds => 1_000_000,
+            Precision::Minutes => 60_000_000,
+            Precision::Hours => 3_600_000_000,
+        };
+        v.checked_mul(factor)
+            .ok_or_else(|| anyhow!("timestamp {} out of range for precision {:?}", v, self))
+    }
+}
+
+fn field_to_json(value: &FieldValue) -> Value {
+    match value {
+        FieldValue::Float(v) => Value::from(*v),
+        FieldValue::Integer(v) => Value::from(*v),
+        FieldValue::UInteger(v) => Value::from(*v),
+        FieldValue::String(v) => Value::from(v.as_str()),
+        FieldValue::Boolean(v) => Value::from(*v),
+    }
+}
+
+/// Maps every point into one row: tags as strings, fields keeping their
+/// type and the time in microseconds as `timestamp`. With
+/// `measurement_tables` each measurement is written to its own table,
+/// otherwise all go to `table` with a `measurement` column.
+pub fn points_to_records(
+    points: &[Point],
+    precision: Precision,
+    table: &str,
+    measurement_tables: bool,
+) -> Result<BTreeMap<String, Vec<Value>>, anyhow::Error> {
+    let now = chrono::Utc::now().timestamp_micros();
+    let mut reserved = vec![TIMPSTAMP_FIELD_NAME];
+    if !measurement_tables {
+        reserved.push(MEASUREMENT_FIELD_NAME);
+    }
+    // names clashing with our own columns are renamed the way Prometheus
+    // renames clashing target labels
+    let column_name = |name: &str| {
+        if reserved.contains(&name) {
+            format!("exported_{}", name)
+        } else {
+            name.to_owned()
+        }
+    };
+
+    let mut tables: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for point in points {
+        let mut record = Map::new();
+        for (k, v) in &point.tags {
+            record.insert(column_name(k), Value::from(v.as_str()));
+        }
+        // a field and a tag of the same name, the field is kept
+        for (k, v) in &point.fields {
+            record.insert(column_name(k), field_to_json(v));
+        }
+        let timestamp = point
+            .timestamp
+            .map(|t| precision.to_micros(t))
+            .transpose()?;
+        record.insert(
+            TIMPSTAMP_FIELD_NAME.to_owned(),
+            Value::from(timestamp.unwrap_or(now)),
+        );
+        let table = if measurement_tables {
+            if !is_valid_table_name(&point.measurement) {
+                return Err(anyhow!("invalid table name: {:?}", point.measurement));
+            }
+            point.measurement.as_str()
+        } else {
+            record.insert(
+                MEASUREMENT_FIELD_NAME.to_owned(),
+                Value::from(point.measurement.as_str()),
+            );
+            table
+        };
+        tables
+            .entry(table.to_owned())
+            .or_default()
+            .push(Value::Object(record));
+    }
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::influx::line_protocol::parse_lines;
+
+    #[test]
+    fn test_points_to_records() {
+        let points = parse_lines(
+            "cpu,host=a,timestamp=x usage=0.5,count=3i,up=true,msg=\"ok\" 1700000000\nmem,host=a used=1u 1700000001",
+        )
+        .unwrap();
+        let tables = points_to_records(&points, Precision::Seconds, "influx", false).unwrap();
+        let records = &tables["influx"];
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["measurement"], "cpu");
+        assert_eq!(records[0]["host"], "a");
+        assert_eq!(records[0]["exported_timestamp"], "x");
+        assert_eq!(records[0]["usage"], 0.5);
+        assert_eq!(records[0]["count"], 3);
+        assert_eq!(records[0]["up"], true);
+        assert_eq!(records[0]["msg"], "ok");
+        assert_eq!(records[0]["timestamp"], 1_700_000_000_000_000i64);
+        assert_eq!(records[1]["measurement"], "mem");
+
+        let tables = points_to_records(&points, Precision::Seconds, "influx", true).unwrap();
+        assert_eq!(tables.keys().collect::<Vec<_>>(), vec!["cpu", "mem"]);
+        assert!(tables["cpu"][0].get("measurement").is_none());
+
+        let points = parse_lines("cpu v=1 9223372036854775807").unwrap();
+        assert!(points_to_records(&points, Precision::Seconds, "influx", false).is_err());
+
+        let points = parse_lines("../x v=1").unwrap();
+        assert!(points_to_records(&points, Precision::Seconds, "influx", true).is_err());
+    }
+
+    #[test]
+    fn test_precision() {
+        let p = Precision::from_param(None).unwrap();
+        assert_eq!(
+            p.to_micros(1_700_000_000_000_000_000).unwrap(),
+            1_700_000_000_000_000
+        );
+        let p = Precision::from_param(Some("ms")).unwrap();
+        assert_eq!(
+            p.to_micros(1_700_000_000_000).unwrap(),
+            1_700_000_000_000_000
+        );
+        assert!(p.to_micros(i64::MAX).is_err());
+        assert!(Precision::Hours.to_micros(i64::MIN / 1000).is_err());
+        assert!(Precision::from_param(Some("d")).is_err());
+    }
+}
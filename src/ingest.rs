@@ -1,23 +1,110 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::Infallible,
+    sync::Arc,
+    time::Duration,
+};
+
 use actix_web::web;
-use ahash::AHashMap;
 use anyhow::*;
+use futures::{future, stream, stream::BoxStream, Stream, StreamExt};
 
 use chrono::prelude::*;
-use datafusion::arrow::{datatypes::Int64Type, record_batch::RecordBatch};
+use datafusion::{
+    arrow::{
+        array::{AsArray, UInt32Array},
+        datatypes::{DataType, Field, Int64Type, Schema, SchemaRef},
+        record_batch::RecordBatch,
+    },
+    dataframe::DataFrame,
+    datasource::{
+        file_format::parquet::ParquetFormat,
+        listing::{ListingOptions, ListingTable, ListingTableConfig, ListingTableUrl},
+        TableProvider,
+    },
+    execution::context::SessionContext,
+    logical_expr::{ident, lit, Expr},
+};
 use serde_derive::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Semaphore};
 
 use crate::{
     config::*,
     exec::{self, Query},
     fusion::compute,
-    fusion::{parquet, recordbatch},
+    fusion::{decoder::JsonDecoder, parquet, recordbatch, schema::merge_schema},
     id_gen::gen_id,
+    import,
     meta::{FileMeta, MetaService},
-    schema::{infer_schema, MeltSchema},
+    otlp::traces::END_TIME,
+    schema::MeltSchema,
     storage::Storage,
-    utils::{json, time::parse_timestamp},
+    trace::Trace,
+    utils::{
+        compress::{ContentEncoding, StreamDecoder},
+        json,
+    },
 };
-use serde_json::Value;
+use serde_json::{Map, Value};
+
+/// An ingest error caused by the records that were sent, any other error
+/// is a failure to write them. Shippers retry the latter, so only these
+/// are answered as bad requests.
+#[derive(Debug)]
+pub struct InvalidRecords(anyhow::Error);
+
+impl InvalidRecords {
+    pub fn wrap(e: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(InvalidRecords(e))
+    }
+}
+
+impl std::fmt::Display for InvalidRecords {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for InvalidRecords {}
+
+/// Marks a query that can not be parsed. Any other failure of a query is
+/// reading the table, which is not the fault of the request.
+#[derive(Debug)]
+pub struct InvalidQuery(anyhow::Error);
+
+impl InvalidQuery {
+    pub fn wrap(e: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(InvalidQuery(e))
+    }
+}
+
+impl std::fmt::Display for InvalidQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for InvalidQuery {}
+
+/// The error of a streamed ingest, with the number of records of the body
+/// that were written before it.
+#[derive(Debug)]
+pub struct BulkError {
+    pub stored: usize,
+    pub error: anyhow::Error,
+}
+
+// decodes the `line`-th record of an NDJSON body
+fn decode_line(
+    records: &mut JsonDecoder,
+    line: &mut usize,
+    data: &[u8],
+) -> Result<(), anyhow::Error> {
+    *line += 1;
+    records
+        .decode(data)
+        .map_err(|e| anyhow!("record {}: {}", line, e))
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Response {
@@ -27,43 +114,111 @@ pub struct Response {
 pub struct IngestService {
     meta: MetaService,
     storage: Storage,
+    // bounds the parquet encodings running on the blocking pool
+    encode_permits: Arc<Semaphore>,
 }
 impl IngestService {
     pub fn new(storage: Storage) -> IngestService {
         IngestService {
             meta: MetaService::new(),
             storage,
+            encode_permits: Arc::new(Semaphore::new(Config::default().max_encode_tasks)),
         }
     }
 
+    pub fn with_max_encode_tasks(mut self, max_encode_tasks: usize) -> IngestService {
+        self.encode_permits = Arc::new(Semaphore::new(max_encode_tasks.max(1)));
+        self
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
     pub fn ensure_dir(&self, table_name: &str, partition: &str) -> Result<String, anyhow::Error> {
         let path = format!("{table_name}/{partition}");
         std::fs::create_dir_all(&path)?;
         Ok(path)
     }
 
-    pub fn process_bulk_item(&self, val: Value) -> Result<(i64, Value), anyhow::Error> {
-        let stamp = parse_timestamp(TIMPSTAMP_FIELD_NAME, &val)?;
-        let mut val = val;
-        let local_val = val
-            .as_object_mut()
-            .ok_or(anyhow!("format wrong, can not convert to map"))?;
-        local_val.insert(TIMPSTAMP_FIELD_NAME.into(), Value::Number(stamp.into()));
-        Ok((stamp, val))
+    pub async fn bulk(&self, table_name: &str, body: web::Bytes) -> Result<(), anyhow::Error> {
+        let body: Result<web::Bytes, Infallible> = std::result::Result::Ok(body);
+        self.bulk_stream(table_name, ContentEncoding::Identity, stream::iter([body]))
+            .await
+            .map(|_| ())
+            .map_err(|e| e.error)
     }
 
-    pub async fn bulk(&self, table_name: &str, body: web::Bytes) -> Result<(), anyhow::Error> {
-        let records = json::parse_lines(body)?;
-        self.ingest_(table_name, records).await
+    /// Ingests an NDJSON body read as a stream of chunks. Records are written
+    /// out about every STREAM_BATCH_SIZE lines, so memory stays bounded
+    /// whatever the size of the body. Returns the number of records stored,
+    /// on error the batches written before it stay stored.
+    pub async fn bulk_stream<S, E>(
+        &self,
+        table_name: &str,
+        encoding: ContentEncoding,
+        body: S,
+    ) -> Result<usize, BulkError>
+    where
+        S: Stream<Item = Result<web::Bytes, E>>,
+        E: std::fmt::Display,
+    {
+        let mut stored = 0;
+        let res = self
+            .write_stream(table_name, encoding, body, &mut stored)
+            .await;
+        res.map(|_| stored)
+            .map_err(|error| BulkError { stored, error })
     }
 
-    pub async fn ingest(&self, table_name: &str, body: web::Bytes) -> Result<(), anyhow::Error> {
-        let records = json::parse_json(body)?;
-        self.ingest_(table_name, records).await?;
+    async fn write_stream<S, E>(
+        &self,
+        table_name: &str,
+        encoding: ContentEncoding,
+        body: S,
+        stored: &mut usize,
+    ) -> Result<(), anyhow::Error>
+    where
+        S: Stream<Item = Result<web::Bytes, E>>,
+        E: std::fmt::Display,
+    {
+        let mut decoder =
+            StreamDecoder::new(encoding, MAX_DECOMPRESSED_SIZE).map_err(InvalidRecords::wrap)?;
+        let mut lines = json::LineDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut records = JsonDecoder::new();
+        let mut line = 0;
 
+        futures::pin_mut!(body);
+        while let Some(chunk) = body.next().await {
+            let chunk =
+                chunk.map_err(|e| InvalidRecords::wrap(anyhow!("error reading body: {}", e)))?;
+            let chunk = decoder.decode(chunk).map_err(InvalidRecords::wrap)?;
+            lines
+                .decode(&chunk, |data| decode_line(&mut records, &mut line, data))
+                .map_err(InvalidRecords::wrap)?;
+            if records.len() >= STREAM_BATCH_SIZE {
+                let n = records.len();
+                self.flush_records(table_name, &mut records).await?;
+                *stored += n;
+            }
+        }
+        let chunk = decoder.finish().map_err(InvalidRecords::wrap)?;
+        lines
+            .decode(&chunk, |data| decode_line(&mut records, &mut line, data))
+            .and_then(|_| lines.finish(|data| decode_line(&mut records, &mut line, data)))
+            .map_err(InvalidRecords::wrap)?;
+        let n = records.len();
+        self.flush_records(table_name, &mut records).await?;
+        *stored += n;
         Ok(())
     }
 
+    pub async fn ingest(&self, table_name: &str, body: web::Bytes) -> Result<(), anyhow::Error> {
+        let mut records = JsonDecoder::new();
+        records.decode(&body).map_err(InvalidRecords::wrap)?;
+        self.flush_records(table_name, &mut records).await
+    }
+
     pub fn parquet_file_path(&self, partition: &str, name: &str) -> String {
         format!("{}/{}.{}", partition, name, PARQUET_EXT)
     }
@@ -76,62 +231,233 @@ impl IngestService {
         time_key.format("%Y-%m-%d-%H").to_string()
     }
 
-    fn partition_records(
+    // splits a batch into hourly partitions by its timestamp column
+    fn partition_batch(
+        &self,
+        batch: RecordBatch,
+    ) -> Result<Vec<(String, RecordBatch)>, anyhow::Error> {
+        let stamps = batch
+            .column_by_name(TIMPSTAMP_FIELD_NAME)
+            .ok_or(anyhow!("missing {} column", TIMPSTAMP_FIELD_NAME))?
+            .as_primitive::<Int64Type>();
+        let mut hours: BTreeMap<i64, Vec<u32>> = BTreeMap::new();
+        for (i, stamp) in stamps.values().iter().enumerate() {
+            let hour = stamp.div_euclid(HOUR_MICROS);
+            hours.entry(hour).or_default().push(i as u32);
+        }
+
+        if hours.len() == 1 {
+            let hour = *hours.keys().next().unwrap();
+            return Ok(vec![(self.get_partition_key(hour * HOUR_MICROS), batch)]);
+        }
+        hours
+            .into_iter()
+            .map(|(hour, rows)| {
+                let batch = compute::take(&batch, &UInt32Array::from(rows))?;
+                Ok((self.get_partition_key(hour * HOUR_MICROS), batch))
+            })
+            .collect()
+    }
+
+    /// Ingests records that were already parsed, e.g. by a protocol adapter.
+    pub async fn ingest_records(
+        &self,
+        table_name: &str,
+        records: &[Value],
+    ) -> Result<(), anyhow::Error> {
+        let mut decoder = JsonDecoder::new();
+        for record in records {
+            decoder.decode_value(record).map_err(InvalidRecords::wrap)?;
+        }
+        self.flush_records(table_name, &mut decoder).await
+    }
+
+    /// Like `ingest_records`, but records that can not be decoded are logged
+    /// and dropped like in `ingest_channel`, so only write failures fail.
+    pub async fn ingest_valid_records(
+        &self,
+        table_name: &str,
+        records: &[Value],
+    ) -> Result<(), anyhow::Error> {
+        let mut decoder = JsonDecoder::new();
+        for record in records {
+            if let Err(e) = decoder.decode_value(record) {
+                log::warn!("dropping record for {}: {}", table_name, e);
+            }
+        }
+        self.flush_records(table_name, &mut decoder).await
+    }
+
+    /// Ingests columnar batches, cast to the types the table already
+    /// stores, see `import::arrow::conform`.
+    pub async fn ingest_batches(
+        &self,
+        table_name: &str,
+        batches: Vec<RecordBatch>,
+    ) -> Result<(), anyhow::Error> {
+        let batches: Vec<RecordBatch> = batches.into_iter().filter(|b| b.num_rows() > 0).collect();
+        if batches.is_empty() {
+            return Ok(());
+        }
+        let schemas = self.schemas(table_name, i64::MIN, i64::MAX).await?;
+        let table = merge_schema(&schemas.iter().collect::<Vec<_>>())?;
+        let now = Utc::now().timestamp_micros();
+        for batch in batches {
+            let batch = import::arrow::conform(batch, &table, now).map_err(InvalidRecords::wrap)?;
+            self.ingest_batch(table_name, batch).await?;
+        }
+        Ok(())
+    }
+
+    /// Ingests records sent by a listener until every sender is gone.
+    /// Records are written out every STREAM_BATCH_SIZE records or
+    /// `interval`, whichever comes first. A record that does not decode is
+    /// rolled back out of the batch and a batch that fails to write is
+    /// dropped, both are logged without stopping the loop.
+    pub async fn ingest_channel(
+        &self,
+        table_name: &str,
+        mut receiver: mpsc::Receiver<Value>,
+        interval: Duration,
+    ) {
+        let mut records = JsonDecoder::new();
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            let flush = tokio::select! {
+                record = receiver.recv() => match record {
+                    Some(record) => {
+                        if let Err(e) = records.decode_value(&record) {
+                            log::warn!("dropping record for {}: {}", table_name, e);
+                        }
+                        records.len() >= STREAM_BATCH_SIZE
+                    }
+                    None => break,
+                },
+                _ = ticker.tick() => !records.is_empty(),
+            };
+            if flush {
+                if let Err(e) = self.flush_records(table_name, &mut records).await {
+                    log::error!("Error writing records to {}: {:?}", table_name, e);
+                }
+            }
+        }
+        if let Err(e) = self.flush_records(table_name, &mut records).await {
+            log::error!("Error writing records to {}: {:?}", table_name, e);
+        }
+    }
+
+    async fn flush_records(
         &self,
-        records: Vec<Value>,
-    ) -> Result<AHashMap<String, Vec<Value>>, anyhow::Error> {
-        let mut partitions: AHashMap<String, Vec<Value>> = AHashMap::new();
-        for record in records.into_iter() {
-            let (stamp, val) = self.process_bulk_item(record)?;
-            let partition_key = self.get_partition_key(stamp);
-            let entry = partitions.entry(partition_key).or_default();
-            entry.push(val);
+        table_name: &str,
+        records: &mut JsonDecoder,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(batch) = records.flush()? {
+            self.ingest_batch(table_name, batch).await?;
         }
-        Ok(partitions)
+        Ok(())
     }
 
-    async fn ingest_(&self, table_name: &str, records: Vec<Value>) -> Result<(), anyhow::Error> {
-        let partitions = self.partition_records(records)?;
-        for (partition, records) in partitions {
-            //infer schema
-            let schema = infer_schema(&records)?;
-            self.write_partition(table_name, &partition, &schema, records)
-                .await?;
+    // a batch becomes visible once every partition of it is written, on
+    // error none is and the batch can be sent again as a whole
+    async fn ingest_batch(
+        &self,
+        table_name: &str,
+        batch: RecordBatch,
+    ) -> Result<(), anyhow::Error> {
+        let partitions = self.partition_batch(batch)?;
+        let written = future::join_all(
+            partitions
+                .iter()
+                .map(|(partition, batch)| self.write_partition(table_name, partition, batch)),
+        )
+        .await;
+        let (files, errors): (Vec<_>, Vec<_>) = written.into_iter().partition(|r| r.is_ok());
+        if let Some(Err(e)) = errors.into_iter().next() {
+            for file in files.into_iter().flatten() {
+                let partition_path = format!("{table_name}/{}", file.partition());
+                self.remove_segment(&partition_path, file.segment()).await;
+            }
+            return Err(e);
         }
+        self.meta
+            .add_files(table_name, files.into_iter().flatten().collect());
         Ok(())
     }
 
+    // writes the segment files of one partition, the meta of the segment is
+    // returned for the caller to register
     async fn write_partition(
         &self,
         table_name: &str,
         partition: &str,
-        schema: &MeltSchema,
-        records: Vec<Value>,
-    ) -> Result<(), anyhow::Error> {
+        batch: &RecordBatch,
+    ) -> Result<FileMeta, anyhow::Error> {
         let partition_path = format!("{table_name}/{partition}");
         self.storage.ensure_dir(&partition_path)?;
 
         let segment_id = gen_id();
-        let filename = self.parquet_file_path(&partition_path, &segment_id);
+        let schema = MeltSchema {
+            schema: batch.schema(),
+        };
+        let datas = self.encode_parquet(&schema, batch).await?;
+        if let Err(e) = self
+            .write_segment(&partition_path, &segment_id, &schema, datas)
+            .await
+        {
+            self.remove_segment(&partition_path, &segment_id).await;
+            return Err(e);
+        }
+
+        let (min_ts, max_ts) = compute::compute_min_max::<Int64Type>(batch, TIMPSTAMP_FIELD_NAME);
+        let mut file = FileMeta::new(partition.into(), segment_id, min_ts, max_ts);
+        if let Some(trace_ids) = compute::build_bloom_filter(batch, TRACE_ID_FIELD_NAME) {
+            file = file.with_trace_ids(trace_ids);
+        }
+        Ok(file)
+    }
 
+    async fn write_segment(
+        &self,
+        partition_path: &str,
+        segment_id: &str,
+        schema: &MeltSchema,
+        datas: Vec<u8>,
+    ) -> Result<(), anyhow::Error> {
         // write dato
-        let batch = recordbatch::json_to_recordbatch(schema, &records)?;
-        let datas = parquet::write_recordbatch(schema.schema.clone(), &batch)?;
+        let filename = self.parquet_file_path(partition_path, segment_id);
         self.storage.put(&filename, datas.into()).await?;
 
         //write schema
-        let filename = self.schema_file_path(&partition_path, &segment_id);
+        let filename = self.schema_file_path(partition_path, segment_id);
         self.storage
             .put(&filename, schema.serialize()?.into())
-            .await?;
+            .await
+    }
 
-        // write meta
-        let (min_ts, max_ts) = compute::compute_min_max::<Int64Type>(&batch, TIMPSTAMP_FIELD_NAME);
-        self.meta.add_file(
-            table_name,
-            FileMeta::new(partition.into(), segment_id.into(), min_ts, max_ts),
-        );
-        Ok(())
+    // removes the files of a segment that was never registered, a failed
+    // removal only leaves an unreferenced file behind
+    async fn remove_segment(&self, partition_path: &str, segment_id: &str) {
+        for filename in [
+            self.parquet_file_path(partition_path, segment_id),
+            self.schema_file_path(partition_path, segment_id),
+        ] {
+            if let Err(e) = self.storage.delete(&filename).await {
+                log::warn!("Error removing {}: {:?}", filename, e);
+            }
+        }
+    }
+
+    // parquet encoding is CPU bound, run it on the blocking pool so it does not
+    // stall the async workers
+    async fn encode_parquet(
+        &self,
+        schema: &MeltSchema,
+        batch: &RecordBatch,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let _permit = self.encode_permits.acquire().await?;
+        let schema = schema.schema.clone();
+        let batch = batch.clone();
+        tokio::task::spawn_blocking(move || parquet::write_recordbatch(schema, &batch)).await?
     }
 
     pub async fn query(
@@ -153,6 +479,313 @@ impl IngestService {
         Ok(Response { hits: batches })
     }
 
+    /// Finds every span of `trace_id`, see `get_traces`.
+    pub async fn get_trace(
+        &self,
+        table_name: &str,
+        trace_id: &str,
+        min_ts: Option<i64>,
+        max_ts: Option<i64>,
+    ) -> Result<Option<Trace>, anyhow::Error> {
+        let mut traces = self
+            .get_traces(table_name, &[trace_id.to_owned()], min_ts, max_ts)
+            .await?;
+        Ok(traces.pop())
+    }
+
+    /// Finds every span of the given traces, those without spans are left
+    /// out and the others keep their order. The search starts in the given
+    /// window, the whole table when unset, and widens until the spans found
+    /// are surrounded by an hour of searched time on both sides and every
+    /// trace has some.
+    pub async fn get_traces(
+        &self,
+        table_name: &str,
+        trace_ids: &[String],
+        min_ts: Option<i64>,
+        max_ts: Option<i64>,
+    ) -> Result<Vec<Trace>, anyhow::Error> {
+        let Some((table_min, table_max)) = self.meta.time_range(table_name) else {
+            return Ok(vec![]);
+        };
+        if trace_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut begin = min_ts.unwrap_or(table_min).max(table_min);
+        let mut end = max_ts.unwrap_or(table_max).min(table_max);
+
+        let ids: Vec<Expr> = trace_ids.iter().map(lit).collect();
+        let filter = |schema: &Schema| {
+            schema.field_with_name(TRACE_ID_FIELD_NAME).ok()?;
+            Some(ident(TRACE_ID_FIELD_NAME).in_list(ids.clone(), false))
+        };
+        let mut searched = HashSet::new();
+        let mut spans: BTreeMap<&str, Vec<Map<String, Value>>> = BTreeMap::new();
+        loop {
+            let files = trace_ids
+                .iter()
+                .flat_map(|id| {
+                    self.meta
+                        .query_trace_files(table_name, id, Some(begin), Some(end))
+                })
+                .filter(|f| searched.insert(f.segment().to_owned()))
+                .map(|f| self.segment_path(table_name, &f))
+                .collect::<Vec<_>>();
+            if !files.is_empty() {
+                let batches = exec::exec_filter(files, filter).await?;
+                let rows = recordbatch::recordbatch_to_jsons(&batches.iter().collect::<Vec<_>>())?;
+                for row in rows {
+                    let id = row.get(TRACE_ID_FIELD_NAME).and_then(Value::as_str);
+                    if let Some(id) = id.and_then(|id| trace_ids.iter().find(|t| *t == id)) {
+                        spans.entry(id).or_default().push(row);
+                    }
+                }
+            }
+
+            let found = spans.values().flatten();
+            let (want_begin, want_end) = if spans.len() < trace_ids.len() {
+                let width = (end - begin).max(HOUR_MICROS);
+                (begin - width, end + width)
+            } else {
+                let first = found
+                    .clone()
+                    .filter_map(|s| s.get(TIMPSTAMP_FIELD_NAME)?.as_i64());
+                let last = found.filter_map(|s| s.get(END_TIME)?.as_i64());
+                (
+                    first.min().unwrap_or(begin) - HOUR_MICROS,
+                    last.max().unwrap_or(end) + HOUR_MICROS,
+                )
+            };
+            let next_begin = begin.min(want_begin).max(table_min);
+            let next_end = end.max(want_end).min(table_max);
+            if next_begin == begin && next_end == end {
+                break;
+            }
+            (begin, end) = (next_begin, next_end);
+        }
+
+        Ok(trace_ids
+            .iter()
+            .filter_map(|id| Some(Trace::build(id, spans.remove(id.as_str())?)))
+            .collect())
+    }
+
+    /// Scans the segments overlapping `[min_ts, max_ts]` with a filter built
+    /// per segment schema, see `exec::exec_filter`.
+    pub async fn scan(
+        &self,
+        table_name: &str,
+        min_ts: i64,
+        max_ts: i64,
+        filter: impl Fn(&Schema) -> Option<Expr>,
+    ) -> Result<Vec<RecordBatch>, anyhow::Error> {
+        let files = self
+            .meta
+            .query_files(table_name, Some(min_ts), Some(max_ts))
+            .iter()
+            .map(|f| self.segment_path(table_name, f))
+            .collect::<Vec<_>>();
+        let time = ident(TIMPSTAMP_FIELD_NAME);
+        let range = time.clone().gt_eq(lit(min_ts)).and(time.lt_eq(lit(max_ts)));
+        exec::exec_filter(files, |schema| Some(filter(schema)?.and(range.clone()))).await
+    }
+
+    /// Aggregates the segments overlapping `[min_ts, max_ts]` with a filter
+    /// built per segment schema, see `exec::exec_aggregate`.
+    pub async fn aggregate(
+        &self,
+        table_name: &str,
+        min_ts: i64,
+        max_ts: i64,
+        filter: impl Fn(&Schema) -> Option<Expr>,
+        group: Vec<Expr>,
+        aggr: Vec<Expr>,
+    ) -> Result<Vec<RecordBatch>, anyhow::Error> {
+        let files = self
+            .meta
+            .query_files(table_name, Some(min_ts), Some(max_ts))
+            .iter()
+            .map(|f| self.segment_path(table_name, f))
+            .collect::<Vec<_>>();
+        let time = ident(TIMPSTAMP_FIELD_NAME);
+        let range = time.clone().gt_eq(lit(min_ts)).and(time.lt_eq(lit(max_ts)));
+        exec::exec_aggregate(
+            files,
+            |schema| Some(filter(schema)?.and(range.clone())),
+            group,
+            aggr,
+        )
+        .await
+    }
+
+    /// Schemas of the segments overlapping `min_ts` to `max_ts`.
+    pub async fn schemas(
+        &self,
+        table_name: &str,
+        min_ts: i64,
+        max_ts: i64,
+    ) -> Result<Vec<Schema>, anyhow::Error> {
+        let files = self
+            .meta
+            .query_files(table_name, Some(min_ts), Some(max_ts))
+            .iter()
+            .map(|f| self.segment_path(table_name, f))
+            .collect::<Vec<_>>();
+        exec::exec_schemas(files).await
+    }
+
+    fn segment_path(&self, table_name: &str, file: &FileMeta) -> String {
+        format!(
+            "{}/{}/{}/{}.{}",
+            self.storage.root(),
+            table_name,
+            file.partition(),
+            file.segment(),
+            PARQUET_EXT
+        )
+    }
+
+    /// Names of the tables that have segments.
+    pub fn tables(&self) -> Vec<String> {
+        self.meta.tables()
+    }
+
+    /// The merged schema of the segments in `files`, `timestamp` first and
+    /// the other columns by name.
+    async fn merged_schema(&self, files: &[String]) -> Result<SchemaRef, anyhow::Error> {
+        let schemas = exec::exec_schemas(files.to_vec()).await?;
+        let table = merge_schema(&schemas.iter().collect::<Vec<_>>())?;
+        let mut fields: Vec<Field> = table.fields().iter().map(|f| f.as_ref().clone()).collect();
+        fields.sort_by(|a, b| {
+            let key = |f: &Field| (f.name() != TIMPSTAMP_FIELD_NAME, f.name().clone());
+            key(a).cmp(&key(b))
+        });
+        Ok(Arc::new(Schema::new(fields)))
+    }
+
+    /// A table over every segment of `table_name` in their merged schema,
+    /// segments without a column read it as null. `None` when the table
+    /// has no segments.
+    pub async fn table_provider(
+        &self,
+        table_name: &str,
+    ) -> Result<Option<Arc<dyn TableProvider>>, anyhow::Error> {
+        self.range_provider(table_name, None, None).await
+    }
+
+    /// Like `table_provider`, over the segments overlapping `min_ts` to
+    /// `max_ts` only.
+    pub async fn range_provider(
+        &self,
+        table_name: &str,
+        min_ts: Option<i64>,
+        max_ts: Option<i64>,
+    ) -> Result<Option<Arc<dyn TableProvider>>, anyhow::Error> {
+        let files = self
+            .meta
+            .query_files(table_name, min_ts, max_ts)
+            .iter()
+            .map(|f| self.segment_path(table_name, f))
+            .collect::<Vec<_>>();
+        if files.is_empty() {
+            return Ok(None);
+        }
+        let schema = self.merged_schema(&files).await?;
+        let urls = files
+            .iter()
+            .map(ListingTableUrl::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        let options = ListingOptions::new(Arc::new(ParquetFormat::default()))
+            .with_file_extension(format!(".{}", PARQUET_EXT));
+        let config = ListingTableConfig::new_with_multi_paths(urls)
+            .with_listing_options(options)
+            .with_schema(schema);
+        Ok(Some(Arc::new(ListingTable::try_new(config)?)))
+    }
+
+    /// The rows of `table_name` between `min_ts` and `max_ts` matching the
+    /// filter `filter` builds from the table schema, as a DataFrame to plan
+    /// on. `None` when no segment overlaps or the filter returns `None`.
+    pub async fn select(
+        &self,
+        table_name: &str,
+        min_ts: i64,
+        max_ts: i64,
+        filter: impl Fn(&Schema) -> Option<Expr>,
+    ) -> Result<Option<DataFrame>, anyhow::Error> {
+        let Some(provider) = self
+            .range_provider(table_name, Some(min_ts), Some(max_ts))
+            .await?
+        else {
+            return Ok(None);
+        };
+        let df = SessionContext::new().read_table(provider)?;
+        let schema: Schema = df.schema().into();
+        let Some(filter) = filter(&schema) else {
+            return Ok(None);
+        };
+        let time = ident(TIMPSTAMP_FIELD_NAME);
+        let range = time.clone().gt_eq(lit(min_ts)).and(time.lt_eq(lit(max_ts)));
+        Ok(Some(df.filter(filter.and(range))?))
+    }
+
+    /// Streams the rows of `table_name` matching the query `s`, all of
+    /// them when it is empty. Batches come in the schema returned with
+    /// them: `fields`, or every column of the table, `timestamp` first.
+    pub async fn query_stream(
+        &self,
+        table_name: &str,
+        s: &str,
+        min_ts: Option<i64>,
+        max_ts: Option<i64>,
+        fields: &[String],
+    ) -> Result<
+        (
+            SchemaRef,
+            BoxStream<'static, Result<RecordBatch, anyhow::Error>>,
+        ),
+        anyhow::Error,
+    > {
+        let files = self
+            .meta
+            .query_files(table_name, min_ts, max_ts)
+            .iter()
+            .map(|f| self.segment_path(table_name, f))
+            .collect::<Vec<_>>();
+        let table = self.merged_schema(&files).await?;
+        let schema = if fields.is_empty() {
+            table
+        } else {
+            // columns the table does not have are all null
+            Arc::new(Schema::new(
+                fields
+                    .iter()
+                    .map(|n| {
+                        table
+                            .field_with_name(n)
+                            .cloned()
+                            .unwrap_or_else(|_| Field::new(n, DataType::Utf8, true))
+                    })
+                    .collect::<Vec<_>>(),
+            ))
+        };
+
+        let filter = if s.trim().is_empty() {
+            let time = ident(TIMPSTAMP_FIELD_NAME);
+            let range = [
+                min_ts.map(|t| time.clone().gt_eq(lit(t))),
+                max_ts.map(|t| time.clone().lt_eq(lit(t))),
+            ];
+            range.into_iter().flatten().fold(lit(true), Expr::and)
+        } else {
+            Query::from_str(s, min_ts, max_ts)
+                .map_err(InvalidQuery::wrap)?
+                .to_exp(&Schema::empty())
+        };
+        let batches = exec::exec_stream(files, filter, schema.clone()).boxed();
+        Ok((schema, batches))
+    }
+
     pub async fn query_(
         &self,
         table_name: &str,
@@ -163,19 +796,10 @@ impl IngestService {
         let files = self.meta.query_files(table_name, min_ts, max_ts);
         let files = files
             .iter()
-            .map(|f| {
-                format!(
-                    "{}/{}/{}/{}.{}",
-                    self.storage.root(),
-                    table_name,
-                    f.partition(),
-                    f.segment(),
-                    PARQUET_EXT
-                )
-            })
+            .map(|f| self.segment_path(table_name, f))
             .collect::<Vec<_>>();
 
-        let query = Query::from_str(s, min_ts, max_ts)?;
+        let query = Query::from_str(s, min_ts, max_ts).map_err(InvalidQuery::wrap)?;
         // log::info!("query:{:?} file:{:?}", query, files);
         let res = exec::exec_search(&query, files).await?;
         Ok(res)
@@ -185,20 +809,25 @@ impl IngestService {
 #[cfg(test)]
 mod tests {
 
+    use std::io::Write;
+
     use crate::{
+        config::{self, STREAM_BATCH_SIZE},
         fusion::recordbatch::{self, recordbatch_to_jsons},
         fusion::{compute, schema},
         ingest,
         storage::Storage,
-        utils::json,
+        utils::{compress::ContentEncoding, json},
     };
     use actix_web::web::Bytes;
+    use flate2::{write::GzEncoder, Compression};
+    use futures::stream;
 
     use datafusion::arrow::datatypes::Int64Type;
     use serde_json::{json, Value};
     use tempfile::*;
 
-    use super::IngestService;
+    use super::{IngestService, InvalidRecords};
 
     fn build_ingest_service() -> IngestService {
         let tmp_dir = tempdir().unwrap();
@@ -235,10 +864,10 @@ mod tests {
         let datas = gen_test_data("f");
         let service = build_ingest_service();
         let table_name = "test";
-        service.ingest_(table_name, datas).await.unwrap();
+        service.ingest_records(table_name, &datas).await.unwrap();
 
         let datas = gen_test_data("t");
-        service.ingest_(table_name, datas).await.unwrap();
+        service.ingest_records(table_name, &datas).await.unwrap();
 
         let batches = service
             .query_(table_name, "a==1", None, None)
@@ -255,12 +884,235 @@ mod tests {
         assert_eq!(json[1]["a"].as_i64(), Some(1));
         assert_eq!(json[1]["b"].as_str(), Some("t-1"));
     }
+    #[tokio::test]
+    async fn test_bulk_stream() {
+        let mut body = String::new();
+        for i in 0..(STREAM_BATCH_SIZE + 10) {
+            body.push_str(&format!("{{\"a\": {i}, \"b\": \"s-{i}\"}}\n"));
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body.as_bytes()).unwrap();
+        let body = encoder.finish().unwrap();
+        let chunks = body
+            .chunks(1024)
+            .map(|c| Ok::<_, std::io::Error>(Bytes::copy_from_slice(c)))
+            .collect::<Vec<_>>();
+
+        let service = build_ingest_service();
+        let table_name = "test";
+        service
+            .bulk_stream(table_name, ContentEncoding::Gzip, stream::iter(chunks))
+            .await
+            .unwrap();
+
+        let batches = service
+            .query_(table_name, "a>=0", None, None)
+            .await
+            .unwrap();
+        let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(rows, STREAM_BATCH_SIZE + 10);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_stream_invalid() {
+        let mut body = String::new();
+        for i in 0..STREAM_BATCH_SIZE + 10 {
+            body.push_str(&format!("{{\"a\": {i}}}\n"));
+        }
+        body.push_str("{\"a\": \n{\"a\": 1}\n");
+        let chunks = body
+            .as_bytes()
+            .chunks(1024)
+            .map(|c| Ok::<_, std::io::Error>(Bytes::copy_from_slice(c)))
+            .collect::<Vec<_>>();
+
+        let service = build_ingest_service();
+        let err = service
+            .bulk_stream("test", ContentEncoding::Identity, stream::iter(chunks))
+            .await
+            .unwrap_err();
+        assert!(err.error.is::<InvalidRecords>());
+        assert!(err
+            .error
+            .to_string()
+            .starts_with(&format!("record {}:", STREAM_BATCH_SIZE + 11)));
+        // the first batch was written before the bad line was read
+        assert!(err.stored >= STREAM_BATCH_SIZE);
+        let batches = service.query_("test", "a>=0", None, None).await.unwrap();
+        let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(rows, err.stored);
+    }
+
+    #[tokio::test]
+    async fn test_ingest_records_invalid() {
+        let service = build_ingest_service();
+        let records = vec![json!({"a": 1}), json!({"a": [{"b": 1}], "c": {"d": {}}})];
+        let err = service.ingest_records("test", &records).await.unwrap_err();
+        assert!(err.is::<InvalidRecords>());
+        assert!(err.to_string().contains("not support array or object"));
+        // the valid records are kept when the invalid ones are dropped
+        service
+            .ingest_valid_records("test", &records)
+            .await
+            .unwrap();
+        let batches = service.query_("test", "a>0", None, None).await.unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 1);
+
+        let err = service
+            .ingest("test", Bytes::from(r#"[{"a": 1}, {"a": "#))
+            .await
+            .unwrap_err();
+        assert!(err.is::<InvalidRecords>());
+    }
+
+    #[tokio::test]
+    async fn test_ingest_channel() {
+        let service = build_ingest_service();
+        let table_name = "test";
+        let (sender, receiver) = tokio::sync::mpsc::channel(8);
+        sender.send(json!({"a": 1, "b": "x"})).await.unwrap();
+        // fails on `c` after `a` and `b` were decoded
+        sender
+            .send(json!({"a": 2, "b": "y", "c": {"d": 1}}))
+            .await
+            .unwrap();
+        sender.send(json!({"b": "z", "a": 3})).await.unwrap();
+        drop(sender);
+        service
+            .ingest_channel(table_name, receiver, std::time::Duration::from_secs(60))
+            .await;
+
+        let batches = service.query_(table_name, "a>0", None, None).await.unwrap();
+        let batches = batches.iter().collect::<Vec<_>>();
+        let mut rows = recordbatch_to_jsons(&batches).unwrap();
+        rows.sort_by_key(|r| r["a"].as_i64());
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["b"], "x");
+        assert_eq!(rows[1]["a"], 3);
+        assert_eq!(rows[1]["b"], "z");
+    }
+
+    #[tokio::test]
+    async fn test_ingest_partitions() {
+        let data = r#"{"a": 1, "timestamp": "2023-12-01T10:10:00Z"}
+{"a": 2, "timestamp": "2023-12-01T11:10:00Z"}
+{"a": 3, "timestamp": "2023-12-01T10:20:00Z"}"#;
+        let service = build_ingest_service();
+        let table_name = "test";
+        service.bulk(table_name, Bytes::from(data)).await.unwrap();
+
+        let files = service.meta.query_files(table_name, None, None);
+        let mut partitions = files.iter().map(|f| f.partition()).collect::<Vec<_>>();
+        partitions.sort();
+        assert_eq!(partitions, vec!["2023-12-01-10", "2023-12-01-11"]);
+
+        let batches = service
+            .query_(table_name, "a>0", Some(1_701_428_400_000_000), None)
+            .await
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ingest_parallel_partitions() {
+        let mut body = String::new();
+        for i in 0..120 {
+            body.push_str(&format!(
+                "{{\"a\": {i}, \"timestamp\": {}}}\n",
+                1_701_424_800 + i * 600
+            ));
+        }
+        let service = build_ingest_service().with_max_encode_tasks(1);
+        let table_name = "test";
+        service.bulk(table_name, Bytes::from(body)).await.unwrap();
+
+        let files = service.meta.query_files(table_name, None, None);
+        assert_eq!(files.len(), 20);
+        let batches = service
+            .query_(table_name, "a>=0", None, None)
+            .await
+            .unwrap();
+        let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(rows, 120);
+    }
+
+    #[tokio::test]
+    async fn test_ingest_partitions_failed() {
+        let dir = tempdir().unwrap();
+        let service = IngestService::new(Storage::new(dir.path()));
+        let table_name = "test";
+        // the second partition can not be created as a file takes its path
+        std::fs::create_dir_all(dir.path().join(table_name)).unwrap();
+        std::fs::write(dir.path().join(table_name).join("2023-12-01-11"), "").unwrap();
+        let data = r#"{"a": 1, "timestamp": "2023-12-01T10:10:00Z"}
+{"a": 2, "timestamp": "2023-12-01T11:10:00Z"}"#;
+        assert!(service.bulk(table_name, Bytes::from(data)).await.is_err());
+
+        assert!(service.meta.query_files(table_name, None, None).is_empty());
+        let written = std::fs::read_dir(dir.path().join(table_name).join("2023-12-01-10"));
+        assert_eq!(written.unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_trace() {
+        let hour = config::HOUR_MICROS;
+        let base = 1_700_000_000_000_000i64 / hour * hour;
+        let span = |trace: &str, id: &str, parent: &str, start: i64| {
+            json!({"timestamp": start, "trace_id": trace, "span_id": id,
+                   "parent_span_id": parent, "start_time": start,
+                   "end_time": start + 1000, "duration": 1000})
+        };
+        let service = build_ingest_service();
+        let table_name = "spans";
+        let spans = [
+            span("t1", "a", "", base),
+            span("t1", "b", "a", base + hour / 2),
+            span("t2", "x", "", base + hour / 2),
+        ];
+        service.ingest_records(table_name, &spans).await.unwrap();
+        // a late span one partition later and an unrelated trace far away
+        let spans = [
+            span("t1", "c", "b", base + hour + hour / 2),
+            span("t3", "y", "", base + 5 * hour),
+        ];
+        service.ingest_records(table_name, &spans).await.unwrap();
+
+        let trace = service
+            .get_trace(table_name, "t1", Some(base), Some(base + 10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(trace.span_count, 3);
+        assert_eq!(trace.roots.len(), 1);
+        assert_eq!(trace.roots[0].children[0].children[0].depth, 2);
+
+        let trace = service.get_trace(table_name, "t3", None, None).await;
+        assert_eq!(trace.unwrap().unwrap().span_count, 1);
+        let trace = service.get_trace(table_name, "t9", None, None).await;
+        assert!(trace.unwrap().is_none());
+        let trace = service.get_trace("missing", "t1", None, None).await;
+        assert!(trace.unwrap().is_none());
+
+        // several traces in one search, in the order asked for
+        let ids = ["t3", "t9", "t1"].map(str::to_owned);
+        let traces = service
+            .get_traces(table_name, &ids, Some(base), Some(base + 10))
+            .await
+            .unwrap();
+        let found: Vec<_> = traces
+            .iter()
+            .map(|t| (t.trace_id.as_str(), t.span_count))
+            .collect();
+        assert_eq!(found, vec![("t3", 1), ("t1", 3)]);
+    }
+
     #[test]
     fn test_dataframe() {
         let data = r#"[{"a": 1, "b": 1},{"a": 2, "b": 1}]"#;
         // let name = "test";
         let data = Bytes::from(data);
-        let s = build_ingest_service();
+        let _s = build_ingest_service();
         let values = json::parse_json(data).unwrap();
         let schema = schema::infer_schema(&values).unwrap();
         let batch = recordbatch::json_to_recordbatch(&schema, &values).unwrap();
@@ -0,0 +1,610 @@
+//! Jaeger query API over the span table, so the Jaeger UI and Grafana's
+//! Jaeger datasource can browse melt traces.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::anyhow;
+use arrow_cast::cast;
+use arrow_schema::{DataType, Schema};
+use datafusion::{
+    arrow::{array::AsArray, datatypes::Int64Type},
+    logical_expr::{ident, lit, max, Expr},
+};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    config::{TIMPSTAMP_FIELD_NAME, TRACE_ID_FIELD_NAME},
+    ingest::IngestService,
+    otlp::traces::{
+        DURATION, KIND, NAME, PARENT_SPAN_ID, SERVICE_NAME, SPAN_ID, START_TIME, STATUS_CODE,
+        STATUS_MESSAGE,
+    },
+    trace::{Trace, TraceSpan},
+};
+
+pub static DEFAULT_LIMIT: usize = 20;
+// searches without `start` look this far back from `end`
+static DEFAULT_LOOKBACK: i64 = 3600 * 1_000_000;
+
+/// Parses a Go style duration like `1h30m` or `1.5ms` into microseconds,
+/// lookbacks may also use days.
+pub fn parse_duration(s: &str) -> Result<i64, anyhow::Error> {
+    let invalid = || anyhow!("invalid duration {:?}", s);
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut total = 0.0;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(invalid)?;
+        let value: f64 = rest[..end].parse().map_err(|_| invalid())?;
+        rest = &rest[end..];
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let micros = match &rest[..unit] {
+            "ns" => 0.001,
+            "us" | "µs" => 1.0,
+            "ms" => 1_000.0,
+            "s" => 1_000_000.0,
+            "m" => 60_000_000.0,
+            "h" => 3_600_000_000.0,
+            "d" => 86_400_000_000.0,
+            _ => return Err(invalid()),
+        };
+        total += value * micros;
+        rest = &rest[unit..];
+    }
+    Ok(total as i64)
+}
+
+/// Trace ids lose their leading zeros in some clients, pads them back to
+/// 16 or 32 lower hex digits.
+pub fn normalize_trace_id(id: &str) -> Result<String, anyhow::Error> {
+    let width = if id.len() <= 16 { 16 } else { 32 };
+    let id = format!("{:0>width$}", id.to_ascii_lowercase());
+    if id.len() > 32 || hex::decode(&id).is_err() {
+        return Err(anyhow!("invalid trace id {:?}", id));
+    }
+    Ok(id)
+}
+
+/// The parameters of a `/api/traces` search, times in microseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceQuery {
+    pub service: String,
+    pub operation: Option<String>,
+    pub tags: Vec<(String, String)>,
+    pub min_duration: Option<i64>,
+    pub max_duration: Option<i64>,
+    pub start: i64,
+    pub end: i64,
+    pub limit: usize,
+}
+
+impl TraceQuery {
+    /// Reads the query string pairs. Tags come either as a `tags` JSON
+    /// object or as repeated `tag=key:value` pairs.
+    pub fn from_params(params: &[(String, String)], now: i64) -> Result<TraceQuery, anyhow::Error> {
+        let get = |name: &str| {
+            params
+                .iter()
+                .find(|(k, v)| k == name && !v.is_empty())
+                .map(|(_, v)| v.as_str())
+        };
+        let service = get("service").ok_or_else(|| anyhow!("parameter 'service' is required"))?;
+
+        let mut tags = vec![];
+        for (k, v) in params {
+            match k.as_str() {
+                "tag" => {
+                    let (key, value) = v
+                        .split_once(':')
+                        .ok_or_else(|| anyhow!("malformed 'tag' parameter, expecting key:value"))?;
+                    tags.push((key.to_owned(), value.to_owned()));
+                }
+                "tags" if !v.is_empty() => {
+                    let map: Map<String, Value> = serde_json::from_str(v)
+                        .map_err(|e| anyhow!("malformed 'tags' parameter: {}", e))?;
+                    for (key, value) in map {
+                        let value = match value {
+                            Value::String(s) => s,
+                            v => v.to_string(),
+                        };
+                        tags.push((key, value));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let parse_micros = |name: &str| -> Result<Option<i64>, anyhow::Error> {
+            get(name)
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| anyhow!("invalid '{}' parameter", name))
+                })
+                .transpose()
+        };
+        let end = parse_micros("end")?.unwrap_or(now);
+        let start = match parse_micros("start")? {
+            Some(v) => v,
+            None => match get("lookback") {
+                Some(v) if v != "custom" => end - parse_duration(v)?,
+                _ => end - DEFAULT_LOOKBACK,
+            },
+        };
+        let limit = match get("limit") {
+            Some(v) => v
+                .parse()
+                .map_err(|_| anyhow!("invalid 'limit' parameter"))?,
+            None => DEFAULT_LIMIT,
+        };
+        Ok(TraceQuery {
+            service: service.to_owned(),
+            operation: get("operation").map(str::to_owned),
+            tags,
+            min_duration: get("minDuration").map(parse_duration).transpose()?,
+            max_duration: get("maxDuration").map(parse_duration).transpose()?,
+            start,
+            end,
+            limit,
+        })
+    }
+
+    // `None` when the segment lacks a column the search needs
+    fn filter(&self, schema: &Schema) -> Option<Expr> {
+        schema.field_with_name(SERVICE_NAME).ok()?;
+        let mut filter = ident(SERVICE_NAME).eq(lit(&self.service));
+        if let Some(operation) = &self.operation {
+            schema.field_with_name(NAME).ok()?;
+            filter = filter.and(ident(NAME).eq(lit(operation)));
+        }
+        for (key, value) in &self.tags {
+            filter = filter.and(tag_filter(key, value, schema)?);
+        }
+        if self.min_duration.is_some() || self.max_duration.is_some() {
+            schema.field_with_name(DURATION).ok()?;
+        }
+        if let Some(min) = self.min_duration {
+            filter = filter.and(ident(DURATION).gt_eq(lit(min)));
+        }
+        if let Some(max) = self.max_duration {
+            filter = filter.and(ident(DURATION).lt_eq(lit(max)));
+        }
+        Some(filter)
+    }
+}
+
+// the tags Jaeger derives from span fields map back to their columns, any
+// other tag is a span attribute or else a resource attribute
+fn tag_filter(key: &str, value: &str, schema: &Schema) -> Option<Expr> {
+    let (column, value) = match key {
+        "error" if value == "true" => (STATUS_CODE.to_owned(), "error".to_owned()),
+        "otel.status_code" => (STATUS_CODE.to_owned(), value.to_ascii_lowercase()),
+        "otel.status_description" => (STATUS_MESSAGE.to_owned(), value.to_owned()),
+        "span.kind" => (KIND.to_owned(), value.to_owned()),
+        _ => {
+            let column = [format!("attributes.{}", key), format!("resource.{}", key)]
+                .into_iter()
+                .find(|c| schema.field_with_name(c).is_ok())?;
+            (column, value.to_owned())
+        }
+    };
+    let field = schema.field_with_name(&column).ok()?;
+    let expr = if field.data_type() == &DataType::Utf8 {
+        ident(column)
+    } else {
+        datafusion::logical_expr::cast(ident(column), DataType::Utf8)
+    };
+    Some(expr.eq(lit(value)))
+}
+
+// distinct non empty values of a Utf8 column over the matching rows,
+// DataFusion reads the column alone and deduplicates it per segment
+async fn distinct_values(
+    service: &IngestService,
+    table: &str,
+    column: &str,
+    filter: impl Fn(&Schema) -> Option<Expr>,
+) -> Result<Vec<String>, anyhow::Error> {
+    let batches = service
+        .aggregate(
+            table,
+            i64::MIN,
+            i64::MAX,
+            |schema| {
+                let field = schema.field_with_name(column).ok()?;
+                if field.data_type() != &DataType::Utf8 {
+                    return None;
+                }
+                Some(filter(schema)?.and(ident(column).not_eq(lit(""))))
+            },
+            vec![ident(column)],
+            vec![],
+        )
+        .await?;
+    let mut values: Vec<String> = batches
+        .iter()
+        .filter_map(|b| b.column(0).as_string_opt::<i32>())
+        .flat_map(|c| c.iter().flatten().map(str::to_owned))
+        .collect();
+    values.sort();
+    values.dedup();
+    Ok(values)
+}
+
+/// Every service that has spans in `table`.
+pub async fn services(service: &IngestService, table: &str) -> Result<Vec<String>, anyhow::Error> {
+    distinct_values(service, table, SERVICE_NAME, |_| Some(lit(true))).await
+}
+
+/// The span names of one service.
+pub async fn operations(
+    service: &IngestService,
+    table: &str,
+    name: &str,
+) -> Result<Vec<String>, anyhow::Error> {
+    distinct_values(service, table, NAME, |schema| {
+        schema.field_with_name(SERVICE_NAME).ok()?;
+        Some(ident(SERVICE_NAME).eq(lit(name)))
+    })
+    .await
+}
+
+/// Finds the traces with a span matching `query`, the most recent first,
+/// and loads them whole in one search.
+pub async fn find_traces(
+    service: &IngestService,
+    table: &str,
+    query: &TraceQuery,
+) -> Result<Vec<Trace>, anyhow::Error> {
+    // the latest span of every matching trace, computed by DataFusion
+    // from the trace id and timestamp columns alone
+    let batches = service
+        .aggregate(
+            table,
+            query.start,
+            query.end,
+            |schema| {
+                schema.field_with_name(TRACE_ID_FIELD_NAME).ok()?;
+                query.filter(schema)
+            },
+            vec![ident(TRACE_ID_FIELD_NAME)],
+            vec![max(ident(TIMPSTAMP_FIELD_NAME))],
+        )
+        .await?;
+    let mut latest: HashMap<String, i64> = HashMap::new();
+    for batch in &batches {
+        let Some(ids) = batch.column(0).as_string_opt::<i32>() else {
+            continue;
+        };
+        let time = cast(batch.column(1), &DataType::Int64)?;
+        let time = time.as_primitive::<Int64Type>();
+        for (id, ts) in ids.iter().zip(time.iter()) {
+            if let (Some(id), Some(ts)) = (id, ts) {
+                let entry = latest.entry(id.to_owned()).or_insert(ts);
+                *entry = (*entry).max(ts);
+            }
+        }
+    }
+    let mut ids: Vec<(String, i64)> = latest.into_iter().collect();
+    ids.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ids.truncate(query.limit);
+
+    let ids: Vec<String> = ids.into_iter().map(|(id, _)| id).collect();
+    service
+        .get_traces(table, &ids, Some(query.start), Some(query.end))
+        .await
+}
+
+fn key_value(key: &str, value: &Value) -> Value {
+    let (kind, value) = match value {
+        Value::Bool(_) => ("bool", value.clone()),
+        Value::Number(n) if n.is_f64() => ("float64", value.clone()),
+        Value::Number(_) => ("int64", value.clone()),
+        Value::String(_) => ("string", value.clone()),
+        v => ("string", Value::from(v.to_string())),
+    };
+    json!({"key": key, "type": kind, "value": value})
+}
+
+fn get_str<'a>(span: &'a Map<String, Value>, key: &str) -> &'a str {
+    span.get(key).and_then(Value::as_str).unwrap_or_default()
+}
+
+fn get_i64(span: &Map<String, Value>, key: &str) -> i64 {
+    span.get(key).and_then(Value::as_i64).unwrap_or_default()
+}
+
+fn flatten<'a>(span: &'a TraceSpan, out: &mut Vec<&'a Map<String, Value>>) {
+    out.push(&span.span);
+    for child in &span.children {
+        flatten(child, out);
+    }
+}
+
+fn span_tags(span: &Map<String, Value>) -> Vec<Value> {
+    let mut tags: Vec<Value> = span
+        .iter()
+        .filter(|(_, v)| !v.is_null())
+        .filter_map(|(k, v)| Some(key_value(k.strip_prefix("attributes.")?, v)))
+        .collect();
+    match get_str(span, KIND) {
+        "" | "unspecified" => {}
+        kind => tags.push(key_value("span.kind", &Value::from(kind))),
+    }
+    let status = get_str(span, STATUS_CODE);
+    // zipkin spans already carry their `error` tag as an attribute
+    if status == "error" && !span.contains_key("attributes.error") {
+        tags.push(key_value("error", &Value::from(true)));
+    }
+    if !status.is_empty() && status != "unset" {
+        tags.push(key_value(
+            "otel.status_code",
+            &Value::from(status.to_ascii_uppercase()),
+        ));
+    }
+    if let Some(message) = span.get(STATUS_MESSAGE).filter(|v| !v.is_null()) {
+        tags.push(key_value("otel.status_description", message));
+    }
+    for (column, key) in [
+        ("scope.name", "otel.library.name"),
+        ("scope.version", "otel.library.version"),
+    ] {
+        if let Some(v) = span.get(column).filter(|v| !v.is_null()) {
+            tags.push(key_value(key, v));
+        }
+    }
+    tags
+}
+
+// events are kept as JSON text, each becomes a log with an `event` field
+fn span_logs(span: &Map<String, Value>) -> Vec<Value> {
+    let events: Vec<Value> = serde_json::from_str(get_str(span, "events")).unwrap_or_default();
+    events
+        .iter()
+        .map(|event| {
+            let mut fields = vec![key_value("event", &event["name"])];
+            if let Some(attributes) = event["attributes"].as_object() {
+                fields.extend(attributes.iter().map(|(k, v)| key_value(k, v)));
+            }
+            json!({"timestamp": event["time"], "fields": fields})
+        })
+        .collect()
+}
+
+fn span_references(trace_id: &str, span: &Map<String, Value>) -> Vec<Value> {
+    let mut references = vec![];
+    let parent = get_str(span, PARENT_SPAN_ID);
+    if !parent.is_empty() {
+        references.push(json!({"refType": "CHILD_OF", "traceID": trace_id, "spanID": parent}));
+    }
+    let links: Vec<Value> = serde_json::from_str(get_str(span, "links")).unwrap_or_default();
+    for link in links {
+        references.push(json!({
+            "refType": "FOLLOWS_FROM",
+            "traceID": link["trace_id"],
+            "spanID": link["span_id"],
+        }));
+    }
+    references
+}
+
+/// A trace in Jaeger's JSON model. Spans of the same service and resource
+/// share a process.
+pub fn trace_to_json(trace: &Trace) -> Value {
+    let mut spans = vec![];
+    for root in &trace.roots {
+        flatten(root, &mut spans);
+    }
+    let mut processes: Vec<Value> = vec![];
+    let mut jaeger_spans = vec![];
+    for span in spans {
+        let tags: Vec<Value> = span
+            .iter()
+            .filter(|(k, v)| k.as_str() != SERVICE_NAME && !v.is_null())
+            .filter_map(|(k, v)| Some(key_value(k.strip_prefix("resource.")?, v)))
+            .collect();
+        let process = json!({"serviceName": get_str(span, SERVICE_NAME), "tags": tags});
+        let index = match processes.iter().position(|p| p == &process) {
+            Some(i) => i,
+            None => {
+                processes.push(process);
+                processes.len() - 1
+            }
+        };
+        let start = span
+            .get(START_TIME)
+            .or_else(|| span.get(TIMPSTAMP_FIELD_NAME))
+            .and_then(Value::as_i64)
+            .unwrap_or_default();
+        jaeger_spans.push(json!({
+            "traceID": trace.trace_id,
+            "spanID": get_str(span, SPAN_ID),
+            "operationName": get_str(span, NAME),
+            "references": span_references(&trace.trace_id, span),
+            "startTime": start,
+            "duration": get_i64(span, DURATION),
+            "flags": span.get("flags").and_then(Value::as_i64).unwrap_or(1),
+            "tags": span_tags(span),
+            "logs": span_logs(span),
+            "processID": format!("p{}", index + 1),
+            "warnings": null,
+        }));
+    }
+    let processes: BTreeMap<String, Value> = processes
+        .into_iter()
+        .enumerate()
+        .map(|(i, p)| (format!("p{}", i + 1), p))
+        .collect();
+    json!({
+        "traceID": trace.trace_id,
+        "spans": jaeger_spans,
+        "processes": processes,
+        "warnings": null,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(k: &str, v: &str) -> (String, String) {
+        (k.to_owned(), v.to_owned())
+    }
+
+    #[test]
+    fn test_parse_params() {
+        assert_eq!(parse_duration("1h30m").unwrap(), 5_400_000_000);
+        assert_eq!(parse_duration("1.5ms").unwrap(), 1_500);
+        assert_eq!(parse_duration("2d").unwrap(), 172_800_000_000);
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("1x").is_err());
+
+        assert_eq!(normalize_trace_id("ABC").unwrap(), "0000000000000abc");
+        assert_eq!(normalize_trace_id("1").unwrap().len(), 16);
+        assert_eq!(normalize_trace_id(&"1".repeat(20)).unwrap().len(), 32);
+        assert!(normalize_trace_id("xyz").is_err());
+
+        let params = vec![
+            pair("service", "api"),
+            pair("operation", ""),
+            pair("tags", r#"{"http.status_code":"500","retry":true}"#),
+            pair("tag", "error:true"),
+            pair("minDuration", "10ms"),
+            pair("lookback", "2h"),
+            pair("end", "10000000000"),
+            pair("limit", "5"),
+        ];
+        let query = TraceQuery::from_params(&params, 0).unwrap();
+        assert_eq!(
+            query,
+            TraceQuery {
+                service: "api".to_owned(),
+                operation: None,
+                tags: vec![
+                    pair("http.status_code", "500"),
+                    pair("retry", "true"),
+                    pair("error", "true"),
+                ],
+                min_duration: Some(10_000),
+                max_duration: None,
+                start: 10_000_000_000 - 7_200_000_000,
+                end: 10_000_000_000,
+                limit: 5,
+            }
+        );
+        let query = TraceQuery::from_params(&[pair("service", "api")], 5_000_000_000).unwrap();
+        assert_eq!(query.start, 5_000_000_000 - DEFAULT_LOOKBACK);
+        assert_eq!(query.limit, DEFAULT_LIMIT);
+        assert!(TraceQuery::from_params(&[], 0).is_err());
+        assert!(TraceQuery::from_params(&[pair("service", "a"), pair("tag", "x")], 0).is_err());
+    }
+
+    #[test]
+    fn test_trace_to_json() {
+        let spans = vec![
+            json!({
+                "trace_id": "t1", "span_id": "a", "parent_span_id": "", "name": "GET /",
+                "kind": "server", "start_time": 100, "end_time": 300, "duration": 200,
+                "status_code": "error", "status_message": "boom",
+                "resource.service.name": "frontend", "resource.host.name": "h1",
+                "attributes.http.status_code": 500,
+                "events": r#"[{"time":150,"name":"retry","attributes":{"attempt":2}}]"#,
+            }),
+            json!({
+                "trace_id": "t1", "span_id": "b", "parent_span_id": "a", "name": "query",
+                "kind": "unspecified", "start_time": 120, "end_time": 200, "duration": 80,
+                "status_code": "unset", "resource.service.name": "db",
+            }),
+        ];
+        let spans = spans
+            .into_iter()
+            .map(|v| v.as_object().unwrap().clone())
+            .collect();
+        let trace = trace_to_json(&Trace::build("t1", spans));
+        assert_eq!(trace["traceID"], "t1");
+        let root = &trace["spans"][0];
+        assert_eq!(root["operationName"], "GET /");
+        assert_eq!(root["references"], json!([]));
+        assert_eq!(root["startTime"], 100);
+        assert_eq!(root["processID"], "p1");
+        let tags = root["tags"].as_array().unwrap();
+        assert!(tags.contains(&json!({"key": "http.status_code", "type": "int64", "value": 500})));
+        assert!(tags.contains(&json!({"key": "span.kind", "type": "string", "value": "server"})));
+        assert!(tags.contains(&json!({"key": "error", "type": "bool", "value": true})));
+        assert_eq!(root["logs"][0]["timestamp"], 150);
+        assert_eq!(
+            root["logs"][0]["fields"][1],
+            json!({"key": "attempt", "type": "int64", "value": 2})
+        );
+
+        let child = &trace["spans"][1];
+        assert_eq!(
+            child["references"],
+            json!([{"refType": "CHILD_OF", "traceID": "t1", "spanID": "a"}])
+        );
+        assert_eq!(child["tags"], json!([]));
+        assert_eq!(child["processID"], "p2");
+        assert_eq!(
+            trace["processes"]["p1"],
+            json!({"serviceName": "frontend", "tags": [{"key": "host.name", "type": "string", "value": "h1"}]})
+        );
+        assert_eq!(trace["processes"]["p2"]["serviceName"], "db");
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        use crate::storage::Storage;
+
+        let dir = tempfile::tempdir().unwrap();
+        let service = IngestService::new(Storage::new(dir.path()));
+        let span = |trace: &str, id: &str, svc: &str, name: &str, start: i64| {
+            json!({"timestamp": start, "trace_id": trace, "span_id": id,
+                   "parent_span_id": "", "name": name, "start_time": start,
+                   "end_time": start + 10, "duration": 10,
+                   "resource.service.name": svc})
+        };
+        let base = 1_700_000_000_000_000i64;
+        let spans = [
+            span("t1", "a", "api", "GET /", base),
+            span("t1", "b", "db", "query", base + 1),
+            span("t2", "c", "api", "POST /", base + 100),
+            span("t3", "d", "api", "GET /", base + 50),
+            span("t4", "e", "", "", base + 60),
+        ];
+        service.ingest_records("spans", &spans).await.unwrap();
+
+        let names = services(&service, "spans").await.unwrap();
+        assert_eq!(names, vec!["api", "db"]);
+        let names = operations(&service, "spans", "api").await.unwrap();
+        assert_eq!(names, vec!["GET /", "POST /"]);
+
+        let query = TraceQuery {
+            service: "api".to_owned(),
+            operation: Some("GET /".to_owned()),
+            tags: vec![],
+            min_duration: None,
+            max_duration: None,
+            start: base,
+            end: base + 1000,
+            limit: 20,
+        };
+        let traces = find_traces(&service, "spans", &query).await.unwrap();
+        let ids: Vec<_> = traces.iter().map(|t| t.trace_id.as_str()).collect();
+        assert_eq!(ids, vec!["t3", "t1"]);
+        assert_eq!(traces[1].span_count, 2);
+
+        let query = TraceQuery {
+            operation: None,
+            limit: 1,
+            ..query
+        };
+        let traces = find_traces(&service, "spans", &query).await.unwrap();
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].trace_id, "t2");
+    }
+}
@@ -2,13 +2,26 @@ pub mod app;
 pub mod compact;
 pub mod config;
 pub mod exec;
+pub mod flight;
+pub mod fluent;
 pub mod fusion;
 pub mod id_gen;
+pub mod import;
+pub mod influx;
 pub mod ingest;
+pub mod jaeger;
+pub mod loki;
 pub mod meta;
+pub mod otlp;
+pub mod pg;
+pub mod prom;
 pub mod query;
 pub mod router;
 pub mod schema;
 pub mod server;
 pub mod storage;
+pub mod syslog;
+pub mod tail;
+pub mod trace;
 pub mod utils;
+pub mod zipkin;
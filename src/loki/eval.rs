@@ -0,0 +1,770 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use arrow_cast::cast;
+use arrow_schema::{DataType, Schema};
+use datafusion::{
+    arrow::{
+        array::{Array, AsArray},
+        datatypes::Int64Type,
+        record_batch::RecordBatch,
+    },
+    logical_expr::{self, binary_expr, count, ident, lit, Expr, Operator},
+};
+use futures::StreamExt;
+use serde_json::{json, Value};
+
+use super::{
+    logql::{LineFilterOp, LogExpr, LogSelector, RangeFunction, Stage},
+    MESSAGE_FIELD_NAME,
+};
+use crate::{
+    config::TIMPSTAMP_FIELD_NAME,
+    ingest::IngestService,
+    prom::eval::{aggregate, gcd, Labels, QueryResult, MAX_POINTS},
+};
+
+// label set on lines a parser stage could not read, as Loki does
+pub static ERROR_LABEL: &str = "__error__";
+
+/// One log line, its time in microseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub timestamp: i64,
+    pub line: String,
+    pub labels: Labels,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+pub enum LogResult {
+    Streams(Vec<(Labels, Vec<LogEntry>)>),
+    Matrix(QueryResult),
+}
+
+impl LogResult {
+    /// The `data` member of a Loki query API response.
+    pub fn to_json(&self) -> Value {
+        match self {
+            LogResult::Streams(streams) => {
+                let result: Vec<Value> = streams
+                    .iter()
+                    .map(|(labels, entries)| {
+                        // nanoseconds do not fit a double, Loki sends strings
+                        let values: Vec<Value> = entries
+                            .iter()
+                            .map(|e| json!([(e.timestamp * 1000).to_string(), e.line]))
+                            .collect();
+                        json!({"stream": labels, "values": values})
+                    })
+                    .collect();
+                json!({"resultType": "streams", "result": result, "stats": {}})
+            }
+            LogResult::Matrix(matrix) => {
+                let mut data = matrix.to_json();
+                data["stats"] = json!({});
+                data
+            }
+        }
+    }
+}
+
+// stream matchers and line filters are pushed down to DataFusion, parser
+// stages and label filters run on the fetched lines
+fn selector_filter(selector: &LogSelector, schema: &Schema) -> Option<Expr> {
+    let mut filter = crate::prom::eval::matcher_filter(&selector.matchers, schema)?;
+    let has_message = schema.field_with_name(MESSAGE_FIELD_NAME).is_ok();
+    for f in selector.line_filters() {
+        if !has_message {
+            if f.matches("") {
+                continue;
+            }
+            return None;
+        }
+        let op = match f.op {
+            LineFilterOp::Contains | LineFilterOp::Regex => Operator::RegexMatch,
+            LineFilterOp::NotContains | LineFilterOp::NotRegex => Operator::RegexNotMatch,
+        };
+        filter = filter.and(binary_expr(ident(MESSAGE_FIELD_NAME), op, lit(f.pattern())));
+    }
+    Some(filter)
+}
+
+/// Reads rows into entries: the stream labels are every non empty Utf8
+/// column besides the line.
+pub fn batches_to_entries(batches: &[RecordBatch]) -> Result<Vec<LogEntry>, anyhow::Error> {
+    let mut entries = vec![];
+    for batch in batches {
+        let Some(time) = batch.column_by_name(TIMPSTAMP_FIELD_NAME) else {
+            continue;
+        };
+        let time = cast(time, &DataType::Int64)?;
+        let time = time.as_primitive::<Int64Type>();
+        let message = batch
+            .column_by_name(MESSAGE_FIELD_NAME)
+            .map(|c| cast(c, &DataType::Utf8))
+            .transpose()?;
+        let message = message.as_ref().map(|c| c.as_string::<i32>());
+        let labels: Vec<_> = batch
+            .schema()
+            .fields()
+            .iter()
+            .zip(batch.columns())
+            .filter(|(f, _)| f.name() != TIMPSTAMP_FIELD_NAME && f.name() != MESSAGE_FIELD_NAME)
+            .filter_map(|(f, c)| Some((f.name().clone(), c.as_string_opt::<i32>()?)))
+            .collect();
+        for row in 0..batch.num_rows() {
+            if time.is_null(row) {
+                continue;
+            }
+            let line = match message {
+                Some(m) if m.is_valid(row) => m.value(row).to_owned(),
+                _ => String::new(),
+            };
+            entries.push(LogEntry {
+                timestamp: time.value(row),
+                line,
+                labels: labels
+                    .iter()
+                    .filter(|(_, c)| c.is_valid(row) && !c.value(row).is_empty())
+                    .map(|(name, c)| (name.clone(), c.value(row).to_owned()))
+                    .collect(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+// label names may only hold [a-zA-Z0-9_]
+fn sanitize_label(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_digit() => format!("_{}", name),
+        _ => name,
+    }
+}
+
+// parsed labels never replace stream labels, they get a suffix instead
+fn add_extracted(labels: &mut Labels, stream: &Labels, name: &str, value: String) {
+    let name = sanitize_label(name);
+    if name.is_empty() {
+        return;
+    }
+    if stream.contains_key(&name) {
+        labels.insert(format!("{}_extracted", name), value);
+    } else {
+        labels.insert(name, value);
+    }
+}
+
+fn flatten_json(prefix: &str, value: &Value, stream: &Labels, labels: &mut Labels) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                let name = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}_{}", prefix, k)
+                };
+                flatten_json(&name, v, stream, labels);
+            }
+        }
+        // arrays are skipped like Loki does without expressions
+        Value::Array(_) | Value::Null => {}
+        Value::String(s) => add_extracted(labels, stream, prefix, s.clone()),
+        v => add_extracted(labels, stream, prefix, v.to_string()),
+    }
+}
+
+fn parse_json(entry: &mut LogEntry) {
+    let stream = entry.labels.clone();
+    match serde_json::from_str::<Value>(&entry.line) {
+        Ok(v @ Value::Object(_)) => flatten_json("", &v, &stream, &mut entry.labels),
+        _ => {
+            entry
+                .labels
+                .insert(ERROR_LABEL.to_owned(), "JSONParserErr".to_owned());
+        }
+    }
+}
+
+// key=value pairs separated by spaces, values may be double quoted
+fn logfmt_pairs(line: &str) -> Option<Vec<(String, String)>> {
+    let mut pairs = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Some(pairs);
+        }
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c.is_whitespace() {
+                break;
+            }
+            if c == '"' {
+                return None;
+            }
+            key.push(c);
+            chars.next();
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            'n' => value.push('\n'),
+                            't' => value.push('\t'),
+                            c => value.push(c),
+                        },
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+        }
+        if key.is_empty() {
+            return None;
+        }
+        pairs.push((key, value));
+    }
+}
+
+fn parse_logfmt(entry: &mut LogEntry) {
+    let stream = entry.labels.clone();
+    match logfmt_pairs(&entry.line) {
+        Some(pairs) => {
+            for (k, v) in pairs {
+                add_extracted(&mut entry.labels, &stream, &k, v);
+            }
+        }
+        None => {
+            entry
+                .labels
+                .insert(ERROR_LABEL.to_owned(), "LogfmtParserErr".to_owned());
+        }
+    }
+}
+
+/// Runs the parser stages and label filters, line filters were already
+/// applied when the lines were read.
+pub fn apply_stages(selector: &LogSelector, entries: Vec<LogEntry>) -> Vec<LogEntry> {
+    entries
+        .into_iter()
+        .filter_map(|mut entry| {
+            for stage in &selector.stages {
+                match stage {
+                    Stage::LineFilter(_) => {}
+                    Stage::Json => parse_json(&mut entry),
+                    Stage::Logfmt => parse_logfmt(&mut entry),
+                    Stage::LabelFilter(m) => {
+                        let value = entry.labels.get(&m.name).map(String::as_str);
+                        if !m.matches(value.unwrap_or_default()) {
+                            return None;
+                        }
+                    }
+                }
+            }
+            Some(entry)
+        })
+        .collect()
+}
+
+// lines a query with parser stages or label filters may read, as those
+// run here on lines DataFusion sorted and held in memory
+pub static MAX_LINES: usize = 1_000_000;
+
+fn has_stages(selector: &LogSelector) -> bool {
+    selector
+        .stages
+        .iter()
+        .any(|s| !matches!(s, Stage::LineFilter(_)))
+}
+
+fn too_many_lines() -> anyhow::Error {
+    anyhow!(
+        "query would read more than {} lines, narrow the time range or the stream selector",
+        MAX_LINES
+    )
+}
+
+/// Returns up to `limit` lines between `start` and `end`, in microseconds,
+/// grouped into streams.
+pub async fn log_query(
+    service: &IngestService,
+    table: &str,
+    selector: &LogSelector,
+    start: i64,
+    end: i64,
+    limit: usize,
+    direction: Direction,
+) -> Result<LogResult, anyhow::Error> {
+    let Some(df) = service
+        .select(table, start, end, |schema| {
+            selector_filter(selector, schema)
+        })
+        .await?
+    else {
+        return Ok(LogResult::Streams(vec![]));
+    };
+    // DataFusion keeps the first lines in `direction` only, the stages then
+    // run on them in order until `limit` entries are found
+    let stages = has_stages(selector);
+    let read = if stages { MAX_LINES } else { limit };
+    let time = ident(TIMPSTAMP_FIELD_NAME).sort(direction == Direction::Forward, false);
+    let mut batches = df
+        .sort(vec![time])?
+        .limit(0, Some(read))?
+        .execute_stream()
+        .await?;
+    let mut entries = vec![];
+    let mut lines = 0;
+    while let Some(batch) = batches.next().await {
+        let batch = batch?;
+        lines += batch.num_rows();
+        entries.extend(apply_stages(selector, batches_to_entries(&[batch])?));
+        if entries.len() >= limit {
+            break;
+        }
+    }
+    if stages && entries.len() < limit && lines >= MAX_LINES {
+        return Err(too_many_lines());
+    }
+    entries.truncate(limit);
+    let mut streams: BTreeMap<Labels, Vec<LogEntry>> = BTreeMap::new();
+    for entry in entries {
+        streams.entry(entry.labels.clone()).or_default().push(entry);
+    }
+    Ok(LogResult::Streams(streams.into_iter().collect()))
+}
+
+// entry counts of every stream per bucket, ordered: bucket `i` counts the
+// entries after `base + i * width` up to `base + (i + 1) * width`, in
+// milliseconds
+struct Counts {
+    base: i64,
+    width: i64,
+    series: Vec<(Labels, Vec<(i64, i64)>)>,
+}
+
+static BUCKET: &str = "__bucket";
+static COUNT: &str = "__count";
+
+// counts the entries per stream and bucket, in DataFusion when the stream
+// labels are the columns, else on the lines the stages ran on
+async fn count_entries(
+    service: &IngestService,
+    table: &str,
+    selector: &LogSelector,
+    base: i64,
+    width: i64,
+    end: i64,
+) -> Result<Counts, anyhow::Error> {
+    let mut grouped: BTreeMap<Labels, Vec<(i64, i64)>> = BTreeMap::new();
+    let df = service
+        .select(table, (base + 1) * 1000, end * 1000, |schema| {
+            selector_filter(selector, schema)
+        })
+        .await?;
+    if let Some(df) = df {
+        if has_stages(selector) {
+            let mut batches = df.execute_stream().await?;
+            let mut lines = 0;
+            while let Some(batch) = batches.next().await {
+                let batch = batch?;
+                lines += batch.num_rows();
+                if lines > MAX_LINES {
+                    return Err(too_many_lines());
+                }
+                for entry in apply_stages(selector, batches_to_entries(&[batch])?) {
+                    let index = (entry.timestamp / 1000 - base - 1) / width;
+                    grouped.entry(entry.labels).or_default().push((index, 1));
+                }
+            }
+        } else {
+            let schema: Schema = df.schema().into();
+            let labels: Vec<Expr> = schema
+                .fields()
+                .iter()
+                .filter(|f| f.data_type() == &DataType::Utf8 && f.name() != MESSAGE_FIELD_NAME)
+                .map(|f| ident(f.name()))
+                .collect();
+            let time =
+                logical_expr::cast(ident(TIMPSTAMP_FIELD_NAME), DataType::Int64) / lit(1000i64);
+            let mut group = labels;
+            group.push(((time - lit(base + 1)) / lit(width)).alias(BUCKET));
+            let batches = df
+                .aggregate(group, vec![count(lit(1)).alias(COUNT)])?
+                .collect()
+                .await?;
+            for batch in batches {
+                let (Some(index), Some(counts)) =
+                    (batch.column_by_name(BUCKET), batch.column_by_name(COUNT))
+                else {
+                    continue;
+                };
+                let index = index.as_primitive::<Int64Type>();
+                let counts = counts.as_primitive::<Int64Type>();
+                let labels: Vec<_> = batch
+                    .schema()
+                    .fields()
+                    .iter()
+                    .zip(batch.columns())
+                    .filter(|(f, _)| f.name() != BUCKET && f.name() != COUNT)
+                    .filter_map(|(f, c)| Some((f.name().clone(), c.as_string_opt::<i32>()?)))
+                    .collect();
+                for row in 0..batch.num_rows() {
+                    let key: Labels = labels
+                        .iter()
+                        .filter(|(_, c)| c.is_valid(row) && !c.value(row).is_empty())
+                        .map(|(name, c)| (name.clone(), c.value(row).to_owned()))
+                        .collect();
+                    grouped
+                        .entry(key)
+                        .or_default()
+                        .push((index.value(row), counts.value(row)));
+                }
+            }
+        }
+    }
+    let series = grouped
+        .into_iter()
+        .map(|(labels, mut buckets)| {
+            buckets.sort_unstable();
+            buckets.dedup_by(|next, prev| {
+                if next.0 != prev.0 {
+                    return false;
+                }
+                prev.1 += next.1;
+                true
+            });
+            (labels, buckets)
+        })
+        .collect();
+    Ok(Counts {
+        base,
+        width,
+        series,
+    })
+}
+
+fn eval(expr: &LogExpr, t: i64, counts: &Counts) -> Result<Vec<(Labels, f64)>, anyhow::Error> {
+    match expr {
+        LogExpr::Range(func, _, range) => Ok(counts
+            .series
+            .iter()
+            .filter_map(|(labels, buckets)| {
+                // entries in (t - range, t]
+                let lo = (t - range - counts.base) / counts.width;
+                let hi = (t - counts.base) / counts.width;
+                let from = buckets.partition_point(|b| b.0 < lo);
+                let to = buckets.partition_point(|b| b.0 < hi);
+                let count = buckets[from..to].iter().map(|b| b.1).sum::<i64>() as f64;
+                let v = match func {
+                    RangeFunction::CountOverTime => count,
+                    RangeFunction::Rate => count / (*range as f64 / 1000.0),
+                };
+                (to > from).then(|| (labels.clone(), v))
+            })
+            .collect()),
+        LogExpr::Aggregate(op, grouping, inner) => {
+            Ok(aggregate(op, grouping, eval(inner, t, counts)?))
+        }
+        LogExpr::Log(_) => Err(anyhow!("expected a metric query")),
+    }
+}
+
+/// Evaluates a metric query at every step from `start` to `end`, in
+/// milliseconds.
+pub async fn range_query(
+    service: &IngestService,
+    table: &str,
+    expr: &LogExpr,
+    start: i64,
+    end: i64,
+    step: i64,
+) -> Result<LogResult, anyhow::Error> {
+    let Some(range) = expr.range() else {
+        return Err(anyhow!("expected a metric query"));
+    };
+    if step <= 0 {
+        return Err(anyhow!(
+            "zero or negative query resolution step widths are not accepted"
+        ));
+    }
+    if end < start {
+        return Err(anyhow!("end timestamp must not be before start time"));
+    }
+    if (end - start) / step > MAX_POINTS {
+        return Err(anyhow!(
+            "exceeded maximum resolution of {} points per timeseries",
+            MAX_POINTS
+        ));
+    }
+    // steps and the range are whole numbers of buckets
+    let base = start - range;
+    let width = gcd(step, range).max(1);
+    let counts = count_entries(service, table, expr.selector(), base, width, end).await?;
+
+    let mut matrix: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+    let mut t = start;
+    while t <= end {
+        for (labels, v) in eval(expr, t, &counts)? {
+            matrix.entry(labels).or_default().push((t, v));
+        }
+        t += step;
+    }
+    Ok(LogResult::Matrix(QueryResult::Matrix(
+        matrix.into_iter().collect(),
+    )))
+}
+
+/// Label names of the streams stored between `start` and `end`.
+pub async fn label_names(
+    service: &IngestService,
+    table: &str,
+    start: i64,
+    end: i64,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut names: Vec<String> = service
+        .schemas(table, start, end)
+        .await?
+        .iter()
+        .flat_map(|s| s.fields().iter())
+        .filter(|f| f.data_type() == &DataType::Utf8)
+        .map(|f| f.name().clone())
+        .filter(|name| name != TIMPSTAMP_FIELD_NAME && name != MESSAGE_FIELD_NAME)
+        .collect();
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Values a label takes in the streams stored between `start` and `end`.
+pub async fn label_values(
+    service: &IngestService,
+    table: &str,
+    name: &str,
+    start: i64,
+    end: i64,
+) -> Result<Vec<String>, anyhow::Error> {
+    // DataFusion reads the label column alone and deduplicates it per
+    // segment
+    let batches = service
+        .aggregate(
+            table,
+            start,
+            end,
+            |schema| {
+                let field = schema.field_with_name(name).ok()?;
+                (field.data_type() == &DataType::Utf8).then(|| ident(name).not_eq(lit("")))
+            },
+            vec![ident(name)],
+            vec![],
+        )
+        .await?;
+    let mut values: Vec<String> = batches
+        .iter()
+        .filter_map(|b| b.column(0).as_string_opt::<i32>())
+        .flat_map(|c| c.iter().flatten().map(str::to_owned))
+        .collect();
+    values.sort();
+    values.dedup();
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn entry(line: &str) -> LogEntry {
+        LogEntry {
+            timestamp: 0,
+            line: line.to_owned(),
+            labels: Labels::from([("job".to_owned(), "api".to_owned())]),
+        }
+    }
+
+    #[test]
+    fn test_parsers() {
+        let mut e = entry(r#"{"level": "error", "job": "x", "http": {"code": 500}, "tags": [1]}"#);
+        parse_json(&mut e);
+        assert_eq!(e.labels["level"], "error");
+        assert_eq!(e.labels["job"], "api");
+        assert_eq!(e.labels["job_extracted"], "x");
+        assert_eq!(e.labels["http_code"], "500");
+        assert!(!e.labels.contains_key("tags"));
+
+        let mut e = entry("not json");
+        parse_json(&mut e);
+        assert_eq!(e.labels[ERROR_LABEL], "JSONParserErr");
+
+        let mut e = entry(r#"level=info msg="hello \"world\"" took=5ms flag"#);
+        parse_logfmt(&mut e);
+        assert_eq!(e.labels["level"], "info");
+        assert_eq!(e.labels["msg"], r#"hello "world""#);
+        assert_eq!(e.labels["took"], "5ms");
+        assert_eq!(e.labels["flag"], "");
+
+        let mut e = entry(r#"msg="unterminated"#);
+        parse_logfmt(&mut e);
+        assert_eq!(e.labels[ERROR_LABEL], "LogfmtParserErr");
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        use crate::storage::Storage;
+
+        let service = IngestService::new(Storage::new(tempfile::tempdir().unwrap().path()));
+        let start = 1_700_000_000_000i64;
+        let mut records = vec![];
+        for i in 0..10 {
+            let level = if i % 2 == 0 { "info" } else { "error" };
+            records.push(
+                json!({"job": "api", "timestamp": (start + i * 10_000) * 1000,
+                                "message": format!(r#"{{"level": "{}", "n": {}}}"#, level, i)}),
+            );
+        }
+        records.push(json!({"job": "web", "timestamp": start * 1000, "message": "GET /"}));
+        service.ingest_records("loki", &records).await.unwrap();
+
+        let expr = LogExpr::from_str(r#"{job="api"} |= "error" | json"#).unwrap();
+        let LogExpr::Log(selector) = &expr else {
+            panic!("expected a log query");
+        };
+        let end = (start + 100_000) * 1000;
+        let res = log_query(
+            &service,
+            "loki",
+            selector,
+            start * 1000,
+            end,
+            3,
+            Direction::Backward,
+        )
+        .await
+        .unwrap();
+        let LogResult::Streams(streams) = &res else {
+            panic!("expected streams");
+        };
+        // each line has its own `n`, so its own stream
+        assert_eq!(streams.len(), 3);
+        let times: Vec<i64> = streams.iter().map(|(_, e)| e[0].timestamp).collect();
+        assert!(times.contains(&((start + 90_000) * 1000)));
+        assert!(streams.iter().all(|(l, _)| l["level"] == "error"));
+        let data = res.to_json();
+        assert_eq!(data["resultType"], "streams");
+
+        let expr = LogExpr::from_str(
+            r#"sum by (level) (count_over_time({job="api"} | json | level=~"info|error" [30s]))"#,
+        )
+        .unwrap();
+        let res = range_query(
+            &service,
+            "loki",
+            &expr,
+            start + 30_000,
+            start + 90_000,
+            30_000,
+        )
+        .await
+        .unwrap();
+        let LogResult::Matrix(QueryResult::Matrix(matrix)) = res else {
+            panic!("expected a matrix");
+        };
+        assert_eq!(matrix.len(), 2);
+        let error = &matrix[0];
+        assert_eq!(error.0["level"], "error");
+        assert_eq!(
+            error.1,
+            vec![
+                (start + 30_000, 2.0),
+                (start + 60_000, 1.0),
+                (start + 90_000, 2.0)
+            ]
+        );
+
+        // without stages the sort and the limit run in DataFusion
+        let expr = LogExpr::from_str(r#"{job=~"api|web"}"#).unwrap();
+        let LogExpr::Log(selector) = &expr else {
+            panic!("expected a log query");
+        };
+        let res = log_query(
+            &service,
+            "loki",
+            selector,
+            start * 1000,
+            end,
+            2,
+            Direction::Forward,
+        )
+        .await
+        .unwrap();
+        let LogResult::Streams(streams) = res else {
+            panic!("expected streams");
+        };
+        let mut times: Vec<i64> = streams
+            .iter()
+            .flat_map(|(_, e)| e.iter().map(|e| e.timestamp))
+            .collect();
+        times.sort();
+        assert_eq!(times, vec![start * 1000, start * 1000]);
+
+        // and the counts per bucket, steps not dividing the range included
+        let expr = LogExpr::from_str(r#"count_over_time({job="api"}[30s])"#).unwrap();
+        let res = range_query(
+            &service,
+            "loki",
+            &expr,
+            start + 30_000,
+            start + 90_000,
+            20_000,
+        )
+        .await
+        .unwrap();
+        let LogResult::Matrix(QueryResult::Matrix(matrix)) = res else {
+            panic!("expected a matrix");
+        };
+        assert_eq!(matrix.len(), 1);
+        assert_eq!(
+            matrix[0].1,
+            vec![
+                (start + 30_000, 3.0),
+                (start + 50_000, 3.0),
+                (start + 70_000, 3.0),
+                (start + 90_000, 3.0)
+            ]
+        );
+
+        let names = label_names(&service, "loki", start * 1000, end)
+            .await
+            .unwrap();
+        assert_eq!(names, vec!["job"]);
+        let values = label_values(&service, "loki", "job", start * 1000, end)
+            .await
+            .unwrap();
+        assert_eq!(values, vec!["api", "web"]);
+    }
+}
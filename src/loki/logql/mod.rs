@@ -0,0 +1,122 @@
+//! The LogQL subset melt evaluates: stream selectors, line filters, the
+//! json and logfmt parsers with label filters, and count_over_time/rate
+//! with sum/avg/min/max/count by/without.
+
+mod parser;
+
+use regex::Regex;
+
+use crate::prom::promql::{AggregateOp, Grouping, Matcher};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LineFilterOp {
+    Contains,
+    NotContains,
+    Regex,
+    NotRegex,
+}
+
+#[derive(Clone, Debug)]
+pub struct LineFilter {
+    pub op: LineFilterOp,
+    pub value: String,
+    regex: Option<Regex>,
+}
+
+impl LineFilter {
+    pub fn new(op: LineFilterOp, value: &str) -> Result<LineFilter, anyhow::Error> {
+        let regex = match op {
+            LineFilterOp::Regex | LineFilterOp::NotRegex => Some(Regex::new(value)?),
+            _ => None,
+        };
+        Ok(LineFilter {
+            op,
+            value: value.to_owned(),
+            regex,
+        })
+    }
+
+    /// The filter as an unanchored regex, the way it is pushed down.
+    pub fn pattern(&self) -> String {
+        match self.op {
+            LineFilterOp::Contains | LineFilterOp::NotContains => regex::escape(&self.value),
+            LineFilterOp::Regex | LineFilterOp::NotRegex => self.value.clone(),
+        }
+    }
+
+    pub fn matches(&self, line: &str) -> bool {
+        match self.op {
+            LineFilterOp::Contains => line.contains(&self.value),
+            LineFilterOp::NotContains => !line.contains(&self.value),
+            LineFilterOp::Regex => self.regex.as_ref().is_some_and(|r| r.is_match(line)),
+            LineFilterOp::NotRegex => !self.regex.as_ref().is_some_and(|r| r.is_match(line)),
+        }
+    }
+}
+
+impl PartialEq for LineFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.op == other.op && self.value == other.value
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stage {
+    LineFilter(LineFilter),
+    Json,
+    Logfmt,
+    LabelFilter(Matcher),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogSelector {
+    pub matchers: Vec<Matcher>,
+    pub stages: Vec<Stage>,
+}
+
+impl LogSelector {
+    pub fn line_filters(&self) -> impl Iterator<Item = &LineFilter> {
+        self.stages.iter().filter_map(|s| match s {
+            Stage::LineFilter(f) => Some(f),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RangeFunction {
+    CountOverTime,
+    Rate,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogExpr {
+    Log(LogSelector),
+    /// A range aggregation over a log selector, the range in milliseconds.
+    Range(RangeFunction, LogSelector, i64),
+    Aggregate(AggregateOp, Grouping, Box<LogExpr>),
+}
+
+impl LogExpr {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Result<LogExpr, anyhow::Error> {
+        parser::parse_logql(s)
+    }
+
+    /// The log selector the expression reads from.
+    pub fn selector(&self) -> &LogSelector {
+        match self {
+            LogExpr::Log(s) | LogExpr::Range(_, s, _) => s,
+            LogExpr::Aggregate(_, _, inner) => inner.selector(),
+        }
+    }
+
+    /// The range of the range aggregation, none for log queries.
+    pub fn range(&self) -> Option<i64> {
+        match self {
+            LogExpr::Log(_) => None,
+            LogExpr::Range(_, _, range) => Some(*range),
+            LogExpr::Aggregate(_, _, inner) => inner.range(),
+        }
+    }
+}
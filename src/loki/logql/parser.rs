@@ -0,0 +1,198 @@
+use anyhow::anyhow;
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, multispace0},
+    combinator::{all_consuming, cut, map, map_res, not, opt, peek, value},
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated, tuple},
+};
+
+use super::{LineFilter, LineFilterOp, LogExpr, LogSelector, RangeFunction, Stage};
+use crate::prom::promql::{
+    parser::{
+        aggregate_op, duration, grouping, matcher, matchers, string_literal, ws, IResult, Span,
+    },
+    Grouping,
+};
+
+pub fn parse_logql(s: &str) -> Result<LogExpr, anyhow::Error> {
+    let (_, expr) = all_consuming(delimited(multispace0, expr, multispace0))(s.into())
+        .map_err(|e| anyhow!("invalid logql {}: {}", s, e))?;
+    if expr.selector().matchers.iter().all(|m| m.matches("")) {
+        return Err(anyhow!(
+            "queries require at least one regexp or equality matcher that does not have an empty-compatible value"
+        ));
+    }
+    Ok(expr)
+}
+
+fn line_filter_op(input: Span) -> IResult<LineFilterOp> {
+    alt((
+        value(LineFilterOp::Contains, tag("|=")),
+        value(LineFilterOp::NotContains, tag("!=")),
+        value(LineFilterOp::Regex, tag("|~")),
+        value(LineFilterOp::NotRegex, tag("!~")),
+    ))(input)
+}
+
+fn line_filter(input: Span) -> IResult<Stage> {
+    map_res(
+        pair(ws(line_filter_op), ws(string_literal)),
+        |(op, value)| LineFilter::new(op, &value).map(Stage::LineFilter),
+    )(input)
+}
+
+// keywords must not be the prefix of a label name, `| jsonish="x"`
+fn keyword<'a>(word: &'static str) -> impl FnMut(Span<'a>) -> IResult<'a, Span<'a>> {
+    terminated(
+        tag(word),
+        not(peek(nom::character::complete::satisfy(|c| {
+            c.is_ascii_alphanumeric() || c == '_'
+        }))),
+    )
+}
+
+fn parser_stage(input: Span) -> IResult<Stage> {
+    preceded(
+        ws(char('|')),
+        alt((
+            value(Stage::Json, ws(keyword("json"))),
+            value(Stage::Logfmt, ws(keyword("logfmt"))),
+            map(matcher, Stage::LabelFilter),
+        )),
+    )(input)
+}
+
+fn log_selector(input: Span) -> IResult<LogSelector> {
+    map(
+        pair(ws(matchers), many0(alt((line_filter, parser_stage)))),
+        |(matchers, stages)| LogSelector { matchers, stages },
+    )(input)
+}
+
+fn range_function(input: Span) -> IResult<RangeFunction> {
+    alt((
+        value(RangeFunction::CountOverTime, tag("count_over_time")),
+        value(RangeFunction::Rate, tag("rate")),
+    ))(input)
+}
+
+fn range_aggregation(input: Span) -> IResult<LogExpr> {
+    let (rest, (func, (selector, range))) = pair(
+        ws(range_function),
+        preceded(
+            ws(char('(')),
+            cut(terminated(
+                pair(
+                    log_selector,
+                    delimited(ws(char('[')), duration, ws(char(']'))),
+                ),
+                ws(char(')')),
+            )),
+        ),
+    )(input)?;
+    Ok((rest, LogExpr::Range(func, selector, range)))
+}
+
+fn metric_expr(input: Span) -> IResult<LogExpr> {
+    alt((
+        aggregate,
+        range_aggregation,
+        delimited(ws(char('(')), metric_expr, ws(char(')'))),
+    ))(input)
+}
+
+fn aggregate(input: Span) -> IResult<LogExpr> {
+    // both `sum by (a) (x)` and `sum (x) by (a)` are valid
+    let (rest, (op, before, inner, after)) = tuple((
+        ws(aggregate_op),
+        opt(grouping),
+        delimited(ws(char('(')), metric_expr, ws(char(')'))),
+        opt(grouping),
+    ))(input)?;
+    let grouping = before.or(after).unwrap_or(Grouping::By(vec![]));
+    Ok((rest, LogExpr::Aggregate(op, grouping, Box::new(inner))))
+}
+
+fn expr(input: Span) -> IResult<LogExpr> {
+    alt((metric_expr, map(log_selector, LogExpr::Log)))(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prom::promql::{AggregateOp, MatchOp, Matcher};
+
+    fn eq(name: &str, value: &str) -> Matcher {
+        Matcher::new(name, MatchOp::Equal, value).unwrap()
+    }
+
+    #[test]
+    fn test_log_query() {
+        let expr = parse_logql(
+            r#"{job="api", env=~"prod|dev"} |= "error" != "timeout" |~ "5\\d\\d" | json | level="error""#,
+        )
+        .unwrap();
+        let LogExpr::Log(selector) = expr else {
+            panic!("expected a log query");
+        };
+        assert_eq!(
+            selector.matchers,
+            vec![
+                eq("job", "api"),
+                Matcher::new("env", MatchOp::Regex, "prod|dev").unwrap()
+            ]
+        );
+        assert_eq!(
+            selector.stages,
+            vec![
+                Stage::LineFilter(LineFilter::new(LineFilterOp::Contains, "error").unwrap()),
+                Stage::LineFilter(LineFilter::new(LineFilterOp::NotContains, "timeout").unwrap()),
+                Stage::LineFilter(LineFilter::new(LineFilterOp::Regex, r"5\d\d").unwrap()),
+                Stage::Json,
+                Stage::LabelFilter(eq("level", "error")),
+            ]
+        );
+
+        let expr = parse_logql(r#"{job="api"} | logfmt | jsonish="x""#).unwrap();
+        assert_eq!(
+            expr.selector().stages,
+            vec![Stage::Logfmt, Stage::LabelFilter(eq("jsonish", "x"))]
+        );
+
+        assert!(parse_logql(r#"{job=~".*"}"#).is_err());
+        assert!(parse_logql(r##"{job="api"} |= "(""##).is_ok());
+        assert!(parse_logql(r##"{job="api"} |~ "(""##).is_err());
+        assert!(parse_logql(r#"job="api""#).is_err());
+    }
+
+    #[test]
+    fn test_metric_query() {
+        let expr =
+            parse_logql(r#"sum by (level) (count_over_time({job="api"} | json [5m]))"#).unwrap();
+        let expect = LogExpr::Aggregate(
+            AggregateOp::Sum,
+            Grouping::By(vec!["level".to_owned()]),
+            Box::new(LogExpr::Range(
+                RangeFunction::CountOverTime,
+                LogSelector {
+                    matchers: vec![eq("job", "api")],
+                    stages: vec![Stage::Json],
+                },
+                300_000,
+            )),
+        );
+        assert_eq!(expr, expect);
+        assert_eq!(expr.range(), Some(300_000));
+
+        let expr = parse_logql(r#"rate({job="api"} |= "error" [1m])"#).unwrap();
+        assert!(matches!(
+            expr,
+            LogExpr::Range(RangeFunction::Rate, _, 60_000)
+        ));
+
+        assert!(parse_logql(r#"rate({job="api"})"#).is_err());
+        assert!(parse_logql(r#"sum({job="api"})"#).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Loki compatible endpoints.
+
+pub mod eval;
+pub mod logql;
+pub mod proto;
+pub mod push;
+
+use anyhow::anyhow;
+
+use crate::{
+    prom::promql::{MatchOp, Matcher},
+    storage::is_valid_table_name,
+};
+
+// column holding the log line
+pub static MESSAGE_FIELD_NAME: &str = "message";
+
+/// The table a query reads: the one its `table_label` equality matcher
+/// names, `default_table` without one.
+pub fn query_table<'a>(
+    matchers: &'a [Matcher],
+    table_label: Option<&str>,
+    default_table: &'a str,
+) -> Result<&'a str, anyhow::Error> {
+    let table = table_label
+        .and_then(|name| {
+            matchers
+                .iter()
+                .find(|m| m.name == name && m.op == MatchOp::Equal)
+        })
+        .map(|m| m.value.as_str())
+        .unwrap_or(default_table);
+    if !is_valid_table_name(table) {
+        return Err(anyhow!("invalid table name: {:?}", table));
+    }
+    Ok(table)
+}
+
+/// Parses a query API time into nanoseconds: unix seconds with a fraction,
+/// integer seconds or nanoseconds, or RFC 3339.
+pub fn parse_time(s: &str) -> Result<i64, anyhow::Error> {
+    let invalid = || anyhow!("cannot parse {:?} to a valid timestamp", s);
+    if let Ok(v) = s.parse::<i64>() {
+        // up to ten digits are seconds, like Loki assumes
+        return if s.trim_start_matches('-').len() <= 10 {
+            v.checked_mul(1_000_000_000).ok_or_else(invalid)
+        } else {
+            Ok(v)
+        };
+    }
+    if let Ok(v) = s.parse::<f64>() {
+        let v = (v * 1e9).round();
+        // casts saturate, out of range values must not become the bounds
+        if !(v > i64::MIN as f64 && v < i64::MAX as f64) {
+            return Err(invalid());
+        }
+        return Ok(v as i64);
+    }
+    let t = chrono::DateTime::parse_from_rfc3339(s).map_err(|_| invalid())?;
+    t.timestamp_nanos_opt()
+        .ok_or_else(|| anyhow!("timestamp {:?} out of range", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1700000000").unwrap(), 1_700_000_000_000_000_000);
+        assert_eq!(
+            parse_time("1700000000000000500").unwrap(),
+            1_700_000_000_000_000_500
+        );
+        assert_eq!(
+            parse_time("1700000000.5").unwrap(),
+            1_700_000_000_500_000_000
+        );
+        assert_eq!(
+            parse_time("2023-11-14T22:13:20Z").unwrap(),
+            1_700_000_000_000_000_000
+        );
+        assert!(parse_time("now").is_err());
+        assert!(parse_time("-9999999999").is_err());
+        assert!(parse_time("1e300").is_err());
+        assert!(parse_time("NaN").is_err());
+    }
+
+    #[test]
+    fn test_query_table() {
+        let matchers = vec![
+            Matcher::new("table", MatchOp::Equal, "app").unwrap(),
+            Matcher::new("job", MatchOp::Equal, "api").unwrap(),
+        ];
+        assert_eq!(
+            query_table(&matchers, Some("table"), "loki").unwrap(),
+            "app"
+        );
+        assert_eq!(query_table(&matchers, None, "loki").unwrap(), "loki");
+        let matchers = vec![Matcher::new("table", MatchOp::Equal, "../x").unwrap()];
+        assert!(query_table(&matchers, Some("table"), "loki").is_err());
+    }
+}
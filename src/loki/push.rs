@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use prost::Message;
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+
+use super::{proto::PushRequest, MESSAGE_FIELD_NAME};
+use crate::{
+    config::TIMPSTAMP_FIELD_NAME, otlp::Encoding, prom::promql, storage::is_valid_table_name,
+};
+
+/// A log stream, from either push encoding.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stream {
+    pub labels: Vec<(String, String)>,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entry {
+    // nanoseconds since the epoch
+    pub timestamp: i64,
+    pub line: String,
+    pub metadata: Vec<(String, String)>,
+}
+
+#[derive(Deserialize)]
+struct JsonPushRequest {
+    #[serde(default)]
+    streams: Vec<JsonStream>,
+}
+
+#[derive(Deserialize)]
+struct JsonStream {
+    #[serde(default)]
+    stream: BTreeMap<String, String>,
+    // `[timestamp, line]` or `[timestamp, line, metadata]`
+    #[serde(default)]
+    values: Vec<Vec<Value>>,
+}
+
+pub fn decode(encoding: Encoding, body: &[u8]) -> Result<Vec<Stream>, anyhow::Error> {
+    match encoding {
+        Encoding::Protobuf => decode_protobuf(body),
+        Encoding::Json => decode_json(body),
+    }
+}
+
+fn decode_protobuf(body: &[u8]) -> Result<Vec<Stream>, anyhow::Error> {
+    let request = PushRequest::decode(body)?;
+    let mut streams = vec![];
+    for stream in request.streams {
+        let entries = stream
+            .entries
+            .into_iter()
+            .map(|e| Entry {
+                timestamp: e
+                    .timestamp
+                    .map(|t| t.seconds * 1_000_000_000 + t.nanos as i64)
+                    .unwrap_or_default(),
+                line: e.line,
+                metadata: e
+                    .structured_metadata
+                    .into_iter()
+                    .map(|l| (l.name, l.value))
+                    .collect(),
+            })
+            .collect();
+        streams.push(Stream {
+            labels: promql::parse_labels(&stream.labels)?,
+            entries,
+        });
+    }
+    Ok(streams)
+}
+
+fn decode_json(body: &[u8]) -> Result<Vec<Stream>, anyhow::Error> {
+    let request: JsonPushRequest = serde_json::from_slice(body)?;
+    let mut streams = vec![];
+    for stream in request.streams {
+        let entries = stream
+            .values
+            .iter()
+            .map(|v| json_entry(v))
+            .collect::<Result<_, _>>()?;
+        streams.push(Stream {
+            labels: stream.stream.into_iter().collect(),
+            entries,
+        });
+    }
+    Ok(streams)
+}
+
+fn json_entry(value: &[Value]) -> Result<Entry, anyhow::Error> {
+    // loki sends nanosecond timestamps as strings, they do not fit a double
+    let timestamp = match value.first() {
+        Some(Value::String(s)) => s.parse::<i64>().ok(),
+        Some(Value::Number(n)) => n.as_i64(),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("invalid entry timestamp: {:?}", value.first()))?;
+    let line = match value.get(1) {
+        Some(Value::String(s)) => s.clone(),
+        v => return Err(anyhow!("invalid entry line: {:?}", v)),
+    };
+    let metadata = match value.get(2) {
+        None | Some(Value::Null) => vec![],
+        Some(Value::Object(m)) => m
+            .iter()
+            .map(|(k, v)| match v {
+                Value::String(s) => (k.clone(), s.clone()),
+                v => (k.clone(), v.to_string()),
+            })
+            .collect(),
+        Some(v) => return Err(anyhow!("invalid entry metadata: {}", v)),
+    };
+    Ok(Entry {
+        timestamp,
+        line,
+        metadata,
+    })
+}
+
+/// Maps every entry into one row: a column per stream label and structured
+/// metadata key, the line as `message` and the entry time in microseconds
+/// as `timestamp`. Rows are grouped by table, taken from the `table_label`
+/// label of a stream when it has one.
+pub fn streams_to_records(
+    streams: &[Stream],
+    table_label: Option<&str>,
+    default_table: &str,
+) -> Result<BTreeMap<String, Vec<Value>>, anyhow::Error> {
+    let mut tables: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for stream in streams {
+        let table = table_label
+            .and_then(|name| stream.labels.iter().find(|(k, _)| k == name))
+            .map(|(_, v)| v.as_str())
+            .unwrap_or(default_table);
+        if !is_valid_table_name(table) {
+            return Err(anyhow!("invalid table name: {:?}", table));
+        }
+        let mut labels = Map::new();
+        for (name, value) in &stream.labels {
+            labels.insert(column_name(name), Value::from(value.as_str()));
+        }
+        let records = tables.entry(table.to_owned()).or_default();
+        for entry in &stream.entries {
+            let mut record = labels.clone();
+            // stream labels win over metadata with the same name
+            for (name, value) in &entry.metadata {
+                record
+                    .entry(column_name(name))
+                    .or_insert_with(|| Value::from(value.as_str()));
+            }
+            record.insert(
+                TIMPSTAMP_FIELD_NAME.to_owned(),
+                Value::from(entry.timestamp / 1000),
+            );
+            record.insert(
+                MESSAGE_FIELD_NAME.to_owned(),
+                Value::from(entry.line.as_str()),
+            );
+            records.push(Value::Object(record));
+        }
+    }
+    Ok(tables)
+}
+
+// labels named like our own columns are renamed the way Prometheus renames
+// clashing target labels
+fn column_name(name: &str) -> String {
+    if name == MESSAGE_FIELD_NAME || name == TIMPSTAMP_FIELD_NAME {
+        format!("exported_{}", name)
+    } else {
+        name.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loki::proto::{EntryAdapter, LabelPairAdapter, StreamAdapter, Timestamp};
+
+    #[test]
+    fn test_decode() {
+        let request = PushRequest {
+            streams: vec![StreamAdapter {
+                labels: r#"{job="api", table="app_logs"}"#.to_owned(),
+                entries: vec![EntryAdapter {
+                    timestamp: Some(Timestamp {
+                        seconds: 1_700_000_000,
+                        nanos: 500,
+                    }),
+                    line: "GET / 200".to_owned(),
+                    structured_metadata: vec![LabelPairAdapter {
+                        name: "trace_id".to_owned(),
+                        value: "abc".to_owned(),
+                    }],
+                }],
+                hash: 0,
+            }],
+        };
+        let from_protobuf = decode(Encoding::Protobuf, &request.encode_to_vec()).unwrap();
+
+        let body = r#"{"streams": [{"stream": {"job": "api", "table": "app_logs"},
+            "values": [["1700000000000000500", "GET / 200", {"trace_id": "abc"}]]}]}"#;
+        let from_json = decode(Encoding::Json, body.as_bytes()).unwrap();
+        assert_eq!(from_protobuf, from_json);
+        assert_eq!(from_json[0].entries[0].timestamp, 1_700_000_000_000_000_500);
+
+        let body = r#"{"streams": [{"stream": {}, "values": [[1, 2]]}]}"#;
+        assert!(decode(Encoding::Json, body.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_streams_to_records() {
+        let entry = |line: &str| Entry {
+            timestamp: 1_700_000_000_000_000_000,
+            line: line.to_owned(),
+            metadata: vec![("job".to_owned(), "x".to_owned())],
+        };
+        let label = |k: &str, v: &str| (k.to_owned(), v.to_owned());
+        let streams = vec![
+            Stream {
+                labels: vec![label("job", "api"), label("table", "app_logs")],
+                entries: vec![entry("a"), entry("b")],
+            },
+            Stream {
+                labels: vec![label("message", "m")],
+                entries: vec![entry("c")],
+            },
+        ];
+        let tables = streams_to_records(&streams, Some("table"), "loki").unwrap();
+        let app = &tables["app_logs"];
+        assert_eq!(app.len(), 2);
+        assert_eq!(app[0]["job"], "api");
+        assert_eq!(app[0]["message"], "a");
+        assert_eq!(app[0]["timestamp"], 1_700_000_000_000_000i64);
+        let loki = &tables["loki"];
+        assert_eq!(loki[0]["exported_message"], "m");
+        assert_eq!(loki[0]["job"], "x");
+        assert_eq!(loki[0]["message"], "c");
+
+        let streams = vec![Stream {
+            labels: vec![label("table", "../x")],
+            entries: vec![entry("a")],
+        }];
+        assert!(streams_to_records(&streams, Some("table"), "loki").is_err());
+    }
+}
@@ -7,5 +7,9 @@ async fn main() -> std::io::Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     log::set_max_level(log::LevelFilter::Info);
 
-    server::start_server("127.0.0.1", 8080, "data").await
+    let config = match std::env::args().nth(1) {
+        Some(path) => config::Config::from_file(path).map_err(std::io::Error::other)?,
+        None => config::Config::default(),
+    };
+    server::start_server(config).await
 }
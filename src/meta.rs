@@ -2,12 +2,16 @@ use std::sync::{Arc, Mutex};
 
 use ahash::AHashMap;
 
+use crate::utils::bloom::BloomFilter;
+
 #[derive(Clone, Debug)]
 pub struct FileMeta {
     segment: String,
     partition: String,
     min_timestamp: i64,
     max_timestamp: i64,
+    // trace ids of the segment, only set for tables holding spans
+    trace_ids: Option<Arc<BloomFilter>>,
 }
 impl FileMeta {
     pub fn new(partition: String, segment: String, min_ts: i64, max_ts: i64) -> FileMeta {
@@ -16,8 +20,20 @@ impl FileMeta {
             segment,
             min_timestamp: min_ts,
             max_timestamp: max_ts,
+            trace_ids: None,
         }
     }
+    pub fn with_trace_ids(mut self, trace_ids: BloomFilter) -> FileMeta {
+        self.trace_ids = Some(Arc::new(trace_ids));
+        self
+    }
+    /// False when the segment surely holds no span of `trace_id`, segments
+    /// without a trace_id column never do.
+    pub fn may_contain_trace(&self, trace_id: &str) -> bool {
+        self.trace_ids
+            .as_ref()
+            .is_some_and(|ids| ids.contains(trace_id))
+    }
     pub fn segment(&self) -> &str {
         &self.segment
     }
@@ -28,6 +44,7 @@ impl FileMeta {
 
 type MetaStorage = Arc<Mutex<AHashMap<String, Vec<FileMeta>>>>;
 
+#[derive(Default)]
 pub struct MetaService {
     files: MetaStorage,
 }
@@ -45,6 +62,45 @@ impl MetaService {
         write.entry(table_name.to_string()).or_default().push(file);
     }
 
+    /// Adds the segments of one write at once, queries see all or none.
+    pub fn add_files(&self, table_name: &str, files: Vec<FileMeta>) {
+        log::info!("add files {} {:?}", table_name, files);
+        let mut write = self.files.lock().unwrap();
+        write
+            .entry(table_name.to_string())
+            .or_default()
+            .extend(files);
+    }
+
+    /// Names of the tables that have segments, sorted.
+    pub fn tables(&self) -> Vec<String> {
+        let files = self.files.lock().unwrap();
+        let mut tables: Vec<String> = files.keys().cloned().collect();
+        tables.sort();
+        tables
+    }
+
+    /// Smallest and largest timestamp stored in the table.
+    pub fn time_range(&self, table_name: &str) -> Option<(i64, i64)> {
+        let files = self.files.lock().unwrap();
+        let files = files.get(table_name)?;
+        let min = files.iter().map(|v| v.min_timestamp).min()?;
+        let max = files.iter().map(|v| v.max_timestamp).max()?;
+        Some((min, max))
+    }
+
+    pub fn query_trace_files(
+        &self,
+        table_name: &str,
+        trace_id: &str,
+        begin: Option<i64>,
+        end: Option<i64>,
+    ) -> Vec<FileMeta> {
+        let mut files = self.query_files(table_name, begin, end);
+        files.retain(|v| v.may_contain_trace(trace_id));
+        files
+    }
+
     pub fn query_files(
         &self,
         table_name: &str,
@@ -54,8 +110,7 @@ impl MetaService {
         let files = self.files.lock().unwrap();
         if let Some(files) = files.get(table_name) {
             files
-                .into_iter()
-                .cloned()
+                .iter()
                 .filter(|v| {
                     if let Some(t) = begin {
                         if v.max_timestamp < t {
@@ -67,11 +122,43 @@ impl MetaService {
                             return false;
                         }
                     }
-                    return true;
+                    true
                 })
+                .cloned()
                 .collect::<Vec<_>>()
         } else {
             vec![]
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_trace_files() {
+        let meta = MetaService::new();
+        let mut ids = BloomFilter::with_capacity(10, 0.01);
+        ids.insert("aaaa");
+        meta.add_file(
+            "t",
+            FileMeta::new("p".into(), "s1".into(), 10, 20).with_trace_ids(ids),
+        );
+        let mut ids = BloomFilter::with_capacity(10, 0.01);
+        ids.insert("bbbb");
+        meta.add_file(
+            "t",
+            FileMeta::new("p".into(), "s2".into(), 30, 40).with_trace_ids(ids),
+        );
+        meta.add_file("t", FileMeta::new("p".into(), "s3".into(), 50, 60));
+
+        let files = meta.query_trace_files("t", "bbbb", None, None);
+        let segments: Vec<_> = files.iter().map(|f| f.segment()).collect();
+        assert_eq!(segments, vec!["s2"]);
+        let files = meta.query_trace_files("t", "bbbb", None, Some(25));
+        assert!(files.is_empty());
+        assert_eq!(meta.time_range("t"), Some((10, 60)));
+        assert_eq!(meta.time_range("x"), None);
+    }
+}
@@ -0,0 +1,150 @@
+use serde_json::{Map, Value};
+
+use super::proto::{ExportLogsServiceRequest, LogRecord};
+use crate::config::TIMPSTAMP_FIELD_NAME;
+
+/// Flattens every log record of the request into one row, the resource and
+/// scope of a record are copied into `resource.*` and `scope.*` columns.
+pub fn logs_to_records(request: &ExportLogsServiceRequest) -> Vec<Value> {
+    let mut records = vec![];
+    for resource_logs in &request.resource_logs {
+        let mut base = Map::new();
+        super::insert_resource(&mut base, resource_logs.resource.as_ref());
+        for scope_logs in &resource_logs.scope_logs {
+            let mut scoped = base.clone();
+            super::insert_scope(&mut scoped, scope_logs.scope.as_ref());
+            for log in &scope_logs.log_records {
+                let mut record = scoped.clone();
+                insert_log(&mut record, log);
+                records.push(Value::Object(record));
+            }
+        }
+    }
+    records
+}
+
+fn insert_log(record: &mut Map<String, Value>, log: &LogRecord) {
+    // records without a time fall back to the time the collector saw them,
+    // and to the ingest time when neither is set
+    let time = if log.time_unix_nano > 0 {
+        log.time_unix_nano
+    } else {
+        log.observed_time_unix_nano
+    };
+    if time > 0 {
+        record.insert(
+            TIMPSTAMP_FIELD_NAME.to_owned(),
+            Value::from(time as i64 / 1000),
+        );
+    }
+    if log.observed_time_unix_nano > 0 {
+        record.insert(
+            "observed_timestamp".to_owned(),
+            Value::from(log.observed_time_unix_nano as i64 / 1000),
+        );
+    }
+    record.insert(
+        "severity_number".to_owned(),
+        Value::from(log.severity_number),
+    );
+    if !log.severity_text.is_empty() {
+        record.insert(
+            "severity_text".to_owned(),
+            Value::from(log.severity_text.as_str()),
+        );
+    }
+    if let Some(body) = &log.body {
+        super::insert_value(record, "body", body);
+    }
+    super::insert_id(record, "trace_id", &log.trace_id);
+    super::insert_id(record, "span_id", &log.span_id);
+    if log.flags > 0 {
+        record.insert("flags".to_owned(), Value::from(log.flags));
+    }
+    super::insert_attributes(record, "attributes", &log.attributes);
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::otlp::{
+        proto::{
+            any_value, AnyValue, InstrumentationScope, KeyValue, Resource, ResourceLogs, ScopeLogs,
+        },
+        Encoding,
+    };
+
+    #[test]
+    fn test_logs_protobuf() {
+        let request = ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(Resource {
+                    attributes: vec![KeyValue {
+                        key: "service.name".to_owned(),
+                        value: Some(AnyValue {
+                            value: Some(any_value::Value::StringValue("api".to_owned())),
+                        }),
+                    }],
+                    dropped_attributes_count: 0,
+                }),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope {
+                        name: "melt".to_owned(),
+                        ..Default::default()
+                    }),
+                    log_records: vec![LogRecord {
+                        time_unix_nano: 1_700_000_000_123_456_789,
+                        severity_number: 9,
+                        severity_text: "INFO".to_owned(),
+                        body: Some(AnyValue {
+                            value: Some(any_value::Value::StringValue("hello".to_owned())),
+                        }),
+                        trace_id: vec![0xab; 16],
+                        span_id: vec![0xcd; 8],
+                        ..Default::default()
+                    }],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        };
+        let body = request.encode_to_vec();
+        let request: ExportLogsServiceRequest = Encoding::Protobuf.decode(&body).unwrap();
+        let records = logs_to_records(&request);
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record["timestamp"], 1_700_000_000_123_456i64);
+        assert_eq!(record["resource.service.name"], "api");
+        assert_eq!(record["scope.name"], "melt");
+        assert_eq!(record["severity_number"], 9);
+        assert_eq!(record["severity_text"], "INFO");
+        assert_eq!(record["body"], "hello");
+        assert_eq!(record["trace_id"], "ab".repeat(16));
+        assert_eq!(record["span_id"], "cd".repeat(8));
+    }
+
+    #[test]
+    fn test_logs_json() {
+        let body = r#"{"resourceLogs":[{"resource":{"attributes":[
+            {"key":"host.name","value":{"stringValue":"web-1"}}]},
+            "scopeLogs":[{"logRecords":[
+                {"observedTimeUnixNano":"1700000000000000000","severityNumber":17,
+                 "body":{"kvlistValue":{"values":[{"key":"msg","value":{"stringValue":"boom"}},
+                                                  {"key":"code","value":{"intValue":"500"}}]}},
+                 "traceId":"5b8efff798038103d269b633813fc60c",
+                 "attributes":[{"key":"retry","value":{"boolValue":true}}]}]}]}]}"#;
+        let request: ExportLogsServiceRequest = Encoding::Json.decode(body.as_bytes()).unwrap();
+        let records = logs_to_records(&request);
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record["timestamp"], 1_700_000_000_000_000i64);
+        assert_eq!(record["observed_timestamp"], 1_700_000_000_000_000i64);
+        assert_eq!(record["resource.host.name"], "web-1");
+        assert_eq!(record["body.msg"], "boom");
+        assert_eq!(record["body.code"], 500);
+        assert_eq!(record["trace_id"], "5b8efff798038103d269b633813fc60c");
+        assert_eq!(record["attributes.retry"], true);
+    }
+}
@@ -0,0 +1,520 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use datafusion::arrow::{
+    array::{ArrayRef, Float64Builder, Int64Builder, ListBuilder},
+    datatypes::{Field, Schema},
+    record_batch::RecordBatch,
+};
+use serde_json::{Map, Value};
+
+use super::proto::{
+    exponential_histogram_data_point::Buckets, metric, number_data_point,
+    ExponentialHistogramDataPoint, ExportMetricsServiceRequest, HistogramDataPoint, KeyValue,
+    Metric, NumberDataPoint,
+};
+use crate::{
+    config::TIMPSTAMP_FIELD_NAME,
+    fusion::decoder::JsonDecoder,
+    prom::{LABELS_FIELD_NAME, METRIC_NAME_LABEL, VALUE_FIELD_NAME},
+};
+
+pub static METRIC_TYPE: &str = "metric_type";
+
+fn temporality_name(temporality: i32) -> &'static str {
+    match temporality {
+        1 => "delta",
+        2 => "cumulative",
+        _ => "unspecified",
+    }
+}
+
+// label sets are unbounded, storing them as one canonical JSON column keeps
+// the table schema fixed no matter how many distinct labels show up.
+// PromQL reads the point labels out of it, see `prom::eval`.
+fn labels_to_json(attributes: &[KeyValue]) -> String {
+    let labels: BTreeMap<&str, Value> = attributes
+        .iter()
+        .filter_map(|kv| {
+            Some((
+                kv.key.as_str(),
+                super::any_value_to_json(kv.value.as_ref()?),
+            ))
+        })
+        .collect();
+    serde_json::to_string(&labels).unwrap_or_default()
+}
+
+// the list columns of a data point, they are built next to the records
+// since JSON records only hold scalars
+#[derive(Default)]
+struct PointBuckets {
+    bucket_counts: Option<Vec<u64>>,
+    explicit_bounds: Option<Vec<f64>>,
+    positive_bucket_counts: Option<Vec<u64>>,
+    negative_bucket_counts: Option<Vec<u64>>,
+}
+
+/// Normalizes every data point of the request into one row of the metrics
+/// table: `__name__`, type, the labels as JSON, the value and start and
+/// observed timestamps. Bucket counts and bounds of histograms are list
+/// columns.
+pub fn metrics_to_batch(
+    request: &ExportMetricsServiceRequest,
+) -> Result<Option<RecordBatch>, anyhow::Error> {
+    let points = metric_points(request);
+    let mut decoder = JsonDecoder::new();
+    for (record, _) in &points {
+        decoder.decode_value(record)?;
+    }
+    let Some(batch) = decoder.flush()? else {
+        return Ok(None);
+    };
+
+    let mut fields: Vec<Field> = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| f.as_ref().clone())
+        .collect();
+    let mut columns = batch.columns().to_vec();
+    let lists = [
+        ("bucket_counts", counts_list(&points, |b| &b.bucket_counts)),
+        ("explicit_bounds", bounds_list(&points)),
+        (
+            "positive_bucket_counts",
+            counts_list(&points, |b| &b.positive_bucket_counts),
+        ),
+        (
+            "negative_bucket_counts",
+            counts_list(&points, |b| &b.negative_bucket_counts),
+        ),
+    ];
+    for (name, list) in lists {
+        if let Some(list) = list {
+            fields.push(Field::new(name, list.data_type().clone(), true));
+            columns.push(list);
+        }
+    }
+    Ok(Some(RecordBatch::try_new(
+        Arc::new(Schema::new(fields)),
+        columns,
+    )?))
+}
+
+// a list column of bucket counts, `None` when no point has them so gauges
+// and sums do not add empty columns
+fn counts_list(
+    points: &[(Value, PointBuckets)],
+    counts: impl Fn(&PointBuckets) -> &Option<Vec<u64>>,
+) -> Option<ArrayRef> {
+    if points.iter().all(|(_, b)| counts(b).is_none()) {
+        return None;
+    }
+    let mut builder = ListBuilder::new(Int64Builder::new());
+    for (_, buckets) in points {
+        builder.append_option(
+            counts(buckets)
+                .as_ref()
+                .map(|v| v.iter().map(|c| Some(*c as i64))),
+        );
+    }
+    Some(Arc::new(builder.finish()))
+}
+
+fn bounds_list(points: &[(Value, PointBuckets)]) -> Option<ArrayRef> {
+    if points.iter().all(|(_, b)| b.explicit_bounds.is_none()) {
+        return None;
+    }
+    let mut builder = ListBuilder::new(Float64Builder::new());
+    for (_, buckets) in points {
+        builder.append_option(
+            buckets
+                .explicit_bounds
+                .as_ref()
+                .map(|v| v.iter().map(|b| Some(*b))),
+        );
+    }
+    Some(Arc::new(builder.finish()))
+}
+
+fn metric_points(request: &ExportMetricsServiceRequest) -> Vec<(Value, PointBuckets)> {
+    let mut points = vec![];
+    for resource_metrics in &request.resource_metrics {
+        let mut base = Map::new();
+        let resource = resource_metrics.resource.clone().unwrap_or_default();
+        base.insert(
+            "resource".to_owned(),
+            Value::from(labels_to_json(&resource.attributes)),
+        );
+        for scope_metrics in &resource_metrics.scope_metrics {
+            let mut scoped = base.clone();
+            super::insert_scope(&mut scoped, scope_metrics.scope.as_ref());
+            for metric in &scope_metrics.metrics {
+                insert_metric(&mut points, &scoped, metric);
+            }
+        }
+    }
+    points
+}
+
+fn insert_metric(
+    points: &mut Vec<(Value, PointBuckets)>,
+    scoped: &Map<String, Value>,
+    metric: &Metric,
+) {
+    let mut base = scoped.clone();
+    base.insert(
+        METRIC_NAME_LABEL.to_owned(),
+        Value::from(metric.name.as_str()),
+    );
+    if !metric.description.is_empty() {
+        base.insert(
+            "description".to_owned(),
+            Value::from(metric.description.as_str()),
+        );
+    }
+    if !metric.unit.is_empty() {
+        base.insert("unit".to_owned(), Value::from(metric.unit.as_str()));
+    }
+
+    match &metric.data {
+        Some(metric::Data::Gauge(gauge)) => {
+            base.insert(METRIC_TYPE.to_owned(), Value::from("gauge"));
+            for point in &gauge.data_points {
+                points.push((number_point(&base, point), PointBuckets::default()));
+            }
+        }
+        Some(metric::Data::Sum(sum)) => {
+            base.insert(METRIC_TYPE.to_owned(), Value::from("sum"));
+            base.insert(
+                "temporality".to_owned(),
+                Value::from(temporality_name(sum.aggregation_temporality)),
+            );
+            base.insert("is_monotonic".to_owned(), Value::from(sum.is_monotonic));
+            for point in &sum.data_points {
+                points.push((number_point(&base, point), PointBuckets::default()));
+            }
+        }
+        Some(metric::Data::Histogram(histogram)) => {
+            base.insert(METRIC_TYPE.to_owned(), Value::from("histogram"));
+            base.insert(
+                "temporality".to_owned(),
+                Value::from(temporality_name(histogram.aggregation_temporality)),
+            );
+            for point in &histogram.data_points {
+                let buckets = PointBuckets {
+                    bucket_counts: Some(point.bucket_counts.clone()),
+                    explicit_bounds: Some(point.explicit_bounds.clone()),
+                    ..Default::default()
+                };
+                points.push((histogram_point(&base, point), buckets));
+            }
+        }
+        Some(metric::Data::ExponentialHistogram(histogram)) => {
+            base.insert(METRIC_TYPE.to_owned(), Value::from("exponential_histogram"));
+            base.insert(
+                "temporality".to_owned(),
+                Value::from(temporality_name(histogram.aggregation_temporality)),
+            );
+            for point in &histogram.data_points {
+                let counts = |b: Option<&Buckets>| {
+                    Some(b.map(|b| b.bucket_counts.clone()).unwrap_or_default())
+                };
+                let buckets = PointBuckets {
+                    positive_bucket_counts: counts(point.positive.as_ref()),
+                    negative_bucket_counts: counts(point.negative.as_ref()),
+                    ..Default::default()
+                };
+                points.push((exponential_histogram_point(&base, point), buckets));
+            }
+        }
+        None => {}
+    }
+}
+
+fn insert_point_common(
+    record: &mut Map<String, Value>,
+    attributes: &[KeyValue],
+    start_time_unix_nano: u64,
+    time_unix_nano: u64,
+    flags: u32,
+) {
+    if time_unix_nano > 0 {
+        record.insert(
+            TIMPSTAMP_FIELD_NAME.to_owned(),
+            Value::from((time_unix_nano / 1000) as i64),
+        );
+    }
+    if start_time_unix_nano > 0 {
+        record.insert(
+            "start_timestamp".to_owned(),
+            Value::from((start_time_unix_nano / 1000) as i64),
+        );
+    }
+    record.insert(
+        LABELS_FIELD_NAME.to_owned(),
+        Value::from(labels_to_json(attributes)),
+    );
+    if flags > 0 {
+        record.insert("flags".to_owned(), Value::from(flags));
+    }
+}
+
+fn number_point(base: &Map<String, Value>, point: &NumberDataPoint) -> Value {
+    let mut record = base.clone();
+    insert_point_common(
+        &mut record,
+        &point.attributes,
+        point.start_time_unix_nano,
+        point.time_unix_nano,
+        point.flags,
+    );
+    // ints are stored as floats so the column keeps one type across segments
+    let value = match point.value {
+        Some(number_data_point::Value::AsDouble(v)) => Value::from(v),
+        Some(number_data_point::Value::AsInt(v)) => Value::from(v as f64),
+        None => Value::Null,
+    };
+    record.insert(VALUE_FIELD_NAME.to_owned(), value);
+    Value::Object(record)
+}
+
+fn insert_summary(
+    record: &mut Map<String, Value>,
+    count: u64,
+    sum: Option<f64>,
+    min: Option<f64>,
+    max: Option<f64>,
+) {
+    record.insert("count".to_owned(), Value::from(count));
+    for (key, value) in [("sum", sum), ("min", min), ("max", max)] {
+        if let Some(v) = value {
+            record.insert(key.to_owned(), Value::from(v));
+        }
+    }
+}
+
+fn histogram_point(base: &Map<String, Value>, point: &HistogramDataPoint) -> Value {
+    let mut record = base.clone();
+    insert_point_common(
+        &mut record,
+        &point.attributes,
+        point.start_time_unix_nano,
+        point.time_unix_nano,
+        point.flags,
+    );
+    insert_summary(&mut record, point.count, point.sum, point.min, point.max);
+    Value::Object(record)
+}
+
+fn exponential_histogram_point(
+    base: &Map<String, Value>,
+    point: &ExponentialHistogramDataPoint,
+) -> Value {
+    let mut record = base.clone();
+    insert_point_common(
+        &mut record,
+        &point.attributes,
+        point.start_time_unix_nano,
+        point.time_unix_nano,
+        point.flags,
+    );
+    insert_summary(&mut record, point.count, point.sum, point.min, point.max);
+    record.insert("scale".to_owned(), Value::from(point.scale));
+    record.insert("zero_count".to_owned(), Value::from(point.zero_count));
+    record.insert(
+        "zero_threshold".to_owned(),
+        Value::from(point.zero_threshold),
+    );
+    for (prefix, buckets) in [("positive", &point.positive), ("negative", &point.negative)] {
+        let offset = buckets.as_ref().map(|b| b.offset).unwrap_or_default();
+        record.insert(format!("{}_offset", prefix), Value::from(offset));
+    }
+    Value::Object(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        fusion::recordbatch::recordbatch_to_jsons,
+        otlp::{
+            proto::{any_value, AnyValue, Gauge, Resource, ResourceMetrics, ScopeMetrics},
+            Encoding,
+        },
+    };
+
+    fn records(request: &ExportMetricsServiceRequest) -> Vec<Map<String, Value>> {
+        let batch = metrics_to_batch(request).unwrap().unwrap();
+        recordbatch_to_jsons(&[&batch]).unwrap()
+    }
+
+    fn label(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_owned(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_owned())),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_gauge_protobuf() {
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![label("service.name", "api")],
+                    dropped_attributes_count: 0,
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    metrics: vec![Metric {
+                        name: "cpu".to_owned(),
+                        data: Some(metric::Data::Gauge(Gauge {
+                            data_points: (0..3)
+                                .map(|i| NumberDataPoint {
+                                    attributes: vec![
+                                        label("host.name", &format!("h{}", i)),
+                                        label("core", "0"),
+                                        label("value", "x"),
+                                    ],
+                                    time_unix_nano: 1_700_000_000_000_000_000,
+                                    value: Some(number_data_point::Value::AsInt(i)),
+                                    ..Default::default()
+                                })
+                                .collect(),
+                        })),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                schema_url: String::new(),
+            }],
+        };
+        let body = request.encode_to_vec();
+        let request: ExportMetricsServiceRequest = Encoding::Protobuf.decode(&body).unwrap();
+        let records = records(&request);
+        assert_eq!(records.len(), 3);
+        let record = &records[2];
+        assert_eq!(record["__name__"], "cpu");
+        assert_eq!(record["metric_type"], "gauge");
+        assert_eq!(record["value"], 2.0);
+        assert_eq!(record["timestamp"], 1_700_000_000_000_000i64);
+        assert_eq!(
+            record["labels"],
+            r#"{"core":"0","host.name":"h2","value":"x"}"#
+        );
+        assert_eq!(record["resource"], r#"{"service.name":"api"}"#);
+        // gauges have no bucket columns
+        assert!(!record.contains_key("bucket_counts"));
+    }
+
+    #[tokio::test]
+    async fn test_promql_labels() {
+        use crate::{
+            ingest::IngestService,
+            prom::{
+                eval::{instant_query, QueryResult},
+                promql::PromExpr,
+            },
+            storage::Storage,
+        };
+
+        let body = r#"{"resourceMetrics":[{"scopeMetrics":[{"metrics":[
+            {"name":"cpu","gauge":{"dataPoints":[
+                {"timeUnixNano":"1700000000000000000","asDouble":0.5,
+                 "attributes":[{"key":"host.name","value":{"stringValue":"a"}}]},
+                {"timeUnixNano":"1700000000000000000","asDouble":0.7,
+                 "attributes":[{"key":"host.name","value":{"stringValue":"b"}}]},
+                {"timeUnixNano":"1700000000000000000","asDouble":0.9,
+                 "attributes":[{"key":"http.method","value":{"stringValue":"GET"}},
+                               {"key":"http_method","value":{"stringValue":"POST"}}]}]}},
+            {"name":"latency","histogram":{"dataPoints":[
+                {"timeUnixNano":"1700000000000000000","count":"3",
+                 "bucketCounts":["1","2"],"explicitBounds":[10]}]}}]}]}]}"#;
+        let request: ExportMetricsServiceRequest = Encoding::Json.decode(body.as_bytes()).unwrap();
+        let batch = metrics_to_batch(&request).unwrap().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let service = IngestService::new(Storage::new(dir.path()));
+        service
+            .ingest_batches("metrics", vec![batch])
+            .await
+            .unwrap();
+
+        let expr = PromExpr::from_str(r#"cpu{host_name="b"}"#).unwrap();
+        let res = instant_query(&service, "metrics", &expr, 1_700_000_000_000).await;
+        let QueryResult::Vector(res) = res.unwrap() else {
+            panic!("expected a vector");
+        };
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].2, 0.7);
+        // keys sanitized to the same name keep both values
+        let expr = PromExpr::from_str(r#"cpu{http_method="GET;POST"}"#).unwrap();
+        let res = instant_query(&service, "metrics", &expr, 1_700_000_000_000).await;
+        let QueryResult::Vector(res) = res.unwrap() else {
+            panic!("expected a vector");
+        };
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].2, 0.9);
+        // and no label gets a column of its own
+        let schemas = service
+            .schemas("metrics", i64::MIN, i64::MAX)
+            .await
+            .unwrap();
+        assert!(schemas
+            .iter()
+            .all(|s| s.field_with_name("host_name").is_err()));
+
+        let batches = service
+            .query_("metrics", "metric_type==histogram", None, None)
+            .await
+            .unwrap();
+        let rows = recordbatch_to_jsons(&batches.iter().collect::<Vec<_>>()).unwrap();
+        assert_eq!(rows[0]["bucket_counts"], json!([1, 2]));
+    }
+
+    #[test]
+    fn test_histograms_json() {
+        let body = r#"{"resourceMetrics":[{"scopeMetrics":[{"metrics":[
+            {"name":"latency","unit":"ms","histogram":{"aggregationTemporality":2,"dataPoints":[
+                {"startTimeUnixNano":"1699999990000000000","timeUnixNano":"1700000000000000000",
+                 "count":"6","sum":42.5,"bucketCounts":["1","2","3"],"explicitBounds":[10,100],
+                 "attributes":[{"key":"route","value":{"stringValue":"/"}}]}]}},
+            {"name":"size","exponentialHistogram":{"aggregationTemporality":1,"dataPoints":[
+                {"timeUnixNano":"1700000000000000000","count":"3","scale":2,"zeroCount":"1",
+                 "positive":{"offset":-1,"bucketCounts":["1","1"]}}]}},
+            {"name":"requests","sum":{"aggregationTemporality":2,"isMonotonic":true,"dataPoints":[
+                {"timeUnixNano":"1700000000000000000","asInt":"7"}]}}]}]}]}"#;
+        let request: ExportMetricsServiceRequest = Encoding::Json.decode(body.as_bytes()).unwrap();
+        let records = records(&request);
+        assert_eq!(records.len(), 3);
+
+        let histogram = &records[0];
+        assert_eq!(histogram["metric_type"], "histogram");
+        assert_eq!(histogram["temporality"], "cumulative");
+        assert_eq!(histogram["start_timestamp"], 1_699_999_990_000_000i64);
+        assert_eq!(histogram["count"], 6);
+        assert_eq!(histogram["sum"], 42.5);
+        assert_eq!(histogram["bucket_counts"], json!([1, 2, 3]));
+        assert_eq!(histogram["explicit_bounds"], json!([10.0, 100.0]));
+        assert_eq!(histogram["labels"], r#"{"route":"/"}"#);
+
+        let exponential = &records[1];
+        assert_eq!(exponential["metric_type"], "exponential_histogram");
+        assert_eq!(exponential["temporality"], "delta");
+        assert_eq!(exponential["scale"], 2);
+        assert_eq!(exponential["zero_count"], 1);
+        assert_eq!(exponential["positive_offset"], -1);
+        assert_eq!(exponential["positive_bucket_counts"], json!([1, 1]));
+        assert_eq!(exponential["negative_bucket_counts"], json!([]));
+        assert!(!exponential.contains_key("bucket_counts"));
+
+        let sum = &records[2];
+        assert_eq!(sum["metric_type"], "sum");
+        assert_eq!(sum["is_monotonic"], true);
+        assert_eq!(sum["value"], 7.0);
+        assert!(!sum.contains_key("bucket_counts"));
+    }
+}
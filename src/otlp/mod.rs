@@ -0,0 +1,210 @@
+//! OpenTelemetry (OTLP/HTTP) ingestion. Requests are decoded from protobuf
+//! or JSON and mapped into flat records for `IngestService::ingest_records`.
+
+pub mod logs;
+pub mod metrics;
+pub mod proto;
+pub mod traces;
+
+use anyhow::anyhow;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use self::proto::{any_value, AnyValue, InstrumentationScope, KeyValue, Resource};
+
+pub static PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+pub static JSON_CONTENT_TYPE: &str = "application/json";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Protobuf,
+    Json,
+}
+
+impl Encoding {
+    pub fn from_content_type(content_type: Option<&str>) -> Result<Encoding, anyhow::Error> {
+        let mime = content_type
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase());
+        match mime.as_deref() {
+            Some("application/x-protobuf") | Some("application/protobuf") => Ok(Encoding::Protobuf),
+            Some("application/json") => Ok(Encoding::Json),
+            Some(v) => Err(anyhow!("unsupported content type: {}", v)),
+            None => Err(anyhow!("missing content type")),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Protobuf => PROTOBUF_CONTENT_TYPE,
+            Encoding::Json => JSON_CONTENT_TYPE,
+        }
+    }
+
+    pub fn decode<T>(&self, body: &[u8]) -> Result<T, anyhow::Error>
+    where
+        T: prost::Message + Default + DeserializeOwned,
+    {
+        match self {
+            Encoding::Protobuf => Ok(T::decode(body)?),
+            Encoding::Json => Ok(serde_json::from_slice(body)?),
+        }
+    }
+
+    pub fn encode<T>(&self, message: &T) -> Vec<u8>
+    where
+        T: prost::Message + Serialize,
+    {
+        match self {
+            Encoding::Protobuf => message.encode_to_vec(),
+            Encoding::Json => serde_json::to_vec(message).unwrap_or_default(),
+        }
+    }
+}
+
+// converts a scalar AnyValue, arrays and maps are kept as JSON text since
+// a column can only hold scalars
+pub fn any_value_to_json(value: &AnyValue) -> Value {
+    match &value.value {
+        Some(any_value::Value::StringValue(v)) => Value::from(v.as_str()),
+        Some(any_value::Value::BoolValue(v)) => Value::from(*v),
+        Some(any_value::Value::IntValue(v)) => Value::from(*v),
+        Some(any_value::Value::DoubleValue(v)) => Value::from(*v),
+        Some(any_value::Value::BytesValue(v)) => {
+            Value::from(base64::engine::general_purpose::STANDARD.encode(v))
+        }
+        Some(any_value::Value::ArrayValue(_)) | Some(any_value::Value::KvlistValue(_)) => {
+            Value::from(any_value_to_nested(value).to_string())
+        }
+        None => Value::Null,
+    }
+}
+
+fn any_value_to_nested(value: &AnyValue) -> Value {
+    match &value.value {
+        Some(any_value::Value::ArrayValue(v)) => {
+            Value::Array(v.values.iter().map(any_value_to_nested).collect())
+        }
+        Some(any_value::Value::KvlistValue(v)) => attributes_to_json(&v.values),
+        _ => any_value_to_json(value),
+    }
+}
+
+/// Converts attributes into one JSON object, used where a list of items
+/// (span events, links) is stored as JSON text in a single column.
+pub fn attributes_to_json(attributes: &[KeyValue]) -> Value {
+    Value::Object(
+        attributes
+            .iter()
+            .map(|kv| {
+                let value = kv.value.as_ref().map(any_value_to_nested);
+                (kv.key.clone(), value.unwrap_or_default())
+            })
+            .collect(),
+    )
+}
+
+/// Inserts `value` under `key`, maps are flattened into `key.<child>`.
+pub fn insert_value(record: &mut Map<String, Value>, key: &str, value: &AnyValue) {
+    match &value.value {
+        Some(any_value::Value::KvlistValue(v)) => insert_attributes(record, key, &v.values),
+        None => {}
+        _ => {
+            record.insert(key.to_owned(), any_value_to_json(value));
+        }
+    }
+}
+
+pub fn insert_attributes(record: &mut Map<String, Value>, prefix: &str, attributes: &[KeyValue]) {
+    for kv in attributes {
+        if let Some(value) = &kv.value {
+            insert_value(record, &format!("{}.{}", prefix, kv.key), value);
+        }
+    }
+}
+
+pub fn insert_resource(record: &mut Map<String, Value>, resource: Option<&Resource>) {
+    if let Some(resource) = resource {
+        insert_attributes(record, "resource", &resource.attributes);
+    }
+}
+
+pub fn insert_scope(record: &mut Map<String, Value>, scope: Option<&InstrumentationScope>) {
+    let Some(scope) = scope else {
+        return;
+    };
+    if !scope.name.is_empty() {
+        record.insert("scope.name".to_owned(), Value::from(scope.name.as_str()));
+    }
+    if !scope.version.is_empty() {
+        record.insert(
+            "scope.version".to_owned(),
+            Value::from(scope.version.as_str()),
+        );
+    }
+    insert_attributes(record, "scope.attributes", &scope.attributes);
+}
+
+pub fn insert_id(record: &mut Map<String, Value>, key: &str, id: &[u8]) {
+    if !id.is_empty() {
+        record.insert(key.to_owned(), Value::from(hex::encode(id)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::otlp::proto::{ArrayValue, KeyValueList};
+
+    fn string_value(v: &str) -> AnyValue {
+        AnyValue {
+            value: Some(any_value::Value::StringValue(v.to_owned())),
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let encoding = Encoding::from_content_type(Some("application/json; charset=utf-8"));
+        assert_eq!(encoding.unwrap(), Encoding::Json);
+        let encoding = Encoding::from_content_type(Some("application/x-protobuf"));
+        assert_eq!(encoding.unwrap(), Encoding::Protobuf);
+        assert!(Encoding::from_content_type(Some("text/plain")).is_err());
+        assert!(Encoding::from_content_type(None).is_err());
+    }
+
+    #[test]
+    fn test_insert_attributes() {
+        let attributes = vec![
+            KeyValue {
+                key: "host".to_owned(),
+                value: Some(string_value("a")),
+            },
+            KeyValue {
+                key: "http".to_owned(),
+                value: Some(AnyValue {
+                    value: Some(any_value::Value::KvlistValue(KeyValueList {
+                        values: vec![KeyValue {
+                            key: "method".to_owned(),
+                            value: Some(string_value("GET")),
+                        }],
+                    })),
+                }),
+            },
+            KeyValue {
+                key: "tags".to_owned(),
+                value: Some(AnyValue {
+                    value: Some(any_value::Value::ArrayValue(ArrayValue {
+                        values: vec![string_value("x"), string_value("y")],
+                    })),
+                }),
+            },
+        ];
+        let mut record = Map::new();
+        insert_attributes(&mut record, "attributes", &attributes);
+        assert_eq!(record["attributes.host"], "a");
+        assert_eq!(record["attributes.http.method"], "GET");
+        assert_eq!(record["attributes.tags"], r#"["x","y"]"#);
+    }
+}
@@ -0,0 +1,216 @@
+use serde_json::{json, Map, Value};
+
+use super::proto::{span, ExportTraceServiceRequest, Span};
+use crate::config::{TIMPSTAMP_FIELD_NAME, TRACE_ID_FIELD_NAME};
+
+// columns of the span table, all times are microseconds like `timestamp`
+pub static SPAN_ID: &str = "span_id";
+pub static PARENT_SPAN_ID: &str = "parent_span_id";
+pub static NAME: &str = "name";
+pub static KIND: &str = "kind";
+pub static START_TIME: &str = "start_time";
+pub static END_TIME: &str = "end_time";
+pub static DURATION: &str = "duration";
+pub static STATUS_CODE: &str = "status_code";
+pub static STATUS_MESSAGE: &str = "status_message";
+pub static SERVICE_NAME: &str = "resource.service.name";
+
+pub fn span_kind_name(kind: i32) -> &'static str {
+    match kind {
+        1 => "internal",
+        2 => "server",
+        3 => "client",
+        4 => "producer",
+        5 => "consumer",
+        _ => "unspecified",
+    }
+}
+
+pub fn status_code_name(code: i32) -> &'static str {
+    match code {
+        1 => "ok",
+        2 => "error",
+        _ => "unset",
+    }
+}
+
+/// Flattens every span of the request into one row. `timestamp` is the
+/// span start, so spans partition and prune like any other record.
+pub fn spans_to_records(request: &ExportTraceServiceRequest) -> Vec<Value> {
+    let mut records = vec![];
+    for resource_spans in &request.resource_spans {
+        let mut base = Map::new();
+        super::insert_resource(&mut base, resource_spans.resource.as_ref());
+        for scope_spans in &resource_spans.scope_spans {
+            let mut scoped = base.clone();
+            super::insert_scope(&mut scoped, scope_spans.scope.as_ref());
+            for span in &scope_spans.spans {
+                let mut record = scoped.clone();
+                insert_span(&mut record, span);
+                records.push(Value::Object(record));
+            }
+        }
+    }
+    records
+}
+
+fn insert_span(record: &mut Map<String, Value>, span: &Span) {
+    let start = (span.start_time_unix_nano / 1000) as i64;
+    let end = (span.end_time_unix_nano / 1000) as i64;
+    if start > 0 {
+        record.insert(TIMPSTAMP_FIELD_NAME.to_owned(), Value::from(start));
+    }
+    record.insert(
+        TRACE_ID_FIELD_NAME.to_owned(),
+        Value::from(hex::encode(&span.trace_id)),
+    );
+    record.insert(SPAN_ID.to_owned(), Value::from(hex::encode(&span.span_id)));
+    // root spans get an empty parent so the column exists in every segment
+    record.insert(
+        PARENT_SPAN_ID.to_owned(),
+        Value::from(hex::encode(&span.parent_span_id)),
+    );
+    if !span.trace_state.is_empty() {
+        record.insert(
+            "trace_state".to_owned(),
+            Value::from(span.trace_state.as_str()),
+        );
+    }
+    record.insert(NAME.to_owned(), Value::from(span.name.as_str()));
+    record.insert(KIND.to_owned(), Value::from(span_kind_name(span.kind)));
+    record.insert(START_TIME.to_owned(), Value::from(start));
+    record.insert(END_TIME.to_owned(), Value::from(end));
+    record.insert(DURATION.to_owned(), Value::from((end - start).max(0)));
+
+    let status = span.status.clone().unwrap_or_default();
+    record.insert(
+        STATUS_CODE.to_owned(),
+        Value::from(status_code_name(status.code)),
+    );
+    if !status.message.is_empty() {
+        record.insert(STATUS_MESSAGE.to_owned(), Value::from(status.message));
+    }
+    if span.flags > 0 {
+        record.insert("flags".to_owned(), Value::from(span.flags));
+    }
+    super::insert_attributes(record, "attributes", &span.attributes);
+
+    // events and links are lists, they are kept as JSON text
+    if !span.events.is_empty() {
+        let events: Vec<Value> = span.events.iter().map(event_to_json).collect();
+        record.insert(
+            "events".to_owned(),
+            Value::from(Value::from(events).to_string()),
+        );
+    }
+    if !span.links.is_empty() {
+        let links: Vec<Value> = span.links.iter().map(link_to_json).collect();
+        record.insert(
+            "links".to_owned(),
+            Value::from(Value::from(links).to_string()),
+        );
+    }
+}
+
+fn event_to_json(event: &span::Event) -> Value {
+    json!({
+        "time": event.time_unix_nano / 1000,
+        "name": event.name,
+        "attributes": super::attributes_to_json(&event.attributes),
+    })
+}
+
+fn link_to_json(link: &span::Link) -> Value {
+    json!({
+        "trace_id": hex::encode(&link.trace_id),
+        "span_id": hex::encode(&link.span_id),
+        "attributes": super::attributes_to_json(&link.attributes),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::otlp::{
+        proto::{any_value, AnyValue, KeyValue, Resource, ResourceSpans, ScopeSpans, SpanStatus},
+        Encoding,
+    };
+
+    #[test]
+    fn test_spans_protobuf() {
+        let request = ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Some(Resource {
+                    attributes: vec![KeyValue {
+                        key: "service.name".to_owned(),
+                        value: Some(AnyValue {
+                            value: Some(any_value::Value::StringValue("api".to_owned())),
+                        }),
+                    }],
+                    dropped_attributes_count: 0,
+                }),
+                scope_spans: vec![ScopeSpans {
+                    spans: vec![Span {
+                        trace_id: vec![1; 16],
+                        span_id: vec![2; 8],
+                        name: "GET /users".to_owned(),
+                        kind: 2,
+                        start_time_unix_nano: 1_700_000_000_000_000_000,
+                        end_time_unix_nano: 1_700_000_000_250_000_000,
+                        status: Some(SpanStatus {
+                            message: "boom".to_owned(),
+                            code: 2,
+                        }),
+                        events: vec![span::Event {
+                            time_unix_nano: 1_700_000_000_100_000_000,
+                            name: "retry".to_owned(),
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                schema_url: String::new(),
+            }],
+        };
+        let body = request.encode_to_vec();
+        let request: ExportTraceServiceRequest = Encoding::Protobuf.decode(&body).unwrap();
+        let records = spans_to_records(&request);
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record["timestamp"], 1_700_000_000_000_000i64);
+        assert_eq!(record["trace_id"], "01".repeat(16));
+        assert_eq!(record["span_id"], "02".repeat(8));
+        assert_eq!(record["parent_span_id"], "");
+        assert_eq!(record["kind"], "server");
+        assert_eq!(record["duration"], 250_000);
+        assert_eq!(record["status_code"], "error");
+        assert_eq!(record["status_message"], "boom");
+        assert_eq!(record["resource.service.name"], "api");
+        assert_eq!(
+            record["events"],
+            r#"[{"attributes":{},"name":"retry","time":1700000000100000}]"#
+        );
+    }
+
+    #[test]
+    fn test_spans_json() {
+        let body = r#"{"resourceSpans":[{"scopeSpans":[{"spans":[{
+            "traceId":"5b8efff798038103d269b633813fc60c","spanId":"eee19b7ec3c1b174",
+            "parentSpanId":"eee19b7ec3c1b173","name":"query","kind":3,
+            "startTimeUnixNano":"1544712660000000000","endTimeUnixNano":"1544712661000000000",
+            "attributes":[{"key":"db.rows","value":{"intValue":"10"}}]}]}]}]}"#;
+        let request: ExportTraceServiceRequest = Encoding::Json.decode(body.as_bytes()).unwrap();
+        let records = spans_to_records(&request);
+        let record = &records[0];
+        assert_eq!(record["parent_span_id"], "eee19b7ec3c1b173");
+        assert_eq!(record["kind"], "client");
+        assert_eq!(record["start_time"], 1_544_712_660_000_000i64);
+        assert_eq!(record["end_time"], 1_544_712_661_000_000i64);
+        assert_eq!(record["duration"], 1_000_000);
+        assert_eq!(record["status_code"], "unset");
+        assert_eq!(record["attributes.db.rows"], 10);
+    }
+}
@@ -0,0 +1,360 @@
+//! Messages of the PostgreSQL frontend/backend protocol 3.0, those of
+//! the simple and extended query flows and of SASL logins.
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+pub static PROTOCOL_VERSION: i32 = 196608;
+pub static SSL_REQUEST: i32 = 80877103;
+pub static GSSENC_REQUEST: i32 = 80877104;
+pub static CANCEL_REQUEST: i32 = 80877102;
+
+// values are text unless a format code says binary
+pub static FORMAT_BINARY: i16 = 1;
+
+/// The first message of a connection, it has no type byte.
+#[derive(Debug, PartialEq)]
+pub enum Startup {
+    Ssl,
+    Gss,
+    Cancel,
+    Params(HashMap<String, String>),
+}
+
+impl Startup {
+    /// Reads the body following the length.
+    pub fn decode(mut body: Bytes) -> Result<Startup, anyhow::Error> {
+        let code = get_i32(&mut body)?;
+        match code {
+            c if c == SSL_REQUEST => Ok(Startup::Ssl),
+            c if c == GSSENC_REQUEST => Ok(Startup::Gss),
+            c if c == CANCEL_REQUEST => Ok(Startup::Cancel),
+            c if c == PROTOCOL_VERSION => {
+                let mut params = HashMap::new();
+                loop {
+                    let key = get_cstr(&mut body)?;
+                    if key.is_empty() {
+                        return Ok(Startup::Params(params));
+                    }
+                    params.insert(key, get_cstr(&mut body)?);
+                }
+            }
+            c => Err(anyhow!("unsupported protocol version {}", c)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Frontend {
+    Query(String),
+    Parse {
+        name: String,
+        query: String,
+        types: Vec<u32>,
+    },
+    Bind {
+        portal: String,
+        statement: String,
+        param_formats: Vec<i16>,
+        params: Vec<Option<Bytes>>,
+        result_formats: Vec<i16>,
+    },
+    /// `kind` is `S` for a statement, `P` for a portal.
+    Describe {
+        kind: u8,
+        name: String,
+    },
+    Execute {
+        portal: String,
+        max_rows: i32,
+    },
+    Close {
+        kind: u8,
+        name: String,
+    },
+    Sync,
+    Flush,
+    Terminate,
+}
+
+impl Frontend {
+    /// Reads a message of type `tag`, `body` follows the length.
+    pub fn decode(tag: u8, mut body: Bytes) -> Result<Frontend, anyhow::Error> {
+        let body = &mut body;
+        let message = match tag {
+            b'Q' => Frontend::Query(get_cstr(body)?),
+            b'P' => {
+                let name = get_cstr(body)?;
+                let query = get_cstr(body)?;
+                let n = get_i16(body)?;
+                let types = (0..n)
+                    .map(|_| get_i32(body).map(|t| t as u32))
+                    .collect::<Result<_, _>>()?;
+                Frontend::Parse { name, query, types }
+            }
+            b'B' => {
+                let portal = get_cstr(body)?;
+                let statement = get_cstr(body)?;
+                let param_formats = get_i16s(body)?;
+                let n = get_i16(body)?;
+                let mut params = Vec::with_capacity(n.max(0) as usize);
+                for _ in 0..n {
+                    let len = get_i32(body)?;
+                    if len < 0 {
+                        params.push(None);
+                        continue;
+                    }
+                    if body.remaining() < len as usize {
+                        return Err(anyhow!("truncated message"));
+                    }
+                    params.push(Some(body.split_to(len as usize)));
+                }
+                let result_formats = get_i16s(body)?;
+                Frontend::Bind {
+                    portal,
+                    statement,
+                    param_formats,
+                    params,
+                    result_formats,
+                }
+            }
+            b'D' | b'C' => {
+                let kind = get_u8(body)?;
+                let name = get_cstr(body)?;
+                if tag == b'D' {
+                    Frontend::Describe { kind, name }
+                } else {
+                    Frontend::Close { kind, name }
+                }
+            }
+            b'E' => Frontend::Execute {
+                portal: get_cstr(body)?,
+                max_rows: get_i32(body)?,
+            },
+            b'S' => Frontend::Sync,
+            b'H' => Frontend::Flush,
+            b'X' => Frontend::Terminate,
+            t => return Err(anyhow!("unsupported message type {:?}", t as char)),
+        };
+        Ok(message)
+    }
+}
+
+/// The mechanism and the data of a SASLInitialResponse, it has the `p`
+/// type byte of the SASLResponse that carries data only.
+pub fn sasl_initial_response(mut body: Bytes) -> Result<(String, Bytes), anyhow::Error> {
+    let mechanism = get_cstr(&mut body)?;
+    let len = get_i32(&mut body)?;
+    if len < 0 {
+        return Ok((mechanism, Bytes::new()));
+    }
+    if body.remaining() < len as usize {
+        return Err(anyhow!("truncated message"));
+    }
+    Ok((mechanism, body.split_to(len as usize)))
+}
+
+fn get_u8(body: &mut Bytes) -> Result<u8, anyhow::Error> {
+    if body.remaining() < 1 {
+        return Err(anyhow!("truncated message"));
+    }
+    Ok(body.get_u8())
+}
+
+fn get_i16(body: &mut Bytes) -> Result<i16, anyhow::Error> {
+    if body.remaining() < 2 {
+        return Err(anyhow!("truncated message"));
+    }
+    Ok(body.get_i16())
+}
+
+fn get_i32(body: &mut Bytes) -> Result<i32, anyhow::Error> {
+    if body.remaining() < 4 {
+        return Err(anyhow!("truncated message"));
+    }
+    Ok(body.get_i32())
+}
+
+fn get_i16s(body: &mut Bytes) -> Result<Vec<i16>, anyhow::Error> {
+    let n = get_i16(body)?;
+    (0..n).map(|_| get_i16(body)).collect()
+}
+
+fn get_cstr(body: &mut Bytes) -> Result<String, anyhow::Error> {
+    let end = body
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| anyhow!("unterminated string"))?;
+    let s = String::from_utf8(body.split_to(end).to_vec())?;
+    body.advance(1);
+    Ok(s)
+}
+
+fn put_cstr(buf: &mut BytesMut, s: &str) {
+    buf.put_slice(s.as_bytes());
+    buf.put_u8(0);
+}
+
+/// Writes a backend message, its length is filled in after `body`.
+pub fn message(buf: &mut BytesMut, tag: u8, body: impl FnOnce(&mut BytesMut)) {
+    buf.put_u8(tag);
+    let start = buf.len();
+    buf.put_i32(0);
+    body(buf);
+    let len = (buf.len() - start) as i32;
+    buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+/// Authentication request, 0 is success, 10 starts a SASL login with the
+/// mechanisms in `data`, 11 and 12 carry the server's SASL messages.
+pub fn authentication(buf: &mut BytesMut, code: i32, data: &[u8]) {
+    message(buf, b'R', |b| {
+        b.put_i32(code);
+        b.put_slice(data);
+    });
+}
+
+pub fn parameter_status(buf: &mut BytesMut, name: &str, value: &str) {
+    message(buf, b'S', |b| {
+        put_cstr(b, name);
+        put_cstr(b, value);
+    });
+}
+
+pub fn backend_key_data(buf: &mut BytesMut, pid: i32, secret: i32) {
+    message(buf, b'K', |b| {
+        b.put_i32(pid);
+        b.put_i32(secret);
+    });
+}
+
+/// Ready for a query outside of any transaction.
+pub fn ready_for_query(buf: &mut BytesMut) {
+    message(buf, b'Z', |b| b.put_u8(b'I'));
+}
+
+/// A column of a row description.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldDescription {
+    pub name: String,
+    pub type_oid: u32,
+    pub type_size: i16,
+    pub format: i16,
+}
+
+pub fn row_description(buf: &mut BytesMut, fields: &[FieldDescription]) {
+    message(buf, b'T', |b| {
+        b.put_i16(fields.len() as i16);
+        for field in fields {
+            put_cstr(b, &field.name);
+            // no table oid nor attribute number
+            b.put_i32(0);
+            b.put_i16(0);
+            b.put_u32(field.type_oid);
+            b.put_i16(field.type_size);
+            b.put_i32(-1);
+            b.put_i16(field.format);
+        }
+    });
+}
+
+pub fn data_row(buf: &mut BytesMut, values: &[Option<&[u8]>]) {
+    message(buf, b'D', |b| {
+        b.put_i16(values.len() as i16);
+        for value in values {
+            match value {
+                Some(v) => {
+                    b.put_i32(v.len() as i32);
+                    b.put_slice(v);
+                }
+                None => b.put_i32(-1),
+            }
+        }
+    });
+}
+
+pub fn parameter_description(buf: &mut BytesMut, types: &[u32]) {
+    message(buf, b't', |b| {
+        b.put_i16(types.len() as i16);
+        for t in types {
+            b.put_u32(*t);
+        }
+    });
+}
+
+pub fn command_complete(buf: &mut BytesMut, tag: &str) {
+    message(buf, b'C', |b| put_cstr(b, tag));
+}
+
+/// The messages without a body: `1` parse, `2` bind and `3` close
+/// complete, `n` no data, `I` empty query.
+pub fn empty(buf: &mut BytesMut, tag: u8) {
+    message(buf, tag, |_| {});
+}
+
+pub fn error_response(buf: &mut BytesMut, code: &str, text: &str) {
+    message(buf, b'E', |b| {
+        for (field, value) in [(b'S', "ERROR"), (b'V', "ERROR"), (b'C', code), (b'M', text)] {
+            b.put_u8(field);
+            put_cstr(b, value);
+        }
+        b.put_u8(0);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let mut body = BytesMut::new();
+        body.put_i32(PROTOCOL_VERSION);
+        for s in ["user", "grafana", "database", "melt", ""] {
+            put_cstr(&mut body, s);
+        }
+        let Startup::Params(params) = Startup::decode(body.freeze()).unwrap() else {
+            panic!("expected startup parameters");
+        };
+        assert_eq!(params["user"], "grafana");
+        assert_eq!(params["database"], "melt");
+
+        let mut body = BytesMut::new();
+        for s in ["", "s1"] {
+            put_cstr(&mut body, s);
+        }
+        body.put_i16(1);
+        body.put_i16(FORMAT_BINARY);
+        body.put_i16(2);
+        body.put_i32(2);
+        body.put_slice(b"42");
+        body.put_i32(-1);
+        body.put_i16(0);
+        assert_eq!(
+            Frontend::decode(b'B', body.freeze()).unwrap(),
+            Frontend::Bind {
+                portal: "".to_owned(),
+                statement: "s1".to_owned(),
+                param_formats: vec![FORMAT_BINARY],
+                params: vec![Some(Bytes::from_static(b"42")), None],
+                result_formats: vec![],
+            }
+        );
+        assert!(Frontend::decode(b'Q', Bytes::from_static(b"SELECT 1")).is_err());
+        assert!(Frontend::decode(b'?', Bytes::new()).is_err());
+
+        let mut body = BytesMut::new();
+        put_cstr(&mut body, "SCRAM-SHA-256");
+        body.put_i32(5);
+        body.put_slice(b"n,,n=");
+        let (mechanism, data) = sasl_initial_response(body.freeze()).unwrap();
+        assert_eq!(mechanism, "SCRAM-SHA-256");
+        assert_eq!(&data[..], b"n,,n=");
+
+        let mut buf = BytesMut::new();
+        command_complete(&mut buf, "SELECT 1");
+        assert_eq!(&buf[..], b"C\x00\x00\x00\x0dSELECT 1\x00");
+    }
+}
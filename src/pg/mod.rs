@@ -0,0 +1,580 @@
+//! PostgreSQL wire protocol listener. Simple and extended queries run on
+//! the DataFusion session of Flight SQL, the melt tables are in schema
+//! `public`. Logins use SCRAM-SHA-256 so passwords never cross the wire,
+//! but there is no TLS for the queries and rows. Portals run to completion
+//! whatever row limit Execute asks for.
+
+pub mod message;
+pub mod scram;
+pub mod types;
+
+use std::{collections::HashMap, sync::Arc};
+
+use actix_web::web;
+use anyhow::anyhow;
+use arrow_schema::{DataType, Schema};
+use bytes::{Bytes, BytesMut};
+use datafusion::{
+    dataframe::DataFrame, error::DataFusionError, execution::context::SessionContext,
+    scalar::ScalarValue, sql::parser::DFParser,
+};
+use futures::StreamExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+};
+
+use self::{
+    message::{FieldDescription, Frontend, Startup},
+    scram::{ScramExchange, Users, MECHANISM},
+    types::{decode_param, encode_column, type_oid, type_size, TEXT},
+};
+use crate::{
+    app::AppState,
+    config::PostgresConfig,
+    flight::sql::{plan, session_context},
+    utils::net,
+};
+
+// largest message accepted from a logged in client
+pub static MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+// largest message accepted before the login, the startup packet limit of
+// Postgres, so clients that can not log in can not make it allocate much
+static MAX_STARTUP_SIZE: usize = 10_000;
+// rows are written out once this much is buffered
+static FLUSH_SIZE: usize = 64 * 1024;
+// clients check it for features, the protocol is the one of 14
+static SERVER_VERSION: &str = "14.0";
+
+// statements clients send on connect or around queries, acknowledged
+// without running them as melt is read only
+static IGNORED_COMMANDS: [&str; 7] = [
+    "SET", "RESET", "BEGIN", "START", "COMMIT", "ROLLBACK", "DISCARD",
+];
+
+/// The command tag of a statement acknowledged without running it.
+pub fn ignored_command(query: &str) -> Option<String> {
+    let word = query
+        .split_whitespace()
+        .next()?
+        .trim_end_matches(';')
+        .to_ascii_uppercase();
+    IGNORED_COMMANDS.contains(&word.as_str()).then_some(word)
+}
+
+/// The SQLSTATE of a failed statement.
+pub fn error_code(e: &anyhow::Error) -> &'static str {
+    match e.downcast_ref::<DataFusionError>() {
+        Some(DataFusionError::SQL(_)) => "42601",
+        Some(DataFusionError::Plan(_) | DataFusionError::SchemaError(_)) => "42000",
+        Some(DataFusionError::NotImplemented(_)) => "0A000",
+        _ => "XX000",
+    }
+}
+
+// the format of column `i`: none given is text, one is for all columns
+fn format(formats: &[i16], i: usize) -> i16 {
+    match formats {
+        [] => 0,
+        [f] => *f,
+        formats => formats.get(i).copied().unwrap_or(0),
+    }
+}
+
+fn fields(schema: &Schema, formats: &[i16]) -> Vec<FieldDescription> {
+    schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, f)| {
+            let type_oid = type_oid(f.data_type());
+            FieldDescription {
+                name: f.name().clone(),
+                type_oid,
+                type_size: type_size(type_oid),
+                format: format(formats, i),
+            }
+        })
+        .collect()
+}
+
+// the types of the `$n` placeholders of a query, by position
+fn param_types(df: &DataFrame) -> Result<Vec<Option<DataType>>, anyhow::Error> {
+    let mut params = vec![];
+    for (id, dt) in df.logical_plan().get_parameter_types()? {
+        let i: usize = id
+            .trim_start_matches('$')
+            .parse()
+            .map_err(|_| anyhow!("invalid parameter {}", id))?;
+        if i == 0 {
+            return Err(anyhow!("invalid parameter {}", id));
+        }
+        if params.len() < i {
+            params.resize(i, None);
+        }
+        params[i - 1] = dt;
+    }
+    Ok(params)
+}
+
+#[derive(Clone)]
+enum Prepared {
+    Query(Box<DataFrame>),
+    Command(String),
+}
+
+struct Statement {
+    prepared: Prepared,
+    // the parameter types the client declared, 0 when it left one out
+    declared: Vec<u32>,
+    expected: Vec<Option<DataType>>,
+}
+
+impl Statement {
+    fn param_count(&self) -> usize {
+        self.declared.len().max(self.expected.len())
+    }
+
+    fn param_oids(&self) -> Vec<u32> {
+        (0..self.param_count())
+            .map(|i| match self.declared.get(i) {
+                Some(oid) if *oid != 0 => *oid,
+                _ => self
+                    .expected
+                    .get(i)
+                    .and_then(Option::as_ref)
+                    .map_or(TEXT, type_oid),
+            })
+            .collect()
+    }
+}
+
+struct Portal {
+    prepared: Prepared,
+    formats: Vec<i16>,
+}
+
+struct Connection {
+    ctx: SessionContext,
+    statements: HashMap<String, Statement>,
+    portals: HashMap<String, Portal>,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    buf: BytesMut,
+    // MAX_STARTUP_SIZE until the client logged in
+    max_message_size: usize,
+}
+
+impl Connection {
+    async fn read(&mut self) -> Result<Option<(u8, Bytes)>, anyhow::Error> {
+        let tag = match self.reader.read_u8().await {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some((tag, self.read_body().await?)))
+    }
+
+    async fn read_body(&mut self) -> Result<Bytes, anyhow::Error> {
+        let len = self.reader.read_i32().await?;
+        if len < 4 || len as usize > self.max_message_size {
+            return Err(anyhow!("invalid message length {}", len));
+        }
+        let mut body = vec![0; len as usize - 4];
+        self.reader.read_exact(&mut body).await?;
+        Ok(body.into())
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.writer.write_all(&self.buf).await?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    // the body of the next `p` message, `None` when the client left
+    async fn read_sasl(&mut self) -> Result<Option<Bytes>, anyhow::Error> {
+        match self.read().await? {
+            Some((b'p', body)) => Ok(Some(body)),
+            Some((tag, _)) => Err(anyhow!("expected a SASL response, got {:?}", tag as char)),
+            None => Ok(None),
+        }
+    }
+
+    // runs a SCRAM-SHA-256 login, false when the client gave a wrong
+    // password or left
+    async fn authenticate(&mut self, users: &Users, user: &str) -> Result<bool, anyhow::Error> {
+        message::authentication(&mut self.buf, 10, format!("{}\0\0", MECHANISM).as_bytes());
+        self.flush().await?;
+        let Some(body) = self.read_sasl().await? else {
+            return Ok(false);
+        };
+        let (mechanism, client_first) = message::sasl_initial_response(body)?;
+        if mechanism != MECHANISM {
+            return Err(anyhow!("unsupported SASL mechanism {:?}", mechanism));
+        }
+        let secret = users.secret(user);
+        let (exchange, server_first) = ScramExchange::start(&secret, &client_first)?;
+        message::authentication(&mut self.buf, 11, server_first.as_bytes());
+        self.flush().await?;
+        let Some(client_final) = self.read_sasl().await? else {
+            return Ok(false);
+        };
+        match exchange.finish(&client_final)? {
+            Some(server_final) => {
+                message::authentication(&mut self.buf, 12, server_final.as_bytes());
+                Ok(true)
+            }
+            None => {
+                let text = format!("password authentication failed for user \"{}\"", user);
+                message::error_response(&mut self.buf, "28P01", &text);
+                self.flush().await?;
+                Ok(false)
+            }
+        }
+    }
+
+    // the startup parameters of the client once it is logged in, `None`
+    // when it only wanted to cancel or failed to log in. Without users any
+    // login is trusted.
+    async fn startup(
+        &mut self,
+        users: Option<&Users>,
+    ) -> Result<Option<HashMap<String, String>>, anyhow::Error> {
+        let params = loop {
+            let body = self.read_body().await?;
+            match Startup::decode(body)? {
+                // no encryption, the client goes on in plain text
+                Startup::Ssl | Startup::Gss => self.writer.write_all(b"N").await?,
+                Startup::Cancel => return Ok(None),
+                Startup::Params(params) => break params,
+            }
+        };
+        let user = params.get("user").cloned().unwrap_or_default();
+        if let Some(users) = users {
+            if !self.authenticate(users, &user).await? {
+                return Ok(None);
+            }
+        }
+        self.max_message_size = MAX_MESSAGE_SIZE;
+        message::authentication(&mut self.buf, 0, &[]);
+        for (name, value) in [
+            ("server_version", SERVER_VERSION),
+            ("server_encoding", "UTF8"),
+            ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO, YMD"),
+            ("TimeZone", "UTC"),
+            ("integer_datetimes", "on"),
+            ("standard_conforming_strings", "on"),
+        ] {
+            message::parameter_status(&mut self.buf, name, value);
+        }
+        // cancel requests are not supported, the key is never checked
+        message::backend_key_data(&mut self.buf, std::process::id() as i32, 0);
+        message::ready_for_query(&mut self.buf);
+        self.flush().await?;
+        Ok(Some(params))
+    }
+
+    async fn prepare(&self, query: &str) -> Result<Prepared, anyhow::Error> {
+        if let Some(tag) = ignored_command(query) {
+            return Ok(Prepared::Command(tag));
+        }
+        Ok(Prepared::Query(Box::new(plan(&self.ctx, query).await?)))
+    }
+
+    // writes the rows of a query, returns the command tag
+    async fn execute(
+        &mut self,
+        prepared: Prepared,
+        formats: &[i16],
+    ) -> Result<String, anyhow::Error> {
+        let df = match prepared {
+            Prepared::Query(df) => *df,
+            Prepared::Command(tag) => return Ok(tag),
+        };
+        let mut batches = df.execute_stream().await?;
+        let mut rows = 0;
+        while let Some(batch) = batches.next().await {
+            let batch = batch?;
+            let columns = batch
+                .columns()
+                .iter()
+                .enumerate()
+                .map(|(i, c)| encode_column(c, format(formats, i)))
+                .collect::<Result<Vec<_>, _>>()?;
+            for row in 0..batch.num_rows() {
+                let values: Vec<Option<&[u8]>> =
+                    columns.iter().map(|c| c[row].as_deref()).collect();
+                message::data_row(&mut self.buf, &values);
+                if self.buf.len() >= FLUSH_SIZE {
+                    self.flush().await?;
+                }
+            }
+            rows += batch.num_rows();
+        }
+        Ok(format!("SELECT {}", rows))
+    }
+
+    async fn run(&mut self, query: &str) -> Result<(), anyhow::Error> {
+        let prepared = self.prepare(query).await?;
+        if let Prepared::Query(df) = &prepared {
+            message::row_description(&mut self.buf, &fields(&df.schema().into(), &[]));
+        }
+        let tag = self.execute(prepared, &[]).await?;
+        message::command_complete(&mut self.buf, &tag);
+        Ok(())
+    }
+
+    // statements of a simple query stop at the first failing one
+    async fn simple_query(&mut self, query: &str) -> Result<(), anyhow::Error> {
+        match DFParser::parse_sql(query) {
+            Ok(statements) if statements.is_empty() => message::empty(&mut self.buf, b'I'),
+            Ok(statements) => {
+                for statement in statements {
+                    if let Err(e) = self.run(&statement.to_string()).await {
+                        message::error_response(&mut self.buf, error_code(&e), &e.to_string());
+                        break;
+                    }
+                }
+            }
+            Err(e) => message::error_response(&mut self.buf, "42601", &e.to_string()),
+        }
+        message::ready_for_query(&mut self.buf);
+        self.flush().await
+    }
+
+    async fn parse(
+        &mut self,
+        name: String,
+        query: &str,
+        declared: Vec<u32>,
+    ) -> Result<(), anyhow::Error> {
+        let prepared = self.prepare(query).await?;
+        let expected = match &prepared {
+            Prepared::Query(df) => param_types(df)?,
+            Prepared::Command(_) => vec![],
+        };
+        let statement = Statement {
+            prepared,
+            declared,
+            expected,
+        };
+        self.statements.insert(name, statement);
+        message::empty(&mut self.buf, b'1');
+        Ok(())
+    }
+
+    fn bind(
+        &mut self,
+        portal: String,
+        statement: &str,
+        param_formats: &[i16],
+        params: &[Option<Bytes>],
+        formats: Vec<i16>,
+    ) -> Result<(), anyhow::Error> {
+        let statement = self
+            .statements
+            .get(statement)
+            .ok_or_else(|| anyhow!("prepared statement \"{}\" does not exist", statement))?;
+        if params.len() != statement.param_count() {
+            return Err(anyhow!(
+                "bind message supplies {} parameters, but prepared statement requires {}",
+                params.len(),
+                statement.param_count()
+            ));
+        }
+        let values = params
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                decode_param(
+                    value.as_deref(),
+                    format(param_formats, i),
+                    statement.declared.get(i).copied().unwrap_or(0),
+                    statement.expected.get(i).and_then(Option::as_ref),
+                )
+            })
+            .collect::<Result<Vec<ScalarValue>, _>>()?;
+        let prepared = match &statement.prepared {
+            Prepared::Query(df) if !values.is_empty() => {
+                Prepared::Query(Box::new(df.as_ref().clone().with_param_values(values)?))
+            }
+            prepared => prepared.clone(),
+        };
+        self.portals.insert(portal, Portal { prepared, formats });
+        message::empty(&mut self.buf, b'2');
+        Ok(())
+    }
+
+    fn describe(&mut self, kind: u8, name: &str) -> Result<(), anyhow::Error> {
+        let (prepared, formats) = match kind {
+            b'S' => {
+                let statement = self
+                    .statements
+                    .get(name)
+                    .ok_or_else(|| anyhow!("prepared statement \"{}\" does not exist", name))?;
+                message::parameter_description(&mut self.buf, &statement.param_oids());
+                (&statement.prepared, &[][..])
+            }
+            b'P' => {
+                let portal = self
+                    .portals
+                    .get(name)
+                    .ok_or_else(|| anyhow!("portal \"{}\" does not exist", name))?;
+                (&portal.prepared, &portal.formats[..])
+            }
+            k => return Err(anyhow!("invalid describe kind {:?}", k as char)),
+        };
+        match prepared {
+            Prepared::Query(df) => {
+                let fields = fields(&df.schema().into(), formats);
+                message::row_description(&mut self.buf, &fields);
+            }
+            Prepared::Command(_) => message::empty(&mut self.buf, b'n'),
+        }
+        Ok(())
+    }
+
+    async fn execute_portal(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        let portal = self
+            .portals
+            .get(name)
+            .ok_or_else(|| anyhow!("portal \"{}\" does not exist", name))?;
+        let (prepared, formats) = (portal.prepared.clone(), portal.formats.clone());
+        let tag = self.execute(prepared, &formats).await?;
+        message::command_complete(&mut self.buf, &tag);
+        Ok(())
+    }
+
+    async fn serve(&mut self) -> Result<(), anyhow::Error> {
+        // after an error of the extended protocol, messages up to the
+        // next Sync are skipped
+        let mut failed = false;
+        while let Some((tag, body)) = self.read().await? {
+            let message = Frontend::decode(tag, body)?;
+            if failed && !matches!(message, Frontend::Sync | Frontend::Terminate) {
+                continue;
+            }
+            let result = match message {
+                Frontend::Query(query) => self.simple_query(&query).await,
+                Frontend::Parse { name, query, types } => self.parse(name, &query, types).await,
+                Frontend::Bind {
+                    portal,
+                    statement,
+                    param_formats,
+                    params,
+                    result_formats,
+                } => self.bind(portal, &statement, &param_formats, &params, result_formats),
+                Frontend::Describe { kind, name } => self.describe(kind, &name),
+                Frontend::Execute { portal, .. } => self.execute_portal(&portal).await,
+                Frontend::Close { kind, name } => {
+                    match kind {
+                        b'S' => self.statements.remove(&name).map(|_| ()),
+                        _ => self.portals.remove(&name).map(|_| ()),
+                    };
+                    message::empty(&mut self.buf, b'3');
+                    Ok(())
+                }
+                Frontend::Sync => {
+                    failed = false;
+                    message::ready_for_query(&mut self.buf);
+                    self.flush().await
+                }
+                Frontend::Flush => self.flush().await,
+                Frontend::Terminate => return Ok(()),
+            };
+            if let Err(e) = result {
+                message::error_response(&mut self.buf, error_code(&e), &e.to_string());
+                failed = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn serve_connection(
+    app: Arc<AppState>,
+    stream: TcpStream,
+    users: Option<Arc<Users>>,
+) -> Result<(), anyhow::Error> {
+    let (reader, writer) = stream.into_split();
+    let mut connection = Connection {
+        ctx: session_context(app)?,
+        statements: HashMap::new(),
+        portals: HashMap::new(),
+        reader: BufReader::new(reader),
+        writer,
+        buf: BytesMut::with_capacity(FLUSH_SIZE),
+        max_message_size: MAX_STARTUP_SIZE,
+    };
+    if connection.startup(users.as_deref()).await?.is_none() {
+        return Ok(());
+    }
+    connection.serve().await
+}
+
+async fn serve(
+    app: Arc<AppState>,
+    listener: std::net::TcpListener,
+    users: Option<Arc<Users>>,
+) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::from_std(listener)?;
+    loop {
+        let (stream, peer) = net::accept(&listener, "postgres").await;
+        let (app, users) = (app.clone(), users.clone());
+        actix_web::rt::spawn(async move {
+            if let Err(e) = serve_connection(app, stream, users).await {
+                log::warn!("postgres connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Binds the configured address, does nothing when no address is set.
+/// Refuses to start without users unless `trust` is set.
+pub fn start(app: web::Data<AppState>, config: &PostgresConfig) -> std::io::Result<()> {
+    let Some(addr) = &config.addr else {
+        return Ok(());
+    };
+    let users = match (config.trust, config.users.is_empty()) {
+        (true, _) => None,
+        (false, false) => Some(Arc::new(Users::new(config.users.clone()))),
+        (false, true) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "postgres.users is empty, set postgres.trust to accept any login",
+            ))
+        }
+    };
+    let listener = net::bind_tcp(addr, "postgres")?;
+    let app: Arc<AppState> = app.into_inner();
+    actix_web::rt::spawn(async move {
+        if let Err(e) = serve(app, listener, users).await {
+            log::error!("postgres listener stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignored_command() {
+        assert_eq!(
+            ignored_command("set extra_float_digits = 3").unwrap(),
+            "SET"
+        );
+        assert_eq!(ignored_command(" BEGIN;").unwrap(), "BEGIN");
+        assert_eq!(ignored_command("SELECT 1"), None);
+        assert_eq!(ignored_command(""), None);
+
+        assert_eq!(format(&[], 3), 0);
+        assert_eq!(format(&[1], 3), 1);
+        assert_eq!(format(&[0, 1], 1), 1);
+    }
+}
@@ -0,0 +1,236 @@
+//! SCRAM-SHA-256 logins, RFC 5802 and RFC 7677, the way PostgreSQL runs
+//! them: the user is the one of the startup message and there is no
+//! channel binding. Passwords are not normalized with SASLprep.
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+pub static MECHANISM: &str = "SCRAM-SHA-256";
+// the iteration count PostgreSQL uses for its own secrets
+static ITERATIONS: u32 = 4096;
+static NONCE_LEN: usize = 24;
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    // HMAC takes keys of any size
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+// PBKDF2 with HMAC-SHA-256, the `Hi` function of the RFC
+fn hi(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut u = hmac(password, &[salt, &1u32.to_be_bytes()].concat());
+    let mut res = u;
+    for _ in 1..iterations {
+        u = hmac(password, &u);
+        res.iter_mut().zip(u).for_each(|(r, u)| *r ^= u);
+    }
+    res
+}
+
+/// The keys a password is checked against.
+pub struct ScramSecret {
+    salt: Vec<u8>,
+    iterations: u32,
+    stored_key: [u8; 32],
+    server_key: [u8; 32],
+}
+
+impl ScramSecret {
+    pub fn new(password: &str, salt: &[u8], iterations: u32) -> ScramSecret {
+        let salted = hi(password.as_bytes(), salt, iterations);
+        ScramSecret {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: Sha256::digest(hmac(&salted, b"Client Key")).into(),
+            server_key: hmac(&salted, b"Server Key"),
+        }
+    }
+}
+
+/// The users allowed to log in. Secrets are derived on every login with a
+/// salt that stays the same for a user while the server runs, unknown
+/// users get one too, so both cost the same and look alike to a client.
+pub struct Users {
+    passwords: HashMap<String, String>,
+    salt_key: [u8; 32],
+}
+
+impl Users {
+    pub fn new(passwords: HashMap<String, String>) -> Users {
+        let mut salt_key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut salt_key);
+        Users {
+            passwords,
+            salt_key,
+        }
+    }
+
+    pub fn secret(&self, user: &str) -> ScramSecret {
+        let salt = &hmac(&self.salt_key, user.as_bytes())[..16];
+        match self.passwords.get(user) {
+            Some(password) => ScramSecret::new(password, salt, ITERATIONS),
+            // a random password, no proof matches it
+            None => ScramSecret::new(&nonce(), salt, ITERATIONS),
+        }
+    }
+}
+
+fn nonce() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(NONCE_LEN)
+        .map(char::from)
+        .collect()
+}
+
+fn attribute(message: &str, name: char) -> Result<&str, anyhow::Error> {
+    message
+        .split(',')
+        .find_map(|a| a.strip_prefix(name)?.strip_prefix('='))
+        .ok_or_else(|| anyhow!("missing SCRAM attribute {}", name))
+}
+
+/// The server side of one login.
+pub struct ScramExchange<'a> {
+    secret: &'a ScramSecret,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+}
+
+impl<'a> ScramExchange<'a> {
+    /// Reads the client-first-message, the server-first-message is
+    /// returned with the exchange.
+    pub fn start(
+        secret: &'a ScramSecret,
+        client_first: &[u8],
+    ) -> Result<(ScramExchange<'a>, String), anyhow::Error> {
+        Self::start_with_nonce(secret, client_first, &nonce())
+    }
+
+    fn start_with_nonce(
+        secret: &'a ScramSecret,
+        client_first: &[u8],
+        server_nonce: &str,
+    ) -> Result<(ScramExchange<'a>, String), anyhow::Error> {
+        let client_first = std::str::from_utf8(client_first)?;
+        // `n` the client does not bind, `y` it could but the server did not
+        // offer it, `p=` asks for a binding there is no TLS for
+        let (gs2_header, bare) = match client_first.splitn(3, ',').collect::<Vec<_>>()[..] {
+            [flag @ ("n" | "y"), authzid, bare] => (format!("{},{},", flag, authzid), bare),
+            [flag, ..] if flag.starts_with("p=") => {
+                return Err(anyhow!("SCRAM channel binding is not supported"))
+            }
+            _ => return Err(anyhow!("invalid SCRAM client-first-message")),
+        };
+        let client_nonce = attribute(bare, 'r')?;
+        if client_nonce.is_empty() {
+            return Err(anyhow!("empty SCRAM client nonce"));
+        }
+        let nonce = format!("{}{}", client_nonce, server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            STANDARD.encode(&secret.salt),
+            secret.iterations
+        );
+        let exchange = ScramExchange {
+            secret,
+            gs2_header,
+            client_first_bare: bare.to_owned(),
+            server_first: server_first.clone(),
+            nonce,
+        };
+        Ok((exchange, server_first))
+    }
+
+    /// Checks the proof of the client-final-message, the server-final-message
+    /// is returned when it is right, `None` when the password is wrong.
+    pub fn finish(self, client_final: &[u8]) -> Result<Option<String>, anyhow::Error> {
+        let client_final = std::str::from_utf8(client_final)?;
+        let (without_proof, proof) = client_final
+            .rsplit_once(",p=")
+            .ok_or_else(|| anyhow!("missing SCRAM client proof"))?;
+        if attribute(without_proof, 'c')? != STANDARD.encode(&self.gs2_header) {
+            return Err(anyhow!("SCRAM channel binding does not match"));
+        }
+        if attribute(without_proof, 'r')? != self.nonce {
+            return Err(anyhow!("SCRAM nonce does not match"));
+        }
+        let proof = STANDARD.decode(proof)?;
+        if proof.len() != 32 {
+            return Err(anyhow!("invalid SCRAM client proof"));
+        }
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+        let signature = hmac(&self.secret.stored_key, auth_message.as_bytes());
+        let client_key: Vec<u8> = proof.iter().zip(signature).map(|(p, s)| p ^ s).collect();
+        let stored_key = Sha256::digest(client_key);
+        if !bool::from(stored_key.as_slice().ct_eq(&self.secret.stored_key)) {
+            return Ok(None);
+        }
+        let server_signature = hmac(&self.secret.server_key, auth_message.as_bytes());
+        Ok(Some(format!("v={}", STANDARD.encode(server_signature))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the example exchange of RFC 7677
+    #[test]
+    fn test_scram() {
+        let salt = STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let secret = ScramSecret::new("pencil", &salt, 4096);
+        let server_nonce = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+        let client_final = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+
+        let client_first = b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+        let (exchange, server_first) =
+            ScramExchange::start_with_nonce(&secret, client_first, server_nonce).unwrap();
+        assert_eq!(
+            server_first,
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+        );
+        assert_eq!(
+            exchange.finish(client_final.as_bytes()).unwrap().unwrap(),
+            "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        );
+
+        // another password gives another proof
+        let secret = ScramSecret::new("pencil2", &salt, 4096);
+        let (exchange, _) =
+            ScramExchange::start_with_nonce(&secret, client_first, server_nonce).unwrap();
+        assert_eq!(exchange.finish(client_final.as_bytes()).unwrap(), None);
+
+        let (exchange, _) = ScramExchange::start(&secret, client_first).unwrap();
+        assert!(exchange.finish(client_final.as_bytes()).is_err());
+        assert!(ScramExchange::start(&secret, b"p=tls-server-end-point,,n=,r=x").is_err());
+        assert!(ScramExchange::start(&secret, b"n,,n=user").is_err());
+    }
+
+    #[test]
+    fn test_users() {
+        let users = Users::new(HashMap::from([("grafana".to_owned(), "pw".to_owned())]));
+        let (a, b) = (users.secret("grafana"), users.secret("grafana"));
+        assert_eq!(a.salt, b.salt);
+        assert_eq!(a.stored_key, b.stored_key);
+        let (a, b) = (users.secret("nobody"), users.secret("nobody"));
+        assert_eq!(a.salt, b.salt);
+        assert_ne!(a.stored_key, b.stored_key);
+    }
+}
@@ -0,0 +1,348 @@
+//! Postgres types of Arrow columns, the text and binary encodings of
+//! their values and the decoding of bound parameters.
+
+use anyhow::anyhow;
+use arrow_cast::{
+    cast::{cast_with_options, CastOptions},
+    display::{ArrayFormatter, FormatOptions},
+};
+use arrow_schema::{DataType, TimeUnit};
+use datafusion::{
+    arrow::{
+        array::{Array, ArrayRef, AsArray},
+        datatypes::{
+            ArrowPrimitiveType, Date32Type, Float32Type, Float64Type, Int16Type, Int32Type,
+            Int64Type, Time64MicrosecondType, TimestampMicrosecondType,
+        },
+    },
+    scalar::ScalarValue,
+};
+
+use super::message::FORMAT_BINARY;
+use crate::fusion::array::cast_array;
+
+pub static BOOL: u32 = 16;
+pub static BYTEA: u32 = 17;
+pub static NAME: u32 = 19;
+pub static INT8: u32 = 20;
+pub static INT2: u32 = 21;
+pub static INT4: u32 = 23;
+pub static TEXT: u32 = 25;
+pub static FLOAT4: u32 = 700;
+pub static FLOAT8: u32 = 701;
+pub static BPCHAR: u32 = 1042;
+pub static VARCHAR: u32 = 1043;
+pub static DATE: u32 = 1082;
+pub static TIME: u32 = 1083;
+pub static TIMESTAMP: u32 = 1114;
+pub static TIMESTAMPTZ: u32 = 1184;
+pub static NUMERIC: u32 = 1700;
+
+// days and micros from the Unix epoch to the Postgres one, 2000-01-01
+static EPOCH_DAYS: i32 = 10957;
+static EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+/// The Postgres type of a column, nested columns are sent as text.
+pub fn type_oid(dt: &DataType) -> u32 {
+    match dt {
+        DataType::Boolean => BOOL,
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => INT2,
+        DataType::Int32 | DataType::UInt16 => INT4,
+        DataType::Int64 | DataType::UInt32 | DataType::UInt64 => INT8,
+        DataType::Float16 | DataType::Float32 => FLOAT4,
+        DataType::Float64 => FLOAT8,
+        DataType::Decimal128(_, _) | DataType::Decimal256(_, _) => NUMERIC,
+        DataType::Binary | DataType::LargeBinary | DataType::FixedSizeBinary(_) => BYTEA,
+        DataType::Date32 | DataType::Date64 => DATE,
+        DataType::Time32(_) | DataType::Time64(_) => TIME,
+        DataType::Timestamp(_, None) => TIMESTAMP,
+        DataType::Timestamp(_, Some(_)) => TIMESTAMPTZ,
+        DataType::Dictionary(_, v) => type_oid(v),
+        _ => TEXT,
+    }
+}
+
+/// The size of a fixed width type, -1 for the others.
+pub fn type_size(oid: u32) -> i16 {
+    match oid {
+        o if o == BOOL => 1,
+        o if o == INT2 => 2,
+        o if o == INT4 || o == FLOAT4 || o == DATE => 4,
+        o if o == INT8 || o == FLOAT8 || o == TIME || o == TIMESTAMP || o == TIMESTAMPTZ => 8,
+        _ => -1,
+    }
+}
+
+/// The Arrow type of parameters a client declares of type `oid`.
+pub fn data_type(oid: u32) -> Option<DataType> {
+    let dt = match oid {
+        o if o == BOOL => DataType::Boolean,
+        o if o == INT2 => DataType::Int16,
+        o if o == INT4 => DataType::Int32,
+        o if o == INT8 => DataType::Int64,
+        o if o == FLOAT4 => DataType::Float32,
+        o if o == FLOAT8 || o == NUMERIC => DataType::Float64,
+        o if o == TEXT || o == VARCHAR || o == BPCHAR || o == NAME => DataType::Utf8,
+        o if o == BYTEA => DataType::Binary,
+        o if o == DATE => DataType::Date32,
+        o if o == TIMESTAMP => DataType::Timestamp(TimeUnit::Microsecond, None),
+        o if o == TIMESTAMPTZ => DataType::Timestamp(TimeUnit::Microsecond, Some("+00:00".into())),
+        _ => return None,
+    };
+    Some(dt)
+}
+
+/// The values of a column in the format of `format`, `None` for nulls.
+pub fn encode_column(array: &ArrayRef, format: i16) -> Result<Vec<Option<Vec<u8>>>, anyhow::Error> {
+    let oid = type_oid(array.data_type());
+    if format == FORMAT_BINARY {
+        encode_binary(array, oid)
+    } else {
+        encode_text(array, oid)
+    }
+}
+
+fn encode_text(array: &ArrayRef, oid: u32) -> Result<Vec<Option<Vec<u8>>>, anyhow::Error> {
+    if oid == BOOL {
+        let array = cast_array(array.clone(), &DataType::Boolean)?;
+        return Ok(array
+            .as_boolean()
+            .iter()
+            .map(|v| v.map(|v| if v { b"t".to_vec() } else { b"f".to_vec() }))
+            .collect());
+    }
+    if oid == BYTEA {
+        let array = cast_array(array.clone(), &DataType::Binary)?;
+        return Ok(array
+            .as_binary::<i32>()
+            .iter()
+            .map(|v| v.map(|v| format!("\\x{}", hex::encode(v)).into_bytes()))
+            .collect());
+    }
+    let options = FormatOptions::default()
+        .with_timestamp_format(Some("%Y-%m-%d %H:%M:%S%.f"))
+        .with_timestamp_tz_format(Some("%Y-%m-%d %H:%M:%S%.f%:z"));
+    let formatter = ArrayFormatter::try_new(array.as_ref(), &options)?;
+    Ok((0..array.len())
+        .map(|i| {
+            array
+                .is_valid(i)
+                .then(|| formatter.value(i).to_string().into_bytes())
+        })
+        .collect())
+}
+
+fn encode_primitive<T: ArrowPrimitiveType>(
+    array: &ArrayRef,
+    dt: &DataType,
+    f: impl Fn(T::Native) -> Vec<u8>,
+) -> Result<Vec<Option<Vec<u8>>>, anyhow::Error> {
+    let array = cast_array(array.clone(), dt)?;
+    Ok(array
+        .as_primitive::<T>()
+        .iter()
+        .map(|v| v.map(&f))
+        .collect())
+}
+
+fn encode_binary(array: &ArrayRef, oid: u32) -> Result<Vec<Option<Vec<u8>>>, anyhow::Error> {
+    match oid {
+        o if o == BOOL => {
+            let array = cast_array(array.clone(), &DataType::Boolean)?;
+            Ok(array
+                .as_boolean()
+                .iter()
+                .map(|v| v.map(|v| vec![v as u8]))
+                .collect())
+        }
+        o if o == INT2 => {
+            encode_primitive::<Int16Type>(array, &DataType::Int16, |v| v.to_be_bytes().to_vec())
+        }
+        o if o == INT4 => {
+            encode_primitive::<Int32Type>(array, &DataType::Int32, |v| v.to_be_bytes().to_vec())
+        }
+        o if o == INT8 => {
+            encode_primitive::<Int64Type>(array, &DataType::Int64, |v| v.to_be_bytes().to_vec())
+        }
+        o if o == FLOAT4 => {
+            encode_primitive::<Float32Type>(array, &DataType::Float32, |v| v.to_be_bytes().to_vec())
+        }
+        o if o == FLOAT8 => {
+            encode_primitive::<Float64Type>(array, &DataType::Float64, |v| v.to_be_bytes().to_vec())
+        }
+        o if o == DATE => encode_primitive::<Date32Type>(array, &DataType::Date32, |v| {
+            (v - EPOCH_DAYS).to_be_bytes().to_vec()
+        }),
+        o if o == TIME => encode_primitive::<Time64MicrosecondType>(
+            array,
+            &DataType::Time64(TimeUnit::Microsecond),
+            |v| v.to_be_bytes().to_vec(),
+        ),
+        o if o == TIMESTAMP || o == TIMESTAMPTZ => {
+            // keeps the zone, the values are UTC either way
+            let tz = match array.data_type() {
+                DataType::Timestamp(_, tz) => tz.clone(),
+                _ => None,
+            };
+            encode_primitive::<TimestampMicrosecondType>(
+                array,
+                &DataType::Timestamp(TimeUnit::Microsecond, tz),
+                |v| (v - EPOCH_MICROS).to_be_bytes().to_vec(),
+            )
+        }
+        o if o == BYTEA => {
+            let array = cast_array(array.clone(), &DataType::Binary)?;
+            Ok(array
+                .as_binary::<i32>()
+                .iter()
+                .map(|v| v.map(<[u8]>::to_vec))
+                .collect())
+        }
+        // the binary form of text is the text
+        o if o == TEXT => encode_text(array, oid),
+        _ => Err(anyhow!(
+            "binary format of type {} is not supported",
+            array.data_type()
+        )),
+    }
+}
+
+fn fixed<const N: usize>(value: &[u8]) -> Result<[u8; N], anyhow::Error> {
+    value
+        .try_into()
+        .map_err(|_| anyhow!("expected a binary parameter of {} bytes", N))
+}
+
+fn decode_binary(value: &[u8], oid: u32) -> Result<ScalarValue, anyhow::Error> {
+    let scalar = match oid {
+        o if o == BOOL => ScalarValue::Boolean(Some(fixed::<1>(value)?[0] != 0)),
+        o if o == INT2 => ScalarValue::Int16(Some(i16::from_be_bytes(fixed(value)?))),
+        o if o == INT4 => ScalarValue::Int32(Some(i32::from_be_bytes(fixed(value)?))),
+        o if o == INT8 => ScalarValue::Int64(Some(i64::from_be_bytes(fixed(value)?))),
+        o if o == FLOAT4 => ScalarValue::Float32(Some(f32::from_be_bytes(fixed(value)?))),
+        o if o == FLOAT8 => ScalarValue::Float64(Some(f64::from_be_bytes(fixed(value)?))),
+        o if o == DATE => ScalarValue::Date32(Some(i32::from_be_bytes(fixed(value)?) + EPOCH_DAYS)),
+        o if o == TIMESTAMP || o == TIMESTAMPTZ => {
+            let micros = i64::from_be_bytes(fixed(value)?) + EPOCH_MICROS;
+            let tz = (o == TIMESTAMPTZ).then(|| "+00:00".into());
+            ScalarValue::TimestampMicrosecond(Some(micros), tz)
+        }
+        o if o == BYTEA => ScalarValue::Binary(Some(value.to_vec())),
+        o if o == TEXT || o == VARCHAR || o == BPCHAR || o == NAME => {
+            ScalarValue::Utf8(Some(String::from_utf8(value.to_vec())?))
+        }
+        o => return Err(anyhow!("binary parameters of type {} are not supported", o)),
+    };
+    Ok(scalar)
+}
+
+/// A bound parameter. `dt` is the type the query expects of it, else the
+/// type the client declared as `oid` is used; text values are cast to it,
+/// binary ones read as it.
+pub fn decode_param(
+    value: Option<&[u8]>,
+    format: i16,
+    oid: u32,
+    dt: Option<&DataType>,
+) -> Result<ScalarValue, anyhow::Error> {
+    let dt = dt.cloned().or_else(|| data_type(oid));
+    let Some(value) = value else {
+        return Ok(match &dt {
+            Some(dt) => ScalarValue::try_from(dt)?,
+            None => ScalarValue::Null,
+        });
+    };
+    let scalar = if format == FORMAT_BINARY {
+        let oid = match (oid, &dt) {
+            (0, Some(dt)) => type_oid(dt),
+            (0, None) => TEXT,
+            (oid, _) => oid,
+        };
+        decode_binary(value, oid)?
+    } else {
+        ScalarValue::Utf8(Some(String::from_utf8(value.to_vec())?))
+    };
+    match dt {
+        Some(dt) if scalar.data_type() != dt => {
+            // unlike the default cast, values that do not parse are errors
+            let options = CastOptions {
+                safe: false,
+                ..Default::default()
+            };
+            let array = cast_with_options(&scalar.to_array()?, &dt, &options)?;
+            Ok(ScalarValue::try_from_array(&array, 0)?)
+        }
+        _ => Ok(scalar),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::array::{
+        BooleanArray, Int64Array, StringArray, TimestampMillisecondArray,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_encode_column() {
+        let stamps: ArrayRef = Arc::new(TimestampMillisecondArray::from(vec![
+            Some(946_684_800_500),
+            None,
+        ]));
+        assert_eq!(type_oid(stamps.data_type()), TIMESTAMP);
+        assert_eq!(
+            encode_column(&stamps, 0).unwrap(),
+            vec![Some(b"2000-01-01 00:00:00.500".to_vec()), None]
+        );
+        assert_eq!(
+            encode_column(&stamps, FORMAT_BINARY).unwrap()[0],
+            Some(500_000i64.to_be_bytes().to_vec())
+        );
+
+        let bools: ArrayRef = Arc::new(BooleanArray::from(vec![true, false]));
+        assert_eq!(
+            encode_column(&bools, 0).unwrap(),
+            vec![Some(b"t".to_vec()), Some(b"f".to_vec())]
+        );
+        let ints: ArrayRef = Arc::new(Int64Array::from(vec![-2]));
+        assert_eq!(encode_column(&ints, 0).unwrap(), vec![Some(b"-2".to_vec())]);
+        assert_eq!(
+            encode_column(&ints, FORMAT_BINARY).unwrap(),
+            vec![Some((-2i64).to_be_bytes().to_vec())]
+        );
+        let strings: ArrayRef = Arc::new(StringArray::from(vec!["a"]));
+        assert_eq!(type_oid(strings.data_type()), TEXT);
+        assert_eq!(
+            encode_column(&strings, FORMAT_BINARY).unwrap(),
+            vec![Some(b"a".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_decode_param() {
+        let int = decode_param(Some(b"42"), 0, 0, Some(&DataType::Int64)).unwrap();
+        assert_eq!(int, ScalarValue::Int64(Some(42)));
+        assert!(decode_param(Some(b"x"), 0, 0, Some(&DataType::Int64)).is_err());
+
+        let int = decode_param(Some(&7i32.to_be_bytes()), FORMAT_BINARY, INT4, None).unwrap();
+        assert_eq!(int, ScalarValue::Int32(Some(7)));
+        // the declared type is read, then cast to the expected one
+        let int = decode_param(
+            Some(&7i32.to_be_bytes()),
+            FORMAT_BINARY,
+            INT4,
+            Some(&DataType::Int64),
+        )
+        .unwrap();
+        assert_eq!(int, ScalarValue::Int64(Some(7)));
+
+        let text = decode_param(Some(b"error"), 0, 0, None).unwrap();
+        assert_eq!(text, ScalarValue::Utf8(Some("error".to_owned())));
+        let null = decode_param(None, 0, 0, Some(&DataType::Float64)).unwrap();
+        assert_eq!(null, ScalarValue::Float64(None));
+        assert!(decode_param(Some(b"\x01"), FORMAT_BINARY, INT8, None).is_err());
+    }
+}
@@ -0,0 +1,948 @@
+//! PromQL evaluation. The matchers and the time range of every selector
+//! are pushed down to DataFusion, which also summarizes the samples of
+//! every series into buckets the width of the largest duration dividing
+//! both the step and the selector range: the window of any step is then a
+//! run of whole buckets. `rate`, `increase`, instant selectors and
+//! aggregations are evaluated on those summaries at every step. The number
+//! of samples summarized by a query is capped by `MAX_SAMPLES`.
+
+use std::collections::{btree_map::Entry, BTreeMap, HashMap};
+
+use anyhow::anyhow;
+use arrow_schema::{DataType, Schema};
+use datafusion::{
+    arrow::{
+        array::{Array, AsArray},
+        datatypes::{Float64Type, Int64Type},
+        record_batch::RecordBatch,
+    },
+    dataframe::DataFrame,
+    logical_expr::{
+        self, binary_expr, count_distinct, expr, ident, lit, max, min, sum, when, window_function,
+        AggregateFunction, BuiltInWindowFunction, Expr, Operator, WindowFrame,
+    },
+};
+use serde_json::{json, Value};
+
+use super::{
+    promql::{AggregateOp, Function, Grouping, MatchOp, Matcher, PromExpr},
+    LABELS_FIELD_NAME, METRIC_NAME_LABEL, VALUE_FIELD_NAME,
+};
+use crate::{
+    config::TIMPSTAMP_FIELD_NAME,
+    ingest::{IngestService, InvalidQuery},
+};
+
+pub type Labels = BTreeMap<String, String>;
+
+// how far back an instant selector looks for the latest sample
+pub static LOOKBACK_MILLIS: i64 = 5 * 60 * 1000;
+// same limit as Prometheus on points of one range query series
+pub static MAX_POINTS: i64 = 11_000;
+// samples one query may summarize, lower than the 50 million Prometheus
+// allows as DataFusion sorts the samples of a selector in memory
+pub static MAX_SAMPLES: usize = 10_000_000;
+
+/// Summary of the samples of one series in a bucket, times in
+/// milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket {
+    pub index: i64,
+    pub first: (i64, f64),
+    pub last: (i64, f64),
+    // samples with distinct timestamps
+    pub count: i64,
+    // sum of the values before every counter reset
+    pub resets: f64,
+}
+
+impl Bucket {
+    // the summary of the samples of `self` followed by those of `next`
+    fn merge(self, next: &Bucket) -> Bucket {
+        let reset = if next.first.1 < self.last.1 {
+            self.last.1
+        } else {
+            0.0
+        };
+        Bucket {
+            index: next.index,
+            first: self.first,
+            last: if next.last.0 >= self.last.0 {
+                next.last
+            } else {
+                self.last
+            },
+            count: self.count + next.count,
+            resets: self.resets + next.resets + reset,
+        }
+    }
+}
+
+/// Buckets of one series, ordered by index.
+#[derive(Clone, Debug)]
+pub struct Series {
+    pub labels: Labels,
+    pub buckets: Vec<Bucket>,
+}
+
+pub enum QueryResult {
+    Scalar(i64, f64),
+    Vector(Vec<(Labels, i64, f64)>),
+    Matrix(Vec<(Labels, Vec<(i64, f64)>)>),
+}
+
+fn format_value(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_owned()
+    } else if v.is_infinite() {
+        if v > 0.0 { "+Inf" } else { "-Inf" }.to_owned()
+    } else {
+        v.to_string()
+    }
+}
+
+fn point(t: i64, v: f64) -> Value {
+    json!([t as f64 / 1000.0, format_value(v)])
+}
+
+impl QueryResult {
+    /// The `data` member of a Prometheus query API response.
+    pub fn to_json(&self) -> Value {
+        match self {
+            QueryResult::Scalar(t, v) => json!({"resultType": "scalar", "result": point(*t, *v)}),
+            QueryResult::Vector(samples) => {
+                let result: Vec<Value> = samples
+                    .iter()
+                    .map(|(labels, t, v)| json!({"metric": labels, "value": point(*t, *v)}))
+                    .collect();
+                json!({"resultType": "vector", "result": result})
+            }
+            QueryResult::Matrix(series) => {
+                let result: Vec<Value> = series
+                    .iter()
+                    .map(|(labels, points)| {
+                        let values: Vec<Value> =
+                            points.iter().map(|(t, v)| point(*t, *v)).collect();
+                        json!({"metric": labels, "values": values})
+                    })
+                    .collect();
+                json!({"resultType": "matrix", "result": result})
+            }
+        }
+    }
+}
+
+// selectors are fetched once per query and looked up by their matchers and
+// range: bucket `i` of a selector holds the samples after `base + i * width`
+// up to `base + (i + 1) * width`
+struct Selected {
+    base: i64,
+    width: i64,
+    range: i64,
+    series: Vec<Series>,
+}
+
+type Fetched = HashMap<String, Selected>;
+
+impl Selected {
+    // the buckets of `series` covering the window of the step `t`, from
+    // `t - range` excluded to `t`
+    fn window<'a>(&self, series: &'a Series, t: i64) -> &'a [Bucket] {
+        let lo = (t - self.range - self.base) / self.width;
+        let hi = (t - self.base) / self.width;
+        let from = series.buckets.partition_point(|b| b.index < lo);
+        let to = series.buckets.partition_point(|b| b.index < hi);
+        &series.buckets[from..to]
+    }
+}
+
+fn selector_key(matchers: &[Matcher], range: i64) -> String {
+    format!("{:?}[{}]", matchers, range)
+}
+
+pub(crate) fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn collect_selectors<'a>(expr: &'a PromExpr, out: &mut Vec<(&'a [Matcher], i64)>) {
+    match expr {
+        PromExpr::Number(_) => {}
+        PromExpr::Selector(m) => out.push((m, LOOKBACK_MILLIS)),
+        PromExpr::Range(m, range) => out.push((m, *range)),
+        PromExpr::Call(_, args) => args.iter().for_each(|a| collect_selectors(a, out)),
+        PromExpr::Aggregate(_, _, inner) => collect_selectors(inner, out),
+    }
+}
+
+// with a JSON labels column a label may be stored in it instead of its own
+// column: a matcher then only drops rows whose column holds a value it
+// rejects, series are matched exactly once their labels are read
+fn selector_filter(matchers: &[Matcher], schema: &Schema) -> Option<Expr> {
+    let has_json = schema
+        .field_with_name(LABELS_FIELD_NAME)
+        .is_ok_and(|f| f.data_type() == &DataType::Utf8);
+    if !has_json {
+        return matcher_filter(matchers, schema);
+    }
+    let mut filter = lit(true);
+    for m in matchers {
+        if schema.field_with_name(&m.name).is_err() {
+            continue;
+        }
+        let matched = matcher_filter(std::slice::from_ref(m), schema)?;
+        filter = filter.and(ident(&m.name).is_null().or(matched));
+    }
+    Some(filter)
+}
+
+// pushes the matchers down to DataFusion, a label that is missing from a
+// segment or null matches like an empty label
+pub(crate) fn matcher_filter(matchers: &[Matcher], schema: &Schema) -> Option<Expr> {
+    let mut filter = lit(true);
+    for m in matchers {
+        if schema.field_with_name(&m.name).is_err() {
+            if m.matches("") {
+                continue;
+            }
+            return None;
+        }
+        let column = ident(&m.name);
+        let expr = match m.op {
+            MatchOp::Equal => column.clone().eq(lit(&m.value)),
+            MatchOp::NotEqual => column.clone().not_eq(lit(&m.value)),
+            MatchOp::Regex => binary_expr(
+                column.clone(),
+                Operator::RegexMatch,
+                lit(Matcher::anchored(&m.value)),
+            ),
+            MatchOp::NotRegex => binary_expr(
+                column.clone(),
+                Operator::RegexNotMatch,
+                lit(Matcher::anchored(&m.value)),
+            ),
+        };
+        let expr = if m.matches("") {
+            column.is_null().or(expr)
+        } else {
+            expr
+        };
+        filter = filter.and(expr);
+    }
+    Some(filter)
+}
+
+// columns of the summarizing plan, `__` names are reserved by Prometheus
+// so they can not clash with a label
+static TIME: &str = "__time";
+static VALUE: &str = "__value";
+static BUCKET: &str = "__bucket";
+static PREV: &str = "__prev";
+static FIRST_TIME: &str = "__first_time";
+static LAST_TIME: &str = "__last_time";
+static FIRST_VALUE: &str = "__first_value";
+static LAST_VALUE: &str = "__last_value";
+static COUNT: &str = "__count";
+static RESETS: &str = "__resets";
+static SUMMARY_COLUMNS: [&str; 7] = [
+    BUCKET,
+    FIRST_TIME,
+    LAST_TIME,
+    FIRST_VALUE,
+    LAST_VALUE,
+    COUNT,
+    RESETS,
+];
+
+fn ordered(fun: AggregateFunction, arg: Expr) -> Expr {
+    Expr::AggregateFunction(expr::AggregateFunction::new(
+        fun,
+        vec![arg],
+        false,
+        None,
+        Some(vec![ident(TIME).sort(true, false)]),
+    ))
+}
+
+// one row per series and bucket: every Utf8 column is a label, the resets
+// are found comparing each sample to the one before it in its bucket
+fn summarize(df: DataFrame, base: i64, width: i64) -> Result<DataFrame, anyhow::Error> {
+    let schema: Schema = df.schema().into();
+    let labels: Vec<Expr> = schema
+        .fields()
+        .iter()
+        .filter(|f| f.data_type() == &DataType::Utf8 && f.name() != VALUE_FIELD_NAME)
+        .map(|f| ident(f.name()))
+        .collect();
+    let time = logical_expr::cast(ident(TIMPSTAMP_FIELD_NAME), DataType::Int64) / lit(1000i64);
+    let mut columns = labels.clone();
+    columns.push(time.clone().alias(TIME));
+    columns.push(logical_expr::cast(ident(VALUE_FIELD_NAME), DataType::Float64).alias(VALUE));
+    columns.push(((time - lit(base + 1)) / lit(width)).alias(BUCKET));
+    let mut group = labels;
+    group.push(ident(BUCKET));
+    let prev = Expr::WindowFunction(expr::WindowFunction::new(
+        window_function::WindowFunction::BuiltInWindowFunction(BuiltInWindowFunction::Lag),
+        vec![ident(VALUE)],
+        group.clone(),
+        vec![ident(TIME).sort(true, false)],
+        WindowFrame::new(true),
+    ));
+    let reset = when(ident(VALUE).lt(ident(PREV)), ident(PREV)).otherwise(lit(0.0))?;
+    Ok(df
+        .select(columns)?
+        .window(vec![prev.alias(PREV)])?
+        .aggregate(
+            group,
+            vec![
+                min(ident(TIME)).alias(FIRST_TIME),
+                max(ident(TIME)).alias(LAST_TIME),
+                ordered(AggregateFunction::FirstValue, ident(VALUE)).alias(FIRST_VALUE),
+                ordered(AggregateFunction::LastValue, ident(VALUE)).alias(LAST_VALUE),
+                count_distinct(ident(TIME)).alias(COUNT),
+                sum(reset).alias(RESETS),
+            ],
+        )?)
+}
+
+fn summary_column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a dyn Array, anyhow::Error> {
+    Ok(batch
+        .column_by_name(name)
+        .ok_or_else(|| anyhow!("missing summary column {}", name))?
+        .as_ref())
+}
+
+// a Prometheus label name: characters other than letters, digits and `_`
+// become `_`, as the Prometheus OTLP receiver does
+fn label_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("key_{}", name)
+    } else {
+        name
+    }
+}
+
+// the labels of a JSON labels column. Keys that become the same label name,
+// like `http.method` and `http_method`, keep all of their values joined
+// with `;` in key order, again like the Prometheus OTLP receiver.
+fn insert_json_labels(labels: &mut Labels, json: &str) {
+    let Ok(Value::Object(object)) = serde_json::from_str::<Value>(json) else {
+        return;
+    };
+    for (key, value) in object {
+        let value = match value {
+            Value::Null => continue,
+            Value::String(v) => v,
+            v => v.to_string(),
+        };
+        if value.is_empty() {
+            continue;
+        }
+        match labels.entry(label_name(&key)) {
+            Entry::Vacant(e) => {
+                e.insert(value);
+            }
+            Entry::Occupied(mut e) => {
+                let joined = e.get_mut();
+                joined.push(';');
+                joined.push_str(&value);
+            }
+        }
+    }
+}
+
+// groups the rows of `summarize` into series, a label missing in some
+// segments splits a bucket in two that are merged back here
+fn summaries_to_series(batches: &[RecordBatch]) -> Result<Vec<Series>, anyhow::Error> {
+    let mut series: BTreeMap<Labels, Vec<Bucket>> = BTreeMap::new();
+    for batch in batches {
+        let index = summary_column(batch, BUCKET)?.as_primitive::<Int64Type>();
+        let first_time = summary_column(batch, FIRST_TIME)?.as_primitive::<Int64Type>();
+        let last_time = summary_column(batch, LAST_TIME)?.as_primitive::<Int64Type>();
+        let first_value = summary_column(batch, FIRST_VALUE)?.as_primitive::<Float64Type>();
+        let last_value = summary_column(batch, LAST_VALUE)?.as_primitive::<Float64Type>();
+        let count = summary_column(batch, COUNT)?.as_primitive::<Int64Type>();
+        let resets = summary_column(batch, RESETS)?.as_primitive::<Float64Type>();
+        let labels: Vec<_> = batch
+            .schema()
+            .fields()
+            .iter()
+            .zip(batch.columns())
+            .filter(|(f, _)| !SUMMARY_COLUMNS.contains(&f.name().as_str()))
+            .filter_map(|(f, c)| Some((f.name().clone(), c.as_string_opt::<i32>()?)))
+            .collect();
+        for row in 0..batch.num_rows() {
+            let mut key = Labels::new();
+            for (name, c) in &labels {
+                if !c.is_valid(row) || c.value(row).is_empty() {
+                    continue;
+                }
+                if name == LABELS_FIELD_NAME {
+                    insert_json_labels(&mut key, c.value(row));
+                } else {
+                    key.insert(name.clone(), c.value(row).to_owned());
+                }
+            }
+            series.entry(key).or_default().push(Bucket {
+                index: index.value(row),
+                first: (first_time.value(row), first_value.value(row)),
+                last: (last_time.value(row), last_value.value(row)),
+                count: count.value(row),
+                resets: resets.value(row),
+            });
+        }
+    }
+    Ok(series
+        .into_iter()
+        .map(|(labels, mut buckets)| {
+            buckets.sort_by_key(|b| (b.index, b.first.0));
+            buckets.dedup_by(|next, prev| {
+                if next.index != prev.index {
+                    return false;
+                }
+                *prev = prev.merge(next);
+                true
+            });
+            Series { labels, buckets }
+        })
+        .collect())
+}
+
+// reads the series of every selector summarized in buckets dividing the
+// window of every step, `step` is 0 for an instant query
+async fn fetch(
+    service: &IngestService,
+    table: &str,
+    expr: &PromExpr,
+    start: i64,
+    end: i64,
+    step: i64,
+) -> Result<Fetched, anyhow::Error> {
+    let mut selectors = vec![];
+    collect_selectors(expr, &mut selectors);
+    let mut fetched = Fetched::new();
+    let mut samples = 0;
+    for (matchers, range) in selectors {
+        let key = selector_key(matchers, range);
+        if fetched.contains_key(&key) {
+            continue;
+        }
+        // a selector without a positive matcher would read every series
+        if !matchers.iter().any(|m| !m.matches("")) {
+            return Err(anyhow!(
+                "vector selector must contain at least one non-empty matcher"
+            ));
+        }
+        let base = start.checked_sub(range);
+        // the selected samples are after `base` and up to `end`, in microseconds
+        let bounds = base.and_then(|base| {
+            let min_ts = base.checked_add(1)?.checked_mul(1000)?;
+            Some((base, min_ts, end.checked_mul(1000)?))
+        });
+        let Some((base, min_ts, max_ts)) = bounds else {
+            return Err(InvalidQuery::wrap(anyhow!(
+                "selector range {}ms from {}ms is out of the timestamp range",
+                range,
+                start
+            )));
+        };
+        let width = gcd(step, range).max(1);
+        let df = service
+            .select(table, min_ts, max_ts, |schema| {
+                schema.field_with_name(VALUE_FIELD_NAME).ok()?;
+                let value = ident(VALUE_FIELD_NAME).is_not_null();
+                Some(selector_filter(matchers, schema)?.and(value))
+            })
+            .await?;
+        let series = match df {
+            Some(df) => {
+                let mut series =
+                    summaries_to_series(&summarize(df, base, width)?.collect().await?)?;
+                // counted on the summaries, so the selector is read once
+                samples += series
+                    .iter()
+                    .flat_map(|s| &s.buckets)
+                    .map(|b| b.count as usize)
+                    .sum::<usize>();
+                if samples > MAX_SAMPLES {
+                    return Err(anyhow!(
+                        "query processing would load too many samples into memory"
+                    ));
+                }
+                series.retain(|s| {
+                    matchers.iter().all(|m| {
+                        m.matches(
+                            s.labels
+                                .get(&m.name)
+                                .map(String::as_str)
+                                .unwrap_or_default(),
+                        )
+                    })
+                });
+                series
+            }
+            None => vec![],
+        };
+        fetched.insert(
+            key,
+            Selected {
+                base,
+                width,
+                range,
+                series,
+            },
+        );
+    }
+    Ok(fetched)
+}
+
+/// Evaluates `expr` at the instant `time`, in milliseconds.
+pub async fn instant_query(
+    service: &IngestService,
+    table: &str,
+    expr: &PromExpr,
+    time: i64,
+) -> Result<QueryResult, anyhow::Error> {
+    if let PromExpr::Number(v) = expr {
+        return Ok(QueryResult::Scalar(time, *v));
+    }
+    let fetched = fetch(service, table, expr, time, time, 0).await?;
+    let vector = eval(expr, time, &fetched)?;
+    Ok(QueryResult::Vector(
+        vector.into_iter().map(|(l, v)| (l, time, v)).collect(),
+    ))
+}
+
+/// Evaluates `expr` at every step from `start` to `end`, in milliseconds.
+pub async fn range_query(
+    service: &IngestService,
+    table: &str,
+    expr: &PromExpr,
+    start: i64,
+    end: i64,
+    step: i64,
+) -> Result<QueryResult, anyhow::Error> {
+    if step <= 0 {
+        return Err(anyhow!(
+            "zero or negative query resolution step widths are not accepted"
+        ));
+    }
+    if end < start {
+        return Err(anyhow!("end timestamp must not be before start time"));
+    }
+    if (end - start) / step > MAX_POINTS {
+        return Err(anyhow!(
+            "exceeded maximum resolution of {} points per timeseries",
+            MAX_POINTS
+        ));
+    }
+    let fetched = fetch(service, table, expr, start, end, step).await?;
+    let mut matrix: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+    let mut t = start;
+    while t <= end {
+        let vector = match expr {
+            PromExpr::Number(v) => vec![(Labels::new(), *v)],
+            expr => eval(expr, t, &fetched)?,
+        };
+        for (labels, v) in vector {
+            matrix.entry(labels).or_default().push((t, v));
+        }
+        t = match t.checked_add(step) {
+            Some(t) => t,
+            None => break,
+        };
+    }
+    Ok(QueryResult::Matrix(matrix.into_iter().collect()))
+}
+
+fn eval(expr: &PromExpr, t: i64, fetched: &Fetched) -> Result<Vec<(Labels, f64)>, anyhow::Error> {
+    match expr {
+        PromExpr::Selector(matchers) => {
+            let Some(selected) = fetched.get(&selector_key(matchers, LOOKBACK_MILLIS)) else {
+                return Ok(vec![]);
+            };
+            Ok(selected
+                .series
+                .iter()
+                .filter_map(|s| {
+                    let bucket = selected.window(s, t).last()?;
+                    Some((s.labels.clone(), bucket.last.1))
+                })
+                .collect())
+        }
+        PromExpr::Call(func @ (Function::Rate | Function::Increase), args) => {
+            let [PromExpr::Range(matchers, range)] = args.as_slice() else {
+                return Err(anyhow!("{:?} expects a range vector", func));
+            };
+            let Some(selected) = fetched.get(&selector_key(matchers, *range)) else {
+                return Ok(vec![]);
+            };
+            Ok(selected
+                .series
+                .iter()
+                .filter_map(|s| {
+                    let (first, rest) = selected.window(s, t).split_first()?;
+                    let window = rest.iter().fold(*first, |acc, b| acc.merge(b));
+                    let v = extrapolated_rate(&window, t - range, t, *func == Function::Rate)?;
+                    let mut labels = s.labels.clone();
+                    labels.remove(METRIC_NAME_LABEL);
+                    Some((labels, v))
+                })
+                .collect())
+        }
+        PromExpr::Call(Function::HistogramQuantile, args) => {
+            let [PromExpr::Number(q), inner] = args.as_slice() else {
+                return Err(anyhow!("histogram_quantile expects a number and a vector"));
+            };
+            Ok(histogram_quantile(*q, eval(inner, t, fetched)?))
+        }
+        PromExpr::Aggregate(op, grouping, inner) => {
+            Ok(aggregate(op, grouping, eval(inner, t, fetched)?))
+        }
+        PromExpr::Number(_) | PromExpr::Range(..) => Err(anyhow!("expected an instant vector")),
+    }
+}
+
+// the extrapolation Prometheus applies in rate() and increase(): counter
+// resets are corrected and the increase is extended to the window borders
+// when the samples end close enough to them
+fn extrapolated_rate(window: &Bucket, start: i64, end: i64, is_rate: bool) -> Option<f64> {
+    if window.count < 2 {
+        return None;
+    }
+    let (first_t, first_v) = window.first;
+    let (last_t, last_v) = window.last;
+    let mut result = last_v - first_v + window.resets;
+
+    let sampled = (last_t - first_t) as f64 / 1000.0;
+    let average = sampled / (window.count - 1) as f64;
+    let mut to_start = (first_t - start) as f64 / 1000.0;
+    let to_end = (end - last_t) as f64 / 1000.0;
+    if result > 0.0 && first_v >= 0.0 {
+        // a counter can not be extrapolated below zero
+        let to_zero = sampled * (first_v / result);
+        if to_zero < to_start {
+            to_start = to_zero;
+        }
+    }
+    let threshold = average * 1.1;
+    let mut interval = sampled;
+    interval += if to_start < threshold {
+        to_start
+    } else {
+        average / 2.0
+    };
+    interval += if to_end < threshold {
+        to_end
+    } else {
+        average / 2.0
+    };
+    result *= interval / sampled;
+    if is_rate {
+        result /= (end - start) as f64 / 1000.0;
+    }
+    Some(result)
+}
+
+fn group_labels(grouping: &Grouping, labels: &Labels) -> Labels {
+    match grouping {
+        Grouping::By(names) => labels
+            .iter()
+            .filter(|(k, _)| names.contains(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        Grouping::Without(names) => labels
+            .iter()
+            .filter(|(k, _)| !names.contains(k) && *k != METRIC_NAME_LABEL)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+    }
+}
+
+pub(crate) fn aggregate(
+    op: &AggregateOp,
+    grouping: &Grouping,
+    vector: Vec<(Labels, f64)>,
+) -> Vec<(Labels, f64)> {
+    let mut groups: BTreeMap<Labels, Vec<f64>> = BTreeMap::new();
+    for (labels, v) in vector {
+        groups
+            .entry(group_labels(grouping, &labels))
+            .or_default()
+            .push(v);
+    }
+    groups
+        .into_iter()
+        .map(|(labels, values)| {
+            let v = match op {
+                AggregateOp::Sum => values.iter().sum(),
+                AggregateOp::Avg => values.iter().sum::<f64>() / values.len() as f64,
+                AggregateOp::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+                AggregateOp::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                AggregateOp::Count => values.len() as f64,
+            };
+            (labels, v)
+        })
+        .collect()
+}
+
+fn histogram_quantile(q: f64, vector: Vec<(Labels, f64)>) -> Vec<(Labels, f64)> {
+    let mut histograms: BTreeMap<Labels, Vec<(f64, f64)>> = BTreeMap::new();
+    for (mut labels, v) in vector {
+        let Some(le) = labels.remove("le") else {
+            continue;
+        };
+        let upper = match le.as_str() {
+            "+Inf" | "Inf" | "inf" => f64::INFINITY,
+            le => match le.parse() {
+                Ok(v) => v,
+                Err(_) => continue,
+            },
+        };
+        labels.remove(METRIC_NAME_LABEL);
+        histograms.entry(labels).or_default().push((upper, v));
+    }
+    histograms
+        .into_iter()
+        .map(|(labels, buckets)| (labels, bucket_quantile(q, buckets)))
+        .collect()
+}
+
+// linear interpolation inside the bucket the rank falls into, as
+// Prometheus does for classic histograms
+fn bucket_quantile(q: f64, mut buckets: Vec<(f64, f64)>) -> f64 {
+    if q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+    buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+    if buckets.len() < 2 || buckets.last().map(|b| b.0) != Some(f64::INFINITY) {
+        return f64::NAN;
+    }
+    // bucket counts must be cumulative, fix decreases from scrape races
+    for i in 1..buckets.len() {
+        if buckets[i].1 < buckets[i - 1].1 {
+            buckets[i].1 = buckets[i - 1].1;
+        }
+    }
+    let total = buckets[buckets.len() - 1].1;
+    if total == 0.0 {
+        return f64::NAN;
+    }
+    let rank = q * total;
+    let b = buckets
+        .iter()
+        .position(|(_, count)| *count >= rank)
+        .unwrap_or(buckets.len() - 1);
+    if b == buckets.len() - 1 {
+        return buckets[buckets.len() - 2].0;
+    }
+    if b == 0 && buckets[0].0 <= 0.0 {
+        return buckets[0].0;
+    }
+    let (start, count_before) = if b == 0 {
+        (0.0, 0.0)
+    } else {
+        (buckets[b - 1].0, buckets[b - 1].1)
+    };
+    let (end, count) = buckets[b];
+    start + (end - start) * ((rank - count_before) / (count - count_before))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    // the samples summarized one bucket at a time
+    fn summary(samples: &[(i64, f64)]) -> Bucket {
+        let bucket = |&(t, v): &(i64, f64)| Bucket {
+            index: t,
+            first: (t, v),
+            last: (t, v),
+            count: 1,
+            resets: 0.0,
+        };
+        samples[1..]
+            .iter()
+            .fold(bucket(&samples[0]), |acc, s| acc.merge(&bucket(s)))
+    }
+
+    #[test]
+    fn test_extrapolated_rate() {
+        // a counter growing 1 per second scraped every 15s over a 60s window
+        let samples: Vec<_> = (1..5).map(|i| (15_000 * i, 15.0 * i as f64)).collect();
+        let rate = extrapolated_rate(&summary(&samples), 0, 60_000, true).unwrap();
+        assert!((rate - 1.0).abs() < 1e-9, "{}", rate);
+        let increase = extrapolated_rate(&summary(&samples), 0, 60_000, false).unwrap();
+        assert!((increase - 60.0).abs() < 1e-9, "{}", increase);
+
+        // a counter reset keeps counting up: 10 + 5 + 10 over 45s of samples
+        let samples = vec![
+            (15_000, 10.0),
+            (30_000, 20.0),
+            (45_000, 5.0),
+            (60_000, 15.0),
+        ];
+        let increase = extrapolated_rate(&summary(&samples), 0, 60_000, false).unwrap();
+        assert!((increase - 25.0 * 60.0 / 45.0).abs() < 1e-9, "{}", increase);
+
+        assert!(extrapolated_rate(&summary(&samples[..1]), 0, 60_000, true).is_none());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let vector = vec![
+            (labels(&[("__name__", "x"), ("job", "a"), ("i", "1")]), 1.0),
+            (labels(&[("__name__", "x"), ("job", "a"), ("i", "2")]), 3.0),
+            (labels(&[("__name__", "x"), ("job", "b"), ("i", "1")]), 5.0),
+        ];
+        let by_job = Grouping::By(vec!["job".to_owned()]);
+        let res = aggregate(&AggregateOp::Sum, &by_job, vector.clone());
+        assert_eq!(
+            res,
+            vec![
+                (labels(&[("job", "a")]), 4.0),
+                (labels(&[("job", "b")]), 5.0)
+            ]
+        );
+        let res = aggregate(&AggregateOp::Avg, &by_job, vector.clone());
+        assert_eq!(res[0].1, 2.0);
+        let res = aggregate(&AggregateOp::Max, &Grouping::By(vec![]), vector.clone());
+        assert_eq!(res, vec![(Labels::new(), 5.0)]);
+        let res = aggregate(
+            &AggregateOp::Count,
+            &Grouping::Without(vec!["i".to_owned()]),
+            vector,
+        );
+        assert_eq!(
+            res,
+            vec![
+                (labels(&[("job", "a")]), 2.0),
+                (labels(&[("job", "b")]), 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_histogram_quantile() {
+        let bucket = |le: &str, v: f64| (labels(&[("le", le), ("job", "a")]), v);
+        let vector = vec![
+            bucket("0.1", 10.0),
+            bucket("0.5", 60.0),
+            bucket("1", 90.0),
+            bucket("+Inf", 100.0),
+        ];
+        let res = histogram_quantile(0.5, vector.clone());
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, labels(&[("job", "a")]));
+        assert!((res[0].1 - 0.42).abs() < 1e-9, "{}", res[0].1);
+        // ranks past the last finite bucket return its upper bound
+        let res = histogram_quantile(0.99, vector);
+        assert_eq!(res[0].1, 1.0);
+        let res = histogram_quantile(0.5, vec![bucket("0.1", 10.0)]);
+        assert!(res[0].1.is_nan());
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        use crate::storage::Storage;
+
+        let dir = tempfile::tempdir().unwrap();
+        let service = IngestService::new(Storage::new(dir.path()));
+        let start = 1_700_000_000_000i64;
+        let mut records = vec![];
+        for i in 0..20 {
+            for (job, step) in [("a", 1.0), ("b", 2.0)] {
+                records.push(json!({"__name__": "requests_total", "job": job,
+                                    "timestamp": (start + i * 15_000) * 1000,
+                                    "value": step * 15.0 * i as f64}));
+            }
+            // a counter restarting half way
+            records.push(json!({"__name__": "requests_total", "job": "c",
+                                "timestamp": (start + i * 15_000) * 1000,
+                                "value": 10.0 * (i % 10) as f64}));
+        }
+        // a series of another metric missing the job label entirely
+        records.push(json!({"__name__": "up", "timestamp": start * 1000, "value": 1.0}));
+        service.ingest_records("prom", &records).await.unwrap();
+
+        let t = start + 19 * 15_000;
+        let expr = PromExpr::from_str(r#"requests_total{job="b"}"#).unwrap();
+        let QueryResult::Vector(res) = instant_query(&service, "prom", &expr, t).await.unwrap()
+        else {
+            panic!("expected a vector");
+        };
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].2, 570.0);
+
+        let expr = PromExpr::from_str("sum by (job) (rate(requests_total[1m]))").unwrap();
+        let QueryResult::Vector(res) = instant_query(&service, "prom", &expr, t).await.unwrap()
+        else {
+            panic!("expected a vector");
+        };
+        assert_eq!(res.len(), 3);
+        assert!((res[1].2 - 2.0).abs() < 1e-9, "{}", res[1].2);
+
+        // steps merge buckets into the same windows instant queries read
+        let expr = PromExpr::from_str(r#"rate(requests_total{job=~"b|c"}[1m])"#).unwrap();
+        let res = range_query(&service, "prom", &expr, t - 180_000, t, 45_000)
+            .await
+            .unwrap();
+        let QueryResult::Matrix(res) = res else {
+            panic!("expected a matrix");
+        };
+        assert_eq!(res.len(), 2);
+        for (labels, points) in res {
+            assert_eq!(points.len(), 5);
+            for (step, v) in points {
+                let res = instant_query(&service, "prom", &expr, step).await.unwrap();
+                let QueryResult::Vector(res) = res else {
+                    panic!("expected a vector");
+                };
+                // a reset in the window must not turn the rate negative
+                assert!(v > 0.0, "{}", v);
+                let instant = res.iter().find(|r| r.0 == labels).unwrap();
+                assert!((instant.2 - v).abs() < 1e-9, "{} {}", instant.2, v);
+            }
+        }
+
+        let expr = PromExpr::from_str(r#"up{job=""}"#).unwrap();
+        let res = range_query(&service, "prom", &expr, start, start + 60_000, 30_000)
+            .await
+            .unwrap();
+        let QueryResult::Matrix(res) = res else {
+            panic!("expected a matrix");
+        };
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].1.len(), 3);
+
+        let expr = PromExpr::from_str(r#"{job=~".*"}"#).unwrap();
+        assert!(instant_query(&service, "prom", &expr, t).await.is_err());
+
+        // times out of the microsecond range are rejected, not overflowed
+        let expr = PromExpr::from_str("rate(requests_total[1m])").unwrap();
+        let Err(err) = instant_query(&service, "prom", &expr, i64::MAX / 100).await else {
+            panic!("expected an error");
+        };
+        assert!(err.is::<InvalidQuery>(), "{}", err);
+    }
+}
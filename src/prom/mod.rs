@@ -0,0 +1,70 @@
+//! Prometheus compatible endpoints.
+
+pub mod eval;
+pub mod promql;
+pub mod proto;
+pub mod remote_write;
+
+// column of the metric name, Prometheus keeps it as the `__name__` label
+pub static METRIC_NAME_LABEL: &str = "__name__";
+pub static VALUE_FIELD_NAME: &str = "value";
+// column of labels kept as one JSON object, like OTLP metrics store them
+pub static LABELS_FIELD_NAME: &str = "labels";
+
+// seconds to milliseconds, None for NaN, infinities and anything that
+// does not fit
+fn seconds_to_millis(v: f64) -> Option<i64> {
+    let ms = (v * 1000.0).round();
+    (ms.is_finite() && ms >= i64::MIN as f64 && ms < i64::MAX as f64).then_some(ms as i64)
+}
+
+/// Parses a query API time, unix seconds with an optional fraction or
+/// RFC 3339, into milliseconds. Times must be representable in the
+/// microseconds samples are stored in.
+pub fn parse_time(s: &str) -> Result<i64, anyhow::Error> {
+    let invalid = || anyhow::anyhow!("cannot parse {:?} to a valid timestamp", s);
+    let ms = match s.parse::<f64>() {
+        Ok(v) => seconds_to_millis(v).ok_or_else(invalid)?,
+        Err(_) => chrono::DateTime::parse_from_rfc3339(s)
+            .map_err(|_| invalid())?
+            .timestamp_millis(),
+    };
+    ms.checked_mul(1000).ok_or_else(invalid)?;
+    Ok(ms)
+}
+
+/// Parses a query resolution step, seconds or a PromQL duration, into
+/// milliseconds.
+pub fn parse_step(s: &str) -> Result<i64, anyhow::Error> {
+    if let Ok(v) = s.parse::<f64>() {
+        return seconds_to_millis(v)
+            .ok_or_else(|| anyhow::anyhow!("cannot parse {:?} to a valid duration", s));
+    }
+    promql::parse_duration(s)
+        .map_err(|_| anyhow::anyhow!("cannot parse {:?} to a valid duration", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_params() {
+        assert_eq!(parse_time("1700000000").unwrap(), 1_700_000_000_000);
+        assert_eq!(parse_time("1700000000.5").unwrap(), 1_700_000_000_500);
+        assert_eq!(
+            parse_time("2023-11-14T22:13:20Z").unwrap(),
+            1_700_000_000_000
+        );
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("NaN").is_err());
+        assert!(parse_time("inf").is_err());
+        assert!(parse_time("1e300").is_err());
+        assert!(parse_time("1e13").is_err());
+        assert!(parse_time("-1e13").is_err());
+        assert_eq!(parse_step("15").unwrap(), 15_000);
+        assert_eq!(parse_step("1m").unwrap(), 60_000);
+        assert!(parse_step("x").is_err());
+        assert!(parse_step("-inf").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! The PromQL subset melt evaluates: selectors with label matchers,
+//! rate/increase, sum/avg/min/max/count with by/without and
+//! histogram_quantile.
+
+pub(crate) mod parser;
+
+use regex::Regex;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    Regex,
+    NotRegex,
+}
+
+#[derive(Clone, Debug)]
+pub struct Matcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+    // anchored form of `value` for the regex operators
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    pub fn new(name: &str, op: MatchOp, value: &str) -> Result<Matcher, anyhow::Error> {
+        let regex = match op {
+            MatchOp::Regex | MatchOp::NotRegex => Some(Regex::new(&Matcher::anchored(value))?),
+            _ => None,
+        };
+        Ok(Matcher {
+            name: name.to_owned(),
+            op,
+            value: value.to_owned(),
+            regex,
+        })
+    }
+
+    /// Prometheus regex matchers must match the whole label value.
+    pub fn anchored(value: &str) -> String {
+        format!("^(?:{})$", value)
+    }
+
+    /// Tests a label value, a missing label matches like an empty one.
+    pub fn matches(&self, value: &str) -> bool {
+        match self.op {
+            MatchOp::Equal => value == self.value,
+            MatchOp::NotEqual => value != self.value,
+            MatchOp::Regex => self.regex.as_ref().is_some_and(|r| r.is_match(value)),
+            MatchOp::NotRegex => !self.regex.as_ref().is_some_and(|r| r.is_match(value)),
+        }
+    }
+}
+
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.op == other.op && self.value == other.value
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Function {
+    Rate,
+    Increase,
+    HistogramQuantile,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Grouping {
+    By(Vec<String>),
+    Without(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PromExpr {
+    Number(f64),
+    /// An instant vector selector, the metric name is a `__name__` matcher.
+    Selector(Vec<Matcher>),
+    /// A range vector selector with its range in milliseconds.
+    Range(Vec<Matcher>, i64),
+    Call(Function, Vec<PromExpr>),
+    Aggregate(AggregateOp, Grouping, Box<PromExpr>),
+}
+
+impl PromExpr {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Result<PromExpr, anyhow::Error> {
+        parser::parse_promql(s)
+    }
+}
+
+pub use parser::{parse_duration, parse_labels};
@@ -0,0 +1,343 @@
+use anyhow::anyhow;
+use nom::{
+    branch::alt,
+    bytes::complete::{escaped_transform, is_not, tag, tag_no_case},
+    character::complete::{alpha1, alphanumeric1, char, digit1, multispace0, one_of},
+    combinator::{all_consuming, cut, map, map_res, opt, peek, recognize, value},
+    multi::{many0, many1, separated_list0},
+    number::complete::double,
+    sequence::{delimited, pair, preceded, terminated, tuple},
+};
+use nom_locate::LocatedSpan;
+
+use super::{AggregateOp, Function, Grouping, MatchOp, Matcher, PromExpr};
+use crate::prom::METRIC_NAME_LABEL;
+
+pub type Span<'a> = LocatedSpan<&'a str>;
+pub type IResult<'a, O> = nom::IResult<Span<'a>, O>;
+
+pub fn parse_promql(s: &str) -> Result<PromExpr, anyhow::Error> {
+    let (_, expr) = all_consuming(delimited(multispace0, expr, multispace0))(s.into())
+        .map_err(|e| anyhow!("invalid promql {}: {}", s, e))?;
+    check(&expr)?;
+    Ok(expr)
+}
+
+/// Parses a PromQL duration such as `5m` or `1h30m` into milliseconds.
+pub fn parse_duration(s: &str) -> Result<i64, anyhow::Error> {
+    let (_, v) =
+        all_consuming(duration)(s.into()).map_err(|_| anyhow!("invalid duration {}", s))?;
+    Ok(v)
+}
+
+/// Parses a label set in selector syntax, `{job="api", env="prod"}`, as
+/// sent in Loki push requests. Only `=` pairs are allowed.
+pub fn parse_labels(s: &str) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let (_, matchers) = all_consuming(ws(matchers))(s.into())
+        .map_err(|e| anyhow!("invalid labels {}: {}", s, e))?;
+    matchers
+        .into_iter()
+        .map(|m| match m.op {
+            MatchOp::Equal => Ok((m.name, m.value)),
+            _ => Err(anyhow!("invalid labels {}: expected only `=` pairs", s)),
+        })
+        .collect()
+}
+
+// argument types are checked once the whole expression is parsed
+fn check(expr: &PromExpr) -> Result<(), anyhow::Error> {
+    match expr {
+        PromExpr::Number(_) | PromExpr::Selector(_) => Ok(()),
+        PromExpr::Range(..) => Err(anyhow!(
+            "range vector can only be used as function argument"
+        )),
+        PromExpr::Aggregate(_, _, inner) => match inner.as_ref() {
+            PromExpr::Number(_) => Err(anyhow!("aggregation expects an instant vector")),
+            inner => check(inner),
+        },
+        PromExpr::Call(Function::Rate | Function::Increase, args) => match args.as_slice() {
+            [PromExpr::Range(..)] => Ok(()),
+            _ => Err(anyhow!("rate and increase expect one range vector")),
+        },
+        PromExpr::Call(Function::HistogramQuantile, args) => match args.as_slice() {
+            [PromExpr::Number(_), inner] => check(inner),
+            _ => Err(anyhow!(
+                "histogram_quantile expects a number and an instant vector"
+            )),
+        },
+    }
+}
+
+pub(crate) fn ws<'a, O>(
+    inner: impl FnMut(Span<'a>) -> IResult<'a, O>,
+) -> impl FnMut(Span<'a>) -> IResult<'a, O> {
+    delimited(multispace0, inner, multispace0)
+}
+
+fn metric_name(input: Span<'_>) -> IResult<'_, &str> {
+    // [a-zA-Z_:][a-zA-Z0-9_:]*
+    let (rest, m) = recognize(pair(
+        alt((alpha1, tag("_"), tag(":"))),
+        many0(alt((alphanumeric1, tag("_"), tag(":")))),
+    ))(input)?;
+    Ok((rest, &m))
+}
+
+pub(crate) fn label_name(input: Span<'_>) -> IResult<'_, &str> {
+    // [a-zA-Z_][a-zA-Z0-9_]*
+    let (rest, m) = recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0(alt((alphanumeric1, tag("_")))),
+    ))(input)?;
+    Ok((rest, &m))
+}
+
+fn quoted(quote: char) -> impl FnMut(Span<'_>) -> IResult<'_, String> {
+    move |input| {
+        let not_quote = if quote == '"' { "\"\\" } else { "'\\" };
+        let body = escaped_transform(
+            is_not(not_quote),
+            '\\',
+            alt((
+                value("\\", tag("\\")),
+                value("\"", tag("\"")),
+                value("'", tag("'")),
+                value("\n", tag("n")),
+                value("\t", tag("t")),
+            )),
+        );
+        let (rest, s) = delimited(char(quote), opt(body), char(quote))(input)?;
+        Ok((rest, s.unwrap_or_default()))
+    }
+}
+
+pub(crate) fn string_literal(input: Span<'_>) -> IResult<'_, String> {
+    alt((quoted('"'), quoted('\'')))(input)
+}
+
+fn match_op(input: Span) -> IResult<MatchOp> {
+    alt((
+        map(tag("=~"), |_| MatchOp::Regex),
+        map(tag("!~"), |_| MatchOp::NotRegex),
+        map(tag("!="), |_| MatchOp::NotEqual),
+        map(tag("="), |_| MatchOp::Equal),
+    ))(input)
+}
+
+pub(crate) fn matcher(input: Span) -> IResult<Matcher> {
+    map_res(
+        tuple((ws(label_name), match_op, ws(string_literal))),
+        |(name, op, value)| Matcher::new(name, op, &value),
+    )(input)
+}
+
+pub(crate) fn matchers(input: Span) -> IResult<Vec<Matcher>> {
+    delimited(
+        ws(char('{')),
+        terminated(separated_list0(ws(char(',')), matcher), opt(ws(char(',')))),
+        ws(char('}')),
+    )(input)
+}
+
+pub(crate) fn duration(input: Span) -> IResult<i64> {
+    let unit = alt((
+        value(1, tag("ms")),
+        value(1000, tag("s")),
+        value(60 * 1000, tag("m")),
+        value(3600 * 1000, tag("h")),
+        value(24 * 3600 * 1000, tag("d")),
+        value(7 * 24 * 3600 * 1000, tag("w")),
+        value(365 * 24 * 3600 * 1000, tag("y")),
+    ));
+    map(
+        many1(pair(map_res(digit1, |d: Span| d.parse::<i64>()), unit)),
+        |parts| parts.iter().map(|(n, unit)| n * unit).sum(),
+    )(input)
+}
+
+fn selector(input: Span) -> IResult<PromExpr> {
+    let (rest, (name, mut labels, range)) = tuple((
+        opt(ws(metric_name)),
+        opt(matchers),
+        opt(delimited(ws(char('[')), duration, ws(char(']')))),
+    ))(input)?;
+    if name.is_none() && labels.is_none() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let mut all = vec![];
+    if let Some(name) = name {
+        all.push(Matcher::new(METRIC_NAME_LABEL, MatchOp::Equal, name).unwrap());
+    }
+    all.append(labels.get_or_insert_with(Vec::new));
+    let expr = match range {
+        Some(range) => PromExpr::Range(all, range),
+        None => PromExpr::Selector(all),
+    };
+    Ok((rest, expr))
+}
+
+pub(crate) fn grouping(input: Span) -> IResult<Grouping> {
+    let labels = || {
+        delimited(
+            ws(char('(')),
+            separated_list0(ws(char(',')), map(ws(label_name), |v| v.to_owned())),
+            ws(char(')')),
+        )
+    };
+    alt((
+        map(preceded(ws(tag_no_case("by")), labels()), Grouping::By),
+        map(
+            preceded(ws(tag_no_case("without")), labels()),
+            Grouping::Without,
+        ),
+    ))(input)
+}
+
+pub(crate) fn aggregate_op(input: Span) -> IResult<AggregateOp> {
+    alt((
+        value(AggregateOp::Sum, tag("sum")),
+        value(AggregateOp::Avg, tag("avg")),
+        value(AggregateOp::Min, tag("min")),
+        value(AggregateOp::Max, tag("max")),
+        value(AggregateOp::Count, tag("count")),
+    ))(input)
+}
+
+fn aggregate(input: Span) -> IResult<PromExpr> {
+    // both `sum by (a) (x)` and `sum (x) by (a)` are valid
+    let (rest, (op, before, inner, after)) = tuple((
+        ws(aggregate_op),
+        opt(grouping),
+        delimited(ws(char('(')), expr, ws(char(')'))),
+        opt(grouping),
+    ))(input)?;
+    let grouping = before.or(after).unwrap_or(Grouping::By(vec![]));
+    Ok((rest, PromExpr::Aggregate(op, grouping, Box::new(inner))))
+}
+
+fn function(input: Span) -> IResult<Function> {
+    alt((
+        value(Function::Rate, tag("rate")),
+        value(Function::Increase, tag("increase")),
+        value(Function::HistogramQuantile, tag("histogram_quantile")),
+    ))(input)
+}
+
+fn call(input: Span) -> IResult<PromExpr> {
+    let (rest, (func, args)) = pair(
+        ws(function),
+        preceded(
+            ws(char('(')),
+            cut(terminated(
+                separated_list0(ws(char(',')), expr),
+                ws(char(')')),
+            )),
+        ),
+    )(input)?;
+    Ok((rest, PromExpr::Call(func, args)))
+}
+
+fn number(input: Span) -> IResult<PromExpr> {
+    // `double` also reads inf and nan, which would eat metric names like info
+    let numeric = preceded(peek(one_of("0123456789.+-")), double);
+    map(ws(numeric), PromExpr::Number)(input)
+}
+
+fn expr(input: Span) -> IResult<PromExpr> {
+    alt((
+        aggregate,
+        call,
+        number,
+        selector,
+        delimited(ws(char('(')), expr, ws(char(')'))),
+    ))(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eq(name: &str, value: &str) -> Matcher {
+        Matcher::new(name, MatchOp::Equal, value).unwrap()
+    }
+
+    #[test]
+    fn test_selector() {
+        let expr =
+            parse_promql(r#"http_requests_total{job="api", path=~"/v1/.*", code!='500'}"#).unwrap();
+        let expect = PromExpr::Selector(vec![
+            eq("__name__", "http_requests_total"),
+            eq("job", "api"),
+            Matcher::new("path", MatchOp::Regex, "/v1/.*").unwrap(),
+            Matcher::new("code", MatchOp::NotEqual, "500").unwrap(),
+        ]);
+        assert_eq!(expr, expect);
+
+        let expr = parse_promql(r#"{__name__="up"}"#).unwrap();
+        assert_eq!(expr, PromExpr::Selector(vec![eq("__name__", "up")]));
+        let expr = parse_promql("info_metric").unwrap();
+        assert_eq!(
+            expr,
+            PromExpr::Selector(vec![eq("__name__", "info_metric")])
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let expr = parse_promql("sum by (job) (rate(http_requests_total[5m]))").unwrap();
+        let expect = PromExpr::Aggregate(
+            AggregateOp::Sum,
+            Grouping::By(vec!["job".to_owned()]),
+            Box::new(PromExpr::Call(
+                Function::Rate,
+                vec![PromExpr::Range(
+                    vec![eq("__name__", "http_requests_total")],
+                    300_000,
+                )],
+            )),
+        );
+        assert_eq!(expr, expect);
+        let same = parse_promql("sum(rate(http_requests_total[5m])) by (job)").unwrap();
+        assert_eq!(same, expect);
+
+        let expr = parse_promql(
+            "histogram_quantile(0.9, sum by (le) (increase(latency_bucket{job='a'}[1h30m])))",
+        )
+        .unwrap();
+        let PromExpr::Call(Function::HistogramQuantile, args) = expr else {
+            panic!("expected a call");
+        };
+        assert_eq!(args[0], PromExpr::Number(0.9));
+
+        assert!(parse_promql("rate(up)").is_err());
+        assert!(parse_promql("up[5m]").is_err());
+        assert!(parse_promql("sum(up").is_err());
+        assert!(parse_promql(r#"up{job=~"("}"#).is_err());
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(parse_duration("15s").unwrap(), 15_000);
+        assert_eq!(parse_duration("1h30m").unwrap(), 5_400_000);
+        assert_eq!(parse_duration("250ms").unwrap(), 250);
+        assert!(parse_duration("5x").is_err());
+    }
+
+    #[test]
+    fn test_labels() {
+        let labels = parse_labels(r#"{job="api", msg="say \"hi\""}"#).unwrap();
+        assert_eq!(
+            labels,
+            vec![
+                ("job".to_owned(), "api".to_owned()),
+                ("msg".to_owned(), "say \"hi\"".to_owned()),
+            ]
+        );
+        assert!(parse_labels("{}").unwrap().is_empty());
+        assert!(parse_labels(r#"{job=~"api"}"#).is_err());
+        assert!(parse_labels("job").is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use prost::Message;
+use serde_json::{Map, Value};
+
+use super::{proto::WriteRequest, VALUE_FIELD_NAME};
+use crate::config::TIMPSTAMP_FIELD_NAME;
+
+pub fn decode(body: &[u8]) -> Result<WriteRequest, anyhow::Error> {
+    Ok(WriteRequest::decode(body)?)
+}
+
+/// Maps every sample into one row: a column per label, `value` and the
+/// sample time in microseconds as `timestamp`. Sample times are always
+/// milliseconds, one out of the microsecond range fails the request.
+pub fn samples_to_records(request: &WriteRequest) -> Result<Vec<Value>, anyhow::Error> {
+    let mut records = vec![];
+    for series in &request.timeseries {
+        let mut labels = Map::new();
+        for label in &series.labels {
+            // labels named like our own columns are renamed the way
+            // Prometheus renames clashing target labels
+            let name = if label.name == VALUE_FIELD_NAME || label.name == TIMPSTAMP_FIELD_NAME {
+                format!("exported_{}", label.name)
+            } else {
+                label.name.clone()
+            };
+            labels.insert(name, Value::from(label.value.as_str()));
+        }
+        for sample in &series.samples {
+            let timestamp = sample.timestamp.checked_mul(1000).ok_or_else(|| {
+                anyhow::anyhow!("sample timestamp {} is out of range", sample.timestamp)
+            })?;
+            let mut record = labels.clone();
+            record.insert(TIMPSTAMP_FIELD_NAME.to_owned(), Value::from(timestamp));
+            // NaN (stale markers) and infinities have no JSON form, they are
+            // stored as null
+            record.insert(VALUE_FIELD_NAME.to_owned(), Value::from(sample.value));
+            records.push(Value::Object(record));
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prom::proto::{Label, Sample, TimeSeries};
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_samples_to_records() {
+        let request = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![
+                    label("__name__", "http_requests_total"),
+                    label("job", "api"),
+                    label("value", "x"),
+                ],
+                samples: vec![
+                    Sample {
+                        value: 1.0,
+                        timestamp: 1_700_000_000_000,
+                    },
+                    Sample {
+                        value: f64::NAN,
+                        timestamp: 1_700_000_015_000,
+                    },
+                    // the epoch itself is kept, not taken for a missing time
+                    Sample {
+                        value: 2.0,
+                        timestamp: 0,
+                    },
+                ],
+            }],
+        };
+        let request = decode(&request.encode_to_vec()).unwrap();
+
+        let records = samples_to_records(&request).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["__name__"], "http_requests_total");
+        assert_eq!(records[0]["job"], "api");
+        assert_eq!(records[0]["exported_value"], "x");
+        assert_eq!(records[0]["value"], 1.0);
+        assert_eq!(records[0]["timestamp"], 1_700_000_000_000_000i64);
+        assert_eq!(records[1]["timestamp"], 1_700_000_015_000_000i64);
+        assert!(records[1]["value"].is_null());
+        assert_eq!(records[2]["timestamp"], 0);
+
+        let request = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![label("__name__", "up")],
+                samples: vec![Sample {
+                    value: 1.0,
+                    timestamp: i64::MAX / 100,
+                }],
+            }],
+        };
+        assert!(samples_to_records(&request).is_err());
+    }
+}
@@ -26,6 +26,7 @@ pub enum QueryExpr {
 }
 
 impl QueryExpr {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Result<QueryExpr, anyhow::Error> {
         parser::parse_query(s)
     }
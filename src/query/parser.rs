@@ -21,7 +21,7 @@ pub fn parse_query(s: &str) -> Result<QueryExpr, anyhow::Error> {
     if !res.is_empty() {
         return Err(anyhow!("can not complete query : {}", res));
     }
-    return Ok(ops);
+    Ok(ops)
 }
 
 fn comparision_operator(input: Span) -> IResult<ComparisionOperator> {
@@ -38,7 +38,7 @@ fn comparision_operator(input: Span) -> IResult<ComparisionOperator> {
     ))(input)
 }
 
-fn identifier(input: Span) -> IResult<&str> {
+fn identifier(input: Span<'_>) -> IResult<'_, &str> {
     // [a-zA-Z_][a-zA-Z0-9_]*
     let (rest, m) = recognize(pair(
         alt((alpha1, tag("_"))),
@@ -47,13 +47,13 @@ fn identifier(input: Span) -> IResult<&str> {
     Ok((rest, &m))
 }
 
-fn value_literal(input: Span) -> IResult<&str> {
+fn value_literal(input: Span<'_>) -> IResult<'_, &str> {
     let (res, m) = recognize(many1(alphanumeric1))(input)?;
     Ok((res, &m))
 }
 
-fn string_literal(input: Span) -> IResult<&str> {
-    let (rest, m) = recognize(delimited(char('"'), many0(is_not("\"")), char('"')))(input.into())?;
+fn string_literal(input: Span<'_>) -> IResult<'_, &str> {
+    let (rest, m) = recognize(delimited(char('"'), many0(is_not("\"")), char('"')))(input)?;
     Ok((rest, &m[1..m.len() - 1]))
 }
 
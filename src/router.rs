@@ -1,7 +1,41 @@
-use actix_web::{get, http::Error, post, web, HttpResponse, Responder};
+use actix_web::{
+    get,
+    http::{header, Error, StatusCode},
+    post, route, web, HttpRequest, HttpResponse, Responder,
+};
+use arrow_schema::SchemaRef;
+use datafusion::arrow::record_batch::RecordBatch;
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
 use serde_derive::{Deserialize, Serialize};
 
-use crate::app;
+use crate::{
+    app,
+    config::{LokiConfig, MAX_DECOMPRESSED_SIZE, MAX_PAYLOAD_SIZE},
+    fusion::format::{encode_stream, ResultFormat},
+    import::{
+        arrow,
+        csv::{self, CsvOptions},
+    },
+    influx,
+    ingest::{BulkError, InvalidQuery, InvalidRecords},
+    jaeger,
+    loki::{self, logql::LogExpr},
+    otlp::{
+        self,
+        proto::{
+            ExportLogsServiceRequest, ExportLogsServiceResponse, ExportMetricsServiceRequest,
+            ExportMetricsServiceResponse, ExportTraceServiceRequest, ExportTraceServiceResponse,
+            Status,
+        },
+    },
+    prom::{self, promql::PromExpr},
+    storage::is_valid_table_name,
+    utils::compress::{self, ContentEncoding},
+    zipkin,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct MeltResponse {
@@ -10,42 +44,984 @@ struct MeltResponse {
     pub error_detail: Option<String>,
 }
 
+impl MeltResponse {
+    fn error(code: StatusCode, message: &str, detail: impl ToString) -> HttpResponse {
+        HttpResponse::build(code).json(MeltResponse {
+            code: code.as_u16(),
+            message: message.to_owned(),
+            error_detail: Some(detail.to_string()),
+        })
+    }
+}
+
+// records that can not be stored are a bad request, a failed write is a
+// server error so that shippers retry instead of dropping the data
+fn ingest_status(e: &anyhow::Error) -> StatusCode {
+    if e.is::<InvalidRecords>() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+fn ingest_error(e: anyhow::Error) -> HttpResponse {
+    log::error!("Error process request {:?}", e);
+    let message = if e.is::<InvalidRecords>() {
+        "invalid request"
+    } else {
+        "write failed"
+    };
+    MeltResponse::error(ingest_status(&e), message, e)
+}
+
+// a query that can not be parsed is a bad request, failing to read the
+// table is a server error
+fn query_status(e: &anyhow::Error) -> StatusCode {
+    if e.is::<InvalidQuery>() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+fn query_error(e: anyhow::Error) -> HttpResponse {
+    let code = query_status(&e);
+    if code == StatusCode::BAD_REQUEST {
+        MeltResponse::error(code, "invalid query", e)
+    } else {
+        log::error!("Error process request {:?}", e);
+        MeltResponse::error(code, "query failed", e)
+    }
+}
+
+fn content_encoding(req: &HttpRequest) -> Result<ContentEncoding, HttpResponse> {
+    let encoding = req
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .map(|v| v.to_str().unwrap_or_default());
+    ContentEncoding::from_header(encoding)
+        .map_err(|e| MeltResponse::error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid request", e))
+}
+
+// reads an ingest body and removes its Content-Encoding, the wire size is
+// bounded by MAX_PAYLOAD_SIZE and the decoded size by MAX_DECOMPRESSED_SIZE
+async fn read_body(req: &HttpRequest, payload: web::Payload) -> Result<web::Bytes, HttpResponse> {
+    let encoding = content_encoding(req)?;
+    read_encoded_body(encoding, payload).await
+}
+
+async fn read_encoded_body(
+    encoding: ContentEncoding,
+    payload: web::Payload,
+) -> Result<web::Bytes, HttpResponse> {
+    let body = match payload.to_bytes_limited(MAX_PAYLOAD_SIZE).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            return Err(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid request",
+                e,
+            ))
+        }
+        Err(e) => {
+            return Err(MeltResponse::error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "invalid request",
+                e,
+            ))
+        }
+    };
+
+    compress::decompress(encoding, body, MAX_DECOMPRESSED_SIZE)
+        .map_err(|e| MeltResponse::error(StatusCode::BAD_REQUEST, "invalid request", e))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct BulkErrorResponse {
+    #[serde(flatten)]
+    pub error: MeltResponse,
+    // records at the start of the body that were stored before the error,
+    // a retry only sends the ones after them
+    pub stored: usize,
+}
+
+fn bulk_error(e: BulkError) -> HttpResponse {
+    log::error!(
+        "Error process request after {} records {:?}",
+        e.stored,
+        e.error
+    );
+    let code = ingest_status(&e.error);
+    let message = if code == StatusCode::BAD_REQUEST {
+        "invalid request"
+    } else {
+        "write failed"
+    };
+    HttpResponse::build(code).json(BulkErrorResponse {
+        error: MeltResponse {
+            code: code.as_u16(),
+            message: message.to_owned(),
+            error_detail: Some(e.error.to_string()),
+        },
+        stored: e.stored,
+    })
+}
+
 #[post("/{name}/_bulk")]
 pub async fn bulk(
     app: web::Data<app::AppState>,
     name: web::Path<String>,
-    body: web::Bytes,
+    req: HttpRequest,
+    payload: web::Payload,
 ) -> Result<HttpResponse, Error> {
     let name: String = name.into_inner();
+    if !is_valid_table_name(&name) {
+        return Ok(MeltResponse::error(
+            StatusCode::BAD_REQUEST,
+            "invalid table name",
+            name,
+        ));
+    }
+    let encoding = match content_encoding(&req) {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
     let service = app.service();
-    let res = service.bulk(&name, body).await;
-    match res {
-        Ok(v) => Ok(HttpResponse::Ok().json(v)),
+    match service.bulk_stream(&name, encoding, payload).await {
+        Ok(_) => Ok(HttpResponse::Ok().json(())),
+        Err(e) => Ok(bulk_error(e)),
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CsvParams {
+    pub delimiter: Option<String>,
+    pub header: Option<String>,
+    pub timestamp_column: Option<String>,
+    pub timestamp_format: Option<String>,
+}
+
+fn csv_options(req: &HttpRequest, params: CsvParams) -> Result<CsvOptions, anyhow::Error> {
+    // TSV bodies only need their content type
+    let is_tsv = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/tab-separated-values"));
+    let delimiter = match params.delimiter.as_deref() {
+        Some(v) => csv::parse_delimiter(v)?,
+        None if is_tsv => b'\t',
+        None => b',',
+    };
+    Ok(CsvOptions {
+        delimiter,
+        header: csv::Header::from_param(params.header.as_deref())?,
+        timestamp_column: params.timestamp_column,
+        timestamp_format: params.timestamp_format,
+    })
+}
+
+#[post("/{name}/_csv")]
+pub async fn csv_import(
+    app: web::Data<app::AppState>,
+    name: web::Path<String>,
+    params: web::Query<CsvParams>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let name = name.into_inner();
+    if !is_valid_table_name(&name) {
+        return Ok(MeltResponse::error(
+            StatusCode::BAD_REQUEST,
+            "invalid table name",
+            name,
+        ));
+    }
+    let body = match read_body(&req, payload).await {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    let records = csv_options(&req, params.into_inner())
+        .and_then(|options| csv::csv_to_records(&body, &options));
+    let records = match records {
+        Ok(v) => v,
         Err(e) => {
-            log::error!("Error process request {:?}", e);
-            Ok(HttpResponse::BadRequest().json(()))
+            return Ok(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid request",
+                e,
+            ))
         }
+    };
+    match app.service().ingest_records(&name, &records).await {
+        Ok(_) => Ok(HttpResponse::Ok().json(())),
+        Err(e) => Ok(ingest_error(e)),
+    }
+}
+
+// writes a body of columnar data read by `read` into the table `name`
+async fn columnar_import(
+    app: &app::AppState,
+    name: String,
+    req: HttpRequest,
+    payload: web::Payload,
+    read: fn(web::Bytes) -> Result<Vec<RecordBatch>, anyhow::Error>,
+) -> HttpResponse {
+    if !is_valid_table_name(&name) {
+        return MeltResponse::error(StatusCode::BAD_REQUEST, "invalid table name", name);
     }
+    let body = match read_body(&req, payload).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let batches = match read(body) {
+        Ok(v) => v,
+        Err(e) => return MeltResponse::error(StatusCode::BAD_REQUEST, "invalid request", e),
+    };
+    match app.service().ingest_batches(&name, batches).await {
+        Ok(_) => HttpResponse::Ok().json(()),
+        Err(e) => ingest_error(e),
+    }
+}
+
+#[post("/{name}/_arrow")]
+pub async fn arrow_import(
+    app: web::Data<app::AppState>,
+    name: web::Path<String>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let read = |body: web::Bytes| arrow::read_ipc(&body);
+    Ok(columnar_import(&app, name.into_inner(), req, payload, read).await)
+}
+
+#[post("/{name}/_parquet")]
+pub async fn parquet_import(
+    app: web::Data<app::AppState>,
+    name: web::Path<String>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    Ok(columnar_import(&app, name.into_inner(), req, payload, arrow::read_parquet).await)
 }
 
 #[post("/{name}/_json")]
 pub async fn injest(
     app: web::Data<app::AppState>,
     name: web::Path<String>,
-    body: web::Bytes,
+    req: HttpRequest,
+    payload: web::Payload,
 ) -> Result<HttpResponse, Error> {
     let name: String = name.into_inner();
-    let service = app.service();
-    let res = service.ingest(&name, body).await;
-    match res {
+    if !is_valid_table_name(&name) {
+        return Ok(MeltResponse::error(
+            StatusCode::BAD_REQUEST,
+            "invalid table name",
+            name,
+        ));
+    }
+    let body = match read_body(&req, payload).await {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    match app.service().ingest(&name, body).await {
         Ok(v) => Ok(HttpResponse::Ok().json(v)),
+        Err(e) => Ok(ingest_error(e)),
+    }
+}
+
+fn otlp_encoding(req: &HttpRequest) -> Result<otlp::Encoding, HttpResponse> {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|v| v.to_str().unwrap_or_default());
+    otlp::Encoding::from_content_type(content_type)
+        .map_err(|e| MeltResponse::error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid request", e))
+}
+
+// OTLP/HTTP answers in the encoding of the request, errors carry a Status
+fn otlp_response<T>(encoding: otlp::Encoding, code: StatusCode, message: &T) -> HttpResponse
+where
+    T: prost::Message + serde::Serialize,
+{
+    HttpResponse::build(code)
+        .content_type(encoding.content_type())
+        .body(encoding.encode(message))
+}
+
+// the Status of OTLP carries a google.rpc.Code, not the HTTP status
+fn rpc_code(code: StatusCode) -> i32 {
+    match code {
+        StatusCode::SERVICE_UNAVAILABLE => 14,
+        c if c.is_client_error() => 3,
+        _ => 13,
+    }
+}
+
+fn otlp_error(encoding: otlp::Encoding, code: StatusCode, e: anyhow::Error) -> HttpResponse {
+    let body = Status {
+        code: rpc_code(code),
+        message: e.to_string(),
+    };
+    otlp_response(encoding, code, &body)
+}
+
+// reads and decodes an OTLP export request, errors are already answered
+async fn otlp_request<Req>(
+    req: &HttpRequest,
+    payload: web::Payload,
+) -> Result<(otlp::Encoding, Req), HttpResponse>
+where
+    Req: prost::Message + Default + serde::de::DeserializeOwned,
+{
+    let encoding = otlp_encoding(req)?;
+    let body = read_body(req, payload).await?;
+    match encoding.decode(&body) {
+        Ok(v) => Ok((encoding, v)),
+        Err(e) => Err(otlp_error(encoding, StatusCode::BAD_REQUEST, e)),
+    }
+}
+
+// answers a written OTLP export with the empty export response of the signal
+fn otlp_written<Resp>(encoding: otlp::Encoding, res: Result<(), anyhow::Error>) -> HttpResponse
+where
+    Resp: prost::Message + Default + serde::Serialize,
+{
+    match res {
+        Ok(_) => otlp_response(encoding, StatusCode::OK, &Resp::default()),
         Err(e) => {
             log::error!("Error process request {:?}", e);
-            Ok(HttpResponse::BadRequest().json(()))
+            otlp_error(encoding, ingest_status(&e), e)
+        }
+    }
+}
+
+// decodes an OTLP export request, maps it into records and writes them
+// into `table`
+async fn otlp_export<Req, Resp>(
+    app: &app::AppState,
+    table: &str,
+    req: HttpRequest,
+    payload: web::Payload,
+    to_records: fn(&Req) -> Vec<serde_json::Value>,
+) -> HttpResponse
+where
+    Req: prost::Message + Default + serde::de::DeserializeOwned,
+    Resp: prost::Message + Default + serde::Serialize,
+{
+    let (encoding, request) = match otlp_request::<Req>(&req, payload).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let records = to_records(&request);
+    let res = app.service().ingest_records(table, &records).await;
+    otlp_written::<Resp>(encoding, res)
+}
+
+#[post("/v1/logs")]
+pub async fn otlp_logs(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let table = &app.config().otlp.logs_table;
+    Ok(
+        otlp_export::<ExportLogsServiceRequest, ExportLogsServiceResponse>(
+            &app,
+            table,
+            req,
+            payload,
+            otlp::logs::logs_to_records,
+        )
+        .await,
+    )
+}
+
+#[post("/v1/traces")]
+pub async fn otlp_traces(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let table = &app.config().otlp.traces_table;
+    Ok(
+        otlp_export::<ExportTraceServiceRequest, ExportTraceServiceResponse>(
+            &app,
+            table,
+            req,
+            payload,
+            otlp::traces::spans_to_records,
+        )
+        .await,
+    )
+}
+
+#[post("/v1/metrics")]
+pub async fn otlp_metrics(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let (encoding, request) = match otlp_request::<ExportMetricsServiceRequest>(&req, payload).await
+    {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    // metrics carry bucket lists, they are written as a batch
+    let batch = match otlp::metrics::metrics_to_batch(&request) {
+        Ok(v) => v,
+        Err(e) => return Ok(otlp_error(encoding, StatusCode::BAD_REQUEST, e)),
+    };
+    let table = &app.config().otlp.metrics_table;
+    let res = app
+        .service()
+        .ingest_batches(table, batch.into_iter().collect())
+        .await;
+    Ok(otlp_written::<ExportMetricsServiceResponse>(encoding, res))
+}
+
+#[post("/api/v1/write")]
+pub async fn prom_write(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    // remote write bodies are always snappy compressed, some senders leave
+    // out the header
+    let encoding = match content_encoding(&req) {
+        Ok(ContentEncoding::Identity) => ContentEncoding::Snappy,
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    let body = match read_encoded_body(encoding, payload).await {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    let request = match prom::remote_write::decode(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid request",
+                e,
+            ))
+        }
+    };
+    let records = match prom::remote_write::samples_to_records(&request) {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid request",
+                e,
+            ))
+        }
+    };
+    let table = &app.config().prometheus.table;
+    match app.service().ingest_records(table, &records).await {
+        Ok(_) => Ok(HttpResponse::NoContent().finish()),
+        Err(e) => Ok(ingest_error(e)),
+    }
+}
+
+#[post("/loki/api/v1/push")]
+pub async fn loki_push(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    // promtail sends protobuf without a content type
+    let encoding = match req.headers().get(header::CONTENT_TYPE) {
+        None => otlp::Encoding::Protobuf,
+        Some(_) => match otlp_encoding(&req) {
+            Ok(v) => v,
+            Err(resp) => return Ok(resp),
+        },
+    };
+    // protobuf pushes are always snappy compressed, like remote write
+    let encoding_header = match content_encoding(&req) {
+        Ok(ContentEncoding::Identity) if encoding == otlp::Encoding::Protobuf => {
+            ContentEncoding::Snappy
+        }
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    let body = match read_encoded_body(encoding_header, payload).await {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    let config = &app.config().loki;
+    let tables = loki::push::decode(encoding, &body).and_then(|streams| {
+        loki::push::streams_to_records(&streams, config.table_label.as_deref(), &config.table)
+    });
+    let tables = match tables {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid request",
+                e,
+            ))
+        }
+    };
+    for (table, records) in tables {
+        if let Err(e) = app.service().ingest_records(&table, &records).await {
+            return Ok(ingest_error(e));
+        }
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InfluxWriteParams {
+    pub precision: Option<String>,
+}
+
+// v1 and v2 writes only differ in parameters melt does not use, the
+// database or bucket and the org
+async fn influx_write(
+    app: &app::AppState,
+    req: HttpRequest,
+    payload: web::Payload,
+    params: &InfluxWriteParams,
+) -> HttpResponse {
+    let precision = match influx::Precision::from_param(params.precision.as_deref()) {
+        Ok(v) => v,
+        Err(e) => return MeltResponse::error(StatusCode::BAD_REQUEST, "invalid request", e),
+    };
+    let body = match read_body(&req, payload).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let config = &app.config().influx;
+    let tables = std::str::from_utf8(&body)
+        .map_err(anyhow::Error::from)
+        .and_then(influx::line_protocol::parse_lines)
+        .and_then(|points| {
+            influx::points_to_records(&points, precision, &config.table, config.measurement_tables)
+        });
+    let tables = match tables {
+        Ok(v) => v,
+        Err(e) => return MeltResponse::error(StatusCode::BAD_REQUEST, "invalid request", e),
+    };
+    for (table, records) in tables {
+        if let Err(e) = app.service().ingest_records(&table, &records).await {
+            return ingest_error(e);
+        }
+    }
+    HttpResponse::NoContent().finish()
+}
+
+#[post("/write")]
+pub async fn influx_write_v1(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    params: web::Query<InfluxWriteParams>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    Ok(influx_write(&app, req, payload, &params).await)
+}
+
+#[post("/api/v2/write")]
+pub async fn influx_write_v2(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    params: web::Query<InfluxWriteParams>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    Ok(influx_write(&app, req, payload, &params).await)
+}
+
+#[post("/api/v2/spans")]
+pub async fn zipkin_spans(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let body = match read_body(&req, payload).await {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    let records = match zipkin::decode(&body).and_then(|spans| zipkin::spans_to_records(&spans)) {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid request",
+                e,
+            ))
+        }
+    };
+    let config = app.config();
+    let table = config
+        .zipkin
+        .table
+        .as_deref()
+        .unwrap_or(&config.otlp.traces_table);
+    match app.service().ingest_records(table, &records).await {
+        Ok(_) => Ok(HttpResponse::Accepted().finish()),
+        Err(e) => Ok(ingest_error(e)),
+    }
+}
+
+// Jaeger wraps results and errors in the same envelope
+fn jaeger_result(data: serde_json::Value) -> HttpResponse {
+    let total = data.as_array().map(Vec::len).unwrap_or_default();
+    HttpResponse::Ok().json(serde_json::json!({
+        "data": data,
+        "total": total,
+        "limit": 0,
+        "offset": 0,
+        "errors": null,
+    }))
+}
+
+fn jaeger_error(code: StatusCode, e: impl ToString) -> HttpResponse {
+    HttpResponse::build(code).json(serde_json::json!({
+        "data": null,
+        "total": 0,
+        "limit": 0,
+        "offset": 0,
+        "errors": [{"code": code.as_u16(), "msg": e.to_string()}],
+    }))
+}
+
+fn jaeger_table(app: &app::AppState) -> String {
+    let config = app.config();
+    config
+        .jaeger
+        .table
+        .clone()
+        .unwrap_or_else(|| config.otlp.traces_table.clone())
+}
+
+#[get("/api/services")]
+pub async fn jaeger_services(app: web::Data<app::AppState>) -> Result<HttpResponse, Error> {
+    match jaeger::services(app.service(), &jaeger_table(&app)).await {
+        Ok(v) => Ok(jaeger_result(serde_json::json!(v))),
+        Err(e) => Ok(jaeger_error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+#[get("/api/services/{service}/operations")]
+pub async fn jaeger_operations(
+    app: web::Data<app::AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let table = jaeger_table(&app);
+    match jaeger::operations(app.service(), &table, &path.into_inner()).await {
+        Ok(v) => Ok(jaeger_result(serde_json::json!(v))),
+        Err(e) => Ok(jaeger_error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+#[get("/api/traces")]
+pub async fn jaeger_find_traces(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    // `tag` may repeat, so the pairs are read as a list
+    let query = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map_err(anyhow::Error::from)
+        .and_then(|params| {
+            let now = chrono::Utc::now().timestamp_micros();
+            jaeger::TraceQuery::from_params(&params, now)
+        });
+    let query = match query {
+        Ok(v) => v,
+        Err(e) => return Ok(jaeger_error(StatusCode::BAD_REQUEST, e)),
+    };
+    let table = jaeger_table(&app);
+    match jaeger::find_traces(app.service(), &table, &query).await {
+        Ok(traces) => Ok(jaeger_result(
+            traces.iter().map(jaeger::trace_to_json).collect(),
+        )),
+        Err(e) => Ok(jaeger_error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+#[get("/api/traces/{trace_id}")]
+pub async fn jaeger_trace(
+    app: web::Data<app::AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let trace_id = match jaeger::normalize_trace_id(&path.into_inner()) {
+        Ok(v) => v,
+        Err(e) => return Ok(jaeger_error(StatusCode::BAD_REQUEST, e)),
+    };
+    let table = jaeger_table(&app);
+    match app.service().get_trace(&table, &trace_id, None, None).await {
+        Ok(Some(v)) => Ok(jaeger_result(serde_json::json!([jaeger::trace_to_json(
+            &v
+        )]))),
+        Ok(None) => Ok(jaeger_error(StatusCode::NOT_FOUND, "trace not found")),
+        Err(e) => Ok(jaeger_error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PromQueryParams {
+    pub query: String,
+    pub time: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub step: Option<String>,
+}
+
+fn prom_error(code: StatusCode, error_type: &str, e: impl ToString) -> HttpResponse {
+    HttpResponse::build(code).json(serde_json::json!({
+        "status": "error",
+        "errorType": error_type,
+        "error": e.to_string(),
+    }))
+}
+
+// query API parameters come from the URL and, on POST, from a form body
+fn prom_params(req: &HttpRequest, body: &web::Bytes) -> Result<PromQueryParams, HttpResponse> {
+    let mut query = req.query_string().to_owned();
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    if is_form {
+        query.push('&');
+        query.push_str(&String::from_utf8_lossy(body));
+    }
+    web::Query::<PromQueryParams>::from_query(&query)
+        .map(|v| v.into_inner())
+        .map_err(|e| prom_error(StatusCode::BAD_REQUEST, "bad_data", e))
+}
+
+fn prom_required<'a>(name: &str, v: &'a Option<String>) -> Result<&'a str, anyhow::Error> {
+    v.as_deref()
+        .ok_or_else(|| anyhow::anyhow!("missing parameter {}", name))
+}
+
+fn prom_result(res: Result<prom::eval::QueryResult, anyhow::Error>) -> HttpResponse {
+    match res {
+        Ok(v) => HttpResponse::Ok().json(serde_json::json!({
+            "status": "success",
+            "data": v.to_json(),
+        })),
+        Err(e) if e.is::<InvalidQuery>() => prom_error(StatusCode::BAD_REQUEST, "bad_data", e),
+        Err(e) => prom_error(StatusCode::UNPROCESSABLE_ENTITY, "execution", e),
+    }
+}
+
+#[route("/api/v1/query", method = "GET", method = "POST")]
+pub async fn prom_query(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let params = match prom_params(&req, &body) {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    let time = match params.time.as_deref().map(prom::parse_time) {
+        None => Ok(chrono::Utc::now().timestamp_millis()),
+        Some(v) => v,
+    };
+    let (time, expr) = match (time, PromExpr::from_str(&params.query)) {
+        (Ok(time), Ok(expr)) => (time, expr),
+        (Err(e), _) | (_, Err(e)) => return Ok(prom_error(StatusCode::BAD_REQUEST, "bad_data", e)),
+    };
+    let table = &app.config().prometheus.table;
+    let res = prom::eval::instant_query(app.service(), table, &expr, time).await;
+    Ok(prom_result(res))
+}
+
+#[route("/api/v1/query_range", method = "GET", method = "POST")]
+pub async fn prom_query_range(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let params = match prom_params(&req, &body) {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    let parsed = (|| {
+        let start = prom::parse_time(prom_required("start", &params.start)?)?;
+        let end = prom::parse_time(prom_required("end", &params.end)?)?;
+        let step = prom::parse_step(prom_required("step", &params.step)?)?;
+        let expr = PromExpr::from_str(&params.query)?;
+        Ok::<_, anyhow::Error>((start, end, step, expr))
+    })();
+    let (start, end, step, expr) = match parsed {
+        Ok(v) => v,
+        Err(e) => return Ok(prom_error(StatusCode::BAD_REQUEST, "bad_data", e)),
+    };
+    let table = &app.config().prometheus.table;
+    let res = prom::eval::range_query(app.service(), table, &expr, start, end, step).await;
+    Ok(prom_result(res))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LokiQueryParams {
+    pub query: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub limit: Option<usize>,
+    pub direction: Option<String>,
+    pub step: Option<String>,
+}
+
+// the default window of the query API, the last hour, in nanoseconds
+fn loki_range(params: &LokiQueryParams) -> Result<(i64, i64), anyhow::Error> {
+    let end = match &params.end {
+        Some(v) => loki::parse_time(v)?,
+        None => chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+    };
+    let start = match &params.start {
+        Some(v) => loki::parse_time(v)?,
+        None => end.checked_sub(3600 * 1_000_000_000).ok_or_else(|| {
+            let end = params.end.as_deref().unwrap_or_default();
+            anyhow::anyhow!("cannot parse {:?} to a valid timestamp", end)
+        })?,
+    };
+    Ok((start, end))
+}
+
+#[route("/loki/api/v1/query_range", method = "GET", method = "POST")]
+pub async fn loki_query_range(
+    app: web::Data<app::AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let mut query = req.query_string().to_owned();
+    if !body.is_empty() {
+        query.push('&');
+        query.push_str(&String::from_utf8_lossy(&body));
+    }
+    let params = match web::Query::<LokiQueryParams>::from_query(&query) {
+        Ok(v) => v.into_inner(),
+        Err(e) => return Ok(prom_error(StatusCode::BAD_REQUEST, "bad_data", e)),
+    };
+    let config = &app.config().loki;
+    let parsed = (|| {
+        let expr = LogExpr::from_str(prom_required("query", &params.query)?)?;
+        let (start, end) = loki_range(&params)?;
+        let table = loki::query_table(
+            &expr.selector().matchers,
+            config.table_label.as_deref(),
+            &config.table,
+        )?
+        .to_owned();
+        let direction = match params.direction.as_deref() {
+            None | Some("backward") => loki::eval::Direction::Backward,
+            Some("forward") => loki::eval::Direction::Forward,
+            Some(v) => return Err(anyhow::anyhow!("invalid direction {:?}", v)),
+        };
+        // like Loki, about 250 points by default and never under a second
+        let step = match &params.step {
+            Some(v) => prom::parse_step(v)?,
+            None => (end.saturating_sub(start) / 1_000_000_000 / 250).max(1) * 1000,
+        };
+        Ok::<_, anyhow::Error>((expr, start, end, table, direction, step))
+    })();
+    let (expr, start, end, table, direction, step) = match parsed {
+        Ok(v) => v,
+        Err(e) => return Ok(prom_error(StatusCode::BAD_REQUEST, "bad_data", e)),
+    };
+    let service = app.service();
+    let res = match &expr {
+        LogExpr::Log(selector) => {
+            let limit = params.limit.unwrap_or(100);
+            loki::eval::log_query(
+                service,
+                &table,
+                selector,
+                start / 1000,
+                end / 1000,
+                limit,
+                direction,
+            )
+            .await
+        }
+        expr => {
+            loki::eval::range_query(
+                service,
+                &table,
+                expr,
+                start / 1_000_000,
+                end / 1_000_000,
+                step,
+            )
+            .await
         }
+    };
+    match res {
+        Ok(v) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "status": "success",
+            "data": v.to_json(),
+        }))),
+        Err(e) => Ok(prom_error(StatusCode::UNPROCESSABLE_ENTITY, "execution", e)),
     }
 }
 
+fn loki_labels_response(res: Result<Vec<String>, anyhow::Error>) -> HttpResponse {
+    match res {
+        Ok(v) => HttpResponse::Ok().json(serde_json::json!({
+            "status": "success",
+            "data": v,
+        })),
+        Err(e) if e.is::<InvalidQuery>() => prom_error(StatusCode::BAD_REQUEST, "bad_data", e),
+        Err(e) => prom_error(StatusCode::UNPROCESSABLE_ENTITY, "execution", e),
+    }
+}
+
+// the time range and table of the label endpoints, routed like queries by
+// the selector of the optional `query` parameter
+fn loki_labels_params(
+    params: &LokiQueryParams,
+    config: &LokiConfig,
+) -> Result<(i64, i64, String), anyhow::Error> {
+    let (start, end) = loki_range(params)?;
+    let table = match &params.query {
+        Some(query) => {
+            let expr = LogExpr::from_str(query)?;
+            loki::query_table(
+                &expr.selector().matchers,
+                config.table_label.as_deref(),
+                &config.table,
+            )?
+            .to_owned()
+        }
+        None => config.table.clone(),
+    };
+    Ok((start, end, table))
+}
+
+#[get("/loki/api/v1/labels")]
+pub async fn loki_labels(
+    app: web::Data<app::AppState>,
+    params: web::Query<LokiQueryParams>,
+) -> Result<HttpResponse, Error> {
+    let (start, end, table) = match loki_labels_params(&params, &app.config().loki) {
+        Ok(v) => v,
+        Err(e) => return Ok(prom_error(StatusCode::BAD_REQUEST, "bad_data", e)),
+    };
+    let res = loki::eval::label_names(app.service(), &table, start / 1000, end / 1000).await;
+    Ok(loki_labels_response(res))
+}
+
+#[get("/loki/api/v1/label/{name}/values")]
+pub async fn loki_label_values(
+    app: web::Data<app::AppState>,
+    name: web::Path<String>,
+    params: web::Query<LokiQueryParams>,
+) -> Result<HttpResponse, Error> {
+    let (start, end, table) = match loki_labels_params(&params, &app.config().loki) {
+        Ok(v) => v,
+        Err(e) => return Ok(prom_error(StatusCode::BAD_REQUEST, "bad_data", e)),
+    };
+    let res =
+        loki::eval::label_values(app.service(), &table, &name, start / 1000, end / 1000).await;
+    Ok(loki_labels_response(res))
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Request {
     pub query: String,
@@ -53,28 +1029,287 @@ struct Request {
     pub end_time: Option<i64>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SearchParams {
+    pub format: Option<String>,
+}
+
+// the `format` parameter wins over the Accept header
+fn result_format(req: &HttpRequest, format: Option<&str>) -> Result<ResultFormat, HttpResponse> {
+    match format {
+        Some(format) => ResultFormat::from_name(format)
+            .map_err(|e| MeltResponse::error(StatusCode::BAD_REQUEST, "invalid format", e)),
+        None => {
+            let accept = req
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok());
+            Ok(ResultFormat::from_accept(accept))
+        }
+    }
+}
+
+// sends batches as they come, a failing batch ends the response early
+fn stream_response(
+    format: ResultFormat,
+    schema: SchemaRef,
+    batches: BoxStream<'static, Result<RecordBatch, anyhow::Error>>,
+) -> HttpResponse {
+    match encode_stream(format, schema, batches) {
+        Ok(chunks) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .streaming(chunks.inspect_err(|e| log::error!("Error streaming results {:?}", e))),
+        Err(e) => MeltResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "encoding failed", e),
+    }
+}
+
 #[post("/{table_name}/_search")]
 pub async fn search(
     app: web::Data<app::AppState>,
     name: web::Path<String>,
+    params: web::Query<SearchParams>,
+    req: HttpRequest,
     body: web::Bytes,
 ) -> Result<HttpResponse, Error> {
+    if !is_valid_table_name(&name) {
+        return Ok(MeltResponse::error(
+            StatusCode::BAD_REQUEST,
+            "invalid table name",
+            name.as_str(),
+        ));
+    }
     let service = app.service();
-    let req: Request = match serde_json::from_slice(&body) {
+    let request: Request = match serde_json::from_slice(&body) {
         Ok(v) => v,
         Err(e) => {
-            return Ok(HttpResponse::BadRequest().json(()));
+            return Ok(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid request",
+                e,
+            ))
         }
     };
+    let format = match result_format(&req, params.format.as_deref()) {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+
+    if format != ResultFormat::Json {
+        let res = service
+            .query_stream(
+                name.as_str(),
+                &request.query,
+                request.start_time,
+                request.end_time,
+                &[],
+            )
+            .await;
+        return match res {
+            Ok((schema, batches)) => Ok(stream_response(format, schema, batches)),
+            Err(e) => Ok(query_error(e)),
+        };
+    }
     let res = service
-        .query(name.as_str(), &req.query, req.start_time, req.end_time)
+        .query(
+            name.as_str(),
+            &request.query,
+            request.start_time,
+            request.end_time,
+        )
         .await;
 
     match res {
         Ok(v) => Ok(HttpResponse::Ok().json(v)),
+        Err(e) => Ok(query_error(e)),
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ExportRequest {
+    pub query: String,
+    pub start_time: Option<i64>,
+    pub end_time: Option<i64>,
+    pub fields: Vec<String>,
+    pub format: Option<String>,
+}
+
+// logs exports the client left before their end, dropping the response
+// stream drops the DataFusion one and stops the scan
+struct ExportGuard {
+    table: String,
+    chunks: usize,
+    done: bool,
+}
+
+impl ExportGuard {
+    fn finish(&mut self) {
+        self.done = true;
+    }
+}
+
+impl Drop for ExportGuard {
+    fn drop(&mut self) {
+        if !self.done {
+            log::info!(
+                "export of {} cancelled after {} chunks",
+                self.table,
+                self.chunks
+            );
+        }
+    }
+}
+
+fn export_stream<S>(table: String, chunks: S) -> impl Stream<Item = S::Item>
+where
+    S: Stream<Item = Result<web::Bytes, anyhow::Error>> + Unpin,
+{
+    let guard = ExportGuard {
+        table,
+        chunks: 0,
+        done: false,
+    };
+    stream::unfold((chunks, guard), |(mut chunks, mut guard)| async move {
+        match chunks.next().await {
+            Some(chunk) => {
+                // a failed chunk ends the response, it is not cancelled
+                guard.done = chunk.is_err();
+                guard.chunks += 1;
+                Some((chunk, (chunks, guard)))
+            }
+            None => {
+                guard.finish();
+                None
+            }
+        }
+    })
+}
+
+#[post("/{table_name}/_export")]
+pub async fn export(
+    app: web::Data<app::AppState>,
+    name: web::Path<String>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let name = name.into_inner();
+    if !is_valid_table_name(&name) {
+        return Ok(MeltResponse::error(
+            StatusCode::BAD_REQUEST,
+            "invalid table name",
+            name,
+        ));
+    }
+    let request: ExportRequest = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid request",
+                e,
+            ))
+        }
+    };
+    let format = match result_format(&req, request.format.as_deref()) {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    // a JSON document can not be streamed, exports default to its lines
+    let format = match (format, &request.format) {
+        (ResultFormat::Json, Some(_)) => {
+            return Ok(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid format",
+                "exports are ndjson, csv, arrow or parquet",
+            ))
+        }
+        (ResultFormat::Json, None) => ResultFormat::Ndjson,
+        (format, _) => format,
+    };
+
+    let res = app
+        .service()
+        .query_stream(
+            &name,
+            &request.query,
+            request.start_time,
+            request.end_time,
+            &request.fields,
+        )
+        .await;
+    let (schema, batches) = match res {
+        Ok(v) => v,
+        Err(e) => return Ok(query_error(e)),
+    };
+    let chunks = match encode_stream(format, schema, batches) {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(MeltResponse::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "encoding failed",
+                e,
+            ))
+        }
+    };
+    let chunks = chunks
+        .inspect_err(|e| log::error!("Error streaming results {:?}", e))
+        .boxed();
+    let disposition = format!("attachment; filename=\"{}.{}\"", name, format.extension());
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::CONTENT_DISPOSITION, disposition))
+        .streaming(export_stream(name, chunks)))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TraceParams {
+    pub start_time: Option<i64>,
+    pub end_time: Option<i64>,
+}
+
+#[get("/{table_name}/_trace/{trace_id}")]
+pub async fn trace(
+    app: web::Data<app::AppState>,
+    path: web::Path<(String, String)>,
+    params: web::Query<TraceParams>,
+) -> Result<HttpResponse, Error> {
+    let (table_name, trace_id) = path.into_inner();
+    if !is_valid_table_name(&table_name) {
+        return Ok(MeltResponse::error(
+            StatusCode::BAD_REQUEST,
+            "invalid table name",
+            table_name,
+        ));
+    }
+    // ids are stored with 16 or 32 lower hex digits
+    let trace_id = match jaeger::normalize_trace_id(&trace_id) {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(MeltResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid trace id",
+                e,
+            ))
+        }
+    };
+    let res = app
+        .service()
+        .get_trace(&table_name, &trace_id, params.start_time, params.end_time)
+        .await;
+    match res {
+        Ok(Some(v)) => Ok(HttpResponse::Ok().json(v)),
+        Ok(None) => Ok(MeltResponse::error(
+            StatusCode::NOT_FOUND,
+            "trace not found",
+            trace_id,
+        )),
         Err(e) => {
             log::error!("Error process request {:?}", e);
-            Ok(HttpResponse::BadRequest().json(()))
+            Ok(MeltResponse::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "query failed",
+                e,
+            ))
         }
     }
 }
@@ -91,10 +1326,21 @@ mod tests {
 
     use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaBuilder};
 
-    use super::Request;
+    use actix_web::http::StatusCode;
+    use anyhow::anyhow;
+
+    use super::{
+        ingest_status, loki_labels_params, query_status, rpc_code, ExportRequest, LokiQueryParams,
+        Request,
+    };
+    use crate::{
+        config::LokiConfig,
+        ingest::{InvalidQuery, InvalidRecords},
+    };
 
     // use super::*;
 
+    #[allow(dead_code)]
     fn test_schema() -> Arc<Schema> {
         let field_a = Field::new("a", DataType::Int64, false);
         let field_b = Field::new("b", DataType::Boolean, false);
@@ -110,5 +1356,49 @@ mod tests {
         assert_eq!(req.query, "a=b");
         assert_eq!(req.start_time, Some(2323));
         assert_eq!(req.end_time, None);
+
+        let data = r#"{"fields":["msg"], "format":"csv"}"#;
+        let req: ExportRequest = serde_json::from_slice(data.as_bytes()).unwrap();
+        assert_eq!(req.query, "");
+        assert_eq!(req.fields, vec!["msg"]);
+        assert_eq!(req.format.as_deref(), Some("csv"));
+    }
+
+    #[test]
+    fn test_ingest_status() {
+        let invalid = InvalidRecords::wrap(anyhow!("not support array or object"));
+        assert_eq!(ingest_status(&invalid), StatusCode::BAD_REQUEST);
+        let failed = anyhow!("No space left on device");
+        assert_eq!(ingest_status(&failed), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let invalid = InvalidQuery::wrap(anyhow!("unexpected end of query"));
+        assert_eq!(query_status(&invalid), StatusCode::BAD_REQUEST);
+        assert_eq!(query_status(&failed), StatusCode::INTERNAL_SERVER_ERROR);
+
+        assert_eq!(rpc_code(StatusCode::BAD_REQUEST), 3);
+        assert_eq!(rpc_code(StatusCode::INTERNAL_SERVER_ERROR), 13);
+        assert_eq!(rpc_code(StatusCode::SERVICE_UNAVAILABLE), 14);
+    }
+
+    #[test]
+    fn test_loki_labels_params() {
+        let config = LokiConfig {
+            table: "loki".to_owned(),
+            table_label: Some("tenant".to_owned()),
+        };
+        let params = |query: Option<&str>| LokiQueryParams {
+            query: query.map(str::to_owned),
+            start: Some("0".to_owned()),
+            end: Some("60".to_owned()),
+            limit: None,
+            direction: None,
+            step: None,
+        };
+        let table = |query| loki_labels_params(&params(query), &config).map(|v| v.2);
+        assert_eq!(table(None).unwrap(), "loki");
+        assert_eq!(table(Some(r#"{app="api"}"#)).unwrap(), "loki");
+        assert_eq!(table(Some(r#"{tenant="acme"}"#)).unwrap(), "acme");
+        assert!(table(Some(r#"{tenant="../x"}"#)).is_err());
+        assert!(table(Some("{")).is_err());
     }
 }
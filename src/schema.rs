@@ -30,7 +30,7 @@ impl MeltSchema {
 }
 
 pub fn infer_schema(vals: &[Value]) -> Result<MeltSchema, anyhow::Error> {
-    let schema = schema::infer_schema(&vals)?;
+    let schema = schema::infer_schema(vals)?;
     Ok(MeltSchema {
         schema: Arc::new(schema),
     })
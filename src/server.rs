@@ -1,19 +1,45 @@
 use crate::*;
 use actix_web::{web, App, HttpServer};
 
-pub async fn start_server(addr: &str, port: u16, root: &str) -> std::io::Result<()> {
-    let root = root.to_owned();
-    let service = web::Data::new(app::AppState::new("openmelt", &root));
+pub async fn start_server(config: config::Config) -> std::io::Result<()> {
+    let service = web::Data::new(app::AppState::new("openmelt", &config));
+    syslog::start(service.clone(), &config.syslog)?;
+    fluent::start(service.clone(), &config.fluent)?;
+    flight::start(service.clone(), &config.flight)?;
+    pg::start(service.clone(), &config.postgres)?;
+    tail::start(service.clone(), &config.tail)?;
     HttpServer::new(move || {
         App::new()
-            .app_data(web::PayloadConfig::new(1024 * 1024 * 10))
+            .app_data(web::PayloadConfig::new(config::MAX_PAYLOAD_SIZE))
             .app_data(service.clone())
             .service(router::status)
             .service(router::bulk)
+            .service(router::csv_import)
+            .service(router::arrow_import)
+            .service(router::parquet_import)
             .service(router::search)
+            .service(router::export)
+            .service(router::trace)
             .service(router::injest)
+            .service(router::otlp_logs)
+            .service(router::otlp_traces)
+            .service(router::otlp_metrics)
+            .service(router::prom_write)
+            .service(router::prom_query)
+            .service(router::prom_query_range)
+            .service(router::loki_push)
+            .service(router::loki_query_range)
+            .service(router::loki_labels)
+            .service(router::loki_label_values)
+            .service(router::influx_write_v1)
+            .service(router::influx_write_v2)
+            .service(router::zipkin_spans)
+            .service(router::jaeger_services)
+            .service(router::jaeger_operations)
+            .service(router::jaeger_find_traces)
+            .service(router::jaeger_trace)
     })
-    .bind((addr, port))?
+    .bind((config.addr.as_str(), config.port))?
     .run()
     .await
 }
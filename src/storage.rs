@@ -2,6 +2,15 @@ use std::path::{Path, PathBuf};
 
 use bytes::Bytes;
 
+/// Table names end up in storage paths, names taken from requests are
+/// limited to `[a-zA-Z0-9_-]`.
+pub fn is_valid_table_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
 pub struct Storage {
     root: PathBuf,
 }
@@ -20,6 +29,15 @@ impl Storage {
         Ok(())
     }
 
+    /// Removes a file, one that does not exist is already removed.
+    pub async fn delete(&self, filename: &str) -> Result<(), anyhow::Error> {
+        let path = self.root.join(filename);
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
     pub fn ensure_dir(&self, name: &str) -> Result<(), anyhow::Error> {
         let path = self.root.join(name);
         std::fs::create_dir_all(path)?;
@@ -0,0 +1,205 @@
+//! Syslog listeners. Messages received over TCP or UDP are parsed and
+//! handed to one batcher per server, which writes them out like a
+//! streaming HTTP ingest.
+
+pub mod parser;
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::web;
+use anyhow::anyhow;
+use bytes::{Buf, Bytes, BytesMut};
+use serde_json::Value;
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+};
+
+use crate::{app::AppState, config::SyslogConfig, utils::net};
+
+// longest message accepted on either transport
+pub static MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// messages waiting for the batcher before the listeners slow down
+static CHANNEL_SIZE: usize = 8192;
+
+/// Splits the next message off a TCP stream buffer. Octet counted frames,
+/// `LEN SP MSG`, start with a digit, anything else runs up to a newline.
+pub fn next_frame(buf: &mut BytesMut) -> Result<Option<Bytes>, anyhow::Error> {
+    // stray newlines between frames
+    while buf.first().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+        buf.advance(1);
+    }
+    match buf.first() {
+        None => Ok(None),
+        Some(b) if b.is_ascii_digit() => {
+            let Some(space) = buf.iter().position(|b| *b == b' ') else {
+                if buf.len() > 10 {
+                    return Err(anyhow!("invalid frame length"));
+                }
+                return Ok(None);
+            };
+            let len: usize = std::str::from_utf8(&buf[..space])
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| anyhow!("invalid frame length"))?;
+            if len > MAX_MESSAGE_SIZE {
+                return Err(anyhow!("frame of {} bytes is too large", len));
+            }
+            if buf.len() < space + 1 + len {
+                return Ok(None);
+            }
+            buf.advance(space + 1);
+            Ok(Some(buf.split_to(len).freeze()))
+        }
+        Some(_) => match buf.iter().position(|b| *b == b'\n') {
+            Some(end) => {
+                let frame = buf.split_to(end).freeze();
+                buf.advance(1);
+                Ok(Some(frame))
+            }
+            None if buf.len() > MAX_MESSAGE_SIZE => Err(anyhow!("message is too large")),
+            None => Ok(None),
+        },
+    }
+}
+
+fn to_record(frame: &[u8]) -> Option<Value> {
+    let received = chrono::Utc::now().timestamp_micros();
+    match parser::parse(&String::from_utf8_lossy(frame)) {
+        Ok(m) => Some(m.to_record(received)),
+        Err(e) => {
+            log::warn!("dropping syslog message: {}", e);
+            None
+        }
+    }
+}
+
+async fn serve_connection(
+    mut stream: TcpStream,
+    sender: mpsc::Sender<Value>,
+) -> Result<(), anyhow::Error> {
+    let mut buf = BytesMut::with_capacity(8192);
+    loop {
+        while let Some(frame) = next_frame(&mut buf)? {
+            if let Some(record) = to_record(&frame) {
+                sender.send(record).await?;
+            }
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            // a last newline framed message may come without its newline
+            if !buf.is_empty() {
+                if let Some(record) = to_record(&buf) {
+                    sender.send(record).await?;
+                }
+            }
+            return Ok(());
+        }
+    }
+}
+
+async fn serve_tcp(
+    listener: std::net::TcpListener,
+    sender: mpsc::Sender<Value>,
+) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::from_std(listener)?;
+    loop {
+        let (stream, peer) = net::accept(&listener, "syslog").await;
+        let sender = sender.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = serve_connection(stream, sender).await {
+                log::warn!("syslog connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve_udp(
+    socket: std::net::UdpSocket,
+    sender: mpsc::Sender<Value>,
+) -> Result<(), anyhow::Error> {
+    let socket = UdpSocket::from_std(socket)?;
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+    loop {
+        // errors are about a single datagram, like one too large for the
+        // buffer or an ICMP error of an earlier send, the next can be read
+        let len = match socket.recv_from(&mut buf).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                log::warn!("syslog udp listener failed to receive: {}", e);
+                continue;
+            }
+        };
+        if let Some(record) = to_record(&buf[..len]) {
+            sender.send(record).await?;
+        }
+    }
+}
+
+/// Binds the configured sockets and starts the batcher, does nothing when
+/// neither address is set.
+pub fn start(app: web::Data<AppState>, config: &SyslogConfig) -> std::io::Result<()> {
+    if config.tcp_addr.is_none() && config.udp_addr.is_none() {
+        return Ok(());
+    }
+    let (sender, receiver) = mpsc::channel(CHANNEL_SIZE);
+    if let Some(addr) = &config.tcp_addr {
+        let listener = net::bind_tcp(addr, "syslog tcp")?;
+        let sender = sender.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = serve_tcp(listener, sender).await {
+                log::error!("syslog tcp listener stopped: {}", e);
+            }
+        });
+    }
+    // bound before returning so a taken port fails the startup
+    if let Some(addr) = &config.udp_addr {
+        let socket = std::net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        log::info!("syslog udp listening on {}", addr);
+        let sender = sender.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = serve_udp(socket, sender).await {
+                log::error!("syslog udp listener stopped: {}", e);
+            }
+        });
+    }
+    let app: Arc<AppState> = app.into_inner();
+    let table = config.table.clone();
+    let interval = Duration::from_millis(config.flush_interval_ms.max(1));
+    actix_web::rt::spawn(async move {
+        app.service()
+            .ingest_channel(&table, receiver, interval)
+            .await
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_frame() {
+        let mut buf = BytesMut::from(&b"11 <13>1 - - -\n<13>plain line\n<13>partial"[..]);
+        assert_eq!(next_frame(&mut buf).unwrap().unwrap(), &b"<13>1 - - -"[..]);
+        assert_eq!(
+            next_frame(&mut buf).unwrap().unwrap(),
+            &b"<13>plain line"[..]
+        );
+        assert_eq!(next_frame(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"<13>partial");
+
+        // an octet counted frame waits for all of its bytes
+        let mut buf = BytesMut::from(&b"20 <13>1 short"[..]);
+        assert_eq!(next_frame(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b" message\n");
+        assert_eq!(
+            next_frame(&mut buf).unwrap().unwrap(),
+            &b"<13>1 short message\n"[..]
+        );
+
+        let mut buf = BytesMut::from(&b"999999999 <13>"[..]);
+        assert!(next_frame(&mut buf).is_err());
+    }
+}
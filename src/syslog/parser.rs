@@ -0,0 +1,354 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, NaiveDateTime, TimeZone, Utc};
+use serde_json::{Map, Value};
+
+use crate::config::TIMPSTAMP_FIELD_NAME;
+
+static FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+static SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+/// A syslog message, nil and missing parts are None.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Message {
+    pub facility: u8,
+    pub severity: u8,
+    // microseconds since the epoch
+    pub timestamp: Option<i64>,
+    pub hostname: Option<String>,
+    pub appname: Option<String>,
+    pub procid: Option<String>,
+    pub msgid: Option<String>,
+    // SD-ID to its params, in message order
+    pub structured_data: Vec<(String, Vec<(String, String)>)>,
+    pub message: String,
+}
+
+impl Message {
+    /// The message as a record, the time of receipt stands in for a
+    /// missing timestamp.
+    pub fn to_record(&self, received: i64) -> Value {
+        let mut record = Map::new();
+        record.insert(
+            TIMPSTAMP_FIELD_NAME.to_owned(),
+            Value::from(self.timestamp.unwrap_or(received)),
+        );
+        let facility = FACILITIES.get(self.facility as usize).copied();
+        record.insert("facility".to_owned(), Value::from(facility.unwrap_or("")));
+        record.insert(
+            "severity".to_owned(),
+            Value::from(SEVERITIES[self.severity as usize % 8]),
+        );
+        for (name, value) in [
+            ("hostname", &self.hostname),
+            ("appname", &self.appname),
+            ("procid", &self.procid),
+            ("msgid", &self.msgid),
+        ] {
+            if let Some(v) = value {
+                record.insert(name.to_owned(), Value::from(v.as_str()));
+            }
+        }
+        // kept as JSON text, SD-IDs vary too much to be columns
+        if !self.structured_data.is_empty() {
+            let sd: Map<String, Value> = self
+                .structured_data
+                .iter()
+                .map(|(id, params)| {
+                    let params: Map<String, Value> = params
+                        .iter()
+                        .map(|(k, v)| (k.clone(), Value::from(v.as_str())))
+                        .collect();
+                    (id.clone(), Value::Object(params))
+                })
+                .collect();
+            record.insert(
+                "structured_data".to_owned(),
+                Value::from(Value::Object(sd).to_string()),
+            );
+        }
+        record.insert("message".to_owned(), Value::from(self.message.as_str()));
+        Value::Object(record)
+    }
+}
+
+/// Parses an RFC 5424 message, or an RFC 3164 one when the version is
+/// missing after the priority.
+pub fn parse(line: &str) -> Result<Message, anyhow::Error> {
+    let line = line.trim_end_matches(['\r', '\n', '\0']);
+    let rest = line
+        .strip_prefix('<')
+        .ok_or_else(|| anyhow!("missing priority"))?;
+    let end = rest
+        .find('>')
+        .filter(|&i| (1..=3).contains(&i))
+        .ok_or_else(|| anyhow!("invalid priority"))?;
+    let pri: u8 = rest[..end]
+        .parse()
+        .ok()
+        .filter(|&p| p < 192)
+        .ok_or_else(|| anyhow!("invalid priority"))?;
+    let rest = &rest[end + 1..];
+    let mut message = match rest.strip_prefix("1 ") {
+        Some(rest) => parse_5424(rest)?,
+        None => parse_3164(rest, Utc::now()),
+    };
+    message.facility = pri / 8;
+    message.severity = pri % 8;
+    Ok(message)
+}
+
+fn nil(v: &str) -> Option<String> {
+    (v != "-").then(|| v.to_owned())
+}
+
+// splits off the next space separated header field
+fn field(s: &str) -> Result<(&str, &str), anyhow::Error> {
+    match s.split_once(' ') {
+        Some((f, rest)) if !f.is_empty() => Ok((f, rest)),
+        None if !s.is_empty() => Ok((s, "")),
+        _ => Err(anyhow!("truncated header")),
+    }
+}
+
+fn parse_5424(s: &str) -> Result<Message, anyhow::Error> {
+    let (timestamp, s) = field(s)?;
+    let timestamp = match timestamp {
+        "-" => None,
+        v => Some(
+            DateTime::parse_from_rfc3339(v)
+                .map_err(|_| anyhow!("invalid timestamp {:?}", v))?
+                .timestamp_micros(),
+        ),
+    };
+    let (hostname, s) = field(s)?;
+    let (appname, s) = field(s)?;
+    let (procid, s) = field(s)?;
+    let (msgid, s) = field(s)?;
+    let (structured_data, s) = parse_structured_data(s)?;
+    let s = s.strip_prefix(' ').unwrap_or(s);
+    Ok(Message {
+        timestamp,
+        hostname: nil(hostname),
+        appname: nil(appname),
+        procid: nil(procid),
+        msgid: nil(msgid),
+        structured_data,
+        message: s.trim_start_matches('\u{feff}').to_owned(),
+        ..Default::default()
+    })
+}
+
+type StructuredData = Vec<(String, Vec<(String, String)>)>;
+
+fn parse_structured_data(s: &str) -> Result<(StructuredData, &str), anyhow::Error> {
+    if let Some(rest) = s.strip_prefix('-') {
+        return Ok((vec![], rest));
+    }
+    let mut elements = vec![];
+    let mut rest = s;
+    while let Some(element) = rest.strip_prefix('[') {
+        let id_end = element
+            .find([' ', ']'])
+            .ok_or_else(|| anyhow!("unterminated structured data"))?;
+        let id = &element[..id_end];
+        let mut params = vec![];
+        let mut s = &element[id_end..];
+        loop {
+            s = s.trim_start_matches(' ');
+            if let Some(after) = s.strip_prefix(']') {
+                s = after;
+                break;
+            }
+            let (name, after) = s
+                .split_once("=\"")
+                .ok_or_else(|| anyhow!("invalid structured data param"))?;
+            // values escape `"`, `\` and `]` with a backslash
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c @ ('"' | '\\' | ']'))) => value.push(c),
+                        Some((_, c)) => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                        None => return Err(anyhow!("unterminated structured data")),
+                    },
+                    Some((_, c)) => value.push(c),
+                    None => return Err(anyhow!("unterminated structured data")),
+                }
+            };
+            params.push((name.to_owned(), value));
+            s = &after[end + 1..];
+        }
+        elements.push((id.to_owned(), params));
+        rest = s;
+    }
+    if elements.is_empty() {
+        return Err(anyhow!("invalid structured data"));
+    }
+    Ok((elements, rest))
+}
+
+// BSD syslog has no year or zone, the time is taken as UTC in the year
+// that puts it closest to `now`
+fn parse_3164_timestamp(s: &str, now: DateTime<Utc>) -> Option<i64> {
+    let with_year = format!(
+        "{} {}",
+        now.year(),
+        s.split_whitespace().collect::<Vec<_>>().join(" ")
+    );
+    let t = NaiveDateTime::parse_from_str(&with_year, "%Y %b %d %H:%M:%S").ok()?;
+    let t = Utc.from_utc_datetime(&t);
+    let t = if t > now + chrono::Duration::days(1) {
+        t.with_year(now.year() - 1)?
+    } else {
+        t
+    };
+    Some(t.timestamp_micros())
+}
+
+fn parse_3164(s: &str, now: DateTime<Utc>) -> Message {
+    let mut message = Message::default();
+    // `Mmm dd hh:mm:ss`, days below 10 are padded with a space
+    let Some(timestamp) = s.get(..15).and_then(|t| parse_3164_timestamp(t, now)) else {
+        message.message = s.to_owned();
+        return message;
+    };
+    message.timestamp = Some(timestamp);
+    let s = s[15..].trim_start_matches(' ');
+    let (hostname, s) = s.split_once(' ').unwrap_or((s, ""));
+    message.hostname = Some(hostname.to_owned());
+
+    // TAG is alphanumeric and ends at `[pid]:` or `:`
+    let tag_end = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || "-_./".contains(c)))
+        .unwrap_or(s.len());
+    let (tag, after) = s.split_at(tag_end);
+    let (procid, after) = match after.strip_prefix('[').and_then(|a| a.split_once(']')) {
+        Some((pid, after)) => (Some(pid.to_owned()), after),
+        None => (None, after),
+    };
+    match after.strip_prefix(':') {
+        Some(msg) if !tag.is_empty() => {
+            message.appname = Some(tag.to_owned());
+            message.procid = procid;
+            message.message = msg.trim_start_matches(' ').to_owned();
+        }
+        _ => message.message = s.to_owned(),
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_5424() {
+        let line = r#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog 42 ID47 [exampleSDID@32473 iut="3" eventSource="Appl\"ication\]"][meta sequenceId="1"] An application event"#;
+        let m = parse(line).unwrap();
+        assert_eq!(m.facility, 20);
+        assert_eq!(m.severity, 5);
+        assert_eq!(m.timestamp, Some(1_065_910_455_003_000));
+        assert_eq!(m.hostname.as_deref(), Some("mymachine.example.com"));
+        assert_eq!(m.appname.as_deref(), Some("evntslog"));
+        assert_eq!(m.procid.as_deref(), Some("42"));
+        assert_eq!(m.msgid.as_deref(), Some("ID47"));
+        assert_eq!(m.structured_data.len(), 2);
+        assert_eq!(
+            m.structured_data[0].1[1],
+            ("eventSource".to_owned(), "Appl\"ication]".to_owned())
+        );
+        assert_eq!(m.message, "An application event");
+
+        let record = m.to_record(0);
+        assert_eq!(record["facility"], "local4");
+        assert_eq!(record["severity"], "notice");
+        assert_eq!(record["timestamp"], 1_065_910_455_003_000i64);
+        let sd: Value = serde_json::from_str(record["structured_data"].as_str().unwrap()).unwrap();
+        assert_eq!(sd["meta"]["sequenceId"], "1");
+
+        let m = parse("<34>1 - - - - - -").unwrap();
+        assert_eq!(m.timestamp, None);
+        assert_eq!(m.hostname, None);
+        assert_eq!(m.message, "");
+        let record = m.to_record(7);
+        assert_eq!(record["timestamp"], 7);
+        assert!(record.get("hostname").is_none());
+
+        assert!(parse("<34>1 2003-10-11 host").is_err());
+        assert!(parse("<34>1 - h a p m [x").is_err());
+        assert!(parse("34 hello").is_err());
+        assert!(parse("<999>hello").is_err());
+    }
+
+    #[test]
+    fn test_parse_3164() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 12, 0, 0, 0).unwrap();
+        let m = parse_3164("Oct 11 22:14:15 mymachine su[123]: 'su root' failed", now);
+        assert_eq!(
+            m.timestamp,
+            Some(
+                Utc.with_ymd_and_hms(2023, 10, 11, 22, 14, 15)
+                    .unwrap()
+                    .timestamp_micros()
+            )
+        );
+        assert_eq!(m.hostname.as_deref(), Some("mymachine"));
+        assert_eq!(m.appname.as_deref(), Some("su"));
+        assert_eq!(m.procid.as_deref(), Some("123"));
+        assert_eq!(m.message, "'su root' failed");
+
+        // a december message received in january is from last year
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 5).unwrap();
+        let m = parse_3164("Dec 31 23:59:59 host cron: job", now);
+        assert_eq!(
+            m.timestamp,
+            Some(
+                Utc.with_ymd_and_hms(2023, 12, 31, 23, 59, 59)
+                    .unwrap()
+                    .timestamp_micros()
+            )
+        );
+        let m = parse_3164("Jan  5 01:02:03 host kernel panic", now);
+        assert_eq!(m.appname, None);
+        assert_eq!(m.message, "kernel panic");
+
+        let m = parse("<13>free form text").unwrap();
+        assert_eq!(m.facility, 1);
+        assert_eq!(m.severity, 5);
+        assert_eq!(m.timestamp, None);
+        assert_eq!(m.message, "free form text");
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+
+// bytes read from one file per poll, so a large backlog is spread out
+static MAX_READ_SIZE: u64 = 4 * 1024 * 1024;
+// a line without a newline this long is taken as complete
+static MAX_LINE_SIZE: usize = 1024 * 1024;
+
+/// Identifies the file behind a path, a new id after a rename means it
+/// was rotated.
+#[cfg(unix)]
+pub fn file_id(meta: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.ino()
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_meta: &std::fs::Metadata) -> u64 {
+    0
+}
+
+/// Joins lines into entries: with a start pattern, lines that do not
+/// match it belong to the entry before them.
+pub struct LineJoiner {
+    start: Option<Regex>,
+    pending: Option<String>,
+    // bytes of the pending entry in the file
+    pending_len: u64,
+}
+
+impl LineJoiner {
+    pub fn new(start: Option<Regex>) -> LineJoiner {
+        LineJoiner {
+            start,
+            pending: None,
+            pending_len: 0,
+        }
+    }
+
+    /// Adds a line of `len` bytes, returns the entry it completes.
+    pub fn push(&mut self, line: String, len: u64) -> Option<String> {
+        let Some(start) = &self.start else {
+            return Some(line);
+        };
+        if let Some(pending) = &mut self.pending {
+            if !start.is_match(&line) {
+                pending.push('\n');
+                pending.push_str(&line);
+                self.pending_len += len;
+                return None;
+            }
+        }
+        let done = self.flush();
+        self.pending = Some(line);
+        self.pending_len = len;
+        done
+    }
+
+    pub fn flush(&mut self) -> Option<String> {
+        self.pending_len = 0;
+        self.pending.take()
+    }
+}
+
+/// One tailed file. Entries are read from the handle opened first, so
+/// the end of a renamed file is still read before its successor.
+pub struct TailedFile {
+    path: PathBuf,
+    file: File,
+    id: u64,
+    // position of the handle
+    pos: u64,
+    // bytes after the last newline
+    partial: Vec<u8>,
+    joiner: LineJoiner,
+}
+
+impl TailedFile {
+    /// Opens `path` at `offset`, from the start when the file is shorter.
+    pub fn open(path: &Path, offset: u64, start: Option<Regex>) -> Result<TailedFile, io::Error> {
+        let mut file = File::open(path)?;
+        let meta = file.metadata()?;
+        let pos = if offset <= meta.len() { offset } else { 0 };
+        file.seek(SeekFrom::Start(pos))?;
+        Ok(TailedFile {
+            path: path.to_owned(),
+            id: file_id(&meta),
+            file,
+            pos,
+            partial: vec![],
+            joiner: LineJoiner::new(start),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// End of the last entry handed out, where a restart resumes.
+    pub fn offset(&self) -> u64 {
+        self.pos - self.partial.len() as u64 - self.joiner.pending_len
+    }
+
+    /// Reads what was appended since the last call and adds the entries
+    /// it completes. A joined entry ends once a poll finds nothing new.
+    pub fn read(&mut self, entries: &mut Vec<String>) -> Result<(), io::Error> {
+        if self.file.metadata()?.len() < self.pos {
+            // truncated in place, the rest of the old content is gone
+            self.file.seek(SeekFrom::Start(0))?;
+            self.pos = 0;
+            self.partial.clear();
+            entries.extend(self.joiner.flush());
+        }
+        let mut buf = vec![];
+        let n = (&mut self.file).take(MAX_READ_SIZE).read_to_end(&mut buf)?;
+        self.pos += n as u64;
+        if n == 0 {
+            entries.extend(self.joiner.flush());
+            return Ok(());
+        }
+        self.partial.extend_from_slice(&buf);
+
+        let mut start = 0;
+        while let Some(end) = self.partial[start..].iter().position(|b| *b == b'\n') {
+            let line = &self.partial[start..start + end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let line = String::from_utf8_lossy(line).into_owned();
+            entries.extend(self.joiner.push(line, end as u64 + 1));
+            start += end + 1;
+        }
+        self.partial.drain(..start);
+        if self.partial.len() > MAX_LINE_SIZE {
+            self.take_partial(entries);
+        }
+        Ok(())
+    }
+
+    /// Reads the file to its end and hands out everything left, for a
+    /// file that was rotated away or deleted.
+    pub fn finish(&mut self, entries: &mut Vec<String>) -> Result<(), io::Error> {
+        loop {
+            let pos = self.pos;
+            self.read(entries)?;
+            if self.pos == pos {
+                break;
+            }
+        }
+        self.take_partial(entries);
+        entries.extend(self.joiner.flush());
+        Ok(())
+    }
+
+    fn take_partial(&mut self, entries: &mut Vec<String>) {
+        if self.partial.is_empty() {
+            return;
+        }
+        let line = String::from_utf8_lossy(&self.partial).into_owned();
+        let len = self.partial.len() as u64;
+        self.partial.clear();
+        entries.extend(self.joiner.push(line, len));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn append(path: &Path, data: &str) {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    fn read(file: &mut TailedFile) -> Vec<String> {
+        let mut entries = vec![];
+        file.read(&mut entries).unwrap();
+        entries
+    }
+
+    #[test]
+    fn test_tailed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        append(&path, "one\r\ntw");
+        let mut file = TailedFile::open(&path, 0, None).unwrap();
+        assert_eq!(read(&mut file), vec!["one"]);
+        assert_eq!(file.offset(), 5);
+        append(&path, "o\nthree\n");
+        assert_eq!(read(&mut file), vec!["two", "three"]);
+        assert_eq!(file.offset(), 15);
+
+        // a restart resumes at the saved offset
+        let mut file = TailedFile::open(&path, 5, None).unwrap();
+        assert_eq!(read(&mut file), vec!["two", "three"]);
+
+        std::fs::write(&path, "new\n").unwrap();
+        assert_eq!(read(&mut file), vec!["new"]);
+
+        // rotated by rename: the old handle still reads the old file
+        append(&path, "last\npart");
+        std::fs::rename(&path, dir.path().join("app.log.1")).unwrap();
+        append(&path, "fresh\n");
+        let mut entries = vec![];
+        file.finish(&mut entries).unwrap();
+        assert_eq!(entries, vec!["last", "part"]);
+        let mut file = TailedFile::open(&path, 0, None).unwrap();
+        assert_eq!(read(&mut file), vec!["fresh"]);
+    }
+
+    #[test]
+    fn test_multiline() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        append(
+            &path,
+            "2024-01-01 error\n  at a()\n  at b()\n2024-01-01 ok\n",
+        );
+        let start = Regex::new(r"^\d{4}-").unwrap();
+        let mut file = TailedFile::open(&path, 0, Some(start)).unwrap();
+        assert_eq!(
+            read(&mut file),
+            vec!["2024-01-01 error\n  at a()\n  at b()"]
+        );
+        // the pending entry is not committed yet
+        assert_eq!(file.offset(), 35);
+        append(&path, "  at c()\n");
+        assert!(read(&mut file).is_empty());
+        // nothing new for a poll ends the entry
+        assert_eq!(read(&mut file), vec!["2024-01-01 ok\n  at c()"]);
+        assert_eq!(file.offset(), 58);
+    }
+}
@@ -0,0 +1,371 @@
+//! File inputs. Files matching the configured globs are polled for new
+//! lines, which are written to the input's table after every poll. Read
+//! offsets are saved under the storage root, so a restart resumes where
+//! the last written entry ended.
+
+pub mod file;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use actix_web::web;
+use anyhow::anyhow;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use self::file::{file_id, TailedFile};
+use crate::{
+    app::AppState,
+    config::{LineFormat, TailConfig, TailInput, TIMPSTAMP_FIELD_NAME},
+    loki::MESSAGE_FIELD_NAME,
+    storage::is_valid_table_name,
+    utils::json::flatten_json,
+};
+
+// offsets of every tailed file, relative to the storage root
+pub static OFFSETS_FILE: &str = "_tail_offsets.json";
+// path of the file a line came from
+pub static FILE_PATH_FIELD_NAME: &str = "log.file.path";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileOffset {
+    pub id: u64,
+    pub offset: u64,
+}
+
+/// A JSON line keeps its fields, nested ones flattened into dotted names,
+/// anything else becomes the `message`.
+pub fn line_to_record(
+    format: LineFormat,
+    line: &str,
+    path: &str,
+    received: i64,
+) -> Result<Value, anyhow::Error> {
+    let parsed = match format {
+        LineFormat::Json => serde_json::from_str::<Map<String, Value>>(line).ok(),
+        LineFormat::Text => None,
+    };
+    let mut record = match parsed {
+        Some(mut map) => {
+            if let Some(v) = map.remove(TIMPSTAMP_FIELD_NAME) {
+                map.insert(format!("exported_{}", TIMPSTAMP_FIELD_NAME), v);
+            }
+            map
+        }
+        None => {
+            let mut map = Map::new();
+            map.insert(MESSAGE_FIELD_NAME.to_owned(), Value::from(line));
+            map
+        }
+    };
+    record.insert(TIMPSTAMP_FIELD_NAME.to_owned(), Value::from(received));
+    record.insert(FILE_PATH_FIELD_NAME.to_owned(), Value::from(path));
+    flatten_json(&Value::Object(record))
+}
+
+/// The files of one input.
+pub struct Tailer {
+    input: TailInput,
+    start: Option<Regex>,
+    files: HashMap<PathBuf, TailedFile>,
+}
+
+impl Tailer {
+    pub fn new(input: TailInput) -> Result<Tailer, anyhow::Error> {
+        if !is_valid_table_name(&input.table) {
+            return Err(anyhow!("invalid table name {:?}", input.table));
+        }
+        for pattern in &input.paths {
+            glob::Pattern::new(pattern)?;
+        }
+        let start = input
+            .multiline_start
+            .as_deref()
+            .map(Regex::new)
+            .transpose()?;
+        Ok(Tailer {
+            input,
+            start,
+            files: HashMap::new(),
+        })
+    }
+
+    pub fn table(&self) -> &str {
+        &self.input.table
+    }
+
+    fn matched_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self
+            .input
+            .paths
+            .iter()
+            .filter_map(|p| glob::glob(p).ok())
+            .flat_map(|paths| paths.flatten())
+            .filter(|p| p.is_file())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Reads every file once and returns the records of the entries it
+    /// completed. Files first seen resume at their saved offset when they
+    /// are still the same file, so after a `rewind` the entries since the
+    /// saved offsets are read again.
+    pub fn poll(&mut self, offsets: &BTreeMap<String, FileOffset>, received: i64) -> Vec<Value> {
+        let mut lines: Vec<(String, Vec<String>)> = vec![];
+        let mut gone = vec![];
+        for (path, file) in self.files.iter_mut() {
+            let mut entries = vec![];
+            let rotated = match std::fs::metadata(path) {
+                Ok(meta) => file_id(&meta) != file.id(),
+                Err(_) => true,
+            };
+            let res = if rotated {
+                gone.push(path.clone());
+                file.finish(&mut entries)
+            } else {
+                file.read(&mut entries)
+            };
+            if let Err(e) = res {
+                log::warn!("reading {}: {}", path.display(), e);
+                gone.push(path.clone());
+            }
+            lines.push((path.display().to_string(), entries));
+        }
+        for path in gone {
+            self.files.remove(&path);
+        }
+
+        for path in self.matched_paths() {
+            if self.files.contains_key(&path) {
+                continue;
+            }
+            let key = path.display().to_string();
+            let offset = match (offsets.get(&key), std::fs::metadata(&path)) {
+                (Some(saved), Ok(meta)) if saved.id == file_id(&meta) => saved.offset,
+                _ => 0,
+            };
+            let mut entries = vec![];
+            let res = TailedFile::open(&path, offset, self.start.clone())
+                .and_then(|mut file| file.read(&mut entries).map(|_| file));
+            match res {
+                Ok(file) => {
+                    self.files.insert(path, file);
+                }
+                Err(e) => log::warn!("opening {}: {}", path.display(), e),
+            }
+            lines.push((key, entries));
+        }
+
+        let format = self.input.format;
+        lines
+            .iter()
+            .flat_map(|(path, entries)| {
+                entries.iter().filter_map(move |line| {
+                    line_to_record(format, line, path, received)
+                        .map_err(|e| log::warn!("dropping line of {}: {}", path, e))
+                        .ok()
+                })
+            })
+            .collect()
+    }
+
+    /// Closes every file, the next poll opens them again at the saved
+    /// offsets. Used when the records of a poll could not be written.
+    pub fn rewind(&mut self) {
+        self.files.clear();
+    }
+
+    pub fn offsets(&self) -> impl Iterator<Item = (String, FileOffset)> + '_ {
+        self.files.values().map(|f| {
+            (
+                f.path().display().to_string(),
+                FileOffset {
+                    id: f.id(),
+                    offset: f.offset(),
+                },
+            )
+        })
+    }
+}
+
+// written to a temporary file first so a crash never leaves half of it
+fn save_offsets(path: &Path, offsets: &BTreeMap<String, FileOffset>) -> Result<(), anyhow::Error> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(offsets)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn load_offsets(path: &Path) -> BTreeMap<String, FileOffset> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            log::warn!("ignoring offsets in {}: {}", path.display(), e);
+            BTreeMap::new()
+        }),
+        Err(_) => BTreeMap::new(),
+    }
+}
+
+/// Starts polling the configured inputs, does nothing when there are
+/// none.
+pub fn start(app: web::Data<AppState>, config: &TailConfig) -> std::io::Result<()> {
+    if config.inputs.is_empty() {
+        return Ok(());
+    }
+    let mut tailers = config
+        .inputs
+        .iter()
+        .map(|input| Tailer::new(input.clone()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let offsets_path = Path::new(app.service().storage().root()).join(OFFSETS_FILE);
+    let mut offsets = load_offsets(&offsets_path);
+    let interval = Duration::from_millis(config.poll_interval_ms.max(1));
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let received = chrono::Utc::now().timestamp_micros();
+            // files are read on the blocking pool, the tailers go and come
+            // back with the records
+            let saved = offsets.clone();
+            let res = tokio::task::spawn_blocking(move || {
+                let records: Vec<Vec<Value>> = tailers
+                    .iter_mut()
+                    .map(|t| t.poll(&saved, received))
+                    .collect();
+                (tailers, records)
+            })
+            .await;
+            let records;
+            (tailers, records) = match res {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("file tailing stopped: {}", e);
+                    return;
+                }
+            };
+            // the offsets only move past records that were written, the
+            // files of a tailer that failed are read again from the saved ones
+            let mut current = BTreeMap::new();
+            for (tailer, records) in tailers.iter_mut().zip(records) {
+                let res = if records.is_empty() {
+                    Ok(())
+                } else {
+                    app.service().ingest_records(tailer.table(), &records).await
+                };
+                match res {
+                    Ok(()) => current.extend(tailer.offsets()),
+                    Err(e) => {
+                        log::error!("Error writing records to {}: {:?}", tailer.table(), e);
+                        for (path, _) in tailer.offsets() {
+                            if let Some(saved) = offsets.get(&path) {
+                                current.insert(path, saved.clone());
+                            }
+                        }
+                        tailer.rewind();
+                    }
+                }
+            }
+            if current != offsets {
+                offsets = current;
+                let (path, saved) = (offsets_path.clone(), offsets.clone());
+                let res = tokio::task::spawn_blocking(move || save_offsets(&path, &saved))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|res| res);
+                if let Err(e) = res {
+                    log::error!("saving {}: {}", offsets_path.display(), e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_line_to_record() {
+        let record = line_to_record(
+            LineFormat::Json,
+            r#"{"level":"info","timestamp":"x","http":{"status":200}}"#,
+            "/a.log",
+            7,
+        )
+        .unwrap();
+        assert_eq!(
+            record,
+            json!({
+                "level": "info",
+                "http.status": 200,
+                "exported_timestamp": "x",
+                "timestamp": 7,
+                "log.file.path": "/a.log"
+            })
+        );
+        let record = line_to_record(LineFormat::Json, "not json", "/a.log", 7).unwrap();
+        assert_eq!(record["message"], "not json");
+        let record = line_to_record(LineFormat::Text, r#"{"level":"info"}"#, "/a.log", 7).unwrap();
+        assert_eq!(record["message"], r#"{"level":"info"}"#);
+    }
+
+    #[test]
+    fn test_tailer() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = TailInput {
+            paths: vec![format!("{}/*.log", dir.path().display())],
+            table: "files".to_owned(),
+            format: LineFormat::Text,
+            multiline_start: None,
+        };
+        let a = dir.path().join("a.log");
+        std::fs::write(&a, "a1\na2\n").unwrap();
+        std::fs::write(dir.path().join("skip.txt"), "x\n").unwrap();
+
+        let mut tailer = Tailer::new(input.clone()).unwrap();
+        let records = tailer.poll(&BTreeMap::new(), 1);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["message"], "a1");
+
+        std::fs::write(dir.path().join("b.log"), "b1\n").unwrap();
+        let records = tailer.poll(&BTreeMap::new(), 2);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["message"], "b1");
+        let offsets: BTreeMap<String, FileOffset> = tailer.offsets().collect();
+        assert_eq!(offsets[&a.display().to_string()].offset, 6);
+
+        // a new tailer resumes at the saved offsets
+        std::fs::write(&a, "a1\na2\na3\n").unwrap();
+        let mut tailer = Tailer::new(input.clone()).unwrap();
+        let records = tailer.poll(&offsets, 3);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["message"], "a3");
+
+        // after a failed write the lines since the saved offsets come again
+        tailer.rewind();
+        let records = tailer.poll(&offsets, 4);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["message"], "a3");
+
+        let path = dir.path().join(OFFSETS_FILE);
+        save_offsets(&path, &offsets).unwrap();
+        assert_eq!(load_offsets(&path), offsets);
+
+        assert!(Tailer::new(TailInput {
+            table: "a/b".to_owned(),
+            ..input
+        })
+        .is_err());
+    }
+}
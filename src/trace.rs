@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde_derive::Serialize;
+use serde_json::{Map, Value};
+
+use crate::otlp::traces::{DURATION, END_TIME, PARENT_SPAN_ID, SERVICE_NAME, SPAN_ID, START_TIME};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TraceSpan {
+    #[serde(flatten)]
+    pub span: Map<String, Value>,
+    pub depth: usize,
+    pub children: Vec<TraceSpan>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ServiceDuration {
+    pub spans: usize,
+    // sum of the span durations of the service
+    pub duration: i64,
+    // duration not covered by child spans
+    pub self_duration: i64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Trace {
+    pub trace_id: String,
+    pub span_count: usize,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub duration: i64,
+    pub critical_path: Vec<String>,
+    pub services: BTreeMap<String, ServiceDuration>,
+    pub roots: Vec<TraceSpan>,
+}
+
+struct Node {
+    span: Map<String, Value>,
+    id: String,
+    start: i64,
+    end: i64,
+    children: Vec<usize>,
+}
+
+fn get_i64(span: &Map<String, Value>, key: &str) -> i64 {
+    span.get(key).and_then(Value::as_i64).unwrap_or_default()
+}
+
+fn get_str<'a>(span: &'a Map<String, Value>, key: &str) -> &'a str {
+    span.get(key).and_then(Value::as_str).unwrap_or_default()
+}
+
+impl Trace {
+    /// Links the spans of one trace into a parent/child tree. Spans whose
+    /// parent is missing become roots.
+    pub fn build(trace_id: &str, spans: Vec<Map<String, Value>>) -> Trace {
+        let mut nodes: Vec<Node> = spans
+            .into_iter()
+            .map(|span| Node {
+                id: get_str(&span, SPAN_ID).to_owned(),
+                start: get_i64(&span, START_TIME),
+                end: get_i64(&span, END_TIME),
+                children: vec![],
+                span,
+            })
+            .collect();
+        // a span id can show up twice when a batch was retried, keep one
+        let mut seen = HashSet::new();
+        nodes.retain(|n| seen.insert(n.id.clone()));
+        let index: HashMap<String, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.id.clone(), i))
+            .collect();
+
+        let mut roots = vec![];
+        for i in 0..nodes.len() {
+            let parent = get_str(&nodes[i].span, PARENT_SPAN_ID);
+            match index.get(parent) {
+                Some(&p) if p != i => nodes[p].children.push(i),
+                _ => roots.push(i),
+            }
+        }
+        let starts: Vec<i64> = nodes.iter().map(|n| n.start).collect();
+        for node in nodes.iter_mut() {
+            node.children.sort_by_key(|&c| starts[c]);
+        }
+        roots.sort_by_key(|&r| starts[r]);
+
+        // spans caught in a parent cycle are not reachable from a root
+        let mut visited = vec![false; nodes.len()];
+        for &root in &roots {
+            mark(&nodes, root, &mut visited);
+        }
+        for i in 0..nodes.len() {
+            if !visited[i] {
+                roots.push(i);
+                mark(&nodes, i, &mut visited);
+            }
+        }
+
+        let start_time = nodes.iter().map(|n| n.start).min().unwrap_or_default();
+        let end_time = nodes.iter().map(|n| n.end).max().unwrap_or_default();
+
+        let mut services: BTreeMap<String, ServiceDuration> = BTreeMap::new();
+        for node in &nodes {
+            let service = get_str(&node.span, SERVICE_NAME);
+            let entry = services.entry(service.to_owned()).or_default();
+            let duration = get_i64(&node.span, DURATION);
+            entry.spans += 1;
+            entry.duration += duration;
+            entry.self_duration += self_duration(&nodes, node);
+        }
+
+        let mut critical_path = vec![];
+        if let Some(&root) = roots.iter().max_by_key(|&&r| nodes[r].end - nodes[r].start) {
+            let mut path = vec![];
+            walk_critical_path(&nodes, root, &mut path, &mut vec![false; nodes.len()]);
+            path.sort_by_key(|&i| nodes[i].start);
+            critical_path = path.into_iter().map(|i| nodes[i].id.clone()).collect();
+        }
+
+        let mut visited = vec![false; nodes.len()];
+        let roots = roots
+            .iter()
+            .map(|&r| to_tree(&nodes, r, 0, &mut visited))
+            .collect();
+
+        Trace {
+            trace_id: trace_id.to_owned(),
+            span_count: nodes.len(),
+            start_time,
+            end_time,
+            duration: end_time - start_time,
+            critical_path,
+            services,
+            roots,
+        }
+    }
+}
+
+fn mark(nodes: &[Node], i: usize, visited: &mut [bool]) {
+    if visited[i] {
+        return;
+    }
+    visited[i] = true;
+    for &c in &nodes[i].children {
+        mark(nodes, c, visited);
+    }
+}
+
+fn to_tree(nodes: &[Node], i: usize, depth: usize, visited: &mut [bool]) -> TraceSpan {
+    visited[i] = true;
+    let mut children = vec![];
+    for &c in &nodes[i].children {
+        if !visited[c] {
+            children.push(to_tree(nodes, c, depth + 1, visited));
+        }
+    }
+    TraceSpan {
+        span: nodes[i].span.clone(),
+        depth,
+        children,
+    }
+}
+
+// time of the span during which none of its children was running
+fn self_duration(nodes: &[Node], node: &Node) -> i64 {
+    let mut covered = 0;
+    let mut cursor = node.start;
+    for &c in &node.children {
+        let start = nodes[c].start.max(cursor);
+        let end = nodes[c].end.min(node.end);
+        if end > start {
+            covered += end - start;
+            cursor = end;
+        }
+    }
+    (node.end - node.start - covered).max(0)
+}
+
+// walks back from the end of a span: the child finishing last is on the
+// critical path, then the child finishing last before that one started
+fn walk_critical_path(nodes: &[Node], i: usize, path: &mut Vec<usize>, visited: &mut [bool]) {
+    if visited[i] {
+        return;
+    }
+    visited[i] = true;
+    path.push(i);
+    let mut children = nodes[i].children.clone();
+    children.sort_by_key(|&c| std::cmp::Reverse(nodes[c].end));
+    let mut cursor = nodes[i].end;
+    for c in children {
+        if nodes[c].end <= cursor && nodes[c].end > nodes[i].start {
+            walk_critical_path(nodes, c, path, visited);
+            cursor = nodes[c].start;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn span(id: &str, parent: &str, service: &str, start: i64, end: i64) -> Map<String, Value> {
+        let span = json!({
+            "span_id": id,
+            "parent_span_id": parent,
+            "resource.service.name": service,
+            "start_time": start,
+            "end_time": end,
+            "duration": end - start,
+        });
+        span.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_build_trace() {
+        let spans = vec![
+            span("c", "a", "db", 60, 90),
+            span("a", "", "api", 0, 100),
+            span("b", "a", "auth", 10, 40),
+            span("d", "c", "db", 65, 80),
+            span("e", "missing", "worker", 120, 130),
+        ];
+        let trace = Trace::build("t1", spans);
+        assert_eq!(trace.span_count, 5);
+        assert_eq!(trace.start_time, 0);
+        assert_eq!(trace.end_time, 130);
+        assert_eq!(trace.roots.len(), 2);
+
+        let root = &trace.roots[0];
+        assert_eq!(root.span["span_id"], "a");
+        assert_eq!(root.depth, 0);
+        let children: Vec<_> = root.children.iter().map(|c| &c.span["span_id"]).collect();
+        assert_eq!(children, vec!["b", "c"]);
+        assert_eq!(root.children[1].children[0].depth, 2);
+        assert_eq!(trace.roots[1].span["span_id"], "e");
+
+        assert_eq!(trace.critical_path, vec!["a", "b", "c", "d"]);
+
+        let api = &trace.services["api"];
+        assert_eq!(api.spans, 1);
+        assert_eq!(api.duration, 100);
+        assert_eq!(api.self_duration, 40);
+        let db = &trace.services["db"];
+        assert_eq!(db.spans, 2);
+        assert_eq!(db.duration, 45);
+        assert_eq!(db.self_duration, 30);
+    }
+
+    #[test]
+    fn test_build_trace_cycle() {
+        let spans = vec![span("a", "b", "x", 0, 10), span("b", "a", "x", 0, 10)];
+        let trace = Trace::build("t1", spans);
+        assert_eq!(trace.roots.len(), 1);
+        assert_eq!(trace.roots[0].children.len(), 1);
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+/// A fixed size bloom filter over strings, used to skip segments that can
+/// not hold a value without opening them.
+#[derive(Clone, Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Sizes the filter for `items` entries at false positive rate `fpp`.
+    pub fn with_capacity(items: usize, fpp: f64) -> BloomFilter {
+        let items = items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-items * fpp.ln() / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let hashes = ((bits as f64 / items) * ln2).round().clamp(1.0, 16.0) as u32;
+        BloomFilter {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes,
+        }
+    }
+
+    // double hashing, the two halves of one 64 bit hash give every probe
+    fn probes(&self, item: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash as u32 as u64, hash >> 32);
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    pub fn insert(&mut self, item: &str) {
+        for bit in self.probes(item).collect::<Vec<_>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn contains(&self, item: &str) -> bool {
+        self.probes(item)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::with_capacity(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(&format!("trace-{}", i));
+        }
+        assert!((0..1000).all(|i| filter.contains(&format!("trace-{}", i))));
+        let false_positives = (1000..11000)
+            .filter(|i| filter.contains(&format!("trace-{}", i)))
+            .count();
+        assert!(false_positives < 300, "{}", false_positives);
+    }
+}
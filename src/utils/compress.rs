@@ -0,0 +1,401 @@
+use std::io::{Read, Write};
+
+use anyhow::anyhow;
+use bytes::{Bytes, BytesMut};
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+// snappy framing format starts with this stream identifier chunk, bodies
+// without it are treated as a raw snappy block (as prometheus/loki send them)
+static SNAPPY_STREAM_IDENTIFIER: &[u8] = b"\xff\x06\x00\x00sNaPpY";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Zstd,
+    Snappy,
+}
+
+impl ContentEncoding {
+    pub fn from_header(value: Option<&str>) -> Result<ContentEncoding, anyhow::Error> {
+        let value = match value {
+            Some(v) => v.trim().to_ascii_lowercase(),
+            None => return Ok(ContentEncoding::Identity),
+        };
+        match value.as_str() {
+            "" | "identity" => Ok(ContentEncoding::Identity),
+            "gzip" | "x-gzip" => Ok(ContentEncoding::Gzip),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            "zstd" => Ok(ContentEncoding::Zstd),
+            "snappy" => Ok(ContentEncoding::Snappy),
+            _ => Err(anyhow!("unsupported content encoding: {}", value)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Snappy => "snappy",
+        }
+    }
+}
+
+/// Decompresses `body` according to `encoding`, failing once the decoded
+/// payload grows beyond `limit` bytes.
+pub fn decompress(
+    encoding: ContentEncoding,
+    body: Bytes,
+    limit: usize,
+) -> Result<Bytes, anyhow::Error> {
+    match encoding {
+        ContentEncoding::Identity => {
+            check_limit(body.len(), limit)?;
+            Ok(body)
+        }
+        ContentEncoding::Gzip => read_limited(encoding, GzDecoder::new(body.as_ref()), limit),
+        ContentEncoding::Deflate => read_limited(encoding, ZlibDecoder::new(body.as_ref()), limit),
+        ContentEncoding::Zstd => {
+            let decoder = zstd::stream::read::Decoder::new(body.as_ref())
+                .map_err(|e| anyhow!("malformed zstd body: {}", e))?;
+            read_limited(encoding, decoder, limit)
+        }
+        ContentEncoding::Snappy => {
+            if body.starts_with(SNAPPY_STREAM_IDENTIFIER) {
+                read_limited(
+                    encoding,
+                    snap::read::FrameDecoder::new(body.as_ref()),
+                    limit,
+                )
+            } else {
+                decompress_snappy_block(&body, limit)
+            }
+        }
+    }
+}
+
+/// Incremental counterpart of [`decompress`] for bodies read chunk by chunk.
+/// Each chunk may decode to at most `limit` bytes, and a snappy body, which
+/// is only decoded at the end, to `limit` bytes in total.
+pub enum StreamDecoder {
+    Identity,
+    Gzip(flate2::write::MultiGzDecoder<LimitedBuf>),
+    Deflate(flate2::write::ZlibDecoder<LimitedBuf>),
+    // the zio writer, unlike `write::Decoder`, tells a truncated frame on
+    // finish
+    Zstd(zstd::stream::zio::Writer<LimitedBuf, zstd::stream::raw::Decoder<'static>>),
+    // snappy bodies can not be decoded in pieces, so they are buffered up to
+    // the limit and decompressed once the stream ends
+    Snappy(BytesMut, usize),
+}
+
+/// The sink of the streaming decoders, it refuses to grow past `limit`
+/// bytes so that one small chunk can not inflate without bound.
+pub struct LimitedBuf {
+    datas: Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl LimitedBuf {
+    fn new(limit: usize) -> LimitedBuf {
+        LimitedBuf {
+            datas: Vec::new(),
+            limit,
+            exceeded: false,
+        }
+    }
+
+    fn take(&mut self) -> Bytes {
+        std::mem::take(&mut self.datas).into()
+    }
+}
+
+impl Write for LimitedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.datas.len() + buf.len() > self.limit {
+            self.exceeded = true;
+            return Err(std::io::Error::other("decoded chunk too large"));
+        }
+        self.datas.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StreamDecoder {
+    pub fn new(encoding: ContentEncoding, limit: usize) -> Result<StreamDecoder, anyhow::Error> {
+        let sink = LimitedBuf::new(limit);
+        Ok(match encoding {
+            ContentEncoding::Identity => StreamDecoder::Identity,
+            ContentEncoding::Gzip => StreamDecoder::Gzip(flate2::write::MultiGzDecoder::new(sink)),
+            ContentEncoding::Deflate => {
+                StreamDecoder::Deflate(flate2::write::ZlibDecoder::new(sink))
+            }
+            ContentEncoding::Zstd => StreamDecoder::Zstd(zstd::stream::zio::Writer::new(
+                sink,
+                zstd::stream::raw::Decoder::new()?,
+            )),
+            ContentEncoding::Snappy => StreamDecoder::Snappy(BytesMut::new(), limit),
+        })
+    }
+
+    fn encoding(&self) -> ContentEncoding {
+        match self {
+            StreamDecoder::Identity => ContentEncoding::Identity,
+            StreamDecoder::Gzip(_) => ContentEncoding::Gzip,
+            StreamDecoder::Deflate(_) => ContentEncoding::Deflate,
+            StreamDecoder::Zstd(_) => ContentEncoding::Zstd,
+            StreamDecoder::Snappy(..) => ContentEncoding::Snappy,
+        }
+    }
+
+    /// Feeds the next chunk of the body and returns what could be decoded so far.
+    pub fn decode(&mut self, chunk: Bytes) -> Result<Bytes, anyhow::Error> {
+        let encoding = self.encoding();
+        match self {
+            StreamDecoder::Identity => Ok(chunk),
+            StreamDecoder::Gzip(w) => {
+                let res = w.write_all(&chunk).and_then(|_| w.flush());
+                drain(encoding, res, w.get_mut())
+            }
+            StreamDecoder::Deflate(w) => {
+                let res = w.write_all(&chunk).and_then(|_| w.flush());
+                drain(encoding, res, w.get_mut())
+            }
+            StreamDecoder::Zstd(w) => {
+                let res = w.write_all(&chunk).and_then(|_| w.flush());
+                drain(encoding, res, w.writer_mut())
+            }
+            StreamDecoder::Snappy(buf, limit) => {
+                if buf.len() + chunk.len() > *limit {
+                    return Err(anyhow!("snappy body exceeds the limit of {} bytes", limit));
+                }
+                buf.extend_from_slice(&chunk);
+                Ok(Bytes::new())
+            }
+        }
+    }
+
+    /// Ends the stream, returning the remaining decoded bytes and failing on
+    /// truncated input.
+    pub fn finish(self) -> Result<Bytes, anyhow::Error> {
+        let encoding = self.encoding();
+        match self {
+            StreamDecoder::Identity => Ok(Bytes::new()),
+            StreamDecoder::Gzip(mut w) => {
+                let res = w.try_finish();
+                drain(encoding, res, w.get_mut())
+            }
+            StreamDecoder::Deflate(mut w) => {
+                let res = w.try_finish();
+                drain(encoding, res, w.get_mut())
+            }
+            StreamDecoder::Zstd(mut w) => {
+                let res = w.finish();
+                drain(encoding, res, w.writer_mut())
+            }
+            StreamDecoder::Snappy(buf, limit) => {
+                decompress(ContentEncoding::Snappy, buf.freeze(), limit)
+            }
+        }
+    }
+}
+
+// the bytes a write to a streaming decoder produced, or why it failed
+fn drain(
+    encoding: ContentEncoding,
+    res: std::io::Result<()>,
+    sink: &mut LimitedBuf,
+) -> Result<Bytes, anyhow::Error> {
+    if sink.exceeded {
+        return Err(anyhow!(
+            "decompressed chunk exceeds the limit of {} bytes",
+            sink.limit
+        ));
+    }
+    res.map_err(|e| anyhow!("malformed {} body: {}", encoding.as_str(), e))?;
+    Ok(sink.take())
+}
+
+fn decompress_snappy_block(body: &[u8], limit: usize) -> Result<Bytes, anyhow::Error> {
+    let len =
+        snap::raw::decompress_len(body).map_err(|e| anyhow!("malformed snappy body: {}", e))?;
+    check_limit(len, limit)?;
+    let datas = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|e| anyhow!("malformed snappy body: {}", e))?;
+    Ok(datas.into())
+}
+
+fn read_limited<R: Read>(
+    encoding: ContentEncoding,
+    reader: R,
+    limit: usize,
+) -> Result<Bytes, anyhow::Error> {
+    let mut datas = Vec::new();
+    // read one byte past the limit to tell "exactly limit" from "too large"
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut datas)
+        .map_err(|e| anyhow!("malformed {} body: {}", encoding.as_str(), e))?;
+    check_limit(datas.len(), limit)?;
+    Ok(datas.into())
+}
+
+fn check_limit(len: usize, limit: usize) -> Result<(), anyhow::Error> {
+    if len > limit {
+        return Err(anyhow!(
+            "decompressed body exceeds the limit of {} bytes",
+            limit
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    static DATA: &str = r#"{"a": 1, "b": "zhong"}
+{"a": 2, "b": "liu"}"#;
+
+    fn gzip(data: &[u8]) -> Bytes {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap().into()
+    }
+
+    #[test]
+    fn test_from_header() {
+        assert_eq!(
+            ContentEncoding::from_header(None).unwrap(),
+            ContentEncoding::Identity
+        );
+        assert_eq!(
+            ContentEncoding::from_header(Some(" GZIP")).unwrap(),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            ContentEncoding::from_header(Some("snappy")).unwrap(),
+            ContentEncoding::Snappy
+        );
+        assert!(ContentEncoding::from_header(Some("br")).is_err());
+    }
+
+    #[test]
+    fn test_decompress() {
+        let res = decompress(ContentEncoding::Gzip, gzip(DATA.as_bytes()), 1024).unwrap();
+        assert_eq!(res, DATA.as_bytes());
+
+        let body = zstd::encode_all(DATA.as_bytes(), 0).unwrap();
+        let res = decompress(ContentEncoding::Zstd, body.into(), 1024).unwrap();
+        assert_eq!(res, DATA.as_bytes());
+
+        let body = snap::raw::Encoder::new()
+            .compress_vec(DATA.as_bytes())
+            .unwrap();
+        let res = decompress(ContentEncoding::Snappy, body.into(), 1024).unwrap();
+        assert_eq!(res, DATA.as_bytes());
+
+        let mut encoder = snap::write::FrameEncoder::new(Vec::new());
+        encoder.write_all(DATA.as_bytes()).unwrap();
+        let body = encoder.into_inner().unwrap();
+        let res = decompress(ContentEncoding::Snappy, body.into(), 1024).unwrap();
+        assert_eq!(res, DATA.as_bytes());
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let data = vec![b'a'; 4096];
+        let err = decompress(ContentEncoding::Gzip, gzip(&data), 1024).unwrap_err();
+        assert!(err.to_string().contains("exceeds"));
+        assert!(decompress(ContentEncoding::Gzip, gzip(&data), 4096).is_ok());
+
+        let body = snap::raw::Encoder::new().compress_vec(&data).unwrap();
+        assert!(decompress(ContentEncoding::Snappy, body.into(), 1024).is_err());
+    }
+
+    fn decode_chunked(encoding: ContentEncoding, body: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut decoder = StreamDecoder::new(encoding, 1024)?;
+        let mut res = Vec::new();
+        for chunk in body.chunks(7) {
+            res.extend_from_slice(&decoder.decode(Bytes::copy_from_slice(chunk))?);
+        }
+        res.extend_from_slice(&decoder.finish()?);
+        Ok(res)
+    }
+
+    #[test]
+    fn test_stream_decoder() {
+        let res = decode_chunked(ContentEncoding::Identity, DATA.as_bytes()).unwrap();
+        assert_eq!(res, DATA.as_bytes());
+
+        let res = decode_chunked(ContentEncoding::Gzip, &gzip(DATA.as_bytes())).unwrap();
+        assert_eq!(res, DATA.as_bytes());
+
+        let body = zstd::encode_all(DATA.as_bytes(), 0).unwrap();
+        let res = decode_chunked(ContentEncoding::Zstd, &body).unwrap();
+        assert_eq!(res, DATA.as_bytes());
+
+        let body = snap::raw::Encoder::new()
+            .compress_vec(DATA.as_bytes())
+            .unwrap();
+        let res = decode_chunked(ContentEncoding::Snappy, &body).unwrap();
+        assert_eq!(res, DATA.as_bytes());
+
+        let body = gzip(DATA.as_bytes());
+        let err = decode_chunked(ContentEncoding::Gzip, &body[..body.len() / 2]).unwrap_err();
+        assert!(err.to_string().starts_with("malformed gzip body"));
+
+        let body = zstd::encode_all(DATA.as_bytes(), 0).unwrap();
+        let err = decode_chunked(ContentEncoding::Zstd, &body[..body.len() - 4]).unwrap_err();
+        assert!(err.to_string().starts_with("malformed zstd body"));
+    }
+
+    #[test]
+    fn test_stream_decoder_limit() {
+        // a few KiB that inflate to 64 MiB, sent as a single chunk
+        let data = vec![b'a'; 64 * 1024 * 1024];
+        let bodies = [
+            (ContentEncoding::Gzip, gzip(&data)),
+            (
+                ContentEncoding::Zstd,
+                zstd::encode_all(&data[..], 19).unwrap().into(),
+            ),
+        ];
+        for (encoding, body) in bodies {
+            assert!(body.len() < 128 * 1024);
+            let mut decoder = StreamDecoder::new(encoding, 1024 * 1024).unwrap();
+            let err = decoder.decode(body).unwrap_err();
+            assert!(err.to_string().contains("exceeds the limit"));
+        }
+
+        // chunks under the limit each go through, however long the body
+        let body = gzip(&data[..4 * 1024 * 1024]);
+        let mut decoder = StreamDecoder::new(ContentEncoding::Gzip, 1024 * 1024).unwrap();
+        let mut len = 0;
+        for chunk in body.chunks(64) {
+            len += decoder.decode(Bytes::copy_from_slice(chunk)).unwrap().len();
+        }
+        len += decoder.finish().unwrap().len();
+        assert_eq!(len, 4 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_decompress_malformed() {
+        let body = Bytes::from_static(b"not compressed");
+        let err = decompress(ContentEncoding::Gzip, body.clone(), 1024).unwrap_err();
+        assert!(err.to_string().starts_with("malformed gzip body"));
+        assert!(decompress(ContentEncoding::Zstd, body.clone(), 1024).is_err());
+        assert!(decompress(ContentEncoding::Snappy, body, 1024).is_err());
+    }
+}
@@ -19,6 +19,64 @@ pub fn parse_lines(body: Bytes) -> Result<Vec<Value>, anyhow::Error> {
     Ok(records)
 }
 
+/// Splits an NDJSON byte stream into lines, keeping the trailing partial
+/// line until the chunk that completes it arrives.
+pub struct LineDecoder {
+    buf: Vec<u8>,
+    max_line_size: usize,
+}
+
+impl LineDecoder {
+    pub fn new(max_line_size: usize) -> LineDecoder {
+        LineDecoder {
+            buf: Vec::new(),
+            max_line_size,
+        }
+    }
+
+    pub fn decode<F>(&mut self, chunk: &[u8], mut f: F) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), anyhow::Error>,
+    {
+        match chunk.iter().rposition(|&b| b == b'\n') {
+            Some(pos) => {
+                self.buf.extend_from_slice(&chunk[..pos]);
+                for line in self.buf.split(|&b| b == b'\n') {
+                    emit_line(line, &mut f)?;
+                }
+                self.buf.clear();
+                self.buf.extend_from_slice(&chunk[pos + 1..]);
+            }
+            None => self.buf.extend_from_slice(chunk),
+        }
+        if self.buf.len() > self.max_line_size {
+            return Err(anyhow::anyhow!(
+                "line exceeds the limit of {} bytes",
+                self.max_line_size
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn finish<F>(self, mut f: F) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), anyhow::Error>,
+    {
+        emit_line(&self.buf, &mut f)
+    }
+}
+
+fn emit_line<F>(line: &[u8], f: &mut F) -> Result<(), anyhow::Error>
+where
+    F: FnMut(&[u8]) -> Result<(), anyhow::Error>,
+{
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.is_empty() {
+        return Ok(());
+    }
+    f(line)
+}
+
 pub fn parse_json(body: Bytes) -> Result<Vec<Value>, anyhow::Error> {
     let val: Value = serde_json::from_slice(&body)?;
     let records = if let Value::Array(val) = val {
@@ -75,6 +133,27 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_line_decoder() {
+        let data = "{\"a\": 1}\r\n\n{\"a\": 2, \"b\": \"zhong\"}\n{\"a\": 3}";
+        let mut decoder = LineDecoder::new(64);
+        let mut records: Vec<Value> = vec![];
+        let mut parse = |line: &[u8]| {
+            records.push(serde_json::from_slice(line)?);
+            Ok(())
+        };
+        for chunk in data.as_bytes().chunks(5) {
+            decoder.decode(chunk, &mut parse).unwrap();
+        }
+        decoder.finish(&mut parse).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1]["b"], Value::from("zhong"));
+        assert_eq!(records[2]["a"], Value::from(3));
+
+        let mut decoder = LineDecoder::new(4);
+        assert!(decoder.decode(b"{\"a\": 1}", |_| Ok(())).is_err());
+    }
+
     #[test]
     fn test_flatten_json() {
         let data = json!({
@@ -1,2 +1,5 @@
+pub mod bloom;
+pub mod compress;
 pub mod json;
+pub mod net;
 pub mod time;
@@ -0,0 +1,30 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::net::{TcpListener, TcpStream};
+
+// pause after a failed accept, long enough for descriptors to be freed
+static ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Binds the listener of the `name` service to be handed to tokio. Called
+/// while starting up, before serving, so a taken port fails the startup.
+pub fn bind_tcp(addr: &str, name: &str) -> std::io::Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    log::info!("{} listening on {}", name, addr);
+    Ok(listener)
+}
+
+/// Accepts the next connection. A failed accept, like running out of file
+/// descriptors or a connection aborted while queued, is logged and retried
+/// after a pause instead of ending the listener.
+pub async fn accept(listener: &TcpListener, name: &str) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(v) => return v,
+            Err(e) => {
+                log::warn!("{} listener failed to accept: {}", name, e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
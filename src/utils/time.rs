@@ -1,5 +1,5 @@
-use chrono::NaiveDateTime;
 use chrono::{DateTime, TimeZone, Utc};
+use chrono::{NaiveDate, NaiveDateTime};
 use once_cell::sync::Lazy;
 use serde_json::Value;
 
@@ -68,6 +68,21 @@ pub fn parse_str_to_timestamp_micros(v: &str) -> Result<i64, anyhow::Error> {
     }
 }
 
+/// Parses `v` with a chrono format, times without a zone are read as UTC
+/// and dates as their midnight.
+pub fn parse_str_with_format_to_timestamp_micros(v: &str, fmt: &str) -> Result<i64, anyhow::Error> {
+    if let Ok(t) = DateTime::parse_from_str(v, fmt) {
+        return Ok(t.timestamp_micros());
+    }
+    if let Ok(t) = NaiveDateTime::parse_from_str(v, fmt) {
+        return Ok(t.and_utc().timestamp_micros());
+    }
+    match NaiveDate::parse_from_str(v, fmt) {
+        Ok(d) => Ok(d.and_time(Default::default()).and_utc().timestamp_micros()),
+        Err(_) => Err(anyhow::anyhow!("invalid time {:?} for format {:?}", v, fmt)),
+    }
+}
+
 #[inline(always)]
 pub fn parse_str_to_time(s: &str) -> Result<DateTime<Utc>, anyhow::Error> {
     if let Ok(v) = s.parse::<f64>() {
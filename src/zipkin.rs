@@ -0,0 +1,229 @@
+//! Zipkin v2 JSON span ingestion. Spans are mapped onto the OTLP span
+//! columns, so both kinds of spans can share a table and its trace view.
+
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use serde_derive::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    config::{TIMPSTAMP_FIELD_NAME, TRACE_ID_FIELD_NAME},
+    otlp::traces::{
+        DURATION, END_TIME, KIND, NAME, PARENT_SPAN_ID, SERVICE_NAME, SPAN_ID, START_TIME,
+        STATUS_CODE, STATUS_MESSAGE,
+    },
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Endpoint {
+    pub service_name: Option<String>,
+    pub ipv4: Option<String>,
+    pub ipv6: Option<String>,
+    pub port: Option<u16>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Annotation {
+    pub timestamp: i64,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Span {
+    pub trace_id: String,
+    pub id: String,
+    pub parent_id: Option<String>,
+    pub name: Option<String>,
+    pub kind: Option<String>,
+    // microseconds since the epoch, like melt timestamps
+    pub timestamp: Option<i64>,
+    pub duration: Option<i64>,
+    pub debug: Option<bool>,
+    pub shared: Option<bool>,
+    pub local_endpoint: Option<Endpoint>,
+    pub remote_endpoint: Option<Endpoint>,
+    pub annotations: Vec<Annotation>,
+    pub tags: BTreeMap<String, String>,
+}
+
+pub fn decode(body: &[u8]) -> Result<Vec<Span>, anyhow::Error> {
+    Ok(serde_json::from_slice(body)?)
+}
+
+// ids are lower hex, 16 or 32 digits for trace ids and 16 for span ids
+fn parse_id(name: &str, id: &str, lengths: &[usize]) -> Result<String, anyhow::Error> {
+    if !lengths.contains(&id.len()) || hex::decode(id).is_err() {
+        return Err(anyhow!("invalid {}: {:?}", name, id));
+    }
+    Ok(id.to_ascii_lowercase())
+}
+
+fn insert_endpoint(record: &mut Map<String, Value>, prefix: &str, endpoint: &Endpoint) {
+    let fields = [
+        (
+            "service_name",
+            endpoint.service_name.clone().map(Value::from),
+        ),
+        ("ipv4", endpoint.ipv4.clone().map(Value::from)),
+        ("ipv6", endpoint.ipv6.clone().map(Value::from)),
+        ("port", endpoint.port.map(Value::from)),
+    ];
+    for (name, value) in fields {
+        if let Some(v) = value {
+            record.insert(format!("{}.{}", prefix, name), v);
+        }
+    }
+}
+
+/// Maps every span into one row with the columns OTLP spans get: the local
+/// service becomes `resource.service.name`, tags become `attributes.*` and
+/// annotations the `events` JSON text.
+pub fn spans_to_records(spans: &[Span]) -> Result<Vec<Value>, anyhow::Error> {
+    let mut records = vec![];
+    for span in spans {
+        let mut record = Map::new();
+        let trace_id = parse_id("traceId", &span.trace_id, &[16, 32])?;
+        let span_id = parse_id("id", &span.id, &[16])?;
+        let parent_id = match span.parent_id.as_deref() {
+            Some(id) if !id.is_empty() => parse_id("parentId", id, &[16])?,
+            // root spans get an empty parent so the column exists in every
+            // segment
+            _ => String::new(),
+        };
+        if let Some(start) = span.timestamp {
+            let duration = span.duration.unwrap_or_default();
+            let end = start.checked_add(duration).ok_or_else(|| {
+                anyhow!(
+                    "span {} ends out of range: timestamp {} duration {}",
+                    span_id,
+                    start,
+                    duration
+                )
+            })?;
+            record.insert(TIMPSTAMP_FIELD_NAME.to_owned(), Value::from(start));
+            record.insert(START_TIME.to_owned(), Value::from(start));
+            record.insert(END_TIME.to_owned(), Value::from(end));
+            record.insert(DURATION.to_owned(), Value::from(duration));
+        }
+        record.insert(TRACE_ID_FIELD_NAME.to_owned(), Value::from(trace_id));
+        record.insert(SPAN_ID.to_owned(), Value::from(span_id));
+        record.insert(PARENT_SPAN_ID.to_owned(), Value::from(parent_id));
+        record.insert(
+            NAME.to_owned(),
+            Value::from(span.name.as_deref().unwrap_or_default()),
+        );
+        let kind = span.kind.as_deref().map(str::to_ascii_lowercase);
+        record.insert(
+            KIND.to_owned(),
+            Value::from(kind.as_deref().unwrap_or("unspecified")),
+        );
+        // zipkin marks failed spans with an `error` tag holding the message
+        match span.tags.get("error") {
+            Some(message) => {
+                record.insert(STATUS_CODE.to_owned(), Value::from("error"));
+                if !message.is_empty() {
+                    record.insert(STATUS_MESSAGE.to_owned(), Value::from(message.as_str()));
+                }
+            }
+            None => {
+                record.insert(STATUS_CODE.to_owned(), Value::from("unset"));
+            }
+        }
+        if let Some(endpoint) = &span.local_endpoint {
+            if let Some(service) = &endpoint.service_name {
+                record.insert(SERVICE_NAME.to_owned(), Value::from(service.as_str()));
+            }
+            insert_endpoint(&mut record, "local_endpoint", endpoint);
+        }
+        if let Some(endpoint) = &span.remote_endpoint {
+            insert_endpoint(&mut record, "remote_endpoint", endpoint);
+        }
+        for (name, value) in [("debug", span.debug), ("shared", span.shared)] {
+            if let Some(v) = value {
+                record.insert(name.to_owned(), Value::from(v));
+            }
+        }
+        for (k, v) in &span.tags {
+            record.insert(format!("attributes.{}", k), Value::from(v.as_str()));
+        }
+        if !span.annotations.is_empty() {
+            let events: Vec<Value> = span
+                .annotations
+                .iter()
+                .map(|a| json!({"time": a.timestamp, "name": a.value, "attributes": {}}))
+                .collect();
+            record.insert(
+                "events".to_owned(),
+                Value::from(Value::from(events).to_string()),
+            );
+        }
+        records.push(Value::Object(record));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spans_to_records() {
+        let body = r#"[{
+            "traceId": "5AF7183FB1D4CF5F",
+            "id": "352bff9a74ca9ad2",
+            "parentId": "6b221d5bc9e6496c",
+            "name": "get /api",
+            "kind": "SERVER",
+            "timestamp": 1700000000000000,
+            "duration": 207000,
+            "shared": true,
+            "localEndpoint": {"serviceName": "backend", "ipv4": "192.168.99.101", "port": 9000},
+            "remoteEndpoint": {"ipv6": "::1"},
+            "annotations": [{"timestamp": 1700000000000010, "value": "wr"}],
+            "tags": {"http.method": "GET", "error": "timeout"}
+        }, {
+            "traceId": "5af7183fb1d4cf5f",
+            "id": "6b221d5bc9e6496c",
+            "name": "root"
+        }]"#;
+        let spans = decode(body.as_bytes()).unwrap();
+        let records = spans_to_records(&spans).unwrap();
+        assert_eq!(records.len(), 2);
+        let span = &records[0];
+        assert_eq!(span["trace_id"], "5af7183fb1d4cf5f");
+        assert_eq!(span["span_id"], "352bff9a74ca9ad2");
+        assert_eq!(span["parent_span_id"], "6b221d5bc9e6496c");
+        assert_eq!(span["kind"], "server");
+        assert_eq!(span["timestamp"], 1_700_000_000_000_000i64);
+        assert_eq!(span["end_time"], 1_700_000_000_207_000i64);
+        assert_eq!(span["duration"], 207_000);
+        assert_eq!(span["resource.service.name"], "backend");
+        assert_eq!(span["local_endpoint.port"], 9000);
+        assert_eq!(span["remote_endpoint.ipv6"], "::1");
+        assert_eq!(span["attributes.http.method"], "GET");
+        assert_eq!(span["status_code"], "error");
+        assert_eq!(span["status_message"], "timeout");
+        assert_eq!(span["shared"], true);
+        let events: Value = serde_json::from_str(span["events"].as_str().unwrap()).unwrap();
+        assert_eq!(events[0]["name"], "wr");
+
+        let root = &records[1];
+        assert_eq!(root["parent_span_id"], "");
+        assert_eq!(root["status_code"], "unset");
+        assert!(root.get("timestamp").is_none());
+
+        let spans = decode(br#"[{"traceId": "xyz", "id": "352bff9a74ca9ad2"}]"#).unwrap();
+        assert!(spans_to_records(&spans).is_err());
+
+        let spans = decode(
+            br#"[{"traceId": "5af7183fb1d4cf5f", "id": "352bff9a74ca9ad2",
+                  "timestamp": 9223372036854775000, "duration": 1000}]"#,
+        )
+        .unwrap();
+        assert!(spans_to_records(&spans).is_err());
+    }
+}